@@ -3,7 +3,6 @@ use proslam::stereo_frame_point_generator::{Frame, StereoFramePointGeneratorCfg}
 // use rerun::ColorModel;
 use rslam_core::Camera;
 use rslam_dataset_reader::kitti_reader::KittiReader;
-use rslam_sensor::HasStereoCamera;
 
 fn main() {
     env_logger::init();
@@ -13,7 +12,7 @@ fn main() {
     //     .unwrap();
 
     let mut reader = KittiReader::new("datasets/01");
-    reader.load_camera();
+    reader.load_camera().unwrap();
     reader.load_timestamp();
 
     let number_of_rows_image = reader.get_cameras()[0].rows();
@@ -22,24 +21,25 @@ fn main() {
     let cameras = reader.get_cameras();
     let cameras_pos = reader.get_cameres_pos();
 
-    let baseline_x = reader.baseline_pixel;
+    let stereo_camera = reader.get_stereo_camera().unwrap().clone();
     let frame_point_cfg = StereoFramePointGeneratorCfg::default();
     let mut frame_point_generator = frame_point_cfg
         .finalize(
             number_of_cols_image,
             number_of_rows_image,
-            cameras[0].clone(),
-            cameras[1].clone(),
-            baseline_x,
+            stereo_camera.clone(),
         )
         .unwrap();
     log::debug!("camera_left: {:?}, camera pos: {:?}", cameras[0], cameras_pos[0].matrix());
     log::debug!("camera_right: {:?}, camera pos: {:?}", cameras[1], cameras_pos[1].matrix());
-    log::debug!("baseline_x: {}", baseline_x);
-    log::debug!("focal_length_pixels: {}", cameras[0].model.params()[0]);
+    log::debug!("baseline_meters: {}", stereo_camera.baseline_meters());
+    log::debug!("focal_length_pixels: {}", stereo_camera.focal_length_pixels());
 
-    // while let Some((left, right)) = reader.get_stereo_frame() {
-    //     let _secs = reader.get_timestamp();
+    // while let Some(message) = reader.next_message().unwrap() {
+    //     let SensorData::StereoPair { left, right } = message.data else {
+    //         continue;
+    //     };
+    //     let secs = message.timestamp_seconds;
         // rec.set_time_seconds("sample_time", secs);
 
         // let mut frame = Frame::new(left.clone(), right.clone());
@@ -0,0 +1,138 @@
+//! Compares the pure Rust FAST and ORB implementations against OpenCV on a grayscale image.
+//!
+//! cargo run -p proslam --features native-features --example validate_native_features -- <image>
+use anyhow::{bail, Result};
+use opencv::{
+    core::{KeyPoint, KeyPointTraitConst, Mat, MatTraitConst, Vector},
+    features2d::{FastFeatureDetector, FastFeatureDetector_DetectorType, ORB_ScoreType, ORB},
+    imgcodecs,
+    prelude::Feature2DTrait,
+};
+use proslam::native_features::{fast::FastDetector, orb::OrbExtractor, OrbDescriptor};
+use rslam_core::{ImageView, Keypoint};
+
+const FAST_THRESHOLD: u8 = 15;
+// the learned pattern of OpenCV
+const PATCH_SIZE: usize = 31;
+const EDGE_THRESHOLD: usize = 31;
+
+fn main() -> Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("datasets/01/image_0/000000.png"));
+    let image = imgcodecs::imread(&path, imgcodecs::IMREAD_GRAYSCALE)?;
+    if image.empty() {
+        bail!("failed to load {}", path);
+    }
+    let gray_image = ImageView::<u8>::from_mat(&image)?;
+    println!("image: {} ({}x{})", path, image.cols(), image.rows());
+
+    let keypoints = validate_fast(&image, &gray_image)?;
+    validate_orb(&image, &gray_image, keypoints)?;
+    Ok(())
+}
+
+fn validate_fast(image: &Mat, gray_image: &ImageView) -> Result<Vec<Keypoint>> {
+    let mut detector = FastFeatureDetector::create(
+        FAST_THRESHOLD as i32,
+        true,
+        FastFeatureDetector_DetectorType::TYPE_9_16,
+    )?;
+    let mut keypoints_opencv = Vector::<KeyPoint>::new();
+    detector.detect_def(image, &mut keypoints_opencv)?;
+    let mut keypoints_opencv: Vec<(i32, i32, i32)> = keypoints_opencv
+        .iter()
+        .map(|keypoint| {
+            (
+                keypoint.pt().x as i32,
+                keypoint.pt().y as i32,
+                keypoint.response() as i32,
+            )
+        })
+        .collect();
+    keypoints_opencv.sort();
+
+    let keypoints_native = FastDetector::new(FAST_THRESHOLD, true).detect(gray_image);
+    let mut keypoints: Vec<(i32, i32, i32)> = keypoints_native
+        .iter()
+        .map(|keypoint| {
+            (
+                keypoint.pt.x as i32,
+                keypoint.pt.y as i32,
+                keypoint.response as i32,
+            )
+        })
+        .collect();
+    keypoints.sort();
+
+    let number_of_common = keypoints
+        .iter()
+        .filter(|keypoint| keypoints_opencv.binary_search(keypoint).is_ok())
+        .count();
+    println!(
+        "FAST: opencv {}, native {}, identical (position and score) {}",
+        keypoints_opencv.len(),
+        keypoints.len(),
+        number_of_common
+    );
+    if keypoints != keypoints_opencv {
+        bail!("FAST keypoints differ");
+    }
+    Ok(keypoints_native)
+}
+
+fn validate_orb(image: &Mat, gray_image: &ImageView, mut keypoints: Vec<Keypoint>) -> Result<()> {
+    let extractor = OrbExtractor::new(PATCH_SIZE, EDGE_THRESHOLD, true);
+    extractor.compute_orientations(gray_image, &mut keypoints);
+    let descriptors_native = extractor.compute(gray_image, &mut keypoints);
+
+    // identical keypoints including the native orientation
+    let mut keypoints_opencv: Vector<KeyPoint> = keypoints
+        .iter()
+        .map(Keypoint::to_opencv)
+        .collect::<opencv::Result<_>>()?;
+    let mut orb = ORB::create(
+        500,
+        1.2,
+        8,
+        extractor.edge_threshold() as i32,
+        0,
+        2,
+        ORB_ScoreType::HARRIS_SCORE,
+        PATCH_SIZE as i32,
+        FAST_THRESHOLD as i32,
+    )?;
+    let mut descriptors_opencv = Mat::default();
+    orb.compute(image, &mut keypoints_opencv, &mut descriptors_opencv)?;
+    if keypoints_opencv.len() != keypoints.len() {
+        bail!(
+            "ORB kept {} keypoints, native {}",
+            keypoints_opencv.len(),
+            keypoints.len()
+        );
+    }
+
+    let mut number_of_identical = 0;
+    let mut total_distance = 0;
+    let mut maximum_distance = 0;
+    for (index, descriptor_native) in descriptors_native.iter().enumerate() {
+        let descriptor_opencv = OrbDescriptor::from_mat_row(&descriptors_opencv, index as i32)?;
+        let distance = descriptor_native.hamming_distance(&descriptor_opencv);
+        if distance == 0 {
+            number_of_identical += 1;
+        }
+        total_distance += distance;
+        maximum_distance = maximum_distance.max(distance);
+    }
+    println!(
+        "ORB: {} descriptors, identical {}, mean hamming distance {:.3}, maximum {}",
+        descriptors_native.len(),
+        number_of_identical,
+        total_distance as f32 / descriptors_native.len().max(1) as f32,
+        maximum_distance
+    );
+    if number_of_identical != descriptors_native.len() {
+        bail!("ORB descriptors differ");
+    }
+    Ok(())
+}
@@ -0,0 +1,775 @@
+//! dense disparity of rectified stereo pairs by block matching (sum of absolute differences) or
+//! semi-global matching (census transform costs), with a confidence per pixel
+use std::{fmt, fmt::Write, path::Path, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+#[cfg(feature = "opencv")]
+use opencv::{boxed_ref::BoxedRef, core::Mat};
+use rayon::prelude::*;
+use rslam_core::{GrayImage, ImageView, PixelCoordinates};
+use rslam_sensor::stereo_camera::StereoCamera;
+use serde::Deserialize;
+use sophus::nalgebra::Vector3;
+
+// disparity of pixels without a valid match
+pub const INVALID_DISPARITY: f32 = -1.0;
+
+// matching cost of disparities without a right pixel in block matching
+const INVALID_COST: u16 = u16::MAX;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DenseStereoMethod {
+    // winner takes all on box filtered absolute differences
+    BlockMatching,
+    // census costs aggregated along 4 or 8 scanline paths
+    SemiGlobalMatching,
+}
+
+impl DenseStereoMethod {
+    pub const ALL: [DenseStereoMethod; 2] = [
+        DenseStereoMethod::BlockMatching,
+        DenseStereoMethod::SemiGlobalMatching,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DenseStereoMethod::BlockMatching => "BM",
+            DenseStereoMethod::SemiGlobalMatching => "SGM",
+        }
+    }
+}
+
+impl fmt::Display for DenseStereoMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for DenseStereoMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let method = match s.trim().to_ascii_uppercase().as_str() {
+            "BM" | "BLOCK-MATCHING" => DenseStereoMethod::BlockMatching,
+            "SGM" | "SEMI-GLOBAL-MATCHING" => DenseStereoMethod::SemiGlobalMatching,
+            _ => bail!(
+                "unknown dense stereo method '{}', expected one of: {}",
+                s,
+                DenseStereoMethod::ALL
+                    .map(|method| method.name())
+                    .join(", ")
+            ),
+        };
+        Ok(method)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DenseStereoCfg {
+    method: String,
+    minimum_disparity: usize,
+    number_of_disparities: usize,
+    // odd side length of the block matching window
+    block_size: usize,
+    // odd side lengths of the census window, at most 65 pixels
+    census_width: usize,
+    census_height: usize,
+    // semi-global penalties of disparity changes by one and by more than one pixel
+    penalty_small: u16,
+    penalty_large: u16,
+    // 4 (horizontal and vertical) or 8 (with diagonals) aggregation paths
+    number_of_paths: usize,
+    // the second best cost has to exceed the best cost by this fraction
+    uniqueness_ratio: f32,
+    // maximum difference of the left and right disparity, None disables the check
+    maximum_left_right_difference: Option<f32>,
+}
+
+impl Default for DenseStereoCfg {
+    fn default() -> Self {
+        Self {
+            method: String::from("SGM"),
+            minimum_disparity: 0,
+            number_of_disparities: 64,
+            block_size: 9,
+            census_width: 9,
+            census_height: 7,
+            penalty_small: 8,
+            penalty_large: 64,
+            number_of_paths: 8,
+            uniqueness_ratio: 0.05,
+            maximum_left_right_difference: Some(1.0),
+        }
+    }
+}
+
+impl DenseStereoCfg {
+    pub fn finalize(self) -> Result<DenseStereo> {
+        let method: DenseStereoMethod =
+            self.method.parse().context("invalid dense stereo method")?;
+        if self.number_of_disparities < 2 {
+            bail!(
+                "number of disparities must be at least 2, got {}",
+                self.number_of_disparities
+            );
+        }
+        // the box filtered absolute differences have to fit the 16 bit cost volume
+        if self.block_size.is_multiple_of(2) || self.block_size > 15 {
+            bail!(
+                "block size must be odd and at most 15, got {}",
+                self.block_size
+            );
+        }
+        if self.census_width.is_multiple_of(2)
+            || self.census_height.is_multiple_of(2)
+            || self.census_width * self.census_height > 65
+        {
+            bail!(
+                "census window must have odd sides and at most 65 pixels, got {}x{}",
+                self.census_width,
+                self.census_height
+            );
+        }
+        if self.penalty_small > self.penalty_large || self.penalty_large > 2048 {
+            bail!(
+                "penalties must satisfy small <= large <= 2048, got {} and {}",
+                self.penalty_small,
+                self.penalty_large
+            );
+        }
+        if self.number_of_paths != 4 && self.number_of_paths != 8 {
+            bail!(
+                "number of paths must be 4 or 8, got {}",
+                self.number_of_paths
+            );
+        }
+
+        Ok(DenseStereo {
+            method,
+            minimum_disparity: self.minimum_disparity,
+            number_of_disparities: self.number_of_disparities,
+            block_size: self.block_size,
+            census_width: self.census_width,
+            census_height: self.census_height,
+            penalty_small: self.penalty_small,
+            penalty_large: self.penalty_large,
+            number_of_paths: self.number_of_paths,
+            uniqueness_ratio: self.uniqueness_ratio,
+            maximum_left_right_difference: self.maximum_left_right_difference,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DenseStereo {
+    method: DenseStereoMethod,
+    minimum_disparity: usize,
+    number_of_disparities: usize,
+    block_size: usize,
+    census_width: usize,
+    census_height: usize,
+    penalty_small: u16,
+    penalty_large: u16,
+    number_of_paths: usize,
+    uniqueness_ratio: f32,
+    maximum_left_right_difference: Option<f32>,
+}
+
+// matching costs of every pixel and disparity index, disparities are the innermost dimension
+struct CostVolume {
+    costs: Vec<u16>,
+    width: usize,
+    height: usize,
+    number_of_disparities: usize,
+}
+
+impl CostVolume {
+    fn new(width: usize, height: usize, number_of_disparities: usize) -> Self {
+        Self {
+            costs: vec![0; width * height * number_of_disparities],
+            width,
+            height,
+            number_of_disparities,
+        }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> &[u16] {
+        let begin = (y * self.width + x) * self.number_of_disparities;
+        &self.costs[begin..begin + self.number_of_disparities]
+    }
+
+    fn rows_mut(&mut self) -> impl IndexedParallelIterator<Item = &mut [u16]> {
+        self.costs
+            .par_chunks_mut(self.width * self.number_of_disparities)
+    }
+}
+
+impl DenseStereo {
+    pub fn method(&self) -> DenseStereoMethod {
+        self.method
+    }
+
+    pub fn compute(&self, image_left: &ImageView, image_right: &ImageView) -> Result<DisparityMap> {
+        if image_left.width() != image_right.width() || image_left.height() != image_right.height()
+        {
+            bail!(
+                "stereo images differ in size: {}x{} and {}x{}",
+                image_left.width(),
+                image_left.height(),
+                image_right.width(),
+                image_right.height()
+            );
+        }
+        if image_left.width() == 0 || image_left.height() == 0 {
+            bail!(
+                "empty stereo images: {}x{}",
+                image_left.width(),
+                image_left.height()
+            );
+        }
+        let costs = match self.method {
+            DenseStereoMethod::BlockMatching => self.block_matching_costs(image_left, image_right),
+            DenseStereoMethod::SemiGlobalMatching => {
+                self.semi_global_costs(image_left, image_right)
+            }
+        };
+        Ok(self.select_disparities(&costs))
+    }
+
+    // right pixel of a disparity index, None outside of the image
+    fn col_right(&self, col_left: usize, index: usize) -> Option<usize> {
+        col_left.checked_sub(self.minimum_disparity + index)
+    }
+
+    // absolute differences summed over the block around each pixel, borders are replicated
+    fn block_matching_costs(&self, image_left: &ImageView, image_right: &ImageView) -> CostVolume {
+        let width = image_left.width();
+        let height = image_left.height();
+        let number_of_disparities = self.number_of_disparities;
+        let half = (self.block_size / 2) as isize;
+        let clamp_row = |row: isize| row.clamp(0, height as isize - 1) as usize;
+        let clamp_col = |col: isize| col.clamp(0, width as isize - 1) as usize;
+
+        let mut volume = CostVolume::new(width, height, number_of_disparities);
+        volume.rows_mut().enumerate().for_each(|(row, costs)| {
+            let rows: Vec<_> = (-half..=half)
+                .map(|offset| clamp_row(row as isize + offset))
+                .collect();
+            let mut column_sums = vec![0u32; width];
+            for index in 0..number_of_disparities {
+                let disparity = self.minimum_disparity + index;
+                if disparity >= width {
+                    for col in 0..width {
+                        costs[col * number_of_disparities + index] = INVALID_COST;
+                    }
+                    continue;
+                }
+                // absolute differences of the block rows, the right image is shifted by the
+                // disparity
+                for (col, column_sum) in column_sums.iter_mut().enumerate() {
+                    let col_right = col.saturating_sub(disparity);
+                    *column_sum = rows
+                        .iter()
+                        .map(|row| {
+                            image_left
+                                .get(col, *row)
+                                .abs_diff(image_right.get(col_right, *row))
+                                as u32
+                        })
+                        .sum();
+                }
+                let mut sum: u32 = (-half..=half)
+                    .map(|offset| column_sums[clamp_col(offset)])
+                    .sum();
+                for col in 0..width {
+                    costs[col * number_of_disparities + index] = if col < disparity {
+                        INVALID_COST
+                    } else {
+                        sum as u16
+                    };
+                    sum += column_sums[clamp_col(col as isize + half + 1)];
+                    sum -= column_sums[clamp_col(col as isize - half)];
+                }
+            }
+        });
+        volume
+    }
+
+    // census signatures of the window around each pixel, a bit is set for a darker neighbor
+    fn census_transform(&self, image: &ImageView) -> Vec<u64> {
+        let width = image.width();
+        let height = image.height();
+        let half_width = (self.census_width / 2) as isize;
+        let half_height = (self.census_height / 2) as isize;
+        let mut offsets = Vec::new();
+        for dy in -half_height..=half_height {
+            for dx in -half_width..=half_width {
+                if dx != 0 || dy != 0 {
+                    offsets.push((dx, dy));
+                }
+            }
+        }
+
+        let mut census = vec![0u64; width * height];
+        census
+            .par_chunks_mut(width)
+            .enumerate()
+            .for_each(|(row, census_row)| {
+                for (col, signature) in census_row.iter_mut().enumerate() {
+                    let center = image.get(col, row);
+                    for (bit, (dx, dy)) in offsets.iter().enumerate() {
+                        let x = (col as isize + dx).clamp(0, width as isize - 1) as usize;
+                        let y = (row as isize + dy).clamp(0, height as isize - 1) as usize;
+                        if image.get(x, y) < center {
+                            *signature |= 1 << bit;
+                        }
+                    }
+                }
+            });
+        census
+    }
+
+    // census hamming costs aggregated along the scanline paths
+    fn semi_global_costs(&self, image_left: &ImageView, image_right: &ImageView) -> CostVolume {
+        let width = image_left.width();
+        let height = image_left.height();
+        let number_of_disparities = self.number_of_disparities;
+        let census_left = self.census_transform(image_left);
+        let census_right = self.census_transform(image_right);
+        let maximum_cost = (self.census_width * self.census_height - 1) as u16;
+
+        let mut costs = CostVolume::new(width, height, number_of_disparities);
+        costs.rows_mut().enumerate().for_each(|(row, costs_row)| {
+            for col in 0..width {
+                let signature_left = census_left[row * width + col];
+                for index in 0..number_of_disparities {
+                    costs_row[col * number_of_disparities + index] = match self
+                        .col_right(col, index)
+                    {
+                        Some(col_right) => (signature_left ^ census_right[row * width + col_right])
+                            .count_ones() as u16,
+                        None => maximum_cost,
+                    };
+                }
+            }
+        });
+
+        let mut directions = vec![(1, 0), (-1, 0), (0, 1), (0, -1)];
+        if self.number_of_paths == 8 {
+            directions.extend([(1, 1), (-1, 1), (1, -1), (-1, -1)]);
+        }
+        let mut sums = CostVolume::new(width, height, number_of_disparities);
+        for (dx, dy) in directions {
+            self.aggregate_path(&costs, dx, dy, &mut sums);
+        }
+        sums
+    }
+
+    // adds the path costs of one direction, pixels depend on their predecessor along the path
+    fn aggregate_path(&self, costs: &CostVolume, dx: isize, dy: isize, sums: &mut CostVolume) {
+        let width = costs.width;
+        let height = costs.height;
+        let number_of_disparities = costs.number_of_disparities;
+
+        if dy == 0 {
+            // rows are independent
+            sums.rows_mut().enumerate().for_each(|(row, sums_row)| {
+                let mut previous = vec![0u16; number_of_disparities];
+                let mut current = vec![0u16; number_of_disparities];
+                let cols: Vec<usize> = if dx > 0 {
+                    (0..width).collect()
+                } else {
+                    (0..width).rev().collect()
+                };
+                for (step, col) in cols.into_iter().enumerate() {
+                    if step == 0 {
+                        current.copy_from_slice(costs.pixel(col, row));
+                    } else {
+                        self.path_costs(costs.pixel(col, row), &previous, &mut current);
+                    }
+                    let sums_pixel = &mut sums_row
+                        [col * number_of_disparities..(col + 1) * number_of_disparities];
+                    for (sum, cost) in sums_pixel.iter_mut().zip(&current) {
+                        *sum += cost;
+                    }
+                    std::mem::swap(&mut previous, &mut current);
+                }
+            });
+            return;
+        }
+
+        // rows in path order, the pixels of a row depend on the previous row only
+        let rows: Vec<usize> = if dy > 0 {
+            (0..height).collect()
+        } else {
+            (0..height).rev().collect()
+        };
+        let row_size = width * number_of_disparities;
+        let mut previous = vec![0u16; row_size];
+        let mut current = vec![0u16; row_size];
+        for (step, row) in rows.into_iter().enumerate() {
+            current
+                .par_chunks_mut(number_of_disparities)
+                .enumerate()
+                .for_each(|(col, current_pixel)| {
+                    let col_previous = col as isize - dx;
+                    if step == 0 || col_previous < 0 || col_previous >= width as isize {
+                        current_pixel.copy_from_slice(costs.pixel(col, row));
+                    } else {
+                        let begin = col_previous as usize * number_of_disparities;
+                        self.path_costs(
+                            costs.pixel(col, row),
+                            &previous[begin..begin + number_of_disparities],
+                            current_pixel,
+                        );
+                    }
+                });
+            let sums_row = &mut sums.costs[row * row_size..(row + 1) * row_size];
+            for (sum, cost) in sums_row.iter_mut().zip(&current) {
+                *sum += cost;
+            }
+            std::mem::swap(&mut previous, &mut current);
+        }
+    }
+
+    // L(p, d) = C(p, d) + min(L(p - r, d), L(p - r, d +- 1) + P1, min L(p - r) + P2) - min L(p - r)
+    fn path_costs(&self, costs: &[u16], previous: &[u16], result: &mut [u16]) {
+        let minimum_previous = *previous.iter().min().unwrap();
+        let penalty_large = minimum_previous + self.penalty_large;
+        for index in 0..costs.len() {
+            let mut cost = previous[index].min(penalty_large);
+            if index > 0 {
+                cost = cost.min(previous[index - 1] + self.penalty_small);
+            }
+            if index + 1 < costs.len() {
+                cost = cost.min(previous[index + 1] + self.penalty_small);
+            }
+            result[index] = costs[index] + cost - minimum_previous;
+        }
+    }
+
+    // winner takes all with parabolic subpixel refinement, uniqueness and left right checks
+    fn select_disparities(&self, volume: &CostVolume) -> DisparityMap {
+        let width = volume.width;
+        let height = volume.height;
+        let mut disparity = GrayImage::<f32>::new(width, height);
+        let mut confidence = GrayImage::<f32>::new(width, height);
+
+        let rows: Vec<(Vec<f32>, Vec<f32>)> = (0..height)
+            .into_par_iter()
+            .map(|row| {
+                let mut disparity_row = vec![INVALID_DISPARITY; width];
+                let mut confidence_row = vec![0.0; width];
+                for col in 0..width {
+                    if let Some((disparity, confidence)) = self.select_pixel(volume.pixel(col, row))
+                    {
+                        disparity_row[col] = disparity;
+                        confidence_row[col] = confidence;
+                    }
+                }
+                if let Some(maximum_difference) = self.maximum_left_right_difference {
+                    self.left_right_check(
+                        volume,
+                        row,
+                        maximum_difference,
+                        &mut disparity_row,
+                        &mut confidence_row,
+                    );
+                }
+                (disparity_row, confidence_row)
+            })
+            .collect();
+
+        for (row, (disparity_row, confidence_row)) in rows.into_iter().enumerate() {
+            disparity.row_mut(row).copy_from_slice(&disparity_row);
+            confidence.row_mut(row).copy_from_slice(&confidence_row);
+        }
+        DisparityMap {
+            disparity,
+            confidence,
+        }
+    }
+
+    // (disparity, confidence) of the best cost, None if it is not unique
+    fn select_pixel(&self, costs: &[u16]) -> Option<(f32, f32)> {
+        let (index_best, cost_best) = costs
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, cost)| **cost)
+            .map(|(index, cost)| (index, *cost))?;
+        if cost_best == INVALID_COST {
+            return None;
+        }
+        // second best apart from the direct neighbors of the best
+        let cost_second = costs
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| index.abs_diff(index_best) > 1)
+            .map(|(_, cost)| *cost)
+            .min()
+            .unwrap_or(INVALID_COST);
+        if (cost_best as f32) > (1.0 - self.uniqueness_ratio) * cost_second as f32 {
+            return None;
+        }
+        let confidence = if cost_second == 0 {
+            0.0
+        } else {
+            1.0 - cost_best as f32 / cost_second as f32
+        };
+
+        let mut offset = 0.0;
+        if index_best > 0 && index_best + 1 < costs.len() {
+            let cost_previous = costs[index_best - 1];
+            let cost_next = costs[index_best + 1];
+            if cost_previous != INVALID_COST && cost_next != INVALID_COST {
+                let curvature = cost_previous as f32 - 2.0 * cost_best as f32 + cost_next as f32;
+                if curvature > 0.0 {
+                    offset = (0.5 * (cost_previous as f32 - cost_next as f32) / curvature)
+                        .clamp(-0.5, 0.5);
+                }
+            }
+        }
+        Some((
+            (self.minimum_disparity + index_best) as f32 + offset,
+            confidence,
+        ))
+    }
+
+    // invalidates pixels whose right pixel prefers a different disparity, the right disparities
+    // are the best costs along the diagonals of the volume
+    fn left_right_check(
+        &self,
+        volume: &CostVolume,
+        row: usize,
+        maximum_difference: f32,
+        disparity_row: &mut [f32],
+        confidence_row: &mut [f32],
+    ) {
+        let width = volume.width;
+        let disparity_right: Vec<Option<usize>> = (0..width)
+            .map(|col_right| {
+                (0..self.number_of_disparities)
+                    .filter_map(|index| {
+                        let col = col_right + self.minimum_disparity + index;
+                        (col < width).then(|| (volume.pixel(col, row)[index], index))
+                    })
+                    .filter(|(cost, _)| *cost != INVALID_COST)
+                    .min()
+                    .map(|(_, index)| self.minimum_disparity + index)
+            })
+            .collect();
+
+        for (col, (disparity, confidence)) in
+            disparity_row.iter_mut().zip(confidence_row).enumerate()
+        {
+            if *disparity == INVALID_DISPARITY {
+                continue;
+            }
+            let consistent = col
+                .checked_sub(disparity.round() as usize)
+                .and_then(|col_right| disparity_right[col_right])
+                .is_some_and(|disparity_right| {
+                    (*disparity - disparity_right as f32).abs() <= maximum_difference
+                });
+            if !consistent {
+                *disparity = INVALID_DISPARITY;
+                *confidence = 0.0;
+            }
+        }
+    }
+}
+
+/// disparities of the left image with their confidence in [0, 1], invalid pixels have
+/// INVALID_DISPARITY and confidence 0
+#[derive(Clone, Debug)]
+pub struct DisparityMap {
+    disparity: GrayImage<f32>,
+    confidence: GrayImage<f32>,
+}
+
+impl DisparityMap {
+    pub fn disparity(&self) -> &GrayImage<f32> {
+        &self.disparity
+    }
+
+    pub fn confidence(&self) -> &GrayImage<f32> {
+        &self.confidence
+    }
+
+    pub fn number_of_valid(&self) -> usize {
+        (0..self.disparity.height())
+            .map(|row| {
+                self.disparity
+                    .row(row)
+                    .iter()
+                    .filter(|disparity| **disparity != INVALID_DISPARITY)
+                    .count()
+            })
+            .sum()
+    }
+
+    // (disparity, confidence) at the nearest pixel, or the most confident disparity of its 3x3
+    // neighborhood if the pixel is invalid
+    pub fn sample(&self, pixel: &PixelCoordinates, minimum_confidence: f32) -> Option<(f32, f32)> {
+        let col = pixel.x.round() as isize;
+        let row = pixel.y.round() as isize;
+        let width = self.disparity.width() as isize;
+        let height = self.disparity.height() as isize;
+        if col < 0 || row < 0 || col >= width || row >= height {
+            return None;
+        }
+        let valid = |col: isize, row: isize| {
+            let disparity = self.disparity.get(col as usize, row as usize);
+            let confidence = self.confidence.get(col as usize, row as usize);
+            (disparity != INVALID_DISPARITY && confidence >= minimum_confidence)
+                .then_some((disparity, confidence))
+        };
+        if let Some(sample) = valid(col, row) {
+            return Some(sample);
+        }
+
+        let mut best: Option<(f32, f32)> = None;
+        for r in (row - 1).max(0)..=(row + 1).min(height - 1) {
+            for c in (col - 1).max(0)..=(col + 1).min(width - 1) {
+                if let Some(sample) = valid(c, r) {
+                    if best.is_none_or(|best| sample.1 > best.1) {
+                        best = Some(sample);
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    // point in left camera coordinates triangulated from the sampled disparity
+    pub fn point_at(
+        &self,
+        stereo_camera: &StereoCamera,
+        pixel: &PixelCoordinates,
+        minimum_confidence: f32,
+    ) -> Option<Vector3<f64>> {
+        let (disparity, _) = self.sample(pixel, minimum_confidence)?;
+        stereo_camera.triangulate(
+            pixel,
+            &PixelCoordinates::new(pixel.x - disparity as f64, pixel.y),
+        )
+    }
+
+    // points in left camera coordinates of every step-th pixel in both directions
+    pub fn to_points(
+        &self,
+        stereo_camera: &StereoCamera,
+        minimum_confidence: f32,
+        step: usize,
+    ) -> Vec<Vector3<f64>> {
+        let mut points = Vec::new();
+        for row in (0..self.disparity.height()).step_by(step.max(1)) {
+            for col in (0..self.disparity.width()).step_by(step.max(1)) {
+                let disparity = self.disparity.get(col, row);
+                if disparity == INVALID_DISPARITY
+                    || self.confidence.get(col, row) < minimum_confidence
+                {
+                    continue;
+                }
+                let pixel_left = PixelCoordinates::new(col as f64, row as f64);
+                let pixel_right = PixelCoordinates::new(col as f64 - disparity as f64, row as f64);
+                if let Some(point) = stereo_camera.triangulate(&pixel_left, &pixel_right) {
+                    points.push(point);
+                }
+            }
+        }
+        points
+    }
+
+    // 32 bit float matrix header on the disparities
+    #[cfg(feature = "opencv")]
+    pub fn disparity_mat(&self) -> opencv::Result<BoxedRef<'_, Mat>> {
+        self.disparity.to_mat()
+    }
+
+    #[cfg(feature = "opencv")]
+    pub fn confidence_mat(&self) -> opencv::Result<BoxedRef<'_, Mat>> {
+        self.confidence.to_mat()
+    }
+}
+
+// ASCII PLY point cloud
+pub fn format_ply(points: &[Vector3<f64>]) -> String {
+    let mut ply = String::new();
+    writeln!(ply, "ply").unwrap();
+    writeln!(ply, "format ascii 1.0").unwrap();
+    writeln!(ply, "element vertex {}", points.len()).unwrap();
+    writeln!(ply, "property float x").unwrap();
+    writeln!(ply, "property float y").unwrap();
+    writeln!(ply, "property float z").unwrap();
+    writeln!(ply, "end_header").unwrap();
+    for point in points {
+        writeln!(ply, "{:.4} {:.4} {:.4}", point.x, point.y, point.z).unwrap();
+    }
+    ply
+}
+
+pub fn write_ply<P: AsRef<Path>>(path: P, points: &[Vector3<f64>]) -> Result<()> {
+    let path = path.as_ref();
+    std::fs::write(path, format_ply(points)).with_context(|| format!("writing {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::shifted_stereo_images;
+
+    const DISPARITY: usize = 12;
+
+    #[test]
+    fn recovers_a_constant_disparity() {
+        let (image_left, image_right) = shifted_stereo_images(160, 48, DISPARITY);
+        for method in DenseStereoMethod::ALL {
+            let dense_stereo = DenseStereoCfg {
+                method: method.name().to_string(),
+                ..Default::default()
+            }
+            .finalize()
+            .unwrap();
+            let disparity_map = dense_stereo
+                .compute(&image_left.view(), &image_right.view())
+                .unwrap();
+
+            // columns with the full disparity range, away from the borders
+            let mut number_of_valid = 0;
+            let mut number_of_pixels = 0;
+            for y in 8..40 {
+                for x in 72..152 {
+                    number_of_pixels += 1;
+                    let disparity = disparity_map.disparity().get(x, y);
+                    if disparity == INVALID_DISPARITY {
+                        continue;
+                    }
+                    number_of_valid += 1;
+                    assert!(
+                        (disparity - DISPARITY as f32).abs() < 0.5,
+                        "{}: disparity {} at ({}, {})",
+                        method,
+                        disparity,
+                        x,
+                        y
+                    );
+                }
+            }
+            assert!(number_of_valid * 10 > number_of_pixels * 9, "{}", method);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_images() {
+        let dense_stereo = DenseStereoCfg::default().finalize().unwrap();
+        let empty = GrayImage::<u8>::new(0, 0);
+        assert!(dense_stereo.compute(&empty.view(), &empty.view()).is_err());
+
+        let (image_left, _) = shifted_stereo_images(64, 32, DISPARITY);
+        let (image_right, _) = shifted_stereo_images(64, 16, DISPARITY);
+        assert!(dense_stereo
+            .compute(&image_left.view(), &image_right.view())
+            .is_err());
+    }
+}
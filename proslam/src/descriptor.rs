@@ -0,0 +1,658 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+use opencv::{
+    core::{
+        KeyPoint, KeyPointTrait, Mat, MatTraitConst, Ptr, Vector, CV_32F, CV_8U, NORM_HAMMING,
+        NORM_L1, NORM_L2,
+    },
+    features2d::{DescriptorExtractor, AKAZE, BRISK, ORB, SIFT},
+    xfeatures2d::{BriefDescriptorExtractor, FREAK, LATCH, SURF},
+};
+use rslam_core::{hamming_distance, PackedDescriptors};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorNorm {
+    // binary descriptors
+    Hamming,
+    // float descriptors
+    L2,
+    L1,
+}
+
+impl DescriptorNorm {
+    pub fn opencv_norm_type(&self) -> i32 {
+        match self {
+            DescriptorNorm::Hamming => NORM_HAMMING,
+            DescriptorNorm::L2 => NORM_L2,
+            DescriptorNorm::L1 => NORM_L1,
+        }
+    }
+}
+
+impl FromStr for DescriptorNorm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim().to_ascii_uppercase().as_str() {
+            "HAMMING" => DescriptorNorm::Hamming,
+            "L2" => DescriptorNorm::L2,
+            "L1" => DescriptorNorm::L1,
+            _ => bail!(
+                "unknown descriptor norm '{}', expected one of: HAMMING, L2, L1",
+                s
+            ),
+        })
+    }
+}
+
+/// descriptors available for stereo matching
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DescriptorType {
+    Orb256,
+    Brief128,
+    Brief256,
+    Brief512,
+    Brisk512,
+    Freak512,
+    Latch256,
+    // modified local difference binary
+    Akaze486,
+    Sift128,
+    Surf64,
+    Surf128,
+}
+
+impl DescriptorType {
+    pub const ALL: [DescriptorType; 11] = [
+        DescriptorType::Orb256,
+        DescriptorType::Brief128,
+        DescriptorType::Brief256,
+        DescriptorType::Brief512,
+        DescriptorType::Brisk512,
+        DescriptorType::Freak512,
+        DescriptorType::Latch256,
+        DescriptorType::Akaze486,
+        DescriptorType::Sift128,
+        DescriptorType::Surf64,
+        DescriptorType::Surf128,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DescriptorType::Orb256 => "ORB-256",
+            DescriptorType::Brief128 => "BRIEF-128",
+            DescriptorType::Brief256 => "BRIEF-256",
+            DescriptorType::Brief512 => "BRIEF-512",
+            DescriptorType::Brisk512 => "BRISK",
+            DescriptorType::Freak512 => "FREAK",
+            DescriptorType::Latch256 => "LATCH",
+            DescriptorType::Akaze486 => "AKAZE",
+            DescriptorType::Sift128 => "SIFT",
+            DescriptorType::Surf64 => "SURF-64",
+            DescriptorType::Surf128 => "SURF-128",
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        !matches!(
+            self,
+            DescriptorType::Sift128 | DescriptorType::Surf64 | DescriptorType::Surf128
+        )
+    }
+
+    pub fn default_norm(&self) -> DescriptorNorm {
+        if self.is_binary() {
+            DescriptorNorm::Hamming
+        } else {
+            DescriptorNorm::L2
+        }
+    }
+
+    // number of bits (binary) or elements (float)
+    pub fn dimension(&self) -> usize {
+        if let Some(bit_length) = self.bit_length() {
+            return bit_length;
+        }
+        match self {
+            DescriptorType::Surf64 => 64,
+            // SIFT and SURF-128
+            _ => 128,
+        }
+    }
+
+    // L2 norm of a float descriptor
+    fn magnitude(&self) -> f64 {
+        match self {
+            // normalized to 512 by OpenCV
+            DescriptorType::Sift128 => 512.0,
+            _ => 1.0,
+        }
+    }
+
+    // SURF sums signed gradients, SIFT histograms are non-negative
+    fn is_signed(&self) -> bool {
+        matches!(self, DescriptorType::Surf64 | DescriptorType::Surf128)
+    }
+
+    // upper bound of the distance of two descriptors under the norm: the bit length for hamming,
+    // opposite vectors of the magnitude for signed and orthogonal ones for non-negative float
+    // descriptors under L2, and the L1 counterparts of those
+    fn maximum_distance(&self, norm: DescriptorNorm) -> f64 {
+        let dimension = self.dimension() as f64;
+        match (norm, self.is_signed()) {
+            (DescriptorNorm::Hamming, _) => dimension,
+            (DescriptorNorm::L2, true) => 2.0 * self.magnitude(),
+            (DescriptorNorm::L2, false) => std::f64::consts::SQRT_2 * self.magnitude(),
+            (DescriptorNorm::L1, true) => 2.0 * dimension.sqrt() * self.magnitude(),
+            (DescriptorNorm::L1, false) => (2.0 * dimension).sqrt() * self.magnitude(),
+        }
+    }
+
+    // size of a binary descriptor in bits, the upper bound of the hamming distance; None for
+    // float descriptors
+    pub fn bit_length(&self) -> Option<usize> {
+        match self {
+            DescriptorType::Orb256 => Some(256),
+            DescriptorType::Brief128 => Some(128),
+            DescriptorType::Brief256 => Some(256),
+            DescriptorType::Brief512 => Some(512),
+            DescriptorType::Brisk512 => Some(512),
+            DescriptorType::Freak512 => Some(512),
+            DescriptorType::Latch256 => Some(256),
+            DescriptorType::Akaze486 => Some(486),
+            DescriptorType::Sift128 | DescriptorType::Surf64 | DescriptorType::Surf128 => None,
+        }
+    }
+
+    // distance thresholds are normalized to [0, 1], they used to be absolute bits: values above 1
+    // are rejected with the normalized equivalent
+    pub fn check_normalized_distance(&self, name: &str, distance: f32) -> Result<()> {
+        if (0.0..=1.0).contains(&distance) {
+            return Ok(());
+        }
+        match self.bit_length() {
+            Some(bit_length) if distance > 1.0 => bail!(
+                "{} {} looks like a number of bits, distances are normalized: use {} ({} of {} bits of {})",
+                name,
+                distance,
+                distance / bit_length as f32,
+                distance,
+                bit_length,
+                self
+            ),
+            _ => bail!(
+                "{} has to be a normalized distance in [0, 1], got {}",
+                name,
+                distance
+            ),
+        }
+    }
+
+    pub fn create_extractor(&self) -> Result<Ptr<DescriptorExtractor>> {
+        Ok(match self {
+            DescriptorType::Orb256 => ORB::create_def()?.into(),
+            DescriptorType::Brief128 => BriefDescriptorExtractor::create(16, false)?.into(),
+            DescriptorType::Brief256 => BriefDescriptorExtractor::create(32, false)?.into(),
+            DescriptorType::Brief512 => BriefDescriptorExtractor::create(64, false)?.into(),
+            DescriptorType::Brisk512 => BRISK::create_def()?.into(),
+            DescriptorType::Freak512 => FREAK::create_def()?.into(),
+            DescriptorType::Latch256 => LATCH::create_def()?.into(),
+            DescriptorType::Akaze486 => AKAZE::create_def()?.into(),
+            DescriptorType::Sift128 => SIFT::create_def()?.into(),
+            // patented, needs OpenCV built with OPENCV_ENABLE_NONFREE
+            DescriptorType::Surf64 => SURF::create(100.0, 4, 3, false, false)?.into(),
+            DescriptorType::Surf128 => SURF::create(100.0, 4, 3, true, false)?.into(),
+        })
+    }
+
+    // AKAZE samples its nonlinear scale space at the evolution level stored in the class id,
+    // keypoints of other detectors are assigned to the finest level
+    pub fn prepare_keypoints(&self, keypoints: &mut Vector<KeyPoint>) {
+        if *self != DescriptorType::Akaze486 {
+            return;
+        }
+        *keypoints = keypoints
+            .iter()
+            .map(|mut keypoint| {
+                keypoint.set_class_id(0);
+                keypoint.set_octave(0);
+                keypoint
+            })
+            .collect();
+    }
+}
+
+impl fmt::Display for DescriptorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for DescriptorType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let descriptor_type = match s.trim().to_ascii_uppercase().as_str() {
+            "ORB" | "ORB-256" => DescriptorType::Orb256,
+            "BRIEF-128" => DescriptorType::Brief128,
+            "BRIEF" | "BRIEF-256" => DescriptorType::Brief256,
+            "BRIEF-512" => DescriptorType::Brief512,
+            "BRISK" | "BRISK-512" => DescriptorType::Brisk512,
+            "FREAK" | "FREAK-512" => DescriptorType::Freak512,
+            "LATCH" | "LATCH-256" => DescriptorType::Latch256,
+            "AKAZE" | "AKAZE-486" => DescriptorType::Akaze486,
+            "SIFT" | "SIFT-128" => DescriptorType::Sift128,
+            "SURF" | "SURF-64" => DescriptorType::Surf64,
+            "SURF-128" => DescriptorType::Surf128,
+            _ => bail!(
+                "unknown descriptor type '{}', expected one of: {}",
+                s,
+                DescriptorType::ALL
+                    .map(|descriptor_type| descriptor_type.name())
+                    .join(", ")
+            ),
+        };
+        Ok(descriptor_type)
+    }
+}
+
+/// descriptor distances of a descriptor type under a norm, normalized to [0, 1] so that
+/// thresholds are shared between binary and float descriptors
+#[derive(Clone, Copy, Debug)]
+pub struct DescriptorMetric {
+    descriptor_type: DescriptorType,
+    norm: DescriptorNorm,
+    maximum_distance: f64,
+}
+
+impl DescriptorMetric {
+    // norm None selects the default norm of the descriptor type
+    pub fn new(descriptor_type: DescriptorType, norm: Option<DescriptorNorm>) -> Result<Self> {
+        let norm = norm.unwrap_or(descriptor_type.default_norm());
+        if descriptor_type.is_binary() != (norm == DescriptorNorm::Hamming) {
+            bail!(
+                "norm {:?} can't be used with {} descriptors",
+                norm,
+                descriptor_type
+            );
+        }
+        Ok(Self {
+            descriptor_type,
+            norm,
+            maximum_distance: descriptor_type.maximum_distance(norm),
+        })
+    }
+
+    pub fn descriptor_type(&self) -> DescriptorType {
+        self.descriptor_type
+    }
+
+    pub fn norm(&self) -> DescriptorNorm {
+        self.norm
+    }
+
+    pub fn maximum_distance(&self) -> f64 {
+        self.maximum_distance
+    }
+
+    // distance of two descriptors of this type divided by the maximum distance, fails for
+    // descriptors of different kinds or sizes
+    pub fn normalized_distance(
+        &self,
+        descriptor_a: DescriptorRow,
+        descriptor_b: DescriptorRow,
+    ) -> Result<f32> {
+        if descriptor_a.len() != descriptor_b.len() {
+            bail!(
+                "descriptors of different sizes can't be compared: {} and {}",
+                descriptor_a.len(),
+                descriptor_b.len()
+            );
+        }
+        let distance = match (descriptor_a, descriptor_b) {
+            (DescriptorRow::Binary(a), DescriptorRow::Binary(b)) => hamming_distance(a, b) as f64,
+            (DescriptorRow::Float(a), DescriptorRow::Float(b)) => {
+                let differences = a.iter().zip(b).map(|(a, b)| (a - b) as f64);
+                match self.norm {
+                    DescriptorNorm::L1 => differences.map(f64::abs).sum(),
+                    _ => differences
+                        .map(|difference| difference * difference)
+                        .sum::<f64>()
+                        .sqrt(),
+                }
+            }
+            _ => bail!("binary and float descriptors can't be compared"),
+        };
+        Ok((distance / self.maximum_distance) as f32)
+    }
+}
+
+/// descriptor of a single keypoint, a row of `Descriptors`
+#[derive(Clone, Copy, Debug)]
+pub enum DescriptorRow<'a> {
+    Binary(&'a [u64]),
+    Float(&'a [f32]),
+}
+
+impl DescriptorRow<'_> {
+    // words (binary) or elements (float)
+    pub fn len(&self) -> usize {
+        match self {
+            DescriptorRow::Binary(words) => words.len(),
+            DescriptorRow::Float(values) => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// descriptors of the keypoints of an image, one row per keypoint
+#[derive(Clone, Debug)]
+pub enum Descriptors {
+    // bit layout of BinaryDescriptor
+    Binary(PackedDescriptors),
+    Float { dimension: usize, values: Vec<f32> },
+}
+
+impl Default for Descriptors {
+    fn default() -> Self {
+        Descriptors::Binary(PackedDescriptors::default())
+    }
+}
+
+impl Descriptors {
+    // copies the rows of an 8 bit (binary) or 32 bit float descriptor matrix
+    pub fn from_mat(descriptors: &Mat) -> Result<Self> {
+        let number_of_cols = descriptors.cols().max(0) as usize;
+        match descriptors.typ() {
+            CV_8U => {
+                let mut packed =
+                    PackedDescriptors::new(PackedDescriptors::words_for_bits(8 * number_of_cols));
+                for row in 0..descriptors.rows() {
+                    packed.push_bytes(descriptors.at_row::<u8>(row)?);
+                }
+                Ok(Descriptors::Binary(packed))
+            }
+            CV_32F => {
+                let mut values =
+                    Vec::with_capacity(descriptors.rows().max(0) as usize * number_of_cols);
+                for row in 0..descriptors.rows() {
+                    values.extend_from_slice(descriptors.at_row::<f32>(row)?);
+                }
+                Ok(Descriptors::Float {
+                    dimension: number_of_cols,
+                    values,
+                })
+            }
+            typ => bail!("unsupported descriptor matrix type {}", typ),
+        }
+    }
+
+    pub fn from_row(descriptor: DescriptorRow) -> Self {
+        match descriptor {
+            DescriptorRow::Binary(words) => {
+                let mut packed = PackedDescriptors::new(words.len());
+                packed.push_words(words);
+                Descriptors::Binary(packed)
+            }
+            DescriptorRow::Float(values) => Descriptors::Float {
+                dimension: values.len(),
+                values: values.to_vec(),
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Descriptors::Binary(packed) => packed.len(),
+            Descriptors::Float { dimension: 0, .. } => 0,
+            Descriptors::Float { dimension, values } => values.len() / dimension,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn row(&self, index: usize) -> DescriptorRow<'_> {
+        match self {
+            Descriptors::Binary(packed) => DescriptorRow::Binary(packed.row(index)),
+            Descriptors::Float { dimension, values } => {
+                DescriptorRow::Float(&values[index * dimension..(index + 1) * dimension])
+            }
+        }
+    }
+
+    // None for float descriptors
+    pub fn packed(&self) -> Option<&PackedDescriptors> {
+        match self {
+            Descriptors::Binary(packed) => Some(packed),
+            Descriptors::Float { .. } => None,
+        }
+    }
+
+    // copies of the given rows in their order
+    pub fn select(&self, rows: impl IntoIterator<Item = usize>) -> Self {
+        match self {
+            Descriptors::Binary(packed) => {
+                let mut selected = PackedDescriptors::new(packed.words_per_row());
+                for row in rows {
+                    selected.push_words(packed.row(row));
+                }
+                Descriptors::Binary(selected)
+            }
+            Descriptors::Float { dimension, values } => Descriptors::Float {
+                dimension: *dimension,
+                values: rows
+                    .into_iter()
+                    .flat_map(|row| &values[row * dimension..(row + 1) * dimension])
+                    .copied()
+                    .collect(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_descriptor_types() {
+        for descriptor_type in DescriptorType::ALL {
+            assert_eq!(
+                descriptor_type.name().parse::<DescriptorType>().unwrap(),
+                descriptor_type
+            );
+        }
+        assert_eq!(
+            "orb".parse::<DescriptorType>().unwrap(),
+            DescriptorType::Orb256
+        );
+        assert!("HOG".parse::<DescriptorType>().is_err());
+    }
+
+    #[test]
+    fn only_binary_descriptors_have_a_bit_length() {
+        for descriptor_type in DescriptorType::ALL {
+            assert_eq!(
+                descriptor_type.bit_length().is_some(),
+                descriptor_type.is_binary()
+            );
+        }
+        assert_eq!(DescriptorType::Akaze486.dimension(), 486);
+        assert_eq!(DescriptorType::Sift128.dimension(), 128);
+        assert_eq!(DescriptorType::Surf64.dimension(), 64);
+        assert_eq!(DescriptorType::Surf128.dimension(), 128);
+    }
+
+    #[test]
+    fn normalizes_hamming_distances_by_the_bit_length() {
+        let metric = DescriptorMetric::new(DescriptorType::Orb256, None).unwrap();
+        assert_eq!(metric.norm(), DescriptorNorm::Hamming);
+        assert_eq!(metric.maximum_distance(), 256.0);
+        let zeros = [0u64; 4];
+        let ones = [u64::MAX; 4];
+        let quarter = [u64::MAX, 0, 0, 0];
+        let distance = |a, b| {
+            metric
+                .normalized_distance(DescriptorRow::Binary(a), DescriptorRow::Binary(b))
+                .unwrap()
+        };
+        assert_eq!(distance(&zeros, &zeros), 0.0);
+        assert_eq!(distance(&zeros, &quarter), 0.25);
+        assert_eq!(distance(&zeros, &ones), 1.0);
+
+        // the padding bits of the last word stay zero
+        let metric = DescriptorMetric::new(DescriptorType::Akaze486, None).unwrap();
+        let mut complement = [u64::MAX; 8];
+        complement[7] = (1 << (486 - 7 * 64)) - 1;
+        assert_eq!(
+            metric
+                .normalized_distance(
+                    DescriptorRow::Binary(&[0u64; 8]),
+                    DescriptorRow::Binary(&complement)
+                )
+                .unwrap(),
+            1.0
+        );
+    }
+
+    #[test]
+    fn rejects_norms_of_the_other_descriptor_kind() {
+        assert!(DescriptorMetric::new(DescriptorType::Orb256, Some(DescriptorNorm::L2)).is_err());
+        assert!(
+            DescriptorMetric::new(DescriptorType::Sift128, Some(DescriptorNorm::Hamming)).is_err()
+        );
+        let metric = DescriptorMetric::new(DescriptorType::Sift128, None).unwrap();
+        assert_eq!(metric.norm(), DescriptorNorm::L2);
+    }
+
+    #[test]
+    fn rejects_distances_in_bits() {
+        let orb = DescriptorType::Orb256;
+        assert!(orb.check_normalized_distance("distance", 0.0).is_ok());
+        assert!(orb.check_normalized_distance("distance", 0.2).is_ok());
+        assert!(orb.check_normalized_distance("distance", 1.0).is_ok());
+        assert!(orb.check_normalized_distance("distance", -0.1).is_err());
+
+        // the old default of 51 bits
+        let error = orb
+            .check_normalized_distance("distance", 51.0)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("use 0.19921875"), "{}", error);
+
+        let error = DescriptorType::Sift128
+            .check_normalized_distance("distance", 51.0)
+            .unwrap_err()
+            .to_string();
+        assert!(!error.contains("bits"), "{}", error);
+    }
+
+    // scaled to the L2 norm of the descriptor type
+    fn normalized(values: Vec<f32>, magnitude: f32) -> Vec<f32> {
+        let norm = values.iter().map(|value| value * value).sum::<f32>().sqrt();
+        values
+            .iter()
+            .map(|value| value / norm * magnitude)
+            .collect()
+    }
+
+    fn float_distance(metric: &DescriptorMetric, a: &[f32], b: &[f32]) -> f32 {
+        metric
+            .normalized_distance(DescriptorRow::Float(a), DescriptorRow::Float(b))
+            .unwrap()
+    }
+
+    #[test]
+    fn reaches_the_float_distance_bounds() {
+        // non-negative SIFT histograms are farthest apart with disjoint supports
+        let half = |first: bool| {
+            let values = (0..128).map(|i| if (i < 64) == first { 1.0 } else { 0.0 });
+            normalized(values.collect(), 512.0)
+        };
+        let sift_l2 = DescriptorMetric::new(DescriptorType::Sift128, None).unwrap();
+        let sift_l1 =
+            DescriptorMetric::new(DescriptorType::Sift128, Some(DescriptorNorm::L1)).unwrap();
+        let mut single_a = vec![0.0; 128];
+        let mut single_b = vec![0.0; 128];
+        single_a[0] = 512.0;
+        single_b[1] = 512.0;
+        assert!((float_distance(&sift_l2, &single_a, &single_b) - 1.0).abs() < 1e-6);
+        assert!((float_distance(&sift_l2, &half(true), &half(false)) - 1.0).abs() < 1e-6);
+        assert!((float_distance(&sift_l1, &half(true), &half(false)) - 1.0).abs() < 1e-6);
+
+        // signed SURF descriptors are farthest apart when opposite
+        let surf = normalized(vec![1.0; 64], 1.0);
+        let opposite: Vec<f32> = surf.iter().map(|value| -value).collect();
+        let surf_l2 = DescriptorMetric::new(DescriptorType::Surf64, None).unwrap();
+        let surf_l1 =
+            DescriptorMetric::new(DescriptorType::Surf64, Some(DescriptorNorm::L1)).unwrap();
+        assert!((float_distance(&surf_l2, &surf, &opposite) - 1.0).abs() < 1e-6);
+        assert!((float_distance(&surf_l1, &surf, &opposite) - 1.0).abs() < 1e-6);
+        assert_eq!(float_distance(&surf_l1, &surf, &surf), 0.0);
+    }
+
+    #[test]
+    fn bounds_float_distances() {
+        // deterministic pseudo random descriptors
+        let mut state = 12345u32;
+        let mut random = move || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 8) as f32 / (1 << 24) as f32
+        };
+        for descriptor_type in [
+            DescriptorType::Sift128,
+            DescriptorType::Surf64,
+            DescriptorType::Surf128,
+        ] {
+            let magnitude = descriptor_type.magnitude() as f32;
+            let signed = descriptor_type.is_signed();
+            for norm in [DescriptorNorm::L2, DescriptorNorm::L1] {
+                let metric = DescriptorMetric::new(descriptor_type, Some(norm)).unwrap();
+                for _ in 0..100 {
+                    let mut descriptor = || {
+                        let values = (0..descriptor_type.dimension()).map(|_| {
+                            if signed {
+                                2.0 * random() - 1.0
+                            } else {
+                                random()
+                            }
+                        });
+                        normalized(values.collect(), magnitude)
+                    };
+                    let (a, b) = (descriptor(), descriptor());
+                    let distance = float_distance(&metric, &a, &b);
+                    assert!(
+                        (0.0..=1.0 + 1e-6).contains(&distance),
+                        "{} {:?}: {}",
+                        descriptor_type,
+                        norm,
+                        distance
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fails_on_incompatible_descriptors() {
+        let metric = DescriptorMetric::new(DescriptorType::Orb256, None).unwrap();
+        assert!(metric
+            .normalized_distance(
+                DescriptorRow::Binary(&[0u64; 4]),
+                DescriptorRow::Float(&[0.0; 4])
+            )
+            .is_err());
+        assert!(metric
+            .normalized_distance(
+                DescriptorRow::Binary(&[0u64; 4]),
+                DescriptorRow::Binary(&[0u64; 8])
+            )
+            .is_err());
+    }
+}
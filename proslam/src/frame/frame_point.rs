@@ -1,4 +1,4 @@
-use sophus::nalgebra::Vector3;
+use sophus::nalgebra::{Matrix3, Vector3};
 
 pub struct FramePoint {
     // 3D point in left camera coordinate frame
@@ -7,7 +7,17 @@ pub struct FramePoint {
     robot_coordinates: Vector3<f64>,
     // 3D point in world coordinate frame
     world_coordinates: Vector3<f64>,
+    // depth (z) in the left camera
     depth_meters: f64,
+    // covariance of the point in left camera coordinates, None if not triangulated
+    covariance_left: Option<Matrix3<f64>>,
+    // subpixel disparity of the stereo match, -1 if not available (unrectified matching)
+    disparity_pixels: f32,
+    // patch score of the subpixel refinement or confidence of a dense disparity, None if not
+    // refined
+    matching_score: Option<f32>,
+    // index of the left keypoint (and descriptor) in the frame, None if not created from one
+    keypoint_index_left: Option<usize>,
 }
 
 impl FramePoint {
@@ -16,7 +26,61 @@ impl FramePoint {
             camera_coordinates_left,
             robot_coordinates,
             world_coordinates,
-            depth_meters: -1.0
+            depth_meters: camera_coordinates_left.z,
+            covariance_left: None,
+            disparity_pixels: -1.0,
+            matching_score: None,
+            keypoint_index_left: None,
         }
     }
+
+    pub fn set_stereo_match(&mut self, disparity_pixels: f32, matching_score: Option<f32>) {
+        self.disparity_pixels = disparity_pixels;
+        self.matching_score = matching_score;
+    }
+
+    pub fn set_keypoint_index_left(&mut self, keypoint_index_left: usize) {
+        self.keypoint_index_left = Some(keypoint_index_left);
+    }
+
+    pub fn set_covariance(&mut self, covariance_left: Matrix3<f64>) {
+        self.covariance_left = Some(covariance_left);
+    }
+
+    pub fn camera_coordinates_left(&self) -> &Vector3<f64> {
+        &self.camera_coordinates_left
+    }
+
+    pub fn robot_coordinates(&self) -> &Vector3<f64> {
+        &self.robot_coordinates
+    }
+
+    pub fn world_coordinates(&self) -> &Vector3<f64> {
+        &self.world_coordinates
+    }
+
+    pub fn depth_meters(&self) -> f64 {
+        self.depth_meters
+    }
+
+    pub fn covariance(&self) -> Option<&Matrix3<f64>> {
+        self.covariance_left.as_ref()
+    }
+
+    // inverse covariance for weighting, None if not triangulated or singular
+    pub fn information(&self) -> Option<Matrix3<f64>> {
+        self.covariance_left?.try_inverse()
+    }
+
+    pub fn disparity_pixels(&self) -> f32 {
+        self.disparity_pixels
+    }
+
+    pub fn matching_score(&self) -> Option<f32> {
+        self.matching_score
+    }
+
+    pub fn keypoint_index_left(&self) -> Option<usize> {
+        self.keypoint_index_left
+    }
 }
\ No newline at end of file
@@ -1,13 +1,27 @@
 use std::{collections::BTreeSet, num::NonZeroUsize, rc::Rc};
 
 use anyhow::{bail, Result};
-use opencv::core::{KeyPoint, KeyPointTraitConst, Mat, MatTraitConst, Vector};
+use rslam_core::{Keypoint, PixelCoordinates};
+
+use crate::{
+    descriptor::{DescriptorMetric, DescriptorNorm, DescriptorRow, Descriptors},
+    spatial_grid::SpatialGrid,
+};
+
+// cell size of the feature grid, in the order of the typical search radius
+const FEATURE_GRID_CELL_SIZE_PIXELS: f64 = 16.0;
 
 pub struct IntensityFeatureMatcher {
     pub number_of_rows: i32,
     pub number_of_cols: i32,
-    pub feature_lattice: Vec<Vec<Option<Rc<IntensityFeature>>>>,
+    // all features of the image by keypoint index (index_in_vector), not pruned
+    pub features: Vec<Rc<IntensityFeature>>,
+    // keypoint positions of the features for radius queries
+    pub feature_grid: SpatialGrid,
     pub feature_vector: Vec<Rc<IntensityFeature>>,
+    pub descriptor_metric: Option<DescriptorMetric>,
+    // descriptors by keypoint index
+    pub descriptors: Descriptors,
 }
 
 impl Default for IntensityFeatureMatcher {
@@ -15,60 +29,78 @@ impl Default for IntensityFeatureMatcher {
         Self {
             number_of_rows: 0,
             number_of_cols: 0,
-            feature_lattice: Vec::new(),
+            features: Vec::new(),
+            feature_grid: SpatialGrid::new(FEATURE_GRID_CELL_SIZE_PIXELS),
             feature_vector: Vec::new(),
+            descriptor_metric: None,
+            descriptors: Descriptors::default(),
         }
     }
 }
 
 impl IntensityFeatureMatcher {
-    pub fn configure(&mut self, rows: NonZeroUsize, cols: NonZeroUsize) {
+    pub fn configure(
+        &mut self,
+        rows: NonZeroUsize,
+        cols: NonZeroUsize,
+        descriptor_metric: DescriptorMetric,
+    ) {
         log::debug!("configuring");
-        if !self.feature_lattice.is_empty() {
-            panic!("Feature lattice is not empty");
+        if !self.features.is_empty() {
+            panic!("Feature grid is not empty");
         }
 
-        self.feature_lattice
-            .resize(rows.into(), vec![None; cols.into()]);
-
         self.number_of_rows = rows.get() as i32;
         self.number_of_cols = cols.get() as i32;
+        self.descriptor_metric = Some(descriptor_metric);
     }
 }
 
 impl IntensityFeatureMatcher {
     pub fn set_fatures(
         &mut self,
-        keypoints: &Vector<KeyPoint>,
-        descriptors: &opencv::prelude::Mat,
+        keypoints: &[Keypoint],
+        descriptors: &Descriptors,
     ) -> Result<()> {
-        if keypoints.len() != descriptors.rows() as usize {
+        if keypoints.len() != descriptors.len() {
             bail!("Number of keypoints and descriptors do not match");
         }
-
-        for row in 0..self.number_of_rows {
-            for col in 0..self.number_of_cols {
-                self.feature_lattice[row as usize][col as usize] = None;
-            }
+        if descriptors.packed().is_some() != self.is_binary() {
+            bail!("descriptors do not match the norm of the feature matcher");
         }
 
+        self.features.clear();
         self.feature_vector.clear();
+        self.descriptors = descriptors.clone();
 
         for (index, keypoint) in keypoints.iter().enumerate() {
-            let feature = Rc::new(IntensityFeature::new(
-                &keypoint,
-                &descriptors.row(index as i32).unwrap(),
-                index,
-            ));
+            let feature = Rc::new(IntensityFeature::new(keypoint, index));
             self.feature_vector.push(feature.clone());
-            let row = feature.row;
-            let col = feature.col;
-            self.feature_lattice[row as usize][col as usize] = Some(feature);
+            self.features.push(feature);
         }
+        self.feature_grid.build(
+            self.features
+                .iter()
+                .map(|feature| feature.keypoint.pixel_coordinates()),
+        );
 
         Ok(())
     }
 
+    pub fn is_binary(&self) -> bool {
+        self.descriptor_metric
+            .is_some_and(|metric| metric.norm() == DescriptorNorm::Hamming)
+    }
+
+    pub fn descriptor(&self, feature: &IntensityFeature) -> DescriptorRow<'_> {
+        self.descriptors.row(feature.index_in_vector)
+    }
+
+    // packed descriptor of a feature of this matcher, None for float descriptors
+    pub fn packed_descriptor(&self, feature: &IntensityFeature) -> Option<&[u64]> {
+        Some(self.descriptors.packed()?.row(feature.index_in_vector))
+    }
+
     pub fn sort_feature_vector(&mut self) {
         self.feature_vector.sort_by(|a, b| {
             if a.row == b.row {
@@ -79,6 +111,61 @@ impl IntensityFeatureMatcher {
         });
     }
 
+    // features with keypoints within the radius of (u, v), in keypoint order
+    pub fn features_in_radius(&self, u: f32, v: f32, radius: f32) -> Vec<Rc<IntensityFeature>> {
+        let mut indices = Vec::new();
+        self.feature_grid.indices_in_radius(
+            &PixelCoordinates::new(u as f64, v as f64),
+            radius as f64,
+            &mut indices,
+        );
+        indices.sort_unstable();
+        indices
+            .into_iter()
+            .map(|index| self.features[index].clone())
+            .collect()
+    }
+
+    // feature within the radius of (u, v) with the smallest normalized descriptor distance below
+    // maximum_descriptor_distance, ties are broken by the pixel distance
+    pub fn best_match_in_window(
+        &self,
+        descriptor_reference: DescriptorRow,
+        u: f32,
+        v: f32,
+        radius: f32,
+        maximum_descriptor_distance: f32,
+    ) -> Result<Option<(Rc<IntensityFeature>, f32)>> {
+        let Some(metric) = self.descriptor_metric else {
+            bail!("feature matcher is not configured");
+        };
+
+        let mut best: Option<(Rc<IntensityFeature>, f32)> = None;
+        let mut pixel_distance_best = f32::MAX;
+        for feature in self.features_in_radius(u, v, radius) {
+            let descriptor_distance =
+                metric.normalized_distance(descriptor_reference, self.descriptor(&feature))?;
+            if descriptor_distance >= maximum_descriptor_distance {
+                continue;
+            }
+            let pixel_distance = (feature.keypoint.pt.x - u).hypot(feature.keypoint.pt.y - v);
+            // prefer the closest descriptor, break ties by pixel distance
+            let is_better = match &best {
+                None => true,
+                Some((_, distance_best)) => {
+                    descriptor_distance < *distance_best
+                        || (descriptor_distance == *distance_best
+                            && pixel_distance < pixel_distance_best)
+                }
+            };
+            if is_better {
+                best = Some((feature, descriptor_distance));
+                pixel_distance_best = pixel_distance;
+            }
+        }
+        Ok(best)
+    }
+
     pub fn prune(&mut self, matched_indices: &BTreeSet<usize>) {
         let mut number_of_unmatched_elements = 0;
         for index in 0..self.feature_vector.len() {
@@ -97,24 +184,22 @@ impl IntensityFeatureMatcher {
 /// @struct container holding spatial and appearance information (used in findStereoKeypoints)
 pub struct IntensityFeature {
     // geometric: feature location in 2D
-    pub keypoint: KeyPoint,
-    // appearance: feature descriptor
-    pub descriptor: Mat,
+    pub keypoint: Keypoint,
+    // appearance: the descriptor is row index_in_vector of the matcher
     // pixel column coordinate (v)
     pub row: i32,
     // pixel row coordinate (u)
     pub col: i32,
     // inverted index for vector containing this
-    index_in_vector: usize,
+    pub index_in_vector: usize,
 }
 
 impl IntensityFeature {
-    pub fn new(keypoint: &KeyPoint, descriptor: &Mat, index_in_vector: usize) -> Self {
+    pub fn new(keypoint: &Keypoint, index_in_vector: usize) -> Self {
         Self {
-            keypoint: keypoint.clone(),
-            descriptor: descriptor.clone(),
-            row: keypoint.pt().y as i32,
-            col: keypoint.pt().x as i32,
+            keypoint: *keypoint,
+            row: keypoint.pt.y as i32,
+            col: keypoint.pt.x as i32,
             index_in_vector,
         }
     }
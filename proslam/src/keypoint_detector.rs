@@ -0,0 +1,406 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+use opencv::{
+    core::{KeyPoint, Ptr, Rect2i, Vector},
+    features2d::{
+        AgastFeatureDetector, AgastFeatureDetector_DetectorType, FastFeatureDetector,
+        FastFeatureDetector_DetectorType, GFTTDetector, ORB,
+    },
+    prelude::{
+        AgastFeatureDetectorTrait, FastFeatureDetectorTrait, Feature2DTrait, GFTTDetectorTrait,
+        ORBTrait,
+    },
+};
+use rslam_core::ImageView;
+
+/// keypoint detectors available for stereo matching
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeypointDetectorType {
+    Fast,
+    Agast,
+    // Shi-Tomasi minimum eigenvalue
+    Gftt,
+    // FAST on an image pyramid, ranked by harris score
+    OrbPyramid,
+    Harris,
+}
+
+impl KeypointDetectorType {
+    pub const ALL: [KeypointDetectorType; 5] = [
+        KeypointDetectorType::Fast,
+        KeypointDetectorType::Agast,
+        KeypointDetectorType::Gftt,
+        KeypointDetectorType::OrbPyramid,
+        KeypointDetectorType::Harris,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeypointDetectorType::Fast => "FAST",
+            KeypointDetectorType::Agast => "AGAST",
+            KeypointDetectorType::Gftt => "GFTT",
+            KeypointDetectorType::OrbPyramid => "ORB",
+            KeypointDetectorType::Harris => "HARRIS",
+        }
+    }
+
+    // (initial, minimum, maximum) of the sensitivity, a larger sensitivity yields fewer keypoints
+    pub fn default_sensitivity_range(&self) -> (f32, f32, f32) {
+        match self {
+            // intensity thresholds
+            KeypointDetectorType::Fast => (15.0, 5.0, 100.0),
+            KeypointDetectorType::Agast => (10.0, 5.0, 100.0),
+            KeypointDetectorType::OrbPyramid => (20.0, 5.0, 100.0),
+            // quality level relative to the strongest corner response
+            KeypointDetectorType::Gftt | KeypointDetectorType::Harris => (0.01, 0.001, 0.5),
+        }
+    }
+
+    // smallest sensitivity change of an adaptation step
+    fn minimum_sensitivity_step(&self) -> f32 {
+        match self {
+            KeypointDetectorType::Fast
+            | KeypointDetectorType::Agast
+            | KeypointDetectorType::OrbPyramid => 1.0,
+            KeypointDetectorType::Gftt | KeypointDetectorType::Harris => 0.0005,
+        }
+    }
+
+    pub fn create(
+        &self,
+        sensitivity: SensitivityControl,
+        target_number_of_keypoints: f32,
+    ) -> Result<Box<dyn KeypointDetector>> {
+        let sensitivity = SensitivityControl {
+            minimum_step: sensitivity
+                .minimum_step
+                .max(self.minimum_sensitivity_step()),
+            ..sensitivity
+        };
+        // corner detectors return at most this many keypoints, leave room for the adaptation
+        // to see an excess
+        let maximum_number_of_keypoints = (2.0 * target_number_of_keypoints).ceil().max(1.0) as i32;
+
+        Ok(match self {
+            KeypointDetectorType::Fast => Box::new(FastDetector {
+                detector: FastFeatureDetector::create(
+                    sensitivity.value.round() as i32,
+                    true,
+                    FastFeatureDetector_DetectorType::TYPE_9_16,
+                )?,
+                sensitivity,
+            }),
+            KeypointDetectorType::Agast => Box::new(AgastDetector {
+                detector: AgastFeatureDetector::create(
+                    sensitivity.value.round() as i32,
+                    true,
+                    AgastFeatureDetector_DetectorType::OAST_9_16,
+                )?,
+                sensitivity,
+            }),
+            KeypointDetectorType::Gftt | KeypointDetectorType::Harris => Box::new(CornerDetector {
+                detector: GFTTDetector::create(
+                    maximum_number_of_keypoints,
+                    sensitivity.value as f64,
+                    1.0,
+                    3,
+                    *self == KeypointDetectorType::Harris,
+                    0.04,
+                )?,
+                sensitivity,
+            }),
+            KeypointDetectorType::OrbPyramid => {
+                let mut detector = ORB::create_def()?;
+                detector.set_max_features(maximum_number_of_keypoints)?;
+                detector.set_fast_threshold(sensitivity.value.round() as i32)?;
+                Box::new(OrbPyramidDetector {
+                    detector,
+                    sensitivity,
+                })
+            }
+        })
+    }
+}
+
+impl fmt::Display for KeypointDetectorType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for KeypointDetectorType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let detector_type = match s.trim().to_ascii_uppercase().as_str() {
+            "FAST" => KeypointDetectorType::Fast,
+            "AGAST" => KeypointDetectorType::Agast,
+            "GFTT" | "SHI-TOMASI" => KeypointDetectorType::Gftt,
+            "ORB" | "ORB-PYRAMID" => KeypointDetectorType::OrbPyramid,
+            "HARRIS" => KeypointDetectorType::Harris,
+            _ => bail!(
+                "unknown detector type '{}', expected one of: {}",
+                s,
+                KeypointDetectorType::ALL
+                    .map(|detector_type| detector_type.name())
+                    .join(", ")
+            ),
+        };
+        Ok(detector_type)
+    }
+}
+
+/// adaptive detector sensitivity, raised when a detector returns too many keypoints and lowered
+/// when it returns too few
+#[derive(Clone, Copy, Debug)]
+pub struct SensitivityControl {
+    pub value: f32,
+    pub minimum: f32,
+    pub maximum: f32,
+    // largest relative change of a single adaptation step
+    pub maximum_change: f32,
+    // smallest absolute change of a single adaptation step
+    pub minimum_step: f32,
+    // relative deviation from the target which is accepted without adaptation
+    pub tolerance: f32,
+}
+
+impl SensitivityControl {
+    pub fn update(&mut self, number_of_keypoints: usize, target_number_of_keypoints: f32) {
+        let delta =
+            (number_of_keypoints as f32 - target_number_of_keypoints) / target_number_of_keypoints;
+
+        if delta < -self.tolerance {
+            // too few keypoints, lower the sensitivity
+            let change = delta.max(-self.maximum_change);
+            self.value += (change * self.value).min(-self.minimum_step);
+        } else if delta > self.tolerance {
+            // too many keypoints, raise the sensitivity
+            let change = delta.min(self.maximum_change);
+            self.value += (change * self.value).max(self.minimum_step);
+        }
+        self.value = self.value.clamp(self.minimum, self.maximum);
+    }
+}
+
+/// keypoint detector with an adaptive sensitivity, detectors of different regions and cameras
+/// run on separate threads
+pub trait KeypointDetector: Send {
+    // keypoints in region coordinates
+    fn detect(&mut self, image: &ImageView, region: Rect2i) -> Result<Vector<KeyPoint>>;
+
+    fn sensitivity(&self) -> &SensitivityControl;
+
+    fn sensitivity_mut(&mut self) -> &mut SensitivityControl;
+
+    // pass the current sensitivity on to the underlying detector
+    fn apply_sensitivity(&mut self) -> Result<()>;
+}
+
+fn detect_in_region(
+    detector: &mut impl Feature2DTrait,
+    image: &ImageView,
+    region: Rect2i,
+) -> Result<Vector<KeyPoint>> {
+    let Some(image_region) = image.sub_view(
+        region.x as usize,
+        region.y as usize,
+        region.width as usize,
+        region.height as usize,
+    ) else {
+        bail!(
+            "detector region {:?} exceeds the image of {}x{}",
+            region,
+            image.width(),
+            image.height()
+        );
+    };
+    let mut keypoints = Vector::<KeyPoint>::new();
+    detector.detect_def(&image_region.to_mat()?, &mut keypoints)?;
+    Ok(keypoints)
+}
+
+struct FastDetector {
+    detector: Ptr<FastFeatureDetector>,
+    sensitivity: SensitivityControl,
+}
+
+impl KeypointDetector for FastDetector {
+    fn detect(&mut self, image: &ImageView, region: Rect2i) -> Result<Vector<KeyPoint>> {
+        detect_in_region(&mut self.detector, image, region)
+    }
+
+    fn sensitivity(&self) -> &SensitivityControl {
+        &self.sensitivity
+    }
+
+    fn sensitivity_mut(&mut self) -> &mut SensitivityControl {
+        &mut self.sensitivity
+    }
+
+    fn apply_sensitivity(&mut self) -> Result<()> {
+        self.detector
+            .set_threshold(self.sensitivity.value.round() as i32)?;
+        Ok(())
+    }
+}
+
+struct AgastDetector {
+    detector: Ptr<AgastFeatureDetector>,
+    sensitivity: SensitivityControl,
+}
+
+impl KeypointDetector for AgastDetector {
+    fn detect(&mut self, image: &ImageView, region: Rect2i) -> Result<Vector<KeyPoint>> {
+        detect_in_region(&mut self.detector, image, region)
+    }
+
+    fn sensitivity(&self) -> &SensitivityControl {
+        &self.sensitivity
+    }
+
+    fn sensitivity_mut(&mut self) -> &mut SensitivityControl {
+        &mut self.sensitivity
+    }
+
+    fn apply_sensitivity(&mut self) -> Result<()> {
+        self.detector
+            .set_threshold(self.sensitivity.value.round() as i32)?;
+        Ok(())
+    }
+}
+
+// GFTT with either the minimum eigenvalue or the harris response
+struct CornerDetector {
+    detector: Ptr<GFTTDetector>,
+    sensitivity: SensitivityControl,
+}
+
+impl KeypointDetector for CornerDetector {
+    fn detect(&mut self, image: &ImageView, region: Rect2i) -> Result<Vector<KeyPoint>> {
+        detect_in_region(&mut self.detector, image, region)
+    }
+
+    fn sensitivity(&self) -> &SensitivityControl {
+        &self.sensitivity
+    }
+
+    fn sensitivity_mut(&mut self) -> &mut SensitivityControl {
+        &mut self.sensitivity
+    }
+
+    fn apply_sensitivity(&mut self) -> Result<()> {
+        self.detector
+            .set_quality_level(self.sensitivity.value as f64)?;
+        Ok(())
+    }
+}
+
+// keypoints closer than the ORB edge threshold to the region border are discarded
+struct OrbPyramidDetector {
+    detector: Ptr<ORB>,
+    sensitivity: SensitivityControl,
+}
+
+impl KeypointDetector for OrbPyramidDetector {
+    fn detect(&mut self, image: &ImageView, region: Rect2i) -> Result<Vector<KeyPoint>> {
+        detect_in_region(&mut self.detector, image, region)
+    }
+
+    fn sensitivity(&self) -> &SensitivityControl {
+        &self.sensitivity
+    }
+
+    fn sensitivity_mut(&mut self) -> &mut SensitivityControl {
+        &mut self.sensitivity
+    }
+
+    fn apply_sensitivity(&mut self) -> Result<()> {
+        self.detector
+            .set_fast_threshold(self.sensitivity.value.round() as i32)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensitivity(value: f32) -> SensitivityControl {
+        SensitivityControl {
+            value,
+            minimum: 5.0,
+            maximum: 100.0,
+            maximum_change: 0.5,
+            minimum_step: 1.0,
+            tolerance: 0.1,
+        }
+    }
+
+    #[test]
+    fn keeps_the_sensitivity_within_the_tolerance() {
+        let mut control = sensitivity(20.0);
+        for number_of_keypoints in [90, 100, 110] {
+            control.update(number_of_keypoints, 100.0);
+            assert_eq!(control.value, 20.0);
+        }
+    }
+
+    #[test]
+    fn adapts_the_sensitivity_by_the_relative_deviation() {
+        let mut control = sensitivity(20.0);
+        control.update(80, 100.0);
+        assert_eq!(control.value, 16.0);
+        control.update(120, 100.0);
+        assert!((control.value - 19.2).abs() < 1e-5);
+
+        // changes are limited to maximum_change
+        let mut control = sensitivity(20.0);
+        control.update(0, 100.0);
+        assert_eq!(control.value, 10.0);
+        control.update(1000, 100.0);
+        assert_eq!(control.value, 15.0);
+    }
+
+    #[test]
+    fn changes_the_sensitivity_by_at_least_the_minimum_step() {
+        // 20% of 2 would be lost in the rounding of integer thresholds
+        let mut control = SensitivityControl {
+            minimum: 0.0,
+            ..sensitivity(2.0)
+        };
+        control.update(80, 100.0);
+        assert_eq!(control.value, 1.0);
+        control.update(120, 100.0);
+        assert_eq!(control.value, 2.0);
+    }
+
+    #[test]
+    fn clamps_the_sensitivity() {
+        let mut control = sensitivity(6.0);
+        control.update(0, 100.0);
+        assert_eq!(control.value, 5.0);
+        control.update(0, 100.0);
+        assert_eq!(control.value, 5.0);
+
+        let mut control = sensitivity(90.0);
+        control.update(1000, 100.0);
+        assert_eq!(control.value, 100.0);
+    }
+
+    #[test]
+    fn default_ranges_contain_the_initial_sensitivity() {
+        for detector_type in KeypointDetectorType::ALL {
+            let (initial, minimum, maximum) = detector_type.default_sensitivity_range();
+            assert!(minimum < initial && initial < maximum, "{}", detector_type);
+            assert_eq!(
+                detector_type
+                    .name()
+                    .parse::<KeypointDetectorType>()
+                    .unwrap(),
+                detector_type
+            );
+        }
+    }
+}
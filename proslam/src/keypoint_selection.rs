@@ -0,0 +1,285 @@
+//! selection of an evenly distributed subset of the detected keypoints, either by grid bucketing
+//! or by adaptive non-maximal suppression with suppression via square covering (SSC, Bailo et al.
+//! 2018)
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+use rslam_core::Keypoint;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeypointSelectionType {
+    // all detected keypoints
+    None,
+    // the strongest keypoints of each bin
+    Bucketing,
+    // suppression via square covering
+    Anms,
+}
+
+impl KeypointSelectionType {
+    pub const ALL: [KeypointSelectionType; 3] = [
+        KeypointSelectionType::None,
+        KeypointSelectionType::Bucketing,
+        KeypointSelectionType::Anms,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeypointSelectionType::None => "NONE",
+            KeypointSelectionType::Bucketing => "BUCKETING",
+            KeypointSelectionType::Anms => "ANMS",
+        }
+    }
+}
+
+impl fmt::Display for KeypointSelectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for KeypointSelectionType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let selection_type = match s.trim().to_ascii_uppercase().as_str() {
+            "NONE" => KeypointSelectionType::None,
+            "BUCKETING" | "GRID" => KeypointSelectionType::Bucketing,
+            "ANMS" | "SSC" => KeypointSelectionType::Anms,
+            _ => bail!(
+                "unknown keypoint selection '{}', expected one of: {}",
+                s,
+                KeypointSelectionType::ALL
+                    .map(|selection_type| selection_type.name())
+                    .join(", ")
+            ),
+        };
+        Ok(selection_type)
+    }
+}
+
+/// selects at most the target number of keypoints of an image
+#[derive(Clone, Copy, Debug)]
+pub struct KeypointSelector {
+    pub selection_type: KeypointSelectionType,
+    pub width: usize,
+    pub height: usize,
+    pub bin_size_pixels: usize,
+    pub keypoints_per_bin: usize,
+    pub target_number_of_keypoints: usize,
+    // accepted relative deviation of the ANMS result from the target
+    pub tolerance: f32,
+}
+
+impl KeypointSelector {
+    // indices of the selected keypoints in ascending order, so that the input order is kept
+    pub fn select(&self, keypoints: &[Keypoint]) -> Vec<usize> {
+        let mut selected = match self.selection_type {
+            KeypointSelectionType::None => return (0..keypoints.len()).collect(),
+            _ if keypoints.len() <= self.target_number_of_keypoints => {
+                return (0..keypoints.len()).collect()
+            }
+            KeypointSelectionType::Bucketing => self.bucketing(keypoints),
+            KeypointSelectionType::Anms => self.ssc(keypoints),
+        };
+        selected.sort_unstable();
+        selected
+    }
+
+    // top keypoints_per_bin of every bin, free slots of empty bins go to the strongest remaining
+    // keypoints
+    fn bucketing(&self, keypoints: &[Keypoint]) -> Vec<usize> {
+        let bin_size = self.bin_size_pixels.max(1) as f32;
+        let number_of_cols_bin = self.width / self.bin_size_pixels.max(1) + 1;
+        let number_of_rows_bin = self.height / self.bin_size_pixels.max(1) + 1;
+
+        let mut number_per_bin = vec![0; number_of_cols_bin * number_of_rows_bin];
+        let mut selected = Vec::with_capacity(self.target_number_of_keypoints);
+        let mut remaining = Vec::new();
+        for index in sorted_by_response(keypoints) {
+            let pt = keypoints[index].pt;
+            let col = ((pt.x.max(0.0) / bin_size) as usize).min(number_of_cols_bin - 1);
+            let row = ((pt.y.max(0.0) / bin_size) as usize).min(number_of_rows_bin - 1);
+            let number = &mut number_per_bin[row * number_of_cols_bin + col];
+            if *number < self.keypoints_per_bin {
+                *number += 1;
+                selected.push(index);
+            } else {
+                remaining.push(index);
+            }
+        }
+
+        selected.truncate(self.target_number_of_keypoints);
+        let number_of_free = self.target_number_of_keypoints - selected.len();
+        selected.extend(remaining.into_iter().take(number_of_free));
+        selected
+    }
+
+    // binary search for the suppression width which keeps the target number of keypoints
+    fn ssc(&self, keypoints: &[Keypoint]) -> Vec<usize> {
+        let sorted = sorted_by_response(keypoints);
+        let target = self.target_number_of_keypoints;
+        if target <= 1 {
+            return sorted.into_iter().take(target).collect();
+        }
+
+        let cols = self.width as f64;
+        let rows = self.height as f64;
+        let number_of_keypoints = keypoints.len() as f64;
+        let k = target as f64;
+
+        // initial upper bound of the width from the closed form of the paper
+        let exp1 = rows + cols + 2.0 * k;
+        let exp2 = 4.0 * cols + 4.0 * k + 4.0 * rows * k + rows * rows + cols * cols
+            - 2.0 * rows * cols
+            + 4.0 * rows * cols * k;
+        let exp3 = exp2.sqrt();
+        let exp4 = k - 1.0;
+        let sol1 = -((exp1 + exp3) / exp4).round();
+        let sol2 = -((exp1 - exp3) / exp4).round();
+        let mut high = sol1.max(sol2) as i64;
+        let mut low = (number_of_keypoints / k).sqrt().floor() as i64;
+
+        let k_min = (k - k * self.tolerance as f64).round() as usize;
+        let k_max = (k + k * self.tolerance as f64).round() as usize;
+
+        let mut result = Vec::new();
+        let mut previous_width = -1;
+        loop {
+            let width = low + (high - low) / 2;
+            if width == previous_width || low > high {
+                break;
+            }
+            result = self.cover(keypoints, &sorted, width.max(1) as f32);
+            if (k_min..=k_max).contains(&result.len()) {
+                break;
+            }
+            if result.len() < k_min {
+                high = width - 1;
+            } else {
+                low = width + 1;
+            }
+            previous_width = width;
+        }
+        // the initial bounds may already be crossed, e.g. many keypoints in a small image
+        if result.is_empty() {
+            return sorted.into_iter().take(target).collect();
+        }
+        result.truncate(target);
+        result
+    }
+
+    // keypoints in order of response, each covering a square of the width around itself
+    fn cover(&self, keypoints: &[Keypoint], sorted: &[usize], width: f32) -> Vec<usize> {
+        let cell = width / 2.0;
+        let number_of_cols = (self.width as f32 / cell) as usize + 1;
+        let number_of_rows = (self.height as f32 / cell) as usize + 1;
+        let cells_covered = (width / cell).floor() as usize;
+
+        let mut covered = vec![false; number_of_cols * number_of_rows];
+        let mut result = Vec::new();
+        for &index in sorted {
+            let pt = keypoints[index].pt;
+            let col = ((pt.x.max(0.0) / cell) as usize).min(number_of_cols - 1);
+            let row = ((pt.y.max(0.0) / cell) as usize).min(number_of_rows - 1);
+            if covered[row * number_of_cols + col] {
+                continue;
+            }
+            result.push(index);
+
+            let row_end = (row + cells_covered).min(number_of_rows - 1);
+            let col_end = (col + cells_covered).min(number_of_cols - 1);
+            for r in row.saturating_sub(cells_covered)..=row_end {
+                for c in col.saturating_sub(cells_covered)..=col_end {
+                    covered[r * number_of_cols + c] = true;
+                }
+            }
+        }
+        result
+    }
+}
+
+// strongest first, ties in input order
+fn sorted_by_response(keypoints: &[Keypoint]) -> Vec<usize> {
+    let mut sorted: Vec<usize> = (0..keypoints.len()).collect();
+    sorted.sort_by(|a, b| keypoints[*b].response.total_cmp(&keypoints[*a].response));
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector(
+        selection_type: KeypointSelectionType,
+        size: usize,
+        target: usize,
+    ) -> KeypointSelector {
+        KeypointSelector {
+            selection_type,
+            width: size,
+            height: size,
+            bin_size_pixels: 4,
+            keypoints_per_bin: 1,
+            target_number_of_keypoints: target,
+            tolerance: 0.1,
+        }
+    }
+
+    // a regular grid of keypoints with distinct responses
+    fn keypoints(size: usize, step: f32) -> Vec<Keypoint> {
+        let number_per_row = (size as f32 / step) as usize;
+        (0..number_per_row * number_per_row)
+            .map(|index| {
+                let x = (index % number_per_row) as f32 * step;
+                let y = (index / number_per_row) as f32 * step;
+                Keypoint::new(x, y, 7.0, ((index * 7919) % 1000) as f32)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ssc_selects_the_target_number_of_keypoints() {
+        let keypoints = keypoints(640, 8.0);
+        let selected = selector(KeypointSelectionType::Anms, 640, 500).select(&keypoints);
+        assert!((450..=500).contains(&selected.len()), "{}", selected.len());
+        assert!(selected.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn ssc_falls_back_to_the_strongest_keypoints_when_the_bounds_cross() {
+        let keypoints = keypoints(10, 0.1);
+        let selected = selector(KeypointSelectionType::Anms, 10, 2).select(&keypoints);
+        let mut expected = sorted_by_response(&keypoints)[..2].to_vec();
+        expected.sort_unstable();
+        assert_eq!(selected, expected);
+    }
+
+    #[test]
+    fn bucketing_fills_free_slots_with_the_strongest_keypoints() {
+        let keypoints = keypoints(16, 1.0);
+        let selected = selector(KeypointSelectionType::Bucketing, 16, 40).select(&keypoints);
+        assert_eq!(selected.len(), 40);
+
+        // the strongest keypoint of each of the 4x4 bins
+        let mut best_per_bin = std::collections::BTreeMap::<(usize, usize), usize>::new();
+        for (index, keypoint) in keypoints.iter().enumerate() {
+            let bin = (keypoint.pt.x as usize / 4, keypoint.pt.y as usize / 4);
+            let best = best_per_bin.entry(bin).or_insert(index);
+            if keypoint.response > keypoints[*best].response {
+                *best = index;
+            }
+        }
+        assert_eq!(best_per_bin.len(), 16);
+        // the 24 free slots go to the strongest of the others
+        let mut expected: Vec<usize> = best_per_bin.values().copied().collect();
+        expected.extend(
+            sorted_by_response(&keypoints)
+                .into_iter()
+                .filter(|index| !best_per_bin.values().any(|best| best == index))
+                .take(24),
+        );
+        expected.sort_unstable();
+        assert_eq!(selected, expected);
+    }
+}
@@ -1,4 +1,22 @@
+pub mod dense_stereo;
+#[cfg(feature = "opencv")]
+pub mod descriptor;
+#[cfg(feature = "opencv")]
 pub mod intensity_feature_matcher;
+#[cfg(feature = "opencv")]
+pub mod keypoint_detector;
+pub mod keypoint_selection;
+#[cfg(feature = "native-features")]
+pub mod native_features;
+pub mod pose_graph;
+pub mod spatial_grid;
+#[cfg(feature = "opencv")]
 pub mod stereo_frame_point_generator;
+#[cfg(feature = "opencv")]
 pub mod stereo_framepoint;
-pub mod frame;
\ No newline at end of file
+pub mod subpixel_refinement;
+#[cfg(test)]
+mod test_utils;
+pub mod frame;
+pub mod tracking;
+pub mod trajectory_export;
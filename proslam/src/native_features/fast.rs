@@ -0,0 +1,233 @@
+use rslam_core::{ImageView, Keypoint};
+
+// bresenham circle of radius 3 as (x, y) offsets, in the order used by OpenCV
+const CIRCLE: [(isize, isize); 16] = [
+    (0, 3),
+    (1, 3),
+    (2, 2),
+    (3, 1),
+    (3, 0),
+    (3, -1),
+    (2, -2),
+    (1, -3),
+    (0, -3),
+    (-1, -3),
+    (-2, -2),
+    (-3, -1),
+    (-3, 0),
+    (-3, 1),
+    (-2, 2),
+    (-1, 3),
+];
+
+// a corner needs an arc of this many brighter or darker pixels
+const ARC_LENGTH: usize = 9;
+
+/// FAST-9 (16 pixel circle) corner detector, equivalent to `cv::FAST` with `TYPE_9_16`
+#[derive(Clone, Copy, Debug)]
+pub struct FastDetector {
+    pub threshold: u8,
+    pub nonmax_suppression: bool,
+}
+
+impl FastDetector {
+    pub fn new(threshold: u8, nonmax_suppression: bool) -> Self {
+        Self {
+            threshold,
+            nonmax_suppression,
+        }
+    }
+
+    // keypoints in row major order, responses are the corner scores if non-maximum suppression
+    // is enabled and 0 otherwise
+    pub fn detect(&self, image: &ImageView) -> Vec<Keypoint> {
+        let width = image.width();
+        let height = image.height();
+        if width < 7 || height < 7 {
+            return Vec::new();
+        }
+
+        let stride = image.stride() as isize;
+        let offsets = CIRCLE.map(|(x, y)| x + y * stride);
+        let data = image.data();
+        let threshold = self.threshold as i16;
+
+        // corner scores, 0 for non corners
+        let mut scores = vec![0u8; width * height];
+        let mut corners = Vec::new();
+        for y in 3..height - 3 {
+            for x in 3..width - 3 {
+                let center = (y * image.stride() + x) as isize;
+                let mut differences = [0i16; 16];
+                for (difference, offset) in differences.iter_mut().zip(offsets) {
+                    *difference =
+                        data[center as usize] as i16 - data[(center + offset) as usize] as i16;
+                }
+                if !is_corner(&differences, threshold) {
+                    continue;
+                }
+                if self.nonmax_suppression {
+                    scores[y * width + x] = corner_score(&differences, threshold) as u8;
+                }
+                corners.push((x, y));
+            }
+        }
+
+        corners
+            .into_iter()
+            .filter_map(|(x, y)| {
+                let score = scores[y * width + x];
+                if self.nonmax_suppression {
+                    for neighbor_y in y - 1..=y + 1 {
+                        for neighbor_x in x - 1..=x + 1 {
+                            if (neighbor_x, neighbor_y) != (x, y)
+                                && scores[neighbor_y * width + neighbor_x] >= score
+                            {
+                                return None;
+                            }
+                        }
+                    }
+                }
+                Some(Keypoint::new(x as f32, y as f32, 7.0, score as f32))
+            })
+            .collect()
+    }
+}
+
+// differences are center minus circle pixel, positive for darker circle pixels
+fn is_corner(differences: &[i16; 16], threshold: i16) -> bool {
+    // an arc of 9 contains at least two of the four compass pixels
+    let compass = [0, 4, 8, 12];
+    let darker = compass
+        .iter()
+        .filter(|&&k| differences[k] > threshold)
+        .count();
+    let brighter = compass
+        .iter()
+        .filter(|&&k| differences[k] < -threshold)
+        .count();
+    if darker < 2 && brighter < 2 {
+        return false;
+    }
+
+    let has_arc = |is_member: &dyn Fn(i16) -> bool| {
+        let mut length = 0;
+        for k in 0..16 + ARC_LENGTH - 1 {
+            if is_member(differences[k % 16]) {
+                length += 1;
+                if length >= ARC_LENGTH {
+                    return true;
+                }
+            } else {
+                length = 0;
+            }
+        }
+        false
+    };
+    (darker >= 2 && has_arc(&|difference| difference > threshold))
+        || (brighter >= 2 && has_arc(&|difference| difference < -threshold))
+}
+
+// largest threshold for which the pixel is still a corner, as in OpenCV's cornerScore<16>
+fn corner_score(differences: &[i16; 16], threshold: i16) -> i16 {
+    let d = |k: usize| differences[k % 16];
+
+    let mut a0 = threshold;
+    for k in (0..16).step_by(2) {
+        let mut a = d(k + 1).min(d(k + 2)).min(d(k + 3));
+        if a <= a0 {
+            continue;
+        }
+        for m in 4..=8 {
+            a = a.min(d(k + m));
+        }
+        a0 = a0.max(a.min(d(k)));
+        a0 = a0.max(a.min(d(k + 9)));
+    }
+
+    let mut b0 = -a0;
+    for k in (0..16).step_by(2) {
+        let mut b = d(k + 1).max(d(k + 2)).max(d(k + 3));
+        b = b.max(d(k + 4)).max(d(k + 5));
+        if b >= b0 {
+            continue;
+        }
+        for m in 6..=8 {
+            b = b.max(d(k + m));
+        }
+        b0 = b0.min(b.max(d(k)));
+        b0 = b0.min(b.max(d(k + 9)));
+    }
+
+    -b0 - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_core::GrayImage;
+
+    // dark image with a bright square from (10, 10) to the bottom right corner, the square
+    // darkens slightly away from its corner so that the corner scores do not tie
+    fn square_image() -> GrayImage<u8> {
+        let mut image = GrayImage::new(32, 32);
+        for y in 10..32 {
+            for x in 10..32 {
+                image.set(x, y, (250 - (x - 10) - (y - 10)) as u8);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn detects_the_corner_of_a_square() {
+        let keypoints = FastDetector::new(20, true).detect(&square_image().view());
+        assert!(!keypoints.is_empty());
+        assert!(keypoints
+            .iter()
+            .any(|keypoint| (keypoint.pt.x - 10.0).abs() <= 1.0
+                && (keypoint.pt.y - 10.0).abs() <= 1.0
+                && keypoint.response > 20.0));
+        // only the corner, straight edges have arcs of at most 7 pixels on either side
+        for keypoint in &keypoints {
+            assert!(
+                keypoint.pt.x < 14.0 && keypoint.pt.y < 14.0,
+                "{:?}",
+                keypoint
+            );
+        }
+    }
+
+    #[test]
+    fn detects_nothing_on_a_flat_image() {
+        let mut image = GrayImage::new(32, 32);
+        for y in 0..32 {
+            for x in 0..32 {
+                image.set(x, y, 100);
+            }
+        }
+        for nonmax_suppression in [false, true] {
+            let detector = FastDetector::new(5, nonmax_suppression);
+            assert!(detector.detect(&image.view()).is_empty());
+        }
+        assert!(FastDetector::new(5, true)
+            .detect(&GrayImage::<u8>::new(6, 6).view())
+            .is_empty());
+    }
+
+    #[test]
+    fn suppresses_neighboring_corners() {
+        let image = square_image();
+        let all = FastDetector::new(20, false).detect(&image.view());
+        let suppressed = FastDetector::new(20, true).detect(&image.view());
+        assert!(suppressed.len() < all.len());
+        assert!(all.iter().all(|keypoint| keypoint.response == 0.0));
+        for keypoint in &suppressed {
+            assert!(all.iter().any(|other| other.pt == keypoint.pt));
+            for other in &suppressed {
+                let distance = (keypoint.pt - other.pt).abs();
+                assert!(keypoint.pt == other.pt || distance.x > 1.0 || distance.y > 1.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,13 @@
+//! OpenCV free FAST-9 detection and rotated BRIEF (ORB) description on plain grayscale buffers.
+//!
+//! Both follow the OpenCV implementations closely so that keypoints and descriptors can be
+//! compared against the OpenCV path, see `examples/validate_native_features.rs`. The stereo
+//! frontend built on them is available without the opencv feature.
+pub mod fast;
+pub mod orb;
+pub mod stereo;
+
+use rslam_core::BinaryDescriptor256;
+
+// 256 bit binary descriptor
+pub type OrbDescriptor = BinaryDescriptor256;
@@ -0,0 +1,644 @@
+use rslam_core::{ImageView, Keypoint};
+
+use super::OrbDescriptor;
+
+const NUMBER_OF_TESTS: usize = 256;
+
+/// rotated BRIEF descriptor extractor following `cv::ORB::compute` for keypoints of octave 0
+///
+/// The default patch size of 31 uses OpenCV's learned test pattern, every other patch size the
+/// seeded random pattern of OpenCV.
+#[derive(Clone, Debug)]
+pub struct OrbExtractor {
+    patch_size: usize,
+    // keypoints closer to the image border are removed
+    edge_threshold: usize,
+    // rotate the tests by the keypoint angle, plain BRIEF otherwise
+    steered: bool,
+    // test point pairs
+    pattern: Vec<(i32, i32)>,
+    // half width of the circular orientation patch per row
+    u_max: Vec<i32>,
+    gaussian_kernel: [u32; 7],
+}
+
+impl Default for OrbExtractor {
+    fn default() -> Self {
+        Self::new(31, 31, true)
+    }
+}
+
+impl OrbExtractor {
+    // the edge threshold is raised to keep rotated tests inside the image
+    pub fn new(patch_size: usize, edge_threshold: usize, steered: bool) -> Self {
+        let patch_size = patch_size.max(2);
+        let minimum_edge_threshold =
+            ((patch_size / 2) as f64 * std::f64::consts::SQRT_2).ceil() as usize + 4;
+        Self {
+            patch_size,
+            edge_threshold: edge_threshold.max(minimum_edge_threshold),
+            steered,
+            pattern: if patch_size == 31 {
+                BIT_PATTERN_31
+                    .iter()
+                    .flat_map(|test| [(test[0], test[1]), (test[2], test[3])])
+                    .map(|(x, y)| (x as i32, y as i32))
+                    .collect()
+            } else {
+                random_pattern(patch_size as i32, 2 * NUMBER_OF_TESTS)
+            },
+            u_max: circular_patch_extents(patch_size / 2),
+            gaussian_kernel: gaussian_kernel_fixed_point(),
+        }
+    }
+
+    pub fn patch_size(&self) -> usize {
+        self.patch_size
+    }
+
+    pub fn edge_threshold(&self) -> usize {
+        self.edge_threshold
+    }
+
+    // intensity centroid orientation, for keypoints at least half a patch away from the border
+    pub fn compute_orientations(&self, image: &ImageView, keypoints: &mut [Keypoint]) {
+        let half_patch_size = (self.patch_size / 2) as isize;
+        let stride = image.stride() as isize;
+        let data = image.data();
+        for keypoint in keypoints.iter_mut() {
+            let x = round_ties_even(keypoint.pt.x) as isize;
+            let y = round_ties_even(keypoint.pt.y) as isize;
+            if x < half_patch_size
+                || y < half_patch_size
+                || x + half_patch_size >= image.width() as isize
+                || y + half_patch_size >= image.height() as isize
+            {
+                continue;
+            }
+            let center = y * stride + x;
+            let pixel = |offset: isize| data[(center + offset) as usize] as i32;
+
+            let mut m_01 = 0;
+            let mut m_10 = 0;
+            for u in -half_patch_size..=half_patch_size {
+                m_10 += u as i32 * pixel(u);
+            }
+            for v in 1..=half_patch_size {
+                let mut v_sum = 0;
+                let d = self.u_max[v as usize] as isize;
+                for u in -d..=d {
+                    let value_plus = pixel(u + v * stride);
+                    let value_minus = pixel(u - v * stride);
+                    v_sum += value_plus - value_minus;
+                    m_10 += u as i32 * (value_plus + value_minus);
+                }
+                m_01 += v as i32 * v_sum;
+            }
+            keypoint.angle = fast_atan2(m_01 as f32, m_10 as f32);
+        }
+    }
+
+    // removes keypoints within the edge threshold of the border, like OpenCV the tests are
+    // rotated by the keypoint angle even if it was not computed (-1)
+    pub fn compute(&self, image: &ImageView, keypoints: &mut Vec<Keypoint>) -> Vec<OrbDescriptor> {
+        let border = self.edge_threshold as f32;
+        let width = image.width() as f32;
+        let height = image.height() as f32;
+        keypoints.retain(|keypoint| {
+            keypoint.pt.x >= border
+                && keypoint.pt.y >= border
+                && keypoint.pt.x < width - border
+                && keypoint.pt.y < height - border
+        });
+        if keypoints.is_empty() {
+            return Vec::new();
+        }
+
+        let blurred = gaussian_blur(image, &self.gaussian_kernel);
+        let width = image.width() as isize;
+        keypoints
+            .iter()
+            .map(|keypoint| {
+                let angle = if self.steered { keypoint.angle } else { 0.0 };
+                let angle = angle * (std::f64::consts::PI / 180.0) as f32;
+                let a = (angle as f64).cos() as f32;
+                let b = (angle as f64).sin() as f32;
+                let center = round_ties_even(keypoint.pt.y) as isize * width
+                    + round_ties_even(keypoint.pt.x) as isize;
+                let value = |&(x, y): &(i32, i32)| {
+                    let (x, y) = (x as f32, y as f32);
+                    let ix = round_ties_even(x * a - y * b) as isize;
+                    let iy = round_ties_even(x * b + y * a) as isize;
+                    blurred[(center + iy * width + ix) as usize]
+                };
+
+                let mut descriptor = OrbDescriptor::default();
+                for (index, test) in self.pattern.chunks_exact(2).enumerate() {
+                    descriptor.set_bit(index, value(&test[0]) < value(&test[1]));
+                }
+                descriptor
+            })
+            .collect()
+    }
+}
+
+// cvRound
+fn round_ties_even(value: f32) -> i32 {
+    value.round_ties_even() as i32
+}
+
+// cv::RNG, multiply with carry
+struct OpencvRng {
+    state: u64,
+}
+
+impl OpencvRng {
+    fn new(seed: u64) -> Self {
+        Self {
+            state: if seed == 0 { 0xffffffff } else { seed },
+        }
+    }
+
+    fn next(&mut self) -> u32 {
+        self.state = (self.state & 0xffffffff) * 4164903690 + (self.state >> 32);
+        self.state as u32
+    }
+
+    // uniform integer in [a, b)
+    fn uniform(&mut self, a: i32, b: i32) -> i32 {
+        if a == b {
+            return a;
+        }
+        (self.next() % (b - a) as u32) as i32 + a
+    }
+}
+
+// makeRandomPattern of OpenCV's orb.cpp
+fn random_pattern(patch_size: i32, number_of_points: usize) -> Vec<(i32, i32)> {
+    let mut rng = OpencvRng::new(0x34985739);
+    (0..number_of_points)
+        .map(|_| {
+            let x = rng.uniform(-patch_size / 2, patch_size / 2 + 1);
+            let y = rng.uniform(-patch_size / 2, patch_size / 2 + 1);
+            (x, y)
+        })
+        .collect()
+}
+
+fn circular_patch_extents(half_patch_size: usize) -> Vec<i32> {
+    let half_patch_size_f = half_patch_size as f32;
+    let mut u_max = vec![0; half_patch_size + 2];
+    let v_max = (half_patch_size_f * 2f32.sqrt() / 2.0 + 1.0).floor() as usize;
+    let v_min = (half_patch_size_f * 2f32.sqrt() / 2.0).ceil() as usize;
+    for (v, u) in u_max.iter_mut().enumerate().take(v_max + 1) {
+        *u = (((half_patch_size * half_patch_size) as f64 - (v * v) as f64).sqrt())
+            .round_ties_even() as i32;
+    }
+    // make the patch symmetric
+    let mut v0 = 0;
+    for v in (v_min..=half_patch_size).rev() {
+        while u_max[v0] == u_max[v0 + 1] {
+            v0 += 1;
+        }
+        u_max[v] = v0 as i32;
+        v0 += 1;
+    }
+    u_max
+}
+
+// cv::fastAtan2, degrees in [0, 360)
+fn fast_atan2(y: f32, x: f32) -> f32 {
+    const SCALE: f32 = (180.0 / std::f64::consts::PI) as f32;
+    let p1 = 0.9997878412794807_f64 as f32 * SCALE;
+    let p3 = -0.3258083974640975_f64 as f32 * SCALE;
+    let p5 = 0.1555786518463281_f64 as f32 * SCALE;
+    let p7 = -0.04432655554792128_f64 as f32 * SCALE;
+
+    let ax = x.abs();
+    let ay = y.abs();
+    let polynomial = |c: f32| {
+        let c2 = c * c;
+        (((p7 * c2 + p5) * c2 + p3) * c2 + p1) * c
+    };
+    let mut a = if ax >= ay {
+        polynomial(ay / (ax + f64::EPSILON as f32))
+    } else {
+        90.0 - polynomial(ax / (ay + f64::EPSILON as f32))
+    };
+    if x < 0.0 {
+        a = 180.0 - a;
+    }
+    if y < 0.0 {
+        a = 360.0 - a;
+    }
+    a
+}
+
+// 7 tap gaussian with sigma 2 in 8 bit fixed point, rounding errors are diffused towards the
+// center tap so that the weights sum to exactly 256 (OpenCV's bit exact GaussianBlur)
+fn gaussian_kernel_fixed_point() -> [u32; 7] {
+    const SIGMA: f64 = 2.0;
+    let weights: Vec<f64> = (0..7)
+        .map(|i| {
+            let x = i as f64 - 3.0;
+            (-x * x / (2.0 * SIGMA * SIGMA)).exp()
+        })
+        .collect();
+    let sum: f64 = weights.iter().sum();
+
+    let mut kernel = [0u32; 7];
+    let mut error = 0.0;
+    let mut sum_fixed = 0;
+    for (i, weight) in weights.iter().enumerate().take(3) {
+        let value = weight / sum * 256.0 + error;
+        let value_fixed = value.round_ties_even();
+        error = value - value_fixed;
+        kernel[i] = value_fixed as u32;
+        kernel[6 - i] = value_fixed as u32;
+        sum_fixed += 2 * value_fixed as u32;
+    }
+    kernel[3] = 256 - sum_fixed;
+    kernel
+}
+
+// separable fixed point blur with BORDER_REFLECT_101, returns a dense width x height image
+fn gaussian_blur(image: &ImageView, kernel: &[u32; 7]) -> Vec<u8> {
+    let width = image.width();
+    let height = image.height();
+    let reflect = |index: isize, length: usize| -> usize {
+        let length = length as isize;
+        if length == 1 {
+            return 0;
+        }
+        let mut index = index;
+        while index < 0 || index >= length {
+            index = if index < 0 {
+                -index
+            } else {
+                2 * (length - 1) - index
+            };
+        }
+        index as usize
+    };
+
+    // horizontal pass, 8 fractional bits
+    let mut horizontal = vec![0u32; width * height];
+    for y in 0..height {
+        let row = image.row(y);
+        for x in 0..width {
+            horizontal[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| weight * row[reflect(x as isize + k as isize - 3, width)] as u32)
+                .sum();
+        }
+    }
+
+    // vertical pass, 16 fractional bits rounded to 8 bit
+    let mut blurred = vec![0u8; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let value: u32 = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, weight)| {
+                    weight * horizontal[reflect(y as isize + k as isize - 3, height) * width + x]
+                })
+                .sum();
+            blurred[y * width + x] = ((value + (1 << 15)) >> 16) as u8;
+        }
+    }
+    blurred
+}
+
+// bit_pattern_31_ of OpenCV's orb.cpp, tests (x0, y0, x1, y1) learned for a patch size of 31
+const BIT_PATTERN_31: [[i8; 4]; NUMBER_OF_TESTS] = [
+    [8, -3, 9, 5],
+    [4, 2, 7, -12],
+    [-11, 9, -8, 2],
+    [7, -12, 12, -13],
+    [2, -13, 2, 12],
+    [1, -7, 1, 6],
+    [-2, -10, -2, -4],
+    [-13, -13, -11, -8],
+    [-13, -3, -12, -9],
+    [10, 4, 11, 9],
+    [-13, -8, -8, -9],
+    [-11, 7, -9, 12],
+    [7, 7, 12, 6],
+    [-4, -5, -3, 0],
+    [-13, 2, -12, -3],
+    [-9, 0, -7, 5],
+    [12, -6, 12, -1],
+    [-3, 6, -2, 12],
+    [-6, -13, -4, -8],
+    [11, -13, 12, -8],
+    [4, 7, 5, 1],
+    [5, -3, 10, -3],
+    [3, -7, 6, 12],
+    [-8, -7, -6, -2],
+    [-2, 11, -1, -10],
+    [-13, 12, -8, 10],
+    [-7, 3, -5, -3],
+    [-4, 2, -3, 7],
+    [-10, -12, -6, 11],
+    [5, -12, 6, -7],
+    [5, -6, 7, -1],
+    [1, 0, 4, -5],
+    [9, 11, 11, -13],
+    [4, 7, 4, 12],
+    [2, -1, 4, 4],
+    [-4, -12, -2, 7],
+    [-8, -5, -7, -10],
+    [4, 11, 9, 12],
+    [0, -8, 1, -13],
+    [-13, -2, -8, 2],
+    [-3, -2, -2, 3],
+    [-6, 9, -4, -9],
+    [8, 12, 10, 7],
+    [0, 9, 1, 3],
+    [7, -5, 11, -10],
+    [-13, -6, -11, 0],
+    [10, 7, 12, 1],
+    [-6, -3, -6, 12],
+    [10, -9, 12, -4],
+    [-13, 8, -8, -12],
+    [-13, 0, -8, -4],
+    [3, 3, 7, 8],
+    [5, 7, 10, -7],
+    [-1, 7, 1, -12],
+    [3, -10, 5, 6],
+    [2, -4, 3, -10],
+    [-13, 0, -13, 5],
+    [-13, -7, -12, 12],
+    [-13, 3, -11, 8],
+    [-7, 12, -4, 7],
+    [6, -10, 12, 8],
+    [-9, -1, -7, -6],
+    [-2, -5, 0, 12],
+    [-12, 5, -7, 5],
+    [3, -10, 8, -13],
+    [-7, -7, -4, 5],
+    [-3, -2, -1, -7],
+    [2, 9, 5, -11],
+    [-11, -13, -5, -13],
+    [-1, 6, 0, -1],
+    [5, -3, 5, 2],
+    [-4, -13, -4, 12],
+    [-9, -6, -9, 6],
+    [-12, -10, -8, -4],
+    [10, 2, 12, -3],
+    [7, 12, 12, 12],
+    [-7, -13, -6, 5],
+    [-4, 9, -3, 4],
+    [7, -1, 12, 2],
+    [-7, 6, -5, 1],
+    [-13, 11, -12, 5],
+    [-3, 7, -2, -6],
+    [7, -8, 12, -7],
+    [-13, -7, -11, -12],
+    [1, -3, 12, 12],
+    [2, -6, 3, 0],
+    [-4, 3, -2, -13],
+    [-1, -13, 1, 9],
+    [7, 1, 8, -6],
+    [1, -1, 3, 12],
+    [9, 1, 12, 6],
+    [-1, -9, -1, 3],
+    [-13, -13, -10, 5],
+    [7, 7, 10, 12],
+    [12, -5, 12, 9],
+    [6, 3, 7, 11],
+    [5, -13, 6, 10],
+    [2, -12, 2, 3],
+    [3, 8, 4, -6],
+    [2, 6, 12, -13],
+    [9, -12, 10, 3],
+    [-8, 4, -7, 9],
+    [-11, 12, -4, -6],
+    [1, 12, 2, -8],
+    [6, -9, 7, -4],
+    [2, 3, 3, -2],
+    [6, 3, 11, 0],
+    [3, -3, 8, -8],
+    [7, 8, 9, 3],
+    [-11, -5, -6, -4],
+    [-10, 11, -5, 10],
+    [-5, -8, -3, 12],
+    [-10, 5, -9, 0],
+    [8, -1, 12, -6],
+    [4, -6, 6, -11],
+    [-10, 12, -8, 7],
+    [4, -2, 6, 7],
+    [-2, 0, -2, 12],
+    [-5, -8, -5, 2],
+    [7, -6, 10, 12],
+    [-9, -13, -8, -8],
+    [-5, -13, -5, -2],
+    [8, -8, 9, -13],
+    [-9, -11, -9, 0],
+    [1, -8, 1, -2],
+    [7, -4, 9, 1],
+    [-2, 1, -1, -4],
+    [11, -6, 12, -11],
+    [-12, -9, -6, 4],
+    [3, 7, 7, 12],
+    [5, 5, 10, 8],
+    [0, -4, 2, 8],
+    [-9, 12, -5, -13],
+    [0, 7, 2, 12],
+    [-1, 2, 1, 7],
+    [5, 11, 7, -9],
+    [3, 5, 6, -8],
+    [-13, -4, -8, 9],
+    [-5, 9, -3, -3],
+    [-4, -7, -3, -12],
+    [6, 5, 8, 0],
+    [-7, 6, -6, 12],
+    [-13, 6, -5, -2],
+    [1, -10, 3, 10],
+    [4, 1, 8, -4],
+    [-2, -2, 2, -13],
+    [2, -12, 12, 12],
+    [-2, -13, 0, -6],
+    [4, 1, 9, 3],
+    [-6, -10, -3, -5],
+    [-3, -13, -1, 1],
+    [7, 5, 12, -11],
+    [4, -2, 5, -7],
+    [-13, 9, -9, -5],
+    [7, 1, 8, 6],
+    [7, -8, 7, 6],
+    [-7, -4, -7, 1],
+    [-8, 11, -7, -8],
+    [-13, 6, -12, -8],
+    [2, 4, 3, 9],
+    [10, -5, 12, 3],
+    [-6, -5, -6, 7],
+    [8, -3, 9, -8],
+    [2, -12, 2, 8],
+    [-11, -2, -10, 3],
+    [-12, -13, -7, -9],
+    [-11, 0, -10, -5],
+    [5, -3, 11, 8],
+    [-2, -13, -1, 12],
+    [-1, -8, 0, 9],
+    [-13, -11, -12, -5],
+    [-10, -2, -10, 11],
+    [-3, 9, -2, -13],
+    [2, -3, 3, 2],
+    [-9, -13, -4, 0],
+    [-4, 6, -3, -10],
+    [-4, 12, -2, -7],
+    [-6, -11, -4, 9],
+    [6, -3, 6, 11],
+    [-13, 11, -5, 5],
+    [11, 11, 12, 6],
+    [7, -5, 12, -2],
+    [-1, 12, 0, 7],
+    [-4, -8, -3, -2],
+    [-7, 1, -6, 7],
+    [-13, -12, -8, -13],
+    [-7, -2, -6, -8],
+    [-8, 5, -6, -9],
+    [-5, -1, -4, 5],
+    [-13, 7, -8, 10],
+    [1, 5, 5, -13],
+    [1, 0, 10, -13],
+    [9, 12, 10, -1],
+    [5, -8, 10, -9],
+    [-1, 11, 1, -13],
+    [-9, -3, -6, 2],
+    [-1, -10, 1, 12],
+    [-13, 1, -8, -10],
+    [8, -11, 10, -6],
+    [2, -13, 3, -6],
+    [7, -13, 12, -9],
+    [-10, -10, -5, -7],
+    [-10, -8, -8, -13],
+    [4, -6, 8, 5],
+    [3, 12, 8, -13],
+    [-4, 2, -3, -3],
+    [5, -13, 10, -12],
+    [4, -13, 5, -1],
+    [-9, 9, -4, 3],
+    [0, 3, 3, -9],
+    [-12, 1, -6, 1],
+    [3, 2, 4, -8],
+    [-10, -10, -10, 9],
+    [8, -13, 12, 12],
+    [-8, -12, -6, -5],
+    [2, 2, 3, 7],
+    [10, 6, 11, -8],
+    [6, 8, 8, -12],
+    [-7, 10, -6, 5],
+    [-3, -9, -3, 9],
+    [-1, -13, -1, 5],
+    [-3, -7, -3, 4],
+    [-8, -2, -8, 3],
+    [4, 2, 12, 12],
+    [2, -5, 3, 11],
+    [6, -9, 11, -13],
+    [3, -1, 7, 12],
+    [11, -1, 12, 4],
+    [-3, 0, -3, 6],
+    [4, -11, 4, 12],
+    [2, -4, 2, 1],
+    [-10, -6, -8, 1],
+    [-13, 7, -11, 1],
+    [-13, 12, -11, -13],
+    [6, 0, 11, -13],
+    [0, -1, 1, 4],
+    [-13, 3, -9, -2],
+    [-9, 8, -6, -3],
+    [-13, -6, -8, -2],
+    [5, -9, 8, 10],
+    [2, 7, 3, -9],
+    [-1, -6, -1, -1],
+    [9, 5, 11, -2],
+    [11, -3, 12, -8],
+    [3, 0, 3, 5],
+    [-1, 4, 0, 10],
+    [3, -6, 4, 5],
+    [-13, 0, -10, 5],
+    [5, 8, 12, 11],
+    [8, 9, 9, -6],
+    [7, -4, 8, -12],
+    [-10, 4, -10, 9],
+    [7, 3, 12, 4],
+    [9, -7, 10, -2],
+    [7, 0, 12, -2],
+    [-1, -6, 0, -11],
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_core::GrayImage;
+
+    const SIZE: usize = 80;
+
+    // intensity 2 * x, or 2 * y for a vertical ramp, the blur keeps linear ramps exact
+    fn ramp_image(vertical: bool) -> GrayImage<u8> {
+        let mut image = GrayImage::new(SIZE, SIZE);
+        for y in 0..SIZE {
+            for x in 0..SIZE {
+                image.set(x, y, 2 * if vertical { y } else { x } as u8);
+            }
+        }
+        image
+    }
+
+    // a test is set if its first point is left of the second one on a horizontal ramp
+    fn expected_ramp_descriptor() -> OrbDescriptor {
+        let mut descriptor = OrbDescriptor::default();
+        for (index, test) in BIT_PATTERN_31.iter().enumerate() {
+            descriptor.set_bit(index, test[0] < test[2]);
+        }
+        descriptor
+    }
+
+    #[test]
+    fn orients_keypoints_towards_the_intensity_centroid() {
+        let extractor = OrbExtractor::default();
+        for (vertical, angle) in [(false, 0.0), (true, 90.0)] {
+            let mut keypoints = [Keypoint::new(40.0, 40.0, 7.0, 0.0)];
+            extractor.compute_orientations(&ramp_image(vertical).view(), &mut keypoints);
+            assert!(
+                (keypoints[0].angle - angle).abs() < 0.1,
+                "{:?}",
+                keypoints[0]
+            );
+        }
+    }
+
+    #[test]
+    fn computes_the_learned_pattern_bits() {
+        let expected = expected_ramp_descriptor();
+        assert!(expected.words().iter().any(|word| *word != 0));
+
+        let mut keypoints = vec![Keypoint::new(40.0, 40.0, 7.0, 0.0)];
+        let descriptors =
+            OrbExtractor::new(31, 31, false).compute(&ramp_image(false).view(), &mut keypoints);
+        assert_eq!(descriptors, vec![expected]);
+
+        // rotated by 90 degrees, the tests along the vertical ramp give the same bits
+        let mut keypoints = vec![Keypoint::new(40.0, 40.0, 7.0, 0.0)];
+        keypoints[0].angle = 90.0;
+        let descriptors = OrbExtractor::default().compute(&ramp_image(true).view(), &mut keypoints);
+        assert_eq!(descriptors, vec![expected]);
+    }
+
+    #[test]
+    fn removes_keypoints_at_the_border() {
+        let extractor = OrbExtractor::default();
+        let mut keypoints = vec![
+            Keypoint::new(5.0, 40.0, 7.0, 0.0),
+            Keypoint::new(40.0, 40.0, 7.0, 0.0),
+            Keypoint::new(40.0, (SIZE - 5) as f32, 7.0, 0.0),
+        ];
+        let descriptors = extractor.compute(&ramp_image(false).view(), &mut keypoints);
+        assert_eq!(descriptors.len(), 1);
+        assert_eq!(keypoints, vec![Keypoint::new(40.0, 40.0, 7.0, 0.0)]);
+    }
+}
@@ -0,0 +1,407 @@
+//! stereo frontend of rectified images without OpenCV: FAST-9 keypoints, ORB descriptors, row
+//! wise hamming matching with subpixel refinement and triangulation of the matches
+use anyhow::{bail, Context, Result};
+use rslam_core::{Camera, ImageView, Keypoint, PackedDescriptors};
+use rslam_sensor::stereo_camera::StereoCamera;
+use serde::Deserialize;
+use sophus::lie::Isometry3F64;
+
+use super::{fast::FastDetector, orb::OrbExtractor};
+use crate::{
+    frame::frame_point::FramePoint,
+    subpixel_refinement::{self, DisparityRefinement, PatchCost},
+};
+
+const DESCRIPTOR_BITS: usize = 256;
+
+#[derive(Debug, Deserialize)]
+pub struct NativeStereoFramePointGeneratorCfg {
+    fast_threshold: u8,
+    // strongest keypoints described per image
+    maximum_number_of_keypoints: usize,
+    // normalized hamming distance (fraction of the descriptor bits)
+    maximum_matching_distance: f32,
+    // the best distance has to be below this fraction of the second best, None disables the test
+    maximum_matching_ratio: Option<f32>,
+    // rows searched above and below the row of the left keypoint
+    maximum_epipolar_search_offset_pixels: usize,
+    minimum_disparity_pixels: f32,
+    // limits the disparity search, None searches the whole row
+    minimum_depth_meters: Option<f64>,
+    // NONE, SAD or ZNCC
+    subpixel_refinement: String,
+    subpixel_half_patch_size: usize,
+    subpixel_search_radius: usize,
+    subpixel_minimum_score: f32,
+    triangulation_pixel_noise_sigma: f64,
+}
+
+impl Default for NativeStereoFramePointGeneratorCfg {
+    fn default() -> Self {
+        Self {
+            fast_threshold: 15,
+            maximum_number_of_keypoints: 1000,
+            maximum_matching_distance: 0.2,
+            maximum_matching_ratio: None,
+            maximum_epipolar_search_offset_pixels: 0,
+            minimum_disparity_pixels: 1.0,
+            minimum_depth_meters: None,
+            subpixel_refinement: String::from("ZNCC"),
+            subpixel_half_patch_size: 3,
+            subpixel_search_radius: 1,
+            subpixel_minimum_score: -1.0,
+            triangulation_pixel_noise_sigma: 1.0,
+        }
+    }
+}
+
+impl NativeStereoFramePointGeneratorCfg {
+    pub fn finalize(self, stereo_camera: StereoCamera) -> Result<NativeStereoFramePointGenerator> {
+        if self.maximum_number_of_keypoints == 0 {
+            bail!("maximum_number_of_keypoints has to be positive");
+        }
+        if !(self.maximum_matching_distance > 0.0 && self.maximum_matching_distance <= 1.0) {
+            bail!(
+                "maximum_matching_distance has to be in (0, 1], got {}",
+                self.maximum_matching_distance
+            );
+        }
+        let maximum_disparity_pixels = match self.minimum_depth_meters {
+            Some(minimum_depth_meters) if minimum_depth_meters <= 0.0 => {
+                bail!(
+                    "minimum_depth_meters has to be positive, got {}",
+                    minimum_depth_meters
+                )
+            }
+            Some(minimum_depth_meters) => {
+                stereo_camera.depth_to_disparity(minimum_depth_meters) as f32
+            }
+            None => f32::INFINITY,
+        };
+        let disparity_refinement = match self.subpixel_refinement.to_ascii_uppercase().as_str() {
+            "NONE" => None,
+            cost => Some(DisparityRefinement {
+                cost: cost
+                    .parse::<PatchCost>()
+                    .context("invalid subpixel_refinement")?,
+                half_patch_size: self.subpixel_half_patch_size,
+                search_radius: self.subpixel_search_radius,
+            }),
+        };
+        log::info!(
+            "native stereo: FAST threshold {}, at most {} keypoints, subpixel refinement {:?}",
+            self.fast_threshold,
+            self.maximum_number_of_keypoints,
+            disparity_refinement
+        );
+
+        Ok(NativeStereoFramePointGenerator {
+            detector: FastDetector::new(self.fast_threshold, true),
+            extractor: OrbExtractor::default(),
+            stereo_camera,
+            maximum_number_of_keypoints: self.maximum_number_of_keypoints,
+            maximum_matching_distance: self.maximum_matching_distance,
+            maximum_matching_ratio: self.maximum_matching_ratio,
+            maximum_epipolar_search_offset_pixels: self.maximum_epipolar_search_offset_pixels,
+            minimum_disparity_pixels: self.minimum_disparity_pixels,
+            maximum_disparity_pixels,
+            disparity_refinement,
+            subpixel_minimum_score: self.subpixel_minimum_score,
+            triangulation_pixel_noise_sigma: self.triangulation_pixel_noise_sigma,
+        })
+    }
+}
+
+/// keypoints and descriptors of a rectified stereo pair with the framepoints of its matches
+pub struct NativeStereoFrame {
+    pub keypoints_left: Vec<Keypoint>,
+    pub keypoints_right: Vec<Keypoint>,
+
+    // one row per keypoint
+    pub descriptors_left: PackedDescriptors,
+    pub descriptors_right: PackedDescriptors,
+
+    // keypoint indices refer to keypoints_left
+    pub created_points: Vec<FramePoint>,
+}
+
+pub struct NativeStereoFramePointGenerator {
+    detector: FastDetector,
+    extractor: OrbExtractor,
+    stereo_camera: StereoCamera,
+    maximum_number_of_keypoints: usize,
+
+    maximum_matching_distance: f32,
+    maximum_matching_ratio: Option<f32>,
+    maximum_epipolar_search_offset_pixels: usize,
+    minimum_disparity_pixels: f32,
+    // infinite without a minimum depth
+    maximum_disparity_pixels: f32,
+    disparity_refinement: Option<DisparityRefinement>,
+    subpixel_minimum_score: f32,
+    triangulation_pixel_noise_sigma: f64,
+}
+
+impl NativeStereoFramePointGenerator {
+    pub fn compute(
+        &self,
+        image_left: &ImageView,
+        image_right: &ImageView,
+        robot_to_world: &Isometry3F64,
+    ) -> Result<NativeStereoFrame> {
+        if image_left.width() != image_right.width() || image_left.height() != image_right.height()
+        {
+            bail!(
+                "stereo images differ in size: {}x{} and {}x{}",
+                image_left.width(),
+                image_left.height(),
+                image_right.width(),
+                image_right.height()
+            );
+        }
+        if image_left.width() == 0 || image_left.height() == 0 {
+            bail!(
+                "empty stereo images: {}x{}",
+                image_left.width(),
+                image_left.height()
+            );
+        }
+
+        let ((keypoints_left, descriptors_left), (keypoints_right, descriptors_right)) =
+            rayon::join(|| self.extract(image_left), || self.extract(image_right));
+        let mut frame = NativeStereoFrame {
+            keypoints_left,
+            keypoints_right,
+            descriptors_left,
+            descriptors_right,
+            created_points: Vec::new(),
+        };
+
+        let matches = self.match_keypoints(&frame, image_left.height());
+        let camera_to_robot = self.stereo_camera.left.camera_to_robot();
+        for (index_l, index_r) in matches.iter() {
+            let keypoint_left = &frame.keypoints_left[*index_l];
+            let keypoint_right = &frame.keypoints_right[*index_r];
+            let Some((pixel_right, matching_score)) = subpixel_refinement::refine_match(
+                self.disparity_refinement.as_ref(),
+                self.subpixel_minimum_score,
+                image_left,
+                image_right,
+                keypoint_left,
+                keypoint_right,
+            ) else {
+                continue;
+            };
+            let pixel_left = keypoint_left.pixel_coordinates();
+            let disparity = (pixel_left.x - pixel_right.x) as f32;
+            if disparity < self.minimum_disparity_pixels {
+                continue;
+            }
+            let Some(point_in_left) = self.stereo_camera.triangulate(&pixel_left, &pixel_right)
+            else {
+                continue;
+            };
+            let Some(covariance) = self.stereo_camera.triangulation_covariance(
+                &pixel_left,
+                &pixel_right,
+                self.triangulation_pixel_noise_sigma,
+            ) else {
+                continue;
+            };
+
+            let point_in_robot = camera_to_robot.transform(&point_in_left);
+            let point_in_world = robot_to_world.transform(&point_in_robot);
+            let mut frame_point = FramePoint::new(point_in_left, point_in_robot, point_in_world);
+            frame_point.set_stereo_match(disparity, matching_score);
+            frame_point.set_keypoint_index_left(*index_l);
+            frame_point.set_covariance(covariance);
+            frame.created_points.push(frame_point);
+        }
+        log::debug!(
+            "native stereo: keypoints L: {} R: {}, matches: {}, framepoints: {}",
+            frame.keypoints_left.len(),
+            frame.keypoints_right.len(),
+            matches.len(),
+            frame.created_points.len()
+        );
+        Ok(frame)
+    }
+
+    // strongest keypoints with their descriptors, keypoints close to the border are dropped
+    fn extract(&self, image: &ImageView) -> (Vec<Keypoint>, PackedDescriptors) {
+        let mut keypoints = self.detector.detect(image);
+        keypoints.sort_by(|a, b| b.response.total_cmp(&a.response));
+        keypoints.truncate(self.maximum_number_of_keypoints);
+        self.extractor.compute_orientations(image, &mut keypoints);
+
+        let mut descriptors =
+            PackedDescriptors::new(PackedDescriptors::words_for_bits(DESCRIPTOR_BITS));
+        for descriptor in self.extractor.compute(image, &mut keypoints) {
+            descriptors.push_words(descriptor.words());
+        }
+        (keypoints, descriptors)
+    }
+
+    // (left index, right index) of the best matches along the image rows, a right keypoint keeps
+    // its best left keypoint only
+    fn match_keypoints(&self, frame: &NativeStereoFrame, height: usize) -> Vec<(usize, usize)> {
+        let row_of =
+            |keypoint: &Keypoint| (keypoint.pt.y.round().max(0.0) as usize).min(height - 1);
+        let mut keypoints_per_row = vec![Vec::new(); height];
+        for (index_r, keypoint_right) in frame.keypoints_right.iter().enumerate() {
+            keypoints_per_row[row_of(keypoint_right)].push(index_r);
+        }
+
+        let offset = self.maximum_epipolar_search_offset_pixels;
+        let mut candidate_rows = Vec::new();
+        let mut hamming_distances = Vec::new();
+        let mut candidates = Vec::new();
+        for (index_l, keypoint_left) in frame.keypoints_left.iter().enumerate() {
+            let row = row_of(keypoint_left);
+            candidate_rows.clear();
+            for keypoints in
+                &keypoints_per_row[row.saturating_sub(offset)..=(row + offset).min(height - 1)]
+            {
+                candidate_rows.extend(keypoints.iter().filter(|index_r| {
+                    let disparity = keypoint_left.pt.x - frame.keypoints_right[**index_r].pt.x;
+                    disparity >= self.minimum_disparity_pixels
+                        && disparity <= self.maximum_disparity_pixels
+                }));
+            }
+            if candidate_rows.is_empty() {
+                continue;
+            }
+
+            frame.descriptors_right.hamming_distances_to_rows(
+                frame.descriptors_left.row(index_l),
+                &candidate_rows,
+                &mut hamming_distances,
+            );
+            let mut distance_best = u32::MAX;
+            let mut distance_second = u32::MAX;
+            let mut index_best_r = 0;
+            for (index_r, distance) in candidate_rows.iter().zip(hamming_distances.iter()) {
+                if *distance < distance_best {
+                    distance_second = distance_best;
+                    distance_best = *distance;
+                    index_best_r = *index_r;
+                } else if *distance < distance_second {
+                    distance_second = *distance;
+                }
+            }
+
+            if distance_best as f32 >= self.maximum_matching_distance * DESCRIPTOR_BITS as f32 {
+                continue;
+            }
+            if let Some(maximum_matching_ratio) = self.maximum_matching_ratio {
+                if distance_second < u32::MAX
+                    && distance_best as f32 >= maximum_matching_ratio * distance_second as f32
+                {
+                    continue;
+                }
+            }
+            candidates.push((distance_best, index_l, index_best_r));
+        }
+
+        // best matches first, ties in keypoint order
+        candidates.sort_by_key(|(distance, _, _)| *distance);
+        let mut is_matched_right = vec![false; frame.keypoints_right.len()];
+        let mut matches = Vec::new();
+        for (_, index_l, index_r) in candidates {
+            if !is_matched_right[index_r] {
+                is_matched_right[index_r] = true;
+                matches.push((index_l, index_r));
+            }
+        }
+        matches.sort_unstable();
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::shifted_stereo_images;
+    use rslam_core::GrayImage;
+    use rslam_sensor::pinhole_camera::PinholeCamera;
+    use sophus::{
+        core::linalg::VecF64, image::ImageSize,
+        sensor::camera_enum::perspective_camera::PinholeCameraF64,
+    };
+
+    const DISPARITY: usize = 12;
+    const BASELINE_METERS: f64 = 0.5;
+    const FOCAL_LENGTH: f64 = 300.0;
+
+    fn stereo_camera(width: usize, height: usize) -> StereoCamera {
+        let camera = PinholeCamera::new(PinholeCameraF64::from_params_and_size(
+            &VecF64::<4>::new(
+                FOCAL_LENGTH,
+                FOCAL_LENGTH,
+                width as f64 / 2.0,
+                height as f64 / 2.0,
+            ),
+            ImageSize::new(width, height),
+        ));
+        StereoCamera::new_rectified(
+            camera.clone(),
+            camera,
+            BASELINE_METERS,
+            Isometry3F64::identity(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn triangulates_a_constant_disparity() {
+        let (width, height) = (320, 120);
+        let (image_left, image_right) = shifted_stereo_images(width, height, DISPARITY);
+        let generator = NativeStereoFramePointGeneratorCfg::default()
+            .finalize(stereo_camera(width, height))
+            .unwrap();
+        let frame = generator
+            .compute(
+                &image_left.view(),
+                &image_right.view(),
+                &Isometry3F64::identity(),
+            )
+            .unwrap();
+
+        assert_eq!(frame.keypoints_left.len(), frame.descriptors_left.len());
+        assert_eq!(frame.keypoints_right.len(), frame.descriptors_right.len());
+        assert!(
+            frame.created_points.len() > frame.keypoints_left.len() / 2,
+            "{} framepoints of {} keypoints",
+            frame.created_points.len(),
+            frame.keypoints_left.len()
+        );
+        let depth_meters = FOCAL_LENGTH * BASELINE_METERS / DISPARITY as f64;
+        for frame_point in &frame.created_points {
+            assert!((frame_point.disparity_pixels() - DISPARITY as f32).abs() < 0.5);
+            assert!((frame_point.depth_meters() - depth_meters).abs() < 0.05 * depth_meters);
+            assert!(frame_point.keypoint_index_left().unwrap() < frame.keypoints_left.len());
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_images() {
+        let generator = NativeStereoFramePointGeneratorCfg::default()
+            .finalize(stereo_camera(64, 48))
+            .unwrap();
+        let image = GrayImage::<u8>::new(64, 48);
+        let smaller = GrayImage::<u8>::new(64, 47);
+        let empty = GrayImage::<u8>::new(0, 0);
+        let identity = Isometry3F64::identity();
+        assert!(generator
+            .compute(&image.view(), &smaller.view(), &identity)
+            .is_err());
+        assert!(generator
+            .compute(&empty.view(), &empty.view(), &identity)
+            .is_err());
+        assert!(generator
+            .compute(&image.view(), &image.view(), &identity)
+            .unwrap()
+            .created_points
+            .is_empty());
+    }
+}
@@ -0,0 +1,579 @@
+//! pose graph over robot poses with relative pose (between) and absolute position factors
+//!
+//! Without position factors the first pose anchors the graph. Poses are refined by
+//! Levenberg-Marquardt with right perturbations (translation, rotation).
+
+use anyhow::{bail, Result};
+use rslam_sensor::gnss::{GnssMeasurement, GnssReceiver, LocalTangentPlane};
+use serde::Deserialize;
+use sophus::{
+    core::linalg::VecF64,
+    lie::{Isometry3F64, Rotation3F64},
+    nalgebra::{DMatrix, DVector, Matrix3, Matrix3x6, Matrix6, SMatrix, Vector3, Vector6},
+};
+
+use crate::tracking::wheel_odometry::WheelOdometryMotionPrior;
+
+/// measured motion between two poses of the graph
+#[derive(Clone, Debug)]
+pub struct BetweenFactor {
+    pub from: usize,
+    pub to: usize,
+    // robot frame of pose `to` in the robot frame of pose `from`
+    pub to_to_from: Isometry3F64,
+    pub information: Matrix6<f64>,
+}
+
+impl BetweenFactor {
+    fn residual(&self, from_to_world: &Isometry3F64, to_to_world: &Isometry3F64) -> Vector6<f64> {
+        self.to_to_from
+            .inverse()
+            .group_mul(&from_to_world.inverse())
+            .group_mul(to_to_world)
+            .log()
+    }
+}
+
+/// measured world position of a point on the robot (GNSS antenna)
+#[derive(Clone, Debug)]
+pub struct PositionFactor {
+    pub pose: usize,
+    pub point_in_robot: Vector3<f64>,
+    pub position_in_world: Vector3<f64>,
+    pub information: Matrix3<f64>,
+}
+
+impl PositionFactor {
+    fn residual(&self, robot_to_world: &Isometry3F64) -> Vector3<f64> {
+        robot_to_world.transform(&self.point_in_robot) - self.position_in_world
+    }
+
+    // w.r.t. the right perturbation (translation, rotation) of the pose
+    fn jacobian(&self, robot_to_world: &Isometry3F64) -> Matrix3x6<f64> {
+        let rotation = robot_to_world.rotation().matrix();
+        let mut jacobian = Matrix3x6::zeros();
+        jacobian.fixed_view_mut::<3, 3>(0, 0).copy_from(&rotation);
+        jacobian
+            .fixed_view_mut::<3, 3>(0, 3)
+            .copy_from(&(-rotation * Rotation3F64::hat(&self.point_in_robot)));
+        jacobian
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PoseGraphOptimizerCfg {
+    pub maximum_iterations: usize,
+    pub function_tolerance: f64,
+}
+
+impl Default for PoseGraphOptimizerCfg {
+    fn default() -> Self {
+        Self {
+            maximum_iterations: 20,
+            function_tolerance: 1e-9,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct PoseGraph {
+    robot_to_world: Vec<Isometry3F64>,
+    between_factors: Vec<BetweenFactor>,
+    position_factors: Vec<PositionFactor>,
+}
+
+impl PoseGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_pose(&mut self, robot_to_world: Isometry3F64) -> usize {
+        self.robot_to_world.push(robot_to_world);
+        self.robot_to_world.len() - 1
+    }
+
+    pub fn robot_to_world(&self, index: usize) -> Option<&Isometry3F64> {
+        self.robot_to_world.get(index)
+    }
+
+    pub fn poses(&self) -> &[Isometry3F64] {
+        &self.robot_to_world
+    }
+
+    pub fn between_factors(&self) -> &[BetweenFactor] {
+        &self.between_factors
+    }
+
+    pub fn add_between_factor(&mut self, factor: BetweenFactor) -> Result<()> {
+        if factor.from >= self.robot_to_world.len() || factor.to >= self.robot_to_world.len() {
+            bail!(
+                "between factor {} -> {} references unknown poses ({} poses)",
+                factor.from,
+                factor.to,
+                self.robot_to_world.len()
+            );
+        }
+        if factor.from == factor.to {
+            bail!("between factor on a single pose {}", factor.from);
+        }
+        self.between_factors.push(factor);
+        Ok(())
+    }
+
+    // adds the wheel odometry motion between the timestamps of two poses
+    pub fn add_wheel_odometry_factor(
+        &mut self,
+        from: usize,
+        to: usize,
+        odometry: &WheelOdometryMotionPrior,
+        timestamp_from: f64,
+        timestamp_to: f64,
+    ) -> Result<()> {
+        let Some((to_to_from, covariance)) = odometry.relative_motion(timestamp_from, timestamp_to)
+        else {
+            bail!(
+                "wheel odometry does not cover [{}, {}]",
+                timestamp_from,
+                timestamp_to
+            );
+        };
+        let Some(information) = covariance.try_inverse() else {
+            bail!("singular wheel odometry covariance");
+        };
+        self.add_between_factor(BetweenFactor {
+            from,
+            to,
+            to_to_from,
+            information,
+        })
+    }
+
+    pub fn position_factors(&self) -> &[PositionFactor] {
+        &self.position_factors
+    }
+
+    pub fn add_position_factor(&mut self, factor: PositionFactor) -> Result<()> {
+        if factor.pose >= self.robot_to_world.len() {
+            bail!(
+                "position factor references unknown pose {} ({} poses)",
+                factor.pose,
+                self.robot_to_world.len()
+            );
+        }
+        self.position_factors.push(factor);
+        Ok(())
+    }
+
+    // adds a GNSS fix of the pose, the world frame is the local tangent plane
+    pub fn add_gnss_factor(
+        &mut self,
+        pose: usize,
+        measurement: &GnssMeasurement,
+        receiver: &GnssReceiver,
+        tangent_plane: &LocalTangentPlane,
+    ) -> Result<()> {
+        let Some(information) = receiver.covariance(measurement).try_inverse() else {
+            bail!(
+                "singular gnss covariance at {}",
+                measurement.timestamp_seconds
+            );
+        };
+        self.add_position_factor(PositionFactor {
+            pose,
+            point_in_robot: receiver.antenna_in_robot(),
+            position_in_world: tangent_plane.geodetic_to_enu(&measurement.geodetic()),
+            information,
+        })
+    }
+
+    // rigidly moves all poses onto the position factors (Horn's method), to initialize the
+    // optimization once the world frame is given by the positions
+    pub fn align_to_position_factors(&mut self) -> Result<Isometry3F64> {
+        if self.position_factors.len() < 3 {
+            bail!(
+                "alignment needs at least 3 position factors, got {}",
+                self.position_factors.len()
+            );
+        }
+        let points: Vec<Vector3<f64>> = self
+            .position_factors
+            .iter()
+            .map(|factor| self.robot_to_world[factor.pose].transform(&factor.point_in_robot))
+            .collect();
+        let number_of_points = points.len() as f64;
+        let centroid = points.iter().sum::<Vector3<f64>>() / number_of_points;
+        let centroid_target = self
+            .position_factors
+            .iter()
+            .map(|factor| factor.position_in_world)
+            .sum::<Vector3<f64>>()
+            / number_of_points;
+
+        let mut covariance = Matrix3::<f64>::zeros();
+        for (point, factor) in points.iter().zip(self.position_factors.iter()) {
+            covariance +=
+                (factor.position_in_world - centroid_target) * (point - centroid).transpose();
+        }
+        let svd = covariance.svd(true, true);
+        let (Some(u), Some(v_t)) = (svd.u, svd.v_t) else {
+            bail!("alignment svd failed");
+        };
+        let mut correction = Matrix3::identity();
+        correction[(2, 2)] = (u * v_t).determinant().signum();
+        if svd.singular_values[1] < 1e-6 * svd.singular_values[0].max(1e-12) {
+            log::warn!(
+                "position factors are (nearly) collinear, rotation about their line is arbitrary"
+            );
+        }
+        let Some(rotation) = Rotation3F64::try_from_mat(&(u * correction * v_t)) else {
+            bail!("alignment produced an invalid rotation");
+        };
+        let world_to_aligned = Isometry3F64::from_translation_and_rotation(
+            &(centroid_target - rotation.matrix() * centroid),
+            &rotation,
+        );
+        for robot_to_world in self.robot_to_world.iter_mut() {
+            *robot_to_world = world_to_aligned.group_mul(robot_to_world);
+        }
+        Ok(world_to_aligned)
+    }
+
+    fn cost(&self, robot_to_world: &[Isometry3F64]) -> f64 {
+        let between: f64 = self
+            .between_factors
+            .iter()
+            .map(|factor| {
+                let residual =
+                    factor.residual(&robot_to_world[factor.from], &robot_to_world[factor.to]);
+                (residual.transpose() * factor.information * residual)[0]
+            })
+            .sum();
+        let position: f64 = self
+            .position_factors
+            .iter()
+            .map(|factor| {
+                let residual = factor.residual(&robot_to_world[factor.pose]);
+                (residual.transpose() * factor.information * residual)[0]
+            })
+            .sum();
+        between + position
+    }
+
+    // optimizes the poses (all but the first one without position factors), returns the
+    // final cost
+    pub fn optimize(&mut self, cfg: &PoseGraphOptimizerCfg) -> Result<f64> {
+        let anchored = self.position_factors.is_empty();
+        if self.robot_to_world.is_empty()
+            || (anchored && (self.robot_to_world.len() < 2 || self.between_factors.is_empty()))
+        {
+            return Ok(self.cost(&self.robot_to_world));
+        }
+        let first_variable = usize::from(anchored);
+        let number_of_parameters = 6 * (self.robot_to_world.len() - first_variable);
+        // parameter offset of a pose, None for the anchored first pose
+        let offset = |index: usize| index.checked_sub(first_variable).map(|index| 6 * index);
+
+        let mut cost = self.cost(&self.robot_to_world);
+        let mut lambda = 1e-4;
+        for iteration in 0..cfg.maximum_iterations {
+            let mut hessian = DMatrix::<f64>::zeros(number_of_parameters, number_of_parameters);
+            let mut gradient = DVector::<f64>::zeros(number_of_parameters);
+            for factor in &self.between_factors {
+                let from = &self.robot_to_world[factor.from];
+                let to = &self.robot_to_world[factor.to];
+                let residual = factor.residual(from, to);
+
+                // central differences w.r.t. the perturbations of both poses
+                let mut jacobian = SMatrix::<f64, 6, 12>::zeros();
+                for k in 0..12 {
+                    let step = 1e-6;
+                    let mut tangent = VecF64::<6>::zeros();
+                    tangent[k % 6] = step;
+                    let plus = Isometry3F64::exp(&tangent);
+                    let minus = Isometry3F64::exp(&-tangent);
+                    let (residual_plus, residual_minus) = if k < 6 {
+                        (
+                            factor.residual(&from.group_mul(&plus), to),
+                            factor.residual(&from.group_mul(&minus), to),
+                        )
+                    } else {
+                        (
+                            factor.residual(from, &to.group_mul(&plus)),
+                            factor.residual(from, &to.group_mul(&minus)),
+                        )
+                    };
+                    jacobian.set_column(k, &((residual_plus - residual_minus) / (2.0 * step)));
+                }
+
+                let blocks = [
+                    (
+                        offset(factor.from),
+                        jacobian.fixed_columns::<6>(0).into_owned(),
+                    ),
+                    (
+                        offset(factor.to),
+                        jacobian.fixed_columns::<6>(6).into_owned(),
+                    ),
+                ];
+                for (row, jacobian_row) in &blocks {
+                    let Some(row) = row else {
+                        continue;
+                    };
+                    let weighted = jacobian_row.transpose() * factor.information;
+                    let mut gradient_block = gradient.fixed_rows_mut::<6>(*row);
+                    gradient_block += weighted * residual;
+                    for (column, jacobian_column) in &blocks {
+                        let Some(column) = column else {
+                            continue;
+                        };
+                        let mut block = hessian.fixed_view_mut::<6, 6>(*row, *column);
+                        block += weighted * jacobian_column;
+                    }
+                }
+            }
+
+            for factor in &self.position_factors {
+                let Some(row) = offset(factor.pose) else {
+                    continue;
+                };
+                let robot_to_world = &self.robot_to_world[factor.pose];
+                let jacobian = factor.jacobian(robot_to_world);
+                let weighted = jacobian.transpose() * factor.information;
+                let mut gradient_block = gradient.fixed_rows_mut::<6>(row);
+                gradient_block += weighted * factor.residual(robot_to_world);
+                let mut block = hessian.fixed_view_mut::<6, 6>(row, row);
+                block += weighted * jacobian;
+            }
+
+            let mut improved = false;
+            while lambda < 1e10 {
+                let mut damped = hessian.clone();
+                for i in 0..number_of_parameters {
+                    damped[(i, i)] += lambda * hessian[(i, i)].max(1e-9);
+                }
+                let Some(cholesky) = damped.cholesky() else {
+                    lambda *= 10.0;
+                    continue;
+                };
+                let delta = -cholesky.solve(&gradient);
+                let candidate: Vec<Isometry3F64> = self
+                    .robot_to_world
+                    .iter()
+                    .enumerate()
+                    .map(|(index, robot_to_world)| match offset(index) {
+                        Some(row) => robot_to_world.group_mul(&Isometry3F64::exp(
+                            &delta.fixed_rows::<6>(row).into_owned(),
+                        )),
+                        None => *robot_to_world,
+                    })
+                    .collect();
+                let candidate_cost = self.cost(&candidate);
+                if candidate_cost < cost {
+                    improved = (cost - candidate_cost) > cfg.function_tolerance * cost.max(1.0);
+                    cost = candidate_cost;
+                    self.robot_to_world = candidate;
+                    lambda = (lambda / 10.0).max(1e-12);
+                    break;
+                }
+                lambda *= 10.0;
+            }
+            log::debug!(
+                "iteration: {}, cost: {}, lambda: {}",
+                iteration,
+                cost,
+                lambda
+            );
+            if !improved {
+                break;
+            }
+        }
+        Ok(cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_sensor::gnss::GeodeticCoordinates;
+    use rslam_sensor::wheel_odometry::{
+        WheelOdometry, WheelOdometryMeasurement, WheelOdometryNoiseParameters,
+    };
+    use sophus::lie::prelude::IsTranslationProductGroup;
+
+    // square loop of 2 m sides, turning left by 90 degrees at every corner
+    fn loop_poses() -> Vec<Isometry3F64> {
+        (0..4)
+            .map(|corner| {
+                let yaw = corner as f64 * std::f64::consts::FRAC_PI_2;
+                let rotation = Rotation3F64::rot_z(yaw);
+                let position = [
+                    Vector3::new(0.0, 0.0, 0.0),
+                    Vector3::new(2.0, 0.0, 0.0),
+                    Vector3::new(2.0, 2.0, 0.0),
+                    Vector3::new(0.0, 2.0, 0.0),
+                ][corner];
+                Isometry3F64::from_translation_and_rotation(&position, &rotation)
+            })
+            .collect()
+    }
+
+    fn between_factor(poses: &[Isometry3F64], from: usize, to: usize) -> BetweenFactor {
+        BetweenFactor {
+            from,
+            to,
+            to_to_from: poses[from].inverse().group_mul(&poses[to]),
+            information: Matrix6::identity() * 100.0,
+        }
+    }
+
+    fn pose_error(a: &Isometry3F64, b: &Isometry3F64) -> f64 {
+        a.inverse().group_mul(b).log().norm()
+    }
+
+    #[test]
+    fn closes_a_loop() {
+        let poses = loop_poses();
+        let mut graph = PoseGraph::new();
+        for (index, robot_to_world) in poses.iter().enumerate() {
+            // drifting initial estimates, the first pose anchors the graph
+            let drift = VecF64::<6>::new(0.1, -0.2, 0.05, 0.02, -0.01, 0.1) * index as f64;
+            graph.add_pose(robot_to_world.group_mul(&Isometry3F64::exp(&drift)));
+        }
+        for (from, to) in [(0, 1), (1, 2), (2, 3), (3, 0)] {
+            graph
+                .add_between_factor(between_factor(&poses, from, to))
+                .unwrap();
+        }
+
+        let cost = graph.optimize(&PoseGraphOptimizerCfg::default()).unwrap();
+        assert!(cost < 1e-12, "{}", cost);
+        for (estimated, expected) in graph.poses().iter().zip(poses.iter()) {
+            assert!(pose_error(estimated, expected) < 1e-6);
+        }
+    }
+
+    #[test]
+    fn spreads_an_inconsistent_loop_closure() {
+        let poses = loop_poses();
+        let mut graph = PoseGraph::new();
+        for robot_to_world in &poses {
+            graph.add_pose(*robot_to_world);
+        }
+        for (from, to) in [(0, 1), (1, 2), (2, 3)] {
+            graph
+                .add_between_factor(between_factor(&poses, from, to))
+                .unwrap();
+        }
+        let mut closure = between_factor(&poses, 3, 0);
+        closure.to_to_from = closure
+            .to_to_from
+            .group_mul(&Isometry3F64::from_translation(&VecF64::<3>::new(
+                0.3, 0.0, 0.0,
+            )));
+        graph.add_between_factor(closure).unwrap();
+
+        let initial_cost = graph.cost(graph.poses());
+        let cost = graph.optimize(&PoseGraphOptimizerCfg::default()).unwrap();
+        assert!(cost < 0.5 * initial_cost);
+        // the error is distributed over all edges instead of the closure alone
+        for factor in graph.between_factors() {
+            let residual = factor.residual(&graph.poses()[factor.from], &graph.poses()[factor.to]);
+            assert!(residual.norm() > 1e-3 && residual.norm() < 0.2);
+        }
+        assert_eq!(graph.robot_to_world(0).unwrap().log(), poses[0].log());
+    }
+
+    #[test]
+    fn adds_wheel_odometry_factors() {
+        let mut odometry = WheelOdometryMotionPrior::new(WheelOdometry::new(
+            WheelOdometryNoiseParameters::default(),
+            Isometry3F64::identity(),
+        ));
+        for timestamp_seconds in [0.0, 0.5, 1.0] {
+            odometry
+                .add_measurement(WheelOdometryMeasurement::twist(
+                    timestamp_seconds,
+                    Vector3::new(2.0, 0.0, 0.0),
+                    Vector3::zeros(),
+                ))
+                .unwrap();
+        }
+        let mut graph = PoseGraph::new();
+        graph.add_pose(Isometry3F64::identity());
+        graph.add_pose(Isometry3F64::identity());
+        graph
+            .add_wheel_odometry_factor(0, 1, &odometry, 0.0, 1.0)
+            .unwrap();
+        assert!(graph
+            .add_wheel_odometry_factor(0, 1, &odometry, 0.5, 1.5)
+            .is_err());
+
+        graph.optimize(&PoseGraphOptimizerCfg::default()).unwrap();
+        let translation = graph.poses()[1].translation();
+        assert!((translation - Vector3::new(2.0, 0.0, 0.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn aligns_the_poses_to_gnss_fixes() {
+        let tangent_plane = LocalTangentPlane::new(GeodeticCoordinates::new(49.011, 8.4237, 116.4));
+        let receiver = GnssReceiver {
+            antenna_in_robot: [0.2, 0.0, 1.2],
+            ..GnssReceiver::default()
+        };
+        // the odometry frame is rotated and shifted against east, north, up
+        let world_to_aligned = Isometry3F64::from_translation_and_rotation(
+            &Vector3::new(35.0, -12.0, 2.5),
+            &Rotation3F64::rot_z(0.7).group_mul(&Rotation3F64::rot_x(0.05)),
+        );
+
+        let poses = loop_poses();
+        let mut pose_graph = PoseGraph::new();
+        for (index, robot_to_world) in poses.iter().enumerate() {
+            pose_graph.add_pose(*robot_to_world);
+            let antenna_in_world = world_to_aligned
+                .group_mul(robot_to_world)
+                .transform(&receiver.antenna_in_robot());
+            let geodetic = tangent_plane.enu_to_geodetic(&antenna_in_world);
+            let measurement = GnssMeasurement::new(
+                index as f64,
+                geodetic.latitude_degrees,
+                geodetic.longitude_degrees,
+                geodetic.altitude_meters,
+            );
+            pose_graph
+                .add_gnss_factor(index, &measurement, &receiver, &tangent_plane)
+                .unwrap();
+            if index == 1 {
+                assert!(pose_graph.align_to_position_factors().is_err());
+            }
+        }
+
+        let estimated = pose_graph.align_to_position_factors().unwrap();
+        assert!(pose_error(&estimated, &world_to_aligned) < 1e-6);
+        for (aligned, robot_to_world) in pose_graph.poses().iter().zip(&poses) {
+            assert!(pose_error(aligned, &world_to_aligned.group_mul(robot_to_world)) < 1e-6);
+        }
+    }
+
+    #[test]
+    fn rejects_factors_on_unknown_poses() {
+        let poses = loop_poses();
+        let mut graph = PoseGraph::new();
+        graph.add_pose(poses[0]);
+        graph.add_pose(poses[1]);
+        assert!(graph
+            .add_between_factor(between_factor(&poses, 0, 2))
+            .is_err());
+        assert!(graph
+            .add_between_factor(between_factor(&poses, 1, 1))
+            .is_err());
+        assert!(graph
+            .add_position_factor(PositionFactor {
+                pose: 2,
+                point_in_robot: Vector3::zeros(),
+                position_in_world: Vector3::zeros(),
+                information: Matrix3::identity(),
+            })
+            .is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//! bucketed spatial index of image points for radius and epipolar band queries
+use rslam_core::PixelCoordinates;
+use sophus::nalgebra::Vector3;
+
+/// points bucketed into square cells covering their bounding box, the cells are stored
+/// contiguously (cell_starts[cell]..cell_starts[cell + 1] of indices)
+#[derive(Clone, Debug)]
+pub struct SpatialGrid {
+    cell_size: f64,
+    // (minimum, maximum) limiting the extent of the cells
+    bounds: Option<(PixelCoordinates, PixelCoordinates)>,
+    origin: PixelCoordinates,
+    number_of_cols: usize,
+    number_of_rows: usize,
+    cell_starts: Vec<usize>,
+    indices: Vec<usize>,
+    // points beyond the bounds, tested one by one
+    outside: Vec<usize>,
+    points: Vec<PixelCoordinates>,
+}
+
+impl SpatialGrid {
+    pub fn new(cell_size: f64) -> Self {
+        assert!(cell_size > 0.0, "invalid cell size: {}", cell_size);
+        Self {
+            cell_size,
+            bounds: None,
+            origin: PixelCoordinates::zeros(),
+            number_of_cols: 0,
+            number_of_rows: 0,
+            cell_starts: vec![0],
+            indices: Vec::new(),
+            outside: Vec::new(),
+            points: Vec::new(),
+        }
+    }
+
+    // cells cover at most the bounds, so that a few far away points don't blow up the grid
+    pub fn with_bounds(mut self, minimum: PixelCoordinates, maximum: PixelCoordinates) -> Self {
+        assert!(
+            minimum.x <= maximum.x && minimum.y <= maximum.y,
+            "invalid bounds: {:?} {:?}",
+            minimum,
+            maximum
+        );
+        self.bounds = Some((minimum, maximum));
+        self
+    }
+
+    pub fn cell_size(&self) -> f64 {
+        self.cell_size
+    }
+
+    // replaces the indexed points, queries return positions in this sequence
+    pub fn build(&mut self, points: impl IntoIterator<Item = PixelCoordinates>) {
+        self.points.clear();
+        self.points.extend(
+            points
+                .into_iter()
+                .filter(|point| point.x.is_finite() && point.y.is_finite()),
+        );
+        self.indices.clear();
+        self.cell_starts.clear();
+        self.outside.clear();
+        let bounds = self.bounds;
+        let is_inside = move |point: &PixelCoordinates| {
+            bounds.is_none_or(|(minimum, maximum)| {
+                point.x >= minimum.x
+                    && point.y >= minimum.y
+                    && point.x <= maximum.x
+                    && point.y <= maximum.y
+            })
+        };
+        self.outside
+            .extend((0..self.points.len()).filter(|index| !is_inside(&self.points[*index])));
+        let Some(first) = self.points.iter().find(|point| is_inside(point)) else {
+            self.number_of_cols = 0;
+            self.number_of_rows = 0;
+            self.cell_starts.push(0);
+            return;
+        };
+
+        let mut minimum = *first;
+        let mut maximum = *first;
+        for point in self.points.iter().filter(|point| is_inside(point)) {
+            minimum = minimum.inf(point);
+            maximum = maximum.sup(point);
+        }
+        self.origin = minimum;
+        self.number_of_cols = ((maximum.x - minimum.x) / self.cell_size) as usize + 1;
+        self.number_of_rows = ((maximum.y - minimum.y) / self.cell_size) as usize + 1;
+
+        // counting sort of the points by cell
+        let number_of_cells = self.number_of_cols * self.number_of_rows;
+        self.cell_starts.resize(number_of_cells + 1, 0);
+        for point in self.points.iter().filter(|point| is_inside(point)) {
+            let cell = self.cell_of(point);
+            self.cell_starts[cell + 1] += 1;
+        }
+        for cell in 0..number_of_cells {
+            self.cell_starts[cell + 1] += self.cell_starts[cell];
+        }
+        let mut next = self.cell_starts[..number_of_cells].to_vec();
+        self.indices.resize(self.cell_starts[number_of_cells], 0);
+        for (index, point) in self.points.iter().enumerate() {
+            if is_inside(point) {
+                let cell = self.cell_of(point);
+                self.indices[next[cell]] = index;
+                next[cell] += 1;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn point(&self, index: usize) -> &PixelCoordinates {
+        &self.points[index]
+    }
+
+    // indices of the points within the radius of the center
+    pub fn indices_in_radius(
+        &self,
+        center: &PixelCoordinates,
+        radius: f64,
+        indices: &mut Vec<usize>,
+    ) {
+        indices.clear();
+        if self.is_empty() || radius < 0.0 {
+            return;
+        }
+        let radius_squared = radius * radius;
+        let is_in_radius =
+            |index: &&usize| (self.points[**index] - center).norm_squared() <= radius_squared;
+        indices.extend(self.outside.iter().filter(is_in_radius));
+        let Some((col_begin, col_end)) = self.cell_range(
+            center.x - radius,
+            center.x + radius,
+            self.origin.x,
+            self.number_of_cols,
+        ) else {
+            return;
+        };
+        let Some((row_begin, row_end)) = self.cell_range(
+            center.y - radius,
+            center.y + radius,
+            self.origin.y,
+            self.number_of_rows,
+        ) else {
+            return;
+        };
+        for row in row_begin..=row_end {
+            for col in col_begin..=col_end {
+                indices.extend(
+                    self.cell(row * self.number_of_cols + col)
+                        .iter()
+                        .filter(is_in_radius),
+                );
+            }
+        }
+    }
+
+    // indices of the points within the distance of the line a x + b y + c = 0
+    pub fn indices_in_band(&self, line: &Vector3<f64>, distance: f64, indices: &mut Vec<usize>) {
+        indices.clear();
+        let normal = line.xy().norm();
+        if self.is_empty() || normal < f64::EPSILON {
+            return;
+        }
+        let line = line / normal;
+        let is_in_band = |index: &&usize| {
+            let point = &self.points[**index];
+            (line.x * point.x + line.y * point.y + line.z).abs() <= distance
+        };
+        indices.extend(self.outside.iter().filter(is_in_band));
+
+        // walk along the major axis of the line, the minor axis range of a cell column (row)
+        // is the line segment above it widened by the band
+        let horizontal = line.y.abs() >= line.x.abs();
+        let (number_of_major, number_of_minor) = if horizontal {
+            (self.number_of_cols, self.number_of_rows)
+        } else {
+            (self.number_of_rows, self.number_of_cols)
+        };
+        let (origin_major, origin_minor, slope_major, slope_minor) = if horizontal {
+            (self.origin.x, self.origin.y, line.x, line.y)
+        } else {
+            (self.origin.y, self.origin.x, line.y, line.x)
+        };
+        let half_width_minor = distance / slope_minor.abs();
+        let minor_at = |major: f64| -(slope_major * major + line.z) / slope_minor;
+
+        for major in 0..number_of_major {
+            let major_begin = origin_major + major as f64 * self.cell_size;
+            let minor_begin = minor_at(major_begin);
+            let minor_end = minor_at(major_begin + self.cell_size);
+            let Some((minor_first, minor_last)) = self.cell_range(
+                minor_begin.min(minor_end) - half_width_minor,
+                minor_begin.max(minor_end) + half_width_minor,
+                origin_minor,
+                number_of_minor,
+            ) else {
+                continue;
+            };
+            for minor in minor_first..=minor_last {
+                let cell = if horizontal {
+                    minor * self.number_of_cols + major
+                } else {
+                    major * self.number_of_cols + minor
+                };
+                indices.extend(self.cell(cell).iter().filter(is_in_band));
+            }
+        }
+    }
+
+    fn cell(&self, cell: usize) -> &[usize] {
+        &self.indices[self.cell_starts[cell]..self.cell_starts[cell + 1]]
+    }
+
+    fn cell_of(&self, point: &PixelCoordinates) -> usize {
+        let col =
+            (((point.x - self.origin.x) / self.cell_size) as usize).min(self.number_of_cols - 1);
+        let row =
+            (((point.y - self.origin.y) / self.cell_size) as usize).min(self.number_of_rows - 1);
+        row * self.number_of_cols + col
+    }
+
+    // inclusive range of the cells overlapping [begin, end] along one axis
+    fn cell_range(
+        &self,
+        begin: f64,
+        end: f64,
+        origin: f64,
+        number_of_cells: usize,
+    ) -> Option<(usize, usize)> {
+        let first = ((begin - origin) / self.cell_size).floor();
+        let last = ((end - origin) / self.cell_size).floor();
+        if first.is_nan() || last.is_nan() || first > last {
+            return None;
+        }
+        if last < 0.0 || first >= number_of_cells as f64 {
+            return None;
+        }
+        Some((
+            first.max(0.0) as usize,
+            (last as usize).min(number_of_cells - 1),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a regular grid of points and a few far outside of the image
+    fn points() -> Vec<PixelCoordinates> {
+        let mut points: Vec<_> = (0..400)
+            .map(|index| {
+                PixelCoordinates::new((index % 20) as f64 * 7.3, (index / 20) as f64 * 4.9)
+            })
+            .collect();
+        points.push(PixelCoordinates::new(-1e7, 35.0));
+        points.push(PixelCoordinates::new(60.0, 1e8));
+        points
+    }
+
+    fn grid() -> SpatialGrid {
+        let mut grid = SpatialGrid::new(8.0).with_bounds(
+            PixelCoordinates::new(-10.0, -10.0),
+            PixelCoordinates::new(150.0, 100.0),
+        );
+        grid.build(points());
+        grid
+    }
+
+    #[test]
+    fn bounds_limit_the_number_of_cells() {
+        let grid = grid();
+        assert_eq!(grid.len(), 402);
+        assert_eq!(grid.outside.len(), 2);
+        assert!(grid.number_of_cols * grid.number_of_rows <= 20 * 13);
+    }
+
+    #[test]
+    fn radius_query_finds_points_inside_and_outside_of_the_bounds() {
+        let grid = grid();
+        let points = points();
+        let mut indices = Vec::new();
+        for (center, radius) in [
+            (PixelCoordinates::new(50.0, 40.0), 12.0),
+            (PixelCoordinates::new(-1e7 + 1.0, 35.0), 2.0),
+            (PixelCoordinates::new(200.0, 200.0), 10.0),
+        ] {
+            grid.indices_in_radius(&center, radius, &mut indices);
+            indices.sort_unstable();
+            let expected: Vec<_> = (0..points.len())
+                .filter(|index| (points[*index] - center).norm() <= radius)
+                .collect();
+            assert_eq!(indices, expected);
+        }
+    }
+
+    #[test]
+    fn band_query_finds_points_inside_and_outside_of_the_bounds() {
+        let grid = grid();
+        let points = points();
+        let mut indices = Vec::new();
+        for line in [
+            // through the left outlier
+            Vector3::new(0.0, 1.0, -35.0),
+            // through the bottom outlier
+            Vector3::new(1.0, 0.0, -60.0),
+            Vector3::new(0.3, -1.0, 12.0),
+            Vector3::new(1.0, 0.4, -80.0),
+        ] {
+            grid.indices_in_band(&line, 1.5, &mut indices);
+            indices.sort_unstable();
+            let normal = line.xy().norm();
+            let expected: Vec<_> = (0..points.len())
+                .filter(|index| {
+                    let point = &points[*index];
+                    (line.x * point.x + line.y * point.y + line.z).abs() / normal <= 1.5
+                })
+                .collect();
+            assert!(!expected.is_empty());
+            assert_eq!(indices, expected);
+        }
+    }
+}
@@ -1,40 +1,130 @@
-use std::{collections::BTreeSet, num::NonZeroUsize};
+use std::{collections::BTreeSet, fmt, num::NonZeroUsize, str::FromStr};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use opencv::{
     core::{
-        KeyPoint, KeyPointTrait, KeyPointTraitConst, Mat, MatTraitConst, Point2f, Ptr, Rect,
-        Rect2i, NORM_HAMMING,
+        KeyPoint, KeyPointTrait, KeyPointTraitConst, Mat, Point2f, Ptr, Rect,
+        Rect2i, Vector,
     },
-    features2d::{
-        self, DescriptorExtractor, FastFeatureDetector, FastFeatureDetector_DetectorType, ORB,
-    },
-    prelude::{FastFeatureDetectorTrait, FastFeatureDetectorTraitConst, Feature2DTrait},
+    features2d::DescriptorExtractor,
+    prelude::Feature2DTrait,
 };
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 use serde::Deserialize;
-use sophus::nalgebra::Vector3;
-use rslam_sensor::pinhole_camera::PinholeCamera;
-use rslam_core::Camera;
+use sophus::nalgebra::{Matrix3, Vector3};
+use rslam_sensor::{
+    pinhole_camera::PinholeCamera,
+    stereo_camera::{StereoCamera, TriangulationMethod},
+};
+use rslam_core::{Camera, GrayImage, ImageView, Keypoint, PixelCoordinates};
 use crate::{
-    frame::frame_point::FramePoint, intensity_feature_matcher::IntensityFeatureMatcher, stereo_framepoint::IntensityFeature
+    dense_stereo::{DenseStereo, DenseStereoCfg, DisparityMap}, descriptor::{DescriptorMetric, DescriptorNorm, DescriptorType, Descriptors}, frame::frame_point::FramePoint, intensity_feature_matcher::{self, IntensityFeatureMatcher}, keypoint_detector::{KeypointDetector, KeypointDetectorType, SensitivityControl}, keypoint_selection::{KeypointSelectionType, KeypointSelector}, spatial_grid::SpatialGrid, subpixel_refinement::{self, DisparityRefinement, PatchCost}
 };
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StereoMatchingMode {
+    // row wise search on the epipolar lines of rectified images
+    Rectified,
+    // band search around the epipolar lines of the essential matrix of the rig
+    Unrectified,
+}
+
+impl StereoMatchingMode {
+    pub const ALL: [StereoMatchingMode; 2] =
+        [StereoMatchingMode::Rectified, StereoMatchingMode::Unrectified];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StereoMatchingMode::Rectified => "RECTIFIED",
+            StereoMatchingMode::Unrectified => "UNRECTIFIED",
+        }
+    }
+}
+
+impl fmt::Display for StereoMatchingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for StereoMatchingMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mode = match s.trim().to_ascii_uppercase().as_str() {
+            "RECTIFIED" => StereoMatchingMode::Rectified,
+            "UNRECTIFIED" => StereoMatchingMode::Unrectified,
+            _ => bail!(
+                "unknown stereo matching mode '{}', expected one of: {}",
+                s,
+                StereoMatchingMode::ALL.map(|mode| mode.name()).join(", ")
+            ),
+        };
+        Ok(mode)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct StereoFramePointGeneratorCfg {
     number_of_detectors_vertical: usize,
     number_of_detectors_horizontal: usize,
     target_number_of_keypoints_tolerance: f32,
-    detector_threshold_maximum_change: f32,
-    detector_threshold_initial: usize,
-    detector_threshold_minimum: usize,
-    detector_threshold_maximum: usize,
+    detector_type: String,
+    // relative change of an adaptation step
+    detector_sensitivity_maximum_change: f32,
+    // None selects the default of the detector type
+    detector_sensitivity_initial: Option<f32>,
+    detector_sensitivity_minimum: Option<f32>,
+    detector_sensitivity_maximum: Option<f32>,
 
     bin_size_pixels: usize,
-    maximum_matching_distance_triangulation: i32,
+    // NONE, BUCKETING (strongest keypoints per bin) or ANMS (suppression via square covering)
+    keypoint_selection: String,
+    keypoints_per_bin: usize,
+    // detectors aim for this multiple of the target when keypoints are selected afterwards
+    detection_oversampling: f32,
+    // normalized descriptor distances (fraction of the maximum distance under the norm), used to
+    // be absolute bits: divide old values by the descriptor length, e.g. 51 bits of ORB -> 0.2
+    maximum_matching_distance_triangulation: f32,
+    // threshold while localizing, the lower bound of the scaled maximum otherwise
+    minimum_matching_distance_triangulation: f32,
     descriptor_type: String,
+    // HAMMING for binary, L2 (default) or L1 for float descriptors
+    descriptor_norm: Option<String>,
+
+    // RECTIFIED or UNRECTIFIED (raw images, e.g. wide angle cameras)
+    stereo_matching: String,
+    // unrectified: distance of the right keypoints to the epipolar line (undistorted pixels)
+    epipolar_band_pixels: f64,
+    // unrectified: MIDPOINT or DLT
+    triangulation_method: String,
 
     minimum_disparity_pixels: f32,
     maximum_epipolar_search_offset_pixels: i32,
+    // NONE, SAD or ZNCC patch cost of the subpixel disparity refinement
+    subpixel_refinement: String,
+    subpixel_half_patch_size: usize,
+    subpixel_search_radius: usize,
+    // refined matches with a lower score are discarded
+    subpixel_minimum_score: f32,
+    // best descriptor distance has to be below this fraction of the second best, None disables
+    maximum_matching_ratio: Option<f32>,
+    // the left feature has to be the best match of the right feature among the left features
+    mutual_best_match: bool,
+    // bounds the disparity of the candidates, None disables
+    minimum_depth_meters: Option<f64>,
+    // keypoint position noise propagated into the point covariances
+    triangulation_pixel_noise_sigma: f64,
+
+    // dense disparity map of every frame, None disables
+    dense_stereo: Option<DenseStereoCfg>,
+    // dense disparities with a lower confidence are not sampled
+    dense_minimum_confidence: f32,
+    // keypoints without a stereo match get their depth from the dense disparity map
+    dense_depth_for_unmatched_keypoints: bool,
+
+    // threads for detection and description, 0 selects the number of cpus
+    number_of_threads: usize,
 }
 
 impl Default for StereoFramePointGeneratorCfg {
@@ -43,17 +133,41 @@ impl Default for StereoFramePointGeneratorCfg {
             number_of_detectors_vertical: 1,
             number_of_detectors_horizontal: 1,
             target_number_of_keypoints_tolerance: 0.1,
-            detector_threshold_maximum_change: 0.5,
-            detector_threshold_initial: 15,
-            detector_threshold_minimum: 15,
-            detector_threshold_maximum: 100,
+            detector_type: String::from("FAST"),
+            detector_sensitivity_maximum_change: 0.5,
+            detector_sensitivity_initial: None,
+            detector_sensitivity_minimum: None,
+            detector_sensitivity_maximum: None,
             bin_size_pixels: 25,
+            keypoint_selection: String::from("NONE"),
+            keypoints_per_bin: 1,
+            detection_oversampling: 2.0,
 
-            maximum_matching_distance_triangulation: (0.2 * 256f32) as i32,
+            maximum_matching_distance_triangulation: 0.2,
+            minimum_matching_distance_triangulation: 0.1,
             descriptor_type: String::from("ORB-256"),
+            descriptor_norm: None,
+
+            stereo_matching: String::from("RECTIFIED"),
+            epipolar_band_pixels: 2.0,
+            triangulation_method: String::from("MIDPOINT"),
 
             minimum_disparity_pixels: 1.0,
             maximum_epipolar_search_offset_pixels: 0,
+            subpixel_refinement: String::from("ZNCC"),
+            subpixel_half_patch_size: 3,
+            subpixel_search_radius: 1,
+            subpixel_minimum_score: -1.0,
+            maximum_matching_ratio: None,
+            mutual_best_match: false,
+            minimum_depth_meters: None,
+            triangulation_pixel_noise_sigma: 1.0,
+
+            dense_stereo: None,
+            dense_minimum_confidence: 0.2,
+            dense_depth_for_unmatched_keypoints: false,
+
+            number_of_threads: 0,
         }
     }
 }
@@ -63,95 +177,153 @@ impl StereoFramePointGeneratorCfg {
         self,
         width: usize,
         height: usize,
-        camera_left: PinholeCamera,
-        camera_right: PinholeCamera,
-        baseline: Vector3<f64>,
+        stereo_camera: StereoCamera,
     ) -> Result<StereoFramePointGenerator> {
-        let mut detectors = vec![];
-        let mut detector_regions = vec![];
-        let mut detector_thresholds: Vec<Vec<f32>> = vec![];
+        let detector_type: KeypointDetectorType = self
+            .detector_type
+            .parse()
+            .context("invalid detector_type")?;
+        let (sensitivity_initial, sensitivity_minimum, sensitivity_maximum) =
+            detector_type.default_sensitivity_range();
+        let sensitivity = SensitivityControl {
+            value: self.detector_sensitivity_initial.unwrap_or(sensitivity_initial),
+            minimum: self.detector_sensitivity_minimum.unwrap_or(sensitivity_minimum),
+            maximum: self.detector_sensitivity_maximum.unwrap_or(sensitivity_maximum),
+            maximum_change: self.detector_sensitivity_maximum_change,
+            minimum_step: 0.0,
+            tolerance: self.target_number_of_keypoints_tolerance,
+        };
+        if sensitivity.minimum > sensitivity.maximum {
+            bail!(
+                "detector sensitivity minimum {} exceeds maximum {}",
+                sensitivity.minimum,
+                sensitivity.maximum
+            );
+        }
 
         let number_of_rows_image = height;
         let number_of_cols_image = width;
 
+        let number_of_detectors =
+            self.number_of_detectors_horizontal * self.number_of_detectors_vertical;
+
+        let number_of_cols_bin = (width as f32 / self.bin_size_pixels as f32).floor() + 1f32;
+        let number_of_rows_bin = (height as f32 / self.bin_size_pixels as f32).floor() + 1f32;
+
+        let target_number_of_keypoints =
+            number_of_cols_bin * number_of_rows_bin * self.keypoints_per_bin.max(1) as f32;
+        log::info!(
+            "current target number of points: {}",
+            target_number_of_keypoints
+        );
+
+        let keypoint_selection_type: KeypointSelectionType = self
+            .keypoint_selection
+            .parse()
+            .context("invalid keypoint_selection")?;
+        let keypoint_selector = KeypointSelector {
+            selection_type: keypoint_selection_type,
+            width,
+            height,
+            bin_size_pixels: self.bin_size_pixels,
+            keypoints_per_bin: self.keypoints_per_bin.max(1),
+            target_number_of_keypoints: target_number_of_keypoints as usize,
+            tolerance: self.target_number_of_keypoints_tolerance,
+        };
+        let detection_oversampling = match keypoint_selection_type {
+            KeypointSelectionType::None => 1.0,
+            _ => self.detection_oversampling.max(1.0),
+        };
+        log::info!(
+            "keypoint selection: {} (detection oversampling {})",
+            keypoint_selection_type,
+            detection_oversampling
+        );
+
+        let target_number_of_keypoints_per_detector =
+            detection_oversampling * target_number_of_keypoints / number_of_detectors as f32;
+        log::info!("current target number of points per image region: {target_number_of_keypoints_per_detector}");
+
         let pixel_rows_per_detector =
             number_of_rows_image as f32 / self.number_of_detectors_vertical as f32;
         let pixel_cols_per_detector =
             number_of_cols_image as f32 / self.number_of_detectors_horizontal as f32;
 
+        // row major, the keypoints of a frame are concatenated in this order
+        let mut detectors = vec![];
         for r in 0..self.number_of_detectors_vertical {
-            let mut sub_detectors = vec![];
-            let mut sub_detector_regions = vec![];
-            let mut sub_detector_thresholds = vec![];
             for c in 0..self.number_of_detectors_horizontal {
-                sub_detectors.push(FastFeatureDetector::create(
-                    self.detector_threshold_minimum as _,
-                    true,
-                    FastFeatureDetector_DetectorType::TYPE_9_16,
-                )?);
-                let rect = Rect::new(
+                let region = Rect::new(
                     (c as f32 * pixel_cols_per_detector).round() as _,
                     (r as f32 * pixel_rows_per_detector).round() as _,
                     pixel_cols_per_detector as _,
                     pixel_rows_per_detector as _,
                 );
-                sub_detector_regions.push(rect);
-                sub_detector_thresholds.push(self.detector_threshold_minimum as f32);
+                detectors.push(DetectorRegion {
+                    region,
+                    detector_left: detector_type
+                        .create(sensitivity, target_number_of_keypoints_per_detector)?,
+                    detector_right: detector_type
+                        .create(sensitivity, target_number_of_keypoints_per_detector)?,
+                });
             }
-            detectors.push(sub_detectors);
-            detector_regions.push(sub_detector_regions);
-            detector_thresholds.push(sub_detector_thresholds);
         }
-
-        let focal_length_pixels  = camera_left.model.params()[0];
-
-        let detectors = detectors
-            .into_iter()
-            .zip(detector_regions)
-            .zip(detector_thresholds)
-            .into_iter()
-            .map(|((detectors, regions), thresholds)| {
-                detectors
-                    .into_iter()
-                    .zip(regions)
-                    .zip(thresholds)
-                    .map(|((detector, region), threshold)| (detector, region, threshold))
-                    .collect()
-            })
-            .collect();
-
-        let number_of_detectors =
-            self.number_of_detectors_horizontal * self.number_of_detectors_vertical;
-
-        let number_of_cols_bin = (width as f32 / self.bin_size_pixels as f32).floor() + 1f32;
-        let number_of_rows_bin = (height as f32 / self.bin_size_pixels as f32).floor() + 1f32;
-
-        let target_number_of_keypoints = number_of_cols_bin * number_of_rows_bin;
+        log::info!("detector: {} (sensitivity {:?})", detector_type, sensitivity);
+
+        let thread_pool = ThreadPoolBuilder::new()
+            .num_threads(self.number_of_threads)
+            .build()
+            .context("failed to create the detection thread pool")?;
+        log::info!("detection threads: {}", thread_pool.current_num_threads());
+
+        let descriptor_type: DescriptorType = self
+            .descriptor_type
+            .parse()
+            .context("invalid descriptor_type")?;
+        let descriptor_norm: Option<DescriptorNorm> = self
+            .descriptor_norm
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("invalid descriptor_norm")?;
+        let descriptor_metric = DescriptorMetric::new(descriptor_type, descriptor_norm)?;
+        descriptor_type.check_normalized_distance(
+            "minimum_matching_distance_triangulation",
+            self.minimum_matching_distance_triangulation,
+        )?;
+        descriptor_type.check_normalized_distance(
+            "maximum_matching_distance_triangulation",
+            self.maximum_matching_distance_triangulation,
+        )?;
+        if self.minimum_matching_distance_triangulation > self.maximum_matching_distance_triangulation
+            || self.maximum_matching_distance_triangulation == 0.0
+        {
+            bail!(
+                "triangulation matching distances must satisfy minimum <= maximum, maximum > 0, got {} and {}",
+                self.minimum_matching_distance_triangulation,
+                self.maximum_matching_distance_triangulation
+            );
+        }
+        let descriptor_extractor_left = descriptor_type.create_extractor()?;
+        let descriptor_extractor_right = descriptor_type.create_extractor()?;
         log::info!(
-            "current target number of points: {}",
-            target_number_of_keypoints
+            "descriptor: {} ({:?}, maximum distance {})",
+            descriptor_type,
+            descriptor_metric.norm(),
+            descriptor_metric.maximum_distance()
         );
 
-        let target_number_of_keypoints_per_detector =
-            target_number_of_keypoints / number_of_detectors as f32;
-        log::info!("current target number of points per image region: {target_number_of_keypoints_per_detector}");
-
-        let descriptor_extract = match self.descriptor_type.as_str() {
-            "ORB-256" => ORB::create_def()?.try_into()?,
-            _ => {
-                unimplemented!()
-            }
-        };
-
         let mut feature_matcher_left = IntensityFeatureMatcher::default();
         let mut feature_matcher_right = IntensityFeatureMatcher::default();
         feature_matcher_left.configure(
             NonZeroUsize::new(number_of_rows_image).unwrap(),
             NonZeroUsize::new(number_of_cols_image).unwrap(),
+            descriptor_metric,
         );
         feature_matcher_right.configure(
             NonZeroUsize::new(number_of_rows_image).unwrap(),
             NonZeroUsize::new(number_of_cols_image).unwrap(),
+            descriptor_metric,
         );
 
         let mut epipolar_search_offset_pixels = vec![0];
@@ -164,93 +336,280 @@ impl StereoFramePointGeneratorCfg {
             "number of epipolar lines considered for stereo matching: {}",
             epipolar_search_offset_pixels.len()
         );
-        log::info!("configured");
-        let baseline_pixelsmeters = baseline[0];
-        let baseline_meters = -baseline_pixelsmeters/focal_length_pixels;
-        if baseline_meters < 0.0 {
-            panic!("invalid baseline (m): {}", baseline_meters);
+        let subpixel_refinement = self.subpixel_refinement.trim().to_ascii_uppercase();
+        let disparity_refinement = match subpixel_refinement.as_str() {
+            "NONE" => None,
+            cost => Some(DisparityRefinement {
+                cost: cost.parse::<PatchCost>().context("invalid subpixel_refinement")?,
+                half_patch_size: self.subpixel_half_patch_size,
+                search_radius: self.subpixel_search_radius,
+            }),
+        };
+        log::info!("subpixel refinement: {:?}", disparity_refinement);
+
+        let maximum_disparity_pixels = match self.minimum_depth_meters {
+            Some(minimum_depth_meters) if minimum_depth_meters <= 0.0 => {
+                bail!("minimum_depth_meters has to be positive, got {}", minimum_depth_meters)
+            }
+            Some(minimum_depth_meters) => {
+                stereo_camera.depth_to_disparity(minimum_depth_meters) as f32
+            }
+            None => f32::INFINITY,
+        };
+        log::info!(
+            "ratio test: {:?}, mutual best match: {}, maximum disparity (pixels): {}",
+            self.maximum_matching_ratio,
+            self.mutual_best_match,
+            maximum_disparity_pixels
+        );
+
+        let dense_stereo = self.dense_stereo.map(DenseStereoCfg::finalize).transpose()?;
+        log::info!(
+            "dense stereo: {:?}",
+            dense_stereo.as_ref().map(DenseStereo::method)
+        );
+
+        let stereo_matching_mode: StereoMatchingMode = self
+            .stereo_matching
+            .parse()
+            .context("invalid stereo_matching")?;
+        let triangulation_method: TriangulationMethod = self
+            .triangulation_method
+            .parse()
+            .context("invalid triangulation_method")?;
+        if stereo_matching_mode == StereoMatchingMode::Unrectified {
+            if dense_stereo.is_some() {
+                bail!("dense stereo requires rectified stereo matching");
+            }
+            if self.epipolar_band_pixels <= 0.0 {
+                bail!(
+                    "epipolar_band_pixels has to be positive, got {}",
+                    self.epipolar_band_pixels
+                );
+            }
+            log::info!(
+                "unrectified stereo matching: epipolar band (pixels) {}, triangulation {}",
+                self.epipolar_band_pixels,
+                triangulation_method
+            );
         }
+        let fundamental_matrix = stereo_camera.fundamental_matrix();
+        // undistorted keypoints of wide angle lenses may lie far outside of the image, the grid
+        // covers the image and a margin of half its size, points beyond are tested one by one
+        let (cols, rows) = (
+            stereo_camera.right.cols() as f64,
+            stereo_camera.right.rows() as f64,
+        );
+        let grid_right = SpatialGrid::new((4.0 * self.epipolar_band_pixels).max(16.0)).with_bounds(
+            PixelCoordinates::new(-0.5 * cols, -0.5 * rows),
+            PixelCoordinates::new(1.5 * cols, 1.5 * rows),
+        );
+
+        log::info!("baseline (m): {}", stereo_camera.baseline_meters());
+        log::info!("configured");
 
         Ok(StereoFramePointGenerator {
             detectors,
             target_number_of_keypoints,
             target_number_of_keypoints_per_detector,
-            target_number_of_keypoints_tolerance: self.target_number_of_keypoints_tolerance,
-            detector_threshold_maximum_change: self.detector_threshold_maximum_change,
-            detector_threshold_minimum: self.detector_threshold_minimum as f32,
+            keypoint_selector,
 
             number_of_detected_keypoints: 0,
-            descriptor_extractor: descriptor_extract,
-
-            current_maximum_descriptor_distance_triangulation: 0.1 * 256f32,
+            descriptor_metric,
+            descriptor_extractor_left,
+            descriptor_extractor_right,
+            thread_pool,
+
+            maximum_descriptor_distance_triangulation: self.maximum_matching_distance_triangulation,
+            minimum_descriptor_distance_triangulation: self.minimum_matching_distance_triangulation,
+            current_maximum_descriptor_distance_triangulation: self
+                .minimum_matching_distance_triangulation,
             epipolar_search_distance: epipolar_search_offset_pixels,
+            candidate_rows: Vec::new(),
+            hamming_distances: Vec::new(),
 
             minimum_disparity_pixels: self.minimum_disparity_pixels,
+            disparity_refinement,
+            subpixel_minimum_score: self.subpixel_minimum_score,
+            maximum_matching_ratio: self.maximum_matching_ratio,
+            mutual_best_match: self.mutual_best_match,
+            maximum_disparity_pixels,
+            triangulation_pixel_noise_sigma: self.triangulation_pixel_noise_sigma,
+            dense_stereo,
+            dense_minimum_confidence: self.dense_minimum_confidence,
+            dense_depth_for_unmatched_keypoints: self.dense_depth_for_unmatched_keypoints,
+
+            stereo_matching_mode,
+            epipolar_band_pixels: self.epipolar_band_pixels,
+            triangulation_method,
+            minimum_depth_meters: self.minimum_depth_meters.unwrap_or(0.0),
+            fundamental_matrix,
+            grid_right,
+            band_candidates: Vec::new(),
+
             feature_matcher_left,
             feature_matcher_right,
 
-            camera_left,
-            camera_right,
-
-            baseline,
-            baseline_meters,
-            baseline_pixelsmeters,
-            focal_length_pixels,
+            stereo_camera,
         })
     }
 }
 
+// one detector per camera, both share the sensitivity of the region
+struct DetectorRegion {
+    region: Rect2i,
+    detector_left: Box<dyn KeypointDetector>,
+    detector_right: Box<dyn KeypointDetector>,
+}
+
+impl DetectorRegion {
+    // keypoints of the left and right image in image coordinates
+    fn detect(
+        &mut self,
+        intensity_image_left: &ImageView,
+        intensity_image_right: &ImageView,
+        target_number_of_keypoints: f32,
+    ) -> Result<(Vector<KeyPoint>, Vector<KeyPoint>)> {
+        let region = self.region;
+        let (keypoints_left, keypoints_right) = rayon::join(
+            || detect_in_image(self.detector_left.as_mut(), intensity_image_left, region),
+            || detect_in_image(self.detector_right.as_mut(), intensity_image_right, region),
+        );
+        let (keypoints_left, keypoints_right) = (keypoints_left?, keypoints_right?);
+
+        self.detector_left.sensitivity_mut().update(
+            (keypoints_left.len() + keypoints_right.len()) / 2,
+            target_number_of_keypoints,
+        );
+        *self.detector_right.sensitivity_mut() = *self.detector_left.sensitivity();
+        Ok((keypoints_left, keypoints_right))
+    }
+
+    fn apply_sensitivity(&mut self) -> Result<()> {
+        self.detector_left.apply_sensitivity()?;
+        self.detector_right.apply_sensitivity()
+    }
+}
+
+fn detect_in_image(
+    detector: &mut dyn KeypointDetector,
+    intensity_image: &ImageView,
+    region: Rect2i,
+) -> Result<Vector<KeyPoint>> {
+    let offset = region.tl();
+    let offset = Point2f::new(offset.x as f32, offset.y as f32);
+    Ok(detector
+        .detect(intensity_image, region)?
+        .iter()
+        .map(|mut x| {
+            x.set_pt(x.pt() + offset);
+            x
+        })
+        .collect())
+}
+
+// the extractor drops keypoints it can't describe, e.g. close to the image border
+fn extract_descriptors(
+    descriptor_type: DescriptorType,
+    descriptor_extractor: &mut Ptr<DescriptorExtractor>,
+    intensity_image: &GrayImage<u8>,
+    keypoints: &mut Vec<Keypoint>,
+    descriptors: &mut Descriptors,
+) -> Result<()> {
+    let mut keypoints_opencv = keypoints
+        .iter()
+        .map(Keypoint::to_opencv)
+        .collect::<opencv::Result<Vector<KeyPoint>>>()?;
+    descriptor_type.prepare_keypoints(&mut keypoints_opencv);
+    let mut descriptors_opencv = Mat::default();
+    descriptor_extractor.compute(
+        &intensity_image.to_mat()?,
+        &mut keypoints_opencv,
+        &mut descriptors_opencv,
+    )?;
+    *keypoints = keypoints_opencv
+        .iter()
+        .map(|keypoint| Keypoint::from_opencv(&keypoint))
+        .collect();
+    *descriptors = Descriptors::from_mat(&descriptors_opencv)?;
+    Ok(())
+}
+
 pub struct StereoFramePointGenerator {
-    detectors: Vec<Vec<(Ptr<features2d::FastFeatureDetector>, Rect2i, f32)>>,
+    detectors: Vec<DetectorRegion>,
     target_number_of_keypoints: f32,
     target_number_of_keypoints_per_detector: f32,
-    target_number_of_keypoints_tolerance: f32,
-    detector_threshold_maximum_change: f32,
-    detector_threshold_minimum: f32,
+    keypoint_selector: KeypointSelector,
 
     number_of_detected_keypoints: usize,
-    descriptor_extractor: Ptr<DescriptorExtractor>,
-
+    descriptor_metric: DescriptorMetric,
+    descriptor_extractor_left: Ptr<DescriptorExtractor>,
+    descriptor_extractor_right: Ptr<DescriptorExtractor>,
+    // regions and cameras are processed in parallel, results are merged in region order
+    thread_pool: ThreadPool,
+
+    // normalized descriptor distances
+    maximum_descriptor_distance_triangulation: f32,
+    minimum_descriptor_distance_triangulation: f32,
     current_maximum_descriptor_distance_triangulation: f32,
     minimum_disparity_pixels: f32,
+    disparity_refinement: Option<DisparityRefinement>,
+    subpixel_minimum_score: f32,
+    maximum_matching_ratio: Option<f32>,
+    mutual_best_match: bool,
+    // infinite without a minimum depth
+    maximum_disparity_pixels: f32,
+    triangulation_pixel_noise_sigma: f64,
+    dense_stereo: Option<DenseStereo>,
+    dense_minimum_confidence: f32,
+    dense_depth_for_unmatched_keypoints: bool,
+
+    stereo_matching_mode: StereoMatchingMode,
+    epipolar_band_pixels: f64,
+    triangulation_method: TriangulationMethod,
+    minimum_depth_meters: f64,
+    // of the undistorted pixels, x_right^T F x_left = 0
+    fundamental_matrix: Matrix3<f64>,
+    // undistorted right keypoints and the scratch buffer of the band queries
+    grid_right: SpatialGrid,
+    band_candidates: Vec<usize>,
 
     epipolar_search_distance: Vec<i32>,
 
+    // scratch buffers of the batched hamming distances
+    candidate_rows: Vec<usize>,
+    hamming_distances: Vec<u32>,
+
     feature_matcher_left: IntensityFeatureMatcher,
     feature_matcher_right: IntensityFeatureMatcher,
 
-    camera_right: PinholeCamera,
-    camera_left: PinholeCamera,
-
-    focal_length_pixels: f64,
-
-    baseline: Vector3<f64>,
-    baseline_pixelsmeters: f64,
-    baseline_meters: f64,
+    stereo_camera: StereoCamera,
 }
 
 impl StereoFramePointGenerator {
     pub fn initialize(&mut self, frame: &mut Frame, extract_features: bool) -> Result<()> {
         if extract_features {
-            frame.keypoints_left = self.detect_keypoints(&frame.intensity_image_left)?;
-            frame.keypoints_right = self.detect_keypoints(&frame.intensity_image_right)?;
+            (frame.keypoints_left, frame.keypoints_right) = self.detect_keypoints(
+                &frame.intensity_image_left.view(),
+                &frame.intensity_image_right.view(),
+            )?;
 
-            self.adjust_detector_thresholds()?;
+            self.adjust_detector_sensitivities()?;
 
             self.number_of_detected_keypoints =
                 (frame.keypoints_left.len() + frame.keypoints_right.len()) / 2;
+
+            // evenly distributed left keypoints for stereo matching and tracking, the right
+            // keypoints are all kept since an independent selection drops the matches of
+            // selected left keypoints
+            if self.keypoint_selector.selection_type != KeypointSelectionType::None {
+                frame.keypoints_left = self.select_keypoints(&frame.keypoints_left);
+                self.number_of_detected_keypoints = frame.keypoints_left.len();
+            }
             frame.number_of_detected_keypoints = self.number_of_detected_keypoints;
 
-            self.compute_descriptors(
-                &frame.intensity_image_left,
-                &mut frame.keypoints_left,
-                &mut frame.descriptors_left,
-            )?;
-            self.compute_descriptors(
-                &frame.intensity_image_right,
-                &mut frame.keypoints_right,
-                &mut frame.descriptors_right,
-            )?;
+            self.compute_descriptors(frame)?;
+            self.compute_disparity_map(frame)?;
             log::debug!(
                 "extracted features L: {} R: {}",
                 frame.keypoints_left.len(),
@@ -258,14 +617,15 @@ impl StereoFramePointGenerator {
             );
 
             if frame.status == FrameStatus::Localizing {
-                self.current_maximum_descriptor_distance_triangulation = 0.1 * 256f32;
+                self.current_maximum_descriptor_distance_triangulation =
+                    self.minimum_descriptor_distance_triangulation;
             } else {
                 let ratio_available_points = (self.number_of_detected_keypoints as f32
                     / self.target_number_of_keypoints)
                     .min(1.0);
                 self.current_maximum_descriptor_distance_triangulation = (ratio_available_points
-                    * self.current_maximum_descriptor_distance_triangulation)
-                    .max(0.1 * 256f32);
+                    * self.maximum_descriptor_distance_triangulation)
+                    .max(self.minimum_descriptor_distance_triangulation);
             }
         }
 
@@ -276,10 +636,12 @@ impl StereoFramePointGenerator {
         Ok(())
     }
 
+    // matched left and right keypoints and the indices of the left keypoints in the frame
     pub fn get_epipolar_matches(
         &mut self,
         epipolar_offset: i32,
-    ) -> Result<(Vec<KeyPoint>, Vec<KeyPoint>)> {
+        statistics: &mut StereoMatchingStatistics,
+    ) -> Result<(Vec<Keypoint>, Vec<Keypoint>, Vec<usize>)> {
         self.feature_matcher_left.sort_feature_vector();
         self.feature_matcher_right.sort_feature_vector();
 
@@ -289,6 +651,8 @@ impl StereoFramePointGenerator {
         let mut matched_indices_left = BTreeSet::<usize>::new();
         let mut matched_indices_right = BTreeSet::<usize>::new();
 
+        let maximum_hamming_distance = self.descriptor_metric.maximum_distance() as f32;
+
         let mut index_r = 0;
         let mut index_l = 0;
 
@@ -313,79 +677,211 @@ impl StereoFramePointGenerator {
                 }
             }
 
-            let mut index_search_r = index_r;
-            let mut descriptor_distance_best =
-                self.current_maximum_descriptor_distance_triangulation;
+            // candidates on the epipolar line, left of the feature
+            let mut index_end_r = index_r;
+            while index_end_r < features_right.len()
+                && feature_left.row == features_right[index_end_r].row + epipolar_offset
+                && feature_left.col - features_right[index_end_r].col >= 0
+            {
+                index_end_r += 1;
+            }
+            if index_end_r == index_r {
+                index_l += 1;
+                continue;
+            }
+            statistics.number_of_candidates += 1;
+
+            // right features are sorted by column, the closest candidates have the smallest
+            // disparity
+            let index_begin_r = index_r
+                + features_right[index_r..index_end_r].partition_point(|feature_right| {
+                    (feature_left.col - feature_right.col) as f32 > self.maximum_disparity_pixels
+                });
+            if index_begin_r == index_end_r {
+                statistics.rejected_maximum_disparity += 1;
+                index_l += 1;
+                continue;
+            }
+
+            let mut descriptor_distance_best = f32::MAX;
+            let mut descriptor_distance_second = f32::MAX;
             let mut index_best_r = 0;
 
-            while feature_left.row == features_right[index_search_r].row + epipolar_offset {
-                if feature_left.col - features_right[index_search_r].col < 0 {
-                    break;
+            if let (Some(descriptor_left), Some(packed_descriptors_right)) = (
+                self.feature_matcher_left.packed_descriptor(&feature_left),
+                self.feature_matcher_right.descriptors.packed(),
+            ) {
+                self.candidate_rows.clear();
+                self.candidate_rows.extend(
+                    features_right[index_begin_r..index_end_r]
+                        .iter()
+                        .map(|feature_right| feature_right.index_in_vector),
+                );
+                packed_descriptors_right
+                    .hamming_distances_to_rows(
+                        descriptor_left,
+                        &self.candidate_rows,
+                        &mut self.hamming_distances,
+                    );
+                for (offset, hamming_distance) in self.hamming_distances.iter().enumerate() {
+                    let descriptor_distance = *hamming_distance as f32 / maximum_hamming_distance;
+                    if descriptor_distance < descriptor_distance_best {
+                        descriptor_distance_second = descriptor_distance_best;
+                        descriptor_distance_best = descriptor_distance;
+                        index_best_r = index_begin_r + offset;
+                    } else if descriptor_distance < descriptor_distance_second {
+                        descriptor_distance_second = descriptor_distance;
+                    }
                 }
+            } else {
+                for (offset, feature_right) in
+                    features_right[index_begin_r..index_end_r].iter().enumerate()
+                {
+                    let descriptor_distance = self.descriptor_distance(&feature_left, feature_right)?;
+                    if descriptor_distance < descriptor_distance_best {
+                        descriptor_distance_second = descriptor_distance_best;
+                        descriptor_distance_best = descriptor_distance;
+                        index_best_r = index_begin_r + offset;
+                    } else if descriptor_distance < descriptor_distance_second {
+                        descriptor_distance_second = descriptor_distance;
+                    }
+                }
+            }
 
-                let descriptor_distance = opencv::core::norm2(
-                    &feature_left.descriptor,
-                    &features_right[index_search_r].descriptor,
-                    NORM_HAMMING,
-                    &Mat::default(),
-                )?;
-                if descriptor_distance < descriptor_distance_best as f64 {
-                    descriptor_distance_best = descriptor_distance as f32;
-                    index_best_r = index_search_r;
+            if descriptor_distance_best >= self.current_maximum_descriptor_distance_triangulation {
+                statistics.rejected_descriptor_distance += 1;
+                index_l += 1;
+                continue;
+            }
+
+            // lowe's ratio test against the second best candidate
+            if let Some(maximum_matching_ratio) = self.maximum_matching_ratio {
+                if descriptor_distance_second < f32::MAX
+                    && descriptor_distance_best >= maximum_matching_ratio * descriptor_distance_second
+                {
+                    statistics.rejected_ratio += 1;
+                    index_l += 1;
+                    continue;
                 }
-                index_search_r += 1;
-                if index_search_r == features_right.len() {
-                    break;
+            }
+
+            // the left feature has to be the best match of the right feature as well
+            if self.mutual_best_match {
+                let feature_right = &features_right[index_best_r];
+                let row_left = feature_right.row + epipolar_offset;
+                let index_begin_l = features_left.partition_point(|feature| {
+                    feature.row < row_left
+                        || (feature.row == row_left && feature.col < feature_right.col)
+                });
+                for (index_other_l, other_left) in
+                    features_left.iter().enumerate().skip(index_begin_l)
+                {
+                    if other_left.row != row_left
+                        || (other_left.col - feature_right.col) as f32 > self.maximum_disparity_pixels
+                    {
+                        break;
+                    }
+                    if index_other_l == index_l {
+                        continue;
+                    }
+                    if self.descriptor_distance(other_left, feature_right)? < descriptor_distance_best {
+                        statistics.rejected_mutual += 1;
+                        index_l += 1;
+                        continue 'out;
+                    }
                 }
             }
 
-            if descriptor_distance_best < self.current_maximum_descriptor_distance_triangulation {
-                matched_indices_left.insert(index_l);
-                matched_indices_right.insert(index_best_r);
+            matched_indices_left.insert(index_l);
+            matched_indices_right.insert(index_best_r);
+            statistics.number_of_matches += 1;
 
-                index_r = index_best_r + 1;
-            }
+            index_r = index_best_r + 1;
             index_l += 1;
         }
 
         let left_key_points: Vec<_> = matched_indices_left
             .iter()
-            .map(|x| {
-                self.feature_matcher_left.feature_vector[*x]
-                    .keypoint
-                    .clone()
-            })
+            .map(|x| self.feature_matcher_left.feature_vector[*x].keypoint)
             .collect();
 
         let right_key_points: Vec<_> = matched_indices_right
             .iter()
-            .map(|x| {
-                self.feature_matcher_right.feature_vector[*x]
-                    .keypoint
-                    .clone()
-            })
+            .map(|x| self.feature_matcher_right.feature_vector[*x].keypoint)
+            .collect();
+
+        let left_keypoint_indices: Vec<_> = matched_indices_left
+            .iter()
+            .map(|x| self.feature_matcher_left.feature_vector[*x].index_in_vector)
             .collect();
 
         self.feature_matcher_left.prune(&matched_indices_left);
         self.feature_matcher_right.prune(&matched_indices_right);
 
-        Ok((left_key_points, right_key_points))
+        Ok((left_key_points, right_key_points, left_keypoint_indices))
+    }
+
+    // normalized descriptor distance between a left and a right feature
+    fn descriptor_distance(
+        &self,
+        feature_left: &intensity_feature_matcher::IntensityFeature,
+        feature_right: &intensity_feature_matcher::IntensityFeature,
+    ) -> Result<f32> {
+        self.descriptor_metric.normalized_distance(
+            self.feature_matcher_left.descriptor(feature_left),
+            self.feature_matcher_right.descriptor(feature_right),
+        )
     }
 
     pub fn compute_frame_point(&mut self, frame: &mut Frame) -> Result<()> {
+        if self.stereo_matching_mode == StereoMatchingMode::Unrectified {
+            return self.compute_frame_point_unrectified(frame);
+        }
+
+        let image_left = frame.intensity_image_left.view();
+        let image_right = frame.intensity_image_right.view();
+
+        let mut statistics = StereoMatchingStatistics::default();
+        let mut stereo_matches = Vec::new();
         for epipolar_offset in self.epipolar_search_distance.clone() {
-            let (features_left, features_right) =
-                self.get_epipolar_matches(epipolar_offset)?;
+            let (features_left, features_right, keypoint_indices_left) =
+                self.get_epipolar_matches(epipolar_offset, &mut statistics)?;
 
             // 跳过视差太小的两点
-            for (feature_left, feature_right) in features_left.iter().zip(features_right.iter()) {
-                let disparity = feature_left.pt().x - feature_right.pt().x;
+            for ((feature_left, feature_right), keypoint_index_left) in features_left
+                .iter()
+                .zip(features_right.iter())
+                .zip(keypoint_indices_left)
+            {
+                let Some((pixel_right, matching_score)) = subpixel_refinement::refine_match(
+                    self.disparity_refinement.as_ref(),
+                    self.subpixel_minimum_score,
+                    &image_left,
+                    &image_right,
+                    feature_left,
+                    feature_right,
+                ) else {
+                    statistics.rejected_matching_score += 1;
+                    continue;
+                };
+                let pixel_left = feature_left.pixel_coordinates();
+                let disparity = (pixel_left.x - pixel_right.x) as f32;
                 if disparity < self.minimum_disparity_pixels {
+                    statistics.rejected_minimum_disparity += 1;
                     continue;
                 }
 
-                let point_in_left = self.get_point_in_left_camera(feature_left, feature_right);
-                frame.create_framepoint(&point_in_left, &self.camera_left);
+                if let Some((point_in_left, covariance)) =
+                    self.get_point_in_left_camera(&pixel_left, &pixel_right)
+                {
+                    stereo_matches.push((
+                        point_in_left,
+                        covariance,
+                        disparity,
+                        matching_score,
+                        keypoint_index_left,
+                    ));
+                }
             }
 
             log::debug!(
@@ -396,111 +892,366 @@ impl StereoFramePointGenerator {
             );
         }
 
-        Ok(())
-    }
-
-    fn get_point_in_left_camera(&self, feature_left: &KeyPoint, feature_right: &KeyPoint) -> sophus::nalgebra::Vector3<f64> {
-        assert!(feature_left.pt().x >= feature_right.pt().x);
-        assert!(feature_left.pt().x - feature_right.pt().x >= self.minimum_disparity_pixels);
+        // remaining unmatched left features
+        if let (true, Some(disparity_map)) = (
+            self.dense_depth_for_unmatched_keypoints,
+            &frame.disparity_map,
+        ) {
+            for feature in self.feature_matcher_left.feature_vector.iter() {
+                let pixel_left = feature.keypoint.pixel_coordinates();
+                let Some((disparity, confidence)) =
+                    disparity_map.sample(&pixel_left, self.dense_minimum_confidence)
+                else {
+                    continue;
+                };
+                if disparity < self.minimum_disparity_pixels {
+                    continue;
+                }
+                let pixel_right = PixelCoordinates::new(pixel_left.x - disparity as f64, pixel_left.y);
+                if let Some((point_in_left, covariance)) =
+                    self.get_point_in_left_camera(&pixel_left, &pixel_right)
+                {
+                    stereo_matches.push((
+                        point_in_left,
+                        covariance,
+                        disparity,
+                        Some(confidence),
+                        feature.index_in_vector,
+                    ));
+                    statistics.number_of_dense_framepoints += 1;
+                }
+            }
+        }
 
-        let f_x = self.camera_right.model.params()[0];
-        let f_y = self.camera_right.model.params()[1];
-        let c_x = self.camera_right.model.params()[2];
-        let c_y = self.camera_right.model.params()[3];
+        statistics.number_of_framepoints = stereo_matches.len();
+        log::debug!("stereo matching: {:?}", statistics);
+        frame.stereo_matching_statistics = statistics;
+
+        for (point_in_left, covariance, disparity, matching_score, keypoint_index_left) in
+            stereo_matches
+        {
+            frame.create_framepoint(
+                &point_in_left,
+                &covariance,
+                &self.stereo_camera.left,
+                disparity,
+                matching_score,
+                keypoint_index_left,
+            );
+        }
 
-        let b_x = self.baseline_pixelsmeters;
-        let z = b_x / ((feature_right.pt().x - feature_left.pt().x) as f64);
-        let x = 1.0/f_x * (feature_left.pt().x as f64 - c_x) * z;
+        Ok(())
+    }
 
-        // average in case we have an epipolar offset in v
-        let y = 1.0/f_y * ((feature_left.pt().y + feature_right.pt().x) as f64 / 2.0 - c_y) * z;
+    // matches of raw images along the epipolar lines of the essential matrix, the right
+    // keypoints are looked up in a band around the line of every left keypoint. a right keypoint
+    // keeps its best left keypoint only
+    fn compute_frame_point_unrectified(&mut self, frame: &mut Frame) -> Result<()> {
+        let mut statistics = StereoMatchingStatistics::default();
+        let features_left = &self.feature_matcher_left.feature_vector;
+        let features_right = &self.feature_matcher_right.feature_vector;
 
-        sophus::nalgebra::Vector3::new(x, y, z)
-    }
+        let camera_right = &self.stereo_camera.right;
+        self.grid_right.build(
+            features_right
+                .iter()
+                .map(|feature| camera_right.ideal_pixel(&feature.keypoint.pixel_coordinates())),
+        );
 
-    fn detect_keypoints(
-        &mut self,
-        intensity_image: &opencv::core::Mat,
-    ) -> anyhow::Result<opencv::core::Vector<KeyPoint>> {
-        let mut key_points = opencv::core::Vector::<KeyPoint>::new();
-        for sub_detectors in self.detectors.iter_mut() {
-            for (detector, region, threshold) in sub_detectors.iter_mut() {
-                let mut keypoints_per_detector = opencv::core::Vector::<KeyPoint>::new();
-                detector.detect_def(
-                    &intensity_image.apply_1(*region)?,
-                    &mut keypoints_per_detector,
-                )?;
-                let mut detector_threshold = detector.get_threshold()? as f32;
-
-                let delta = (keypoints_per_detector.len() as f32
-                    - self.target_number_of_keypoints_per_detector)
-                    / self.target_number_of_keypoints_per_detector;
-
-                //检查target points是否大量缺失
-                if delta < -self.target_number_of_keypoints_tolerance {
-                    let change = delta.max(self.detector_threshold_maximum_change);
-
-                    detector_threshold += (change * detector_threshold as f32).min(-1.0);
-
-                    if detector_threshold < self.detector_threshold_minimum {
-                        detector_threshold = self.detector_threshold_minimum;
-                    }
-                } else if delta > self.target_number_of_keypoints_tolerance {
-                    let change = delta.min(self.detector_threshold_maximum_change);
+        let mut candidates = Vec::new();
+        for (index_l, feature_left) in features_left.iter().enumerate() {
+            let pixel_left = self
+                .stereo_camera
+                .left
+                .ideal_pixel(&feature_left.keypoint.pixel_coordinates());
+            let epipolar_line = self.fundamental_matrix * pixel_left.push(1.0);
+            self.grid_right.indices_in_band(
+                &epipolar_line,
+                self.epipolar_band_pixels,
+                &mut self.band_candidates,
+            );
+            if self.band_candidates.is_empty() {
+                continue;
+            }
+            statistics.number_of_candidates += 1;
 
-                    detector_threshold += (change * detector_threshold as f32).max(1.0);
+            let mut descriptor_distance_best = f32::MAX;
+            let mut descriptor_distance_second = f32::MAX;
+            let mut index_best_r = 0;
+            for index_r in self.band_candidates.iter() {
+                let descriptor_distance =
+                    self.descriptor_distance(feature_left, &features_right[*index_r])?;
+                if descriptor_distance < descriptor_distance_best {
+                    descriptor_distance_second = descriptor_distance_best;
+                    descriptor_distance_best = descriptor_distance;
+                    index_best_r = *index_r;
+                } else if descriptor_distance < descriptor_distance_second {
+                    descriptor_distance_second = descriptor_distance;
                 }
+            }
 
-                *threshold = detector_threshold;
+            if descriptor_distance_best >= self.current_maximum_descriptor_distance_triangulation {
+                statistics.rejected_descriptor_distance += 1;
+                continue;
+            }
+            if let Some(maximum_matching_ratio) = self.maximum_matching_ratio {
+                if descriptor_distance_second < f32::MAX
+                    && descriptor_distance_best >= maximum_matching_ratio * descriptor_distance_second
+                {
+                    statistics.rejected_ratio += 1;
+                    continue;
+                }
+            }
+            candidates.push((descriptor_distance_best, index_l, index_best_r));
+        }
 
-                let offset = region.tl();
-                let offset = Point2f::new(offset.x as f32, offset.y as f32);
-                let keypoints_per_detector: opencv::core::Vector<_> = keypoints_per_detector
-                    .iter()
-                    .map(|mut x| {
-                        x.set_pt(x.pt() + offset);
-                        x
-                    })
-                    .collect();
+        // best matches first, ties in feature order
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
 
-                key_points.extend(keypoints_per_detector.iter());
+        // a right feature is claimed by its best left match, features without a framepoint
+        // stay in the matchers
+        let mut claimed_indices_right = BTreeSet::<usize>::new();
+        let mut matched_indices_left = BTreeSet::<usize>::new();
+        let mut matched_indices_right = BTreeSet::<usize>::new();
+        let mut stereo_matches = Vec::new();
+        for (_, index_l, index_r) in candidates {
+            if !claimed_indices_right.insert(index_r) {
+                statistics.rejected_mutual += 1;
+                continue;
             }
+            statistics.number_of_matches += 1;
+
+            let pixel_left = features_left[index_l].keypoint.pixel_coordinates();
+            let pixel_right = features_right[index_r].keypoint.pixel_coordinates();
+            let Some(point_in_left) = self.stereo_camera.triangulate_unrectified(
+                &pixel_left,
+                &pixel_right,
+                self.triangulation_method,
+            ) else {
+                statistics.rejected_triangulation += 1;
+                continue;
+            };
+            if point_in_left.z < self.minimum_depth_meters {
+                statistics.rejected_minimum_depth += 1;
+                continue;
+            }
+            let Some(covariance) = self.stereo_camera.unrectified_triangulation_covariance(
+                &pixel_left,
+                &pixel_right,
+                self.triangulation_method,
+                self.triangulation_pixel_noise_sigma,
+            ) else {
+                statistics.rejected_triangulation += 1;
+                continue;
+            };
+            matched_indices_left.insert(index_l);
+            matched_indices_right.insert(index_r);
+            stereo_matches.push((
+                point_in_left,
+                covariance,
+                features_left[index_l].index_in_vector,
+            ));
         }
-        Ok(key_points)
-    }
 
-    pub fn adjust_detector_thresholds(&mut self) -> anyhow::Result<()> {
-        for sub_detectors in self.detectors.iter_mut() {
-            for (detector, _, threshold) in sub_detectors.iter_mut() {
-                detector.set_threshold(*threshold as _)?;
-            }
+        self.feature_matcher_left.prune(&matched_indices_left);
+        self.feature_matcher_right.prune(&matched_indices_right);
+
+        statistics.number_of_framepoints = stereo_matches.len();
+        log::debug!("unrectified stereo matching: {:?}", statistics);
+        frame.stereo_matching_statistics = statistics;
+
+        // disparities are not defined without rectification
+        for (point_in_left, covariance, keypoint_index_left) in stereo_matches {
+            frame.create_framepoint(
+                &point_in_left,
+                &covariance,
+                &self.stereo_camera.left,
+                -1.0,
+                None,
+                keypoint_index_left,
+            );
         }
+
         Ok(())
     }
 
-    pub fn compute_descriptors(
-        &mut self,
-        intensity_image: &opencv::core::Mat,
-        keypoints: &mut opencv::core::Vector<KeyPoint>,
-        descriptors: &mut Mat,
-    ) -> anyhow::Result<()> {
-        self.descriptor_extractor
-            .compute(intensity_image, keypoints, descriptors)?;
+    // all left features of the current frame, for frame to frame tracking
+    pub fn feature_matcher_left(&self) -> &IntensityFeatureMatcher {
+        &self.feature_matcher_left
+    }
+
+    pub fn stereo_camera(&self) -> &StereoCamera {
+        &self.stereo_camera
+    }
+
+    // dense disparity map of the frame if configured
+    pub fn compute_disparity_map(&self, frame: &mut Frame) -> Result<()> {
+        let Some(dense_stereo) = &self.dense_stereo else {
+            return Ok(());
+        };
+        let disparity_map = dense_stereo.compute(
+            &frame.intensity_image_left.view(),
+            &frame.intensity_image_right.view(),
+        )?;
+        log::debug!(
+            "dense stereo: {} valid disparities",
+            disparity_map.number_of_valid()
+        );
+        frame.disparity_map = Some(disparity_map);
         Ok(())
     }
+
+    // point in left camera coordinates at an arbitrary left pixel from the dense disparity map
+    pub fn sample_point_in_left_camera(
+        &self,
+        frame: &Frame,
+        pixel_left: &PixelCoordinates,
+    ) -> Option<Vector3<f64>> {
+        frame.disparity_map.as_ref()?.point_at(
+            &self.stereo_camera,
+            pixel_left,
+            self.dense_minimum_confidence,
+        )
+    }
+
+    // point in left camera coordinates with its covariance
+    fn get_point_in_left_camera(
+        &self,
+        pixel_left: &PixelCoordinates,
+        pixel_right: &PixelCoordinates,
+    ) -> Option<(Vector3<f64>, Matrix3<f64>)> {
+        assert!(pixel_left.x >= pixel_right.x);
+        assert!((pixel_left.x - pixel_right.x) as f32 >= self.minimum_disparity_pixels);
+
+        let point_in_left = self.stereo_camera.triangulate(pixel_left, pixel_right)?;
+        let covariance = self.stereo_camera.triangulation_covariance(
+            pixel_left,
+            pixel_right,
+            self.triangulation_pixel_noise_sigma,
+        )?;
+        Some((point_in_left, covariance))
+    }
+
+    // keypoints of the left and right image, ordered by region independent of the scheduling
+    fn detect_keypoints(
+        &mut self,
+        intensity_image_left: &ImageView,
+        intensity_image_right: &ImageView,
+    ) -> Result<(Vec<Keypoint>, Vec<Keypoint>)> {
+        let target_number_of_keypoints = self.target_number_of_keypoints_per_detector;
+        let detectors = &mut self.detectors;
+        let keypoints_per_region = self.thread_pool.install(|| {
+            detectors
+                .par_iter_mut()
+                .map(|detector| {
+                    detector.detect(
+                        intensity_image_left,
+                        intensity_image_right,
+                        target_number_of_keypoints,
+                    )
+                })
+                .collect::<Result<Vec<_>>>()
+        })?;
+
+        let mut keypoints_left = Vec::new();
+        let mut keypoints_right = Vec::new();
+        for (keypoints_region_left, keypoints_region_right) in keypoints_per_region {
+            keypoints_left.extend(keypoints_region_left.iter().map(|x| Keypoint::from_opencv(&x)));
+            keypoints_right.extend(keypoints_region_right.iter().map(|x| Keypoint::from_opencv(&x)));
+        }
+        Ok((keypoints_left, keypoints_right))
+    }
+
+    // selected keypoints in detection order
+    fn select_keypoints(&self, keypoints: &[Keypoint]) -> Vec<Keypoint> {
+        self.keypoint_selector
+            .select(keypoints)
+            .into_iter()
+            .map(|index| keypoints[index])
+            .collect()
+    }
+
+    pub fn adjust_detector_sensitivities(&mut self) -> Result<()> {
+        let detectors = &mut self.detectors;
+        self.thread_pool.install(|| {
+            detectors
+                .par_iter_mut()
+                .try_for_each(DetectorRegion::apply_sensitivity)
+        })
+    }
+
+    // descriptors of the left and right image in parallel
+    pub fn compute_descriptors(&mut self, frame: &mut Frame) -> Result<()> {
+        let descriptor_type = self.descriptor_metric.descriptor_type();
+        let descriptor_extractor_left = &mut self.descriptor_extractor_left;
+        let descriptor_extractor_right = &mut self.descriptor_extractor_right;
+        let (result_left, result_right) = self.thread_pool.join(
+            || {
+                extract_descriptors(
+                    descriptor_type,
+                    descriptor_extractor_left,
+                    &frame.intensity_image_left,
+                    &mut frame.keypoints_left,
+                    &mut frame.descriptors_left,
+                )
+            },
+            || {
+                extract_descriptors(
+                    descriptor_type,
+                    descriptor_extractor_right,
+                    &frame.intensity_image_right,
+                    &mut frame.keypoints_right,
+                    &mut frame.descriptors_right,
+                )
+            },
+        );
+        result_left?;
+        result_right
+    }
+}
+
+/// outcome of the stereo matching of a frame, counted over all epipolar offsets
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StereoMatchingStatistics {
+    // left features with right features on the epipolar line
+    pub number_of_candidates: usize,
+    pub number_of_matches: usize,
+    pub number_of_framepoints: usize,
+
+    // all candidates exceed the maximum disparity
+    pub rejected_maximum_disparity: usize,
+    // best candidate above the descriptor distance threshold
+    pub rejected_descriptor_distance: usize,
+    pub rejected_ratio: usize,
+    // unrectified: right feature is the best match of another left feature
+    pub rejected_mutual: usize,
+    // subpixel refinement score below the minimum
+    pub rejected_matching_score: usize,
+    pub rejected_minimum_disparity: usize,
+    // unrectified: point closer than the minimum depth
+    pub rejected_minimum_depth: usize,
+    // unrectified: parallel rays or point behind a camera
+    pub rejected_triangulation: usize,
+
+    // unmatched keypoints with a depth from the dense disparity map
+    pub number_of_dense_framepoints: usize,
 }
 
 pub struct Frame {
-    pub keypoints_left: opencv::core::Vector<KeyPoint>,
-    pub keypoints_right: opencv::core::Vector<KeyPoint>,
+    pub keypoints_left: Vec<Keypoint>,
+    pub keypoints_right: Vec<Keypoint>,
 
-    pub descriptors_left: Mat,
-    pub descriptors_right: Mat,
+    // one row per keypoint
+    pub descriptors_left: Descriptors,
+    pub descriptors_right: Descriptors,
 
-    pub intensity_image_left: opencv::core::Mat,
-    pub intensity_image_right: opencv::core::Mat,
+    pub intensity_image_left: GrayImage<u8>,
+    pub intensity_image_right: GrayImage<u8>,
 
     pub number_of_detected_keypoints: usize,
+    pub stereo_matching_statistics: StereoMatchingStatistics,
+    // dense disparities of the left image if dense stereo is configured
+    pub disparity_map: Option<DisparityMap>,
 
     pub status: FrameStatus,
 
@@ -516,21 +1267,21 @@ pub enum FrameStatus {
 }
 
 impl Frame {
-    pub fn new(
-        intensity_image_left: opencv::core::Mat,
-        intensity_image_right: opencv::core::Mat,
-    ) -> Self {
+    // see GrayImage::from_mat for OpenCV images
+    pub fn new(intensity_image_left: GrayImage<u8>, intensity_image_right: GrayImage<u8>) -> Self {
         Self {
-            keypoints_left: opencv::core::Vector::<KeyPoint>::new(),
-            keypoints_right: opencv::core::Vector::<KeyPoint>::new(),
+            keypoints_left: Vec::new(),
+            keypoints_right: Vec::new(),
 
-            descriptors_left: Mat::default(),
-            descriptors_right: Mat::default(),
+            descriptors_left: Descriptors::default(),
+            descriptors_right: Descriptors::default(),
 
             intensity_image_left,
             intensity_image_right,
 
             number_of_detected_keypoints: 0,
+            stereo_matching_statistics: StereoMatchingStatistics::default(),
+            disparity_map: None,
 
             status: FrameStatus::Localizing,
             robot_to_world: sophus::lie::Isometry3F64::identity(),
@@ -542,11 +1293,18 @@ impl Frame {
     pub fn create_framepoint(
         &mut self,
         camera_coordinates_left: &Vector3<f64>,
-        camera: &PinholeCamera
+        covariance_left: &Matrix3<f64>,
+        camera: &PinholeCamera,
+        disparity_pixels: f32,
+        matching_score: Option<f32>,
+        keypoint_index_left: usize,
     ) {
         let point_in_robot = camera.camera_to_robot().transform(camera_coordinates_left);
         let point_in_world = self.robot_to_world().transform(&point_in_robot);
-        let frame_point = FramePoint::new(camera_coordinates_left.clone(), point_in_world, point_in_world);
+        let mut frame_point = FramePoint::new(camera_coordinates_left.clone(), point_in_robot, point_in_world);
+        frame_point.set_stereo_match(disparity_pixels, matching_score);
+        frame_point.set_keypoint_index_left(keypoint_index_left);
+        frame_point.set_covariance(*covariance_left);
         self.created_points.push(frame_point);
     }
 
@@ -0,0 +1,366 @@
+//! subpixel refinement of stereo matches, patch costs along the epipolar line of the right image
+//! around the matched keypoint with a parabola fitted to the best cost and its neighbors
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+use rslam_core::{ImageView, Keypoint, PixelCoordinates};
+use sophus::nalgebra::Vector2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatchCost {
+    // sum of absolute differences, score 1 - mean absolute difference / 255
+    Sad,
+    // zero mean normalized cross correlation, score in [-1, 1]
+    Zncc,
+}
+
+impl PatchCost {
+    pub const ALL: [PatchCost; 2] = [PatchCost::Sad, PatchCost::Zncc];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PatchCost::Sad => "SAD",
+            PatchCost::Zncc => "ZNCC",
+        }
+    }
+}
+
+impl fmt::Display for PatchCost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for PatchCost {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let cost = match s.trim().to_ascii_uppercase().as_str() {
+            "SAD" => PatchCost::Sad,
+            "ZNCC" => PatchCost::Zncc,
+            _ => bail!(
+                "unknown patch cost '{}', expected one of: {}",
+                s,
+                PatchCost::ALL.map(|cost| cost.name()).join(", ")
+            ),
+        };
+        Ok(cost)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RefinedMatch {
+    // subpixel column in the right image matching the column of the left keypoint
+    pub col_right: f32,
+    pub disparity: f32,
+    // higher is better, see PatchCost
+    pub score: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DisparityRefinement {
+    pub cost: PatchCost,
+    // patches of (2 * half_patch_size + 1)^2 pixels
+    pub half_patch_size: usize,
+    // integer columns searched on either side of the matched right keypoint
+    pub search_radius: usize,
+}
+
+impl DisparityRefinement {
+    // None if a patch leaves the image or is textureless (ZNCC)
+    pub fn refine(
+        &self,
+        image_left: &ImageView,
+        image_right: &ImageView,
+        keypoint_left: &Vector2<f32>,
+        keypoint_right: &Vector2<f32>,
+    ) -> Option<RefinedMatch> {
+        let half = self.half_patch_size as isize;
+        let radius = self.search_radius as isize;
+        let col_left = keypoint_left.x.round() as isize;
+        let row_left = keypoint_left.y.round() as isize;
+        let col_right = keypoint_right.x.round() as isize;
+        let row_right = keypoint_right.y.round() as isize;
+
+        let patch_left = Patch::new(image_left, col_left, row_left, half)?;
+
+        // one more column on either side for the parabola at the search boundary
+        let mut scores = Vec::with_capacity(2 * self.search_radius + 3);
+        for col in col_right - radius - 1..=col_right + radius + 1 {
+            let score = Patch::new(image_right, col, row_right, half)
+                .and_then(|patch_right| self.score(&patch_left, &patch_right));
+            scores.push(score);
+        }
+
+        // best score within the search range
+        let mut index_best = None;
+        for index in 1..scores.len() - 1 {
+            let Some(score) = scores[index] else {
+                continue;
+            };
+            if index_best.is_none_or(|best: usize| score > scores[best].unwrap()) {
+                index_best = Some(index);
+            }
+        }
+        let index_best = index_best?;
+        let score_best = scores[index_best].unwrap();
+
+        let offset = match (scores[index_best - 1], scores[index_best + 1]) {
+            (Some(score_previous), Some(score_next)) => {
+                let curvature = score_previous - 2.0 * score_best + score_next;
+                if curvature < 0.0 {
+                    (0.5 * (score_previous - score_next) / curvature).clamp(-0.5, 0.5)
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        };
+
+        // the patches are centered on whole pixels, the disparity holds for the left keypoint too
+        let disparity = (col_left - col_right + radius + 1 - index_best as isize) as f32 - offset;
+        Some(RefinedMatch {
+            col_right: keypoint_left.x - disparity,
+            disparity,
+            score: score_best,
+        })
+    }
+
+    fn score(&self, patch_left: &Patch, patch_right: &Patch) -> Option<f32> {
+        match self.cost {
+            PatchCost::Sad => {
+                let sad: u32 = patch_left
+                    .rows()
+                    .zip(patch_right.rows())
+                    .flat_map(|(row_left, row_right)| row_left.iter().zip(row_right))
+                    .map(|(left, right)| left.abs_diff(*right) as u32)
+                    .sum();
+                Some(1.0 - sad as f32 / (255.0 * patch_left.number_of_pixels() as f32))
+            }
+            PatchCost::Zncc => {
+                let mean_left = patch_left.mean();
+                let mean_right = patch_right.mean();
+                let mut covariance = 0.0;
+                let mut variance_left = 0.0;
+                let mut variance_right = 0.0;
+                for (row_left, row_right) in patch_left.rows().zip(patch_right.rows()) {
+                    for (left, right) in row_left.iter().zip(row_right) {
+                        let left = *left as f32 - mean_left;
+                        let right = *right as f32 - mean_right;
+                        covariance += left * right;
+                        variance_left += left * left;
+                        variance_right += right * right;
+                    }
+                }
+                let normalization = (variance_left * variance_right).sqrt();
+                if normalization < f32::EPSILON {
+                    return None;
+                }
+                Some(covariance / normalization)
+            }
+        }
+    }
+}
+
+// subpixel position of the right keypoint and the patch score, None if the score is below the
+// minimum. the keypoint position is kept without refinement or if the patches leave the image
+pub fn refine_match(
+    disparity_refinement: Option<&DisparityRefinement>,
+    minimum_score: f32,
+    image_left: &ImageView,
+    image_right: &ImageView,
+    keypoint_left: &Keypoint,
+    keypoint_right: &Keypoint,
+) -> Option<(PixelCoordinates, Option<f32>)> {
+    let pixel_right = keypoint_right.pixel_coordinates();
+    let Some(disparity_refinement) = disparity_refinement else {
+        return Some((pixel_right, None));
+    };
+
+    match disparity_refinement.refine(
+        image_left,
+        image_right,
+        &keypoint_left.pt,
+        &keypoint_right.pt,
+    ) {
+        Some(refined_match) if refined_match.score < minimum_score => None,
+        Some(refined_match) => Some((
+            PixelCoordinates::new(refined_match.col_right as f64, pixel_right.y),
+            Some(refined_match.score),
+        )),
+        None => Some((pixel_right, None)),
+    }
+}
+
+// square patch around a pixel, completely inside the image
+struct Patch<'a> {
+    image: ImageView<'a>,
+    col_start: usize,
+    row_start: usize,
+    size: usize,
+}
+
+impl<'a> Patch<'a> {
+    fn new(image: &ImageView<'a>, col: isize, row: isize, half: isize) -> Option<Self> {
+        if col < half
+            || row < half
+            || col + half >= image.width() as isize
+            || row + half >= image.height() as isize
+        {
+            return None;
+        }
+        Some(Self {
+            image: *image,
+            col_start: (col - half) as usize,
+            row_start: (row - half) as usize,
+            size: (2 * half + 1) as usize,
+        })
+    }
+
+    fn rows(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        (self.row_start..self.row_start + self.size)
+            .map(|row| &self.image.row(row)[self.col_start..self.col_start + self.size])
+    }
+
+    fn number_of_pixels(&self) -> usize {
+        self.size * self.size
+    }
+
+    fn mean(&self) -> f32 {
+        let sum: u32 = self.rows().flatten().map(|x| *x as u32).sum();
+        sum as f32 / self.number_of_pixels() as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_core::GrayImage;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 32;
+
+    // smooth texture, the right image is the left one shifted by a subpixel disparity
+    fn stereo_images(disparity: f32) -> (GrayImage<u8>, GrayImage<u8>) {
+        let texture = |x: f32, y: f32| {
+            128.0 + 60.0 * (0.35 * x + 0.2 * y).sin() + 40.0 * (0.23 * x - 0.41 * y).cos()
+        };
+        let mut image_left = GrayImage::new(WIDTH, HEIGHT);
+        let mut image_right = GrayImage::new(WIDTH, HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let (x, y) = (x as f32, y as f32);
+                image_left.set(x as usize, y as usize, texture(x, y).round() as u8);
+                image_right.set(
+                    x as usize,
+                    y as usize,
+                    texture(x + disparity, y).round() as u8,
+                );
+            }
+        }
+        (image_left, image_right)
+    }
+
+    fn refinement(cost: PatchCost) -> DisparityRefinement {
+        DisparityRefinement {
+            cost,
+            half_patch_size: 3,
+            search_radius: 1,
+        }
+    }
+
+    #[test]
+    fn recovers_a_subpixel_disparity() {
+        let disparity = 10.3;
+        let (image_left, image_right) = stereo_images(disparity);
+        // the left keypoint is off the pixel grid, the right keypoint at the integer disparity
+        let keypoint_left = Vector2::new(30.4, 16.0);
+        let keypoint_right = Vector2::new(20.4, 16.0);
+        for cost in PatchCost::ALL {
+            let refined_match = refinement(cost)
+                .refine(
+                    &image_left.view(),
+                    &image_right.view(),
+                    &keypoint_left,
+                    &keypoint_right,
+                )
+                .unwrap();
+            assert!(
+                (refined_match.disparity - disparity).abs() < 0.1,
+                "{}: {:?}",
+                cost,
+                refined_match
+            );
+            assert!(
+                (keypoint_left.x - refined_match.col_right - refined_match.disparity).abs() < 1e-5
+            );
+            assert!(refined_match.score > 0.9, "{}: {:?}", cost, refined_match);
+        }
+    }
+
+    #[test]
+    fn rejects_patches_leaving_the_image() {
+        let (image_left, image_right) = stereo_images(5.0);
+        for cost in PatchCost::ALL {
+            // left patch at the border
+            assert!(refinement(cost)
+                .refine(
+                    &image_left.view(),
+                    &image_right.view(),
+                    &Vector2::new(2.0, 16.0),
+                    &Vector2::new(1.0, 16.0),
+                )
+                .is_none());
+            // the whole search range of the right patch leaves the image
+            assert!(refinement(cost)
+                .refine(
+                    &image_left.view(),
+                    &image_right.view(),
+                    &Vector2::new(30.0, 16.0),
+                    &Vector2::new(30.0, 30.0),
+                )
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_textureless_patches_with_zncc() {
+        let mut image = GrayImage::new(WIDTH, HEIGHT);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                image.set(x, y, 100);
+            }
+        }
+        let keypoint_left = Vector2::new(30.0, 16.0);
+        let keypoint_right = Vector2::new(25.0, 16.0);
+        assert!(refinement(PatchCost::Zncc)
+            .refine(
+                &image.view(),
+                &image.view(),
+                &keypoint_left,
+                &keypoint_right
+            )
+            .is_none());
+        // SAD still scores flat patches
+        let refined_match = refinement(PatchCost::Sad)
+            .refine(
+                &image.view(),
+                &image.view(),
+                &keypoint_left,
+                &keypoint_right,
+            )
+            .unwrap();
+        assert_eq!(refined_match.score, 1.0);
+    }
+
+    #[test]
+    fn parses_patch_costs() {
+        for cost in PatchCost::ALL {
+            assert_eq!(
+                cost.name().to_lowercase().parse::<PatchCost>().unwrap(),
+                cost
+            );
+        }
+        assert!("NCC".parse::<PatchCost>().is_err());
+    }
+}
@@ -0,0 +1,27 @@
+//! fixtures shared by the unit tests
+use rslam_core::GrayImage;
+
+// random texture in blocks of 2x2 pixels, the right image is the left one shifted by a constant
+// disparity
+pub fn shifted_stereo_images(
+    width: usize,
+    height: usize,
+    disparity: usize,
+) -> (GrayImage<u8>, GrayImage<u8>) {
+    let texture = |x: usize, y: usize| {
+        let mut hash = ((x / 2) as u64).wrapping_mul(0x9E3779B97F4A7C15)
+            ^ ((y / 2) as u64).wrapping_mul(0xC2B2AE3D27D4EB4F);
+        hash ^= hash >> 29;
+        hash = hash.wrapping_mul(0xBF58476D1CE4E5B9);
+        (hash >> 56) as u8
+    };
+    let mut image_left = GrayImage::new(width, height);
+    let mut image_right = GrayImage::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            image_left.set(x, y, texture(x, y));
+            image_right.set(x, y, texture(x + disparity, y));
+        }
+    }
+    (image_left, image_right)
+}
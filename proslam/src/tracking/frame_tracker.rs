@@ -0,0 +1,551 @@
+//! frame to frame tracking of framepoints by projection
+//!
+//! The framepoints of the previous frame are projected into the left image of the new frame at
+//! the robot pose predicted by a motion prior. Each projection is associated with the keypoint of
+//! the closest descriptor within a search window around it, a keypoint is assigned to at most one
+//! track. The window grows until enough tracks survive or the maximum radius is reached.
+
+use std::collections::{btree_map::Entry, BTreeMap};
+
+use anyhow::{bail, Result};
+use rslam_core::{Camera, PixelCoordinates};
+use rslam_sensor::pinhole_camera::PinholeCamera;
+use serde::Deserialize;
+use sophus::{lie::Isometry3F64, nalgebra::Vector3};
+
+use super::MotionPrior;
+use crate::{
+    descriptor::Descriptors, intensity_feature_matcher::IntensityFeatureMatcher,
+    stereo_frame_point_generator::Frame,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct FrameTrackerCfg {
+    initial_search_radius_pixels: f32,
+    maximum_search_radius_pixels: f32,
+    // factor of the search radius between attempts
+    search_radius_growth: f32,
+    // the window grows while fewer tracks survive
+    minimum_number_of_tracks: usize,
+    // normalized descriptor distance
+    maximum_descriptor_distance: f32,
+}
+
+impl Default for FrameTrackerCfg {
+    fn default() -> Self {
+        Self {
+            initial_search_radius_pixels: 10.0,
+            maximum_search_radius_pixels: 50.0,
+            search_radius_growth: 2.0,
+            minimum_number_of_tracks: 100,
+            maximum_descriptor_distance: 0.25,
+        }
+    }
+}
+
+impl FrameTrackerCfg {
+    pub fn finalize(self) -> Result<FrameTracker> {
+        if self.initial_search_radius_pixels <= 0.0 {
+            bail!(
+                "initial search radius must be positive, got {}",
+                self.initial_search_radius_pixels
+            );
+        }
+        if self.maximum_search_radius_pixels < self.initial_search_radius_pixels {
+            bail!(
+                "maximum search radius {} below initial search radius {}",
+                self.maximum_search_radius_pixels,
+                self.initial_search_radius_pixels
+            );
+        }
+        if self.search_radius_growth <= 1.0 {
+            bail!(
+                "search radius growth must exceed 1, got {}",
+                self.search_radius_growth
+            );
+        }
+
+        Ok(FrameTracker {
+            cfg: self,
+            tracks: vec![],
+            descriptors: Descriptors::default(),
+            previous_robot_to_world: Isometry3F64::identity(),
+            previous_timestamp_seconds: None,
+            next_track_identifier: 0,
+        })
+    }
+}
+
+/// association of a track of the previous frame with a keypoint of the new left image
+#[derive(Clone, Debug)]
+pub struct TrackCorrespondence {
+    // stable over the lifetime of the track, usable as landmark identifier
+    pub track_identifier: usize,
+    // index of the framepoint in the previous frame
+    pub index_previous: usize,
+    // index of the left keypoint (and descriptor) in the new frame
+    pub keypoint_index: usize,
+    pub world_coordinates: Vector3<f64>,
+    pub pixel_predicted: PixelCoordinates,
+    pub pixel_measured: PixelCoordinates,
+    pub descriptor_distance: f32,
+    // number of frames the point has been observed in, including the new frame
+    pub track_length: usize,
+}
+
+#[derive(Debug)]
+struct Track {
+    track_identifier: usize,
+    world_coordinates: Vector3<f64>,
+    track_length: usize,
+}
+
+pub struct FrameTracker {
+    cfg: FrameTrackerCfg,
+    // framepoints of the previous frame and their left descriptors, one row per track
+    tracks: Vec<Track>,
+    descriptors: Descriptors,
+    previous_robot_to_world: Isometry3F64,
+    previous_timestamp_seconds: Option<f64>,
+    next_track_identifier: usize,
+}
+
+impl FrameTracker {
+    pub fn number_of_tracks(&self) -> usize {
+        self.tracks.len()
+    }
+
+    // robot_to_world of the new frame from the motion prior, the previous pose without a prior
+    // or if it does not cover the interval
+    pub fn predict_robot_to_world(
+        &self,
+        motion_prior: Option<&dyn MotionPrior>,
+        timestamp_seconds: f64,
+    ) -> Isometry3F64 {
+        let Some(previous_timestamp_seconds) = self.previous_timestamp_seconds else {
+            return self.previous_robot_to_world;
+        };
+        motion_prior
+            .and_then(|motion_prior| {
+                motion_prior.predict_robot_to_world(
+                    &self.previous_robot_to_world,
+                    previous_timestamp_seconds,
+                    timestamp_seconds,
+                )
+            })
+            .unwrap_or(self.previous_robot_to_world)
+    }
+
+    // correspondences of the previous framepoints with the left features of the new frame
+    pub fn track(
+        &self,
+        feature_matcher_left: &IntensityFeatureMatcher,
+        camera: &PinholeCamera,
+        motion_prior: Option<&dyn MotionPrior>,
+        timestamp_seconds: f64,
+    ) -> Result<Vec<TrackCorrespondence>> {
+        if let Some(previous_timestamp_seconds) = self.previous_timestamp_seconds {
+            if timestamp_seconds <= previous_timestamp_seconds {
+                bail!(
+                    "frame timestamp {} not after previous frame {}",
+                    timestamp_seconds,
+                    previous_timestamp_seconds
+                );
+            }
+        }
+        let robot_to_world = self.predict_robot_to_world(motion_prior, timestamp_seconds);
+        let world_to_camera = camera
+            .camera_to_robot()
+            .inverse()
+            .group_mul(&robot_to_world.inverse());
+
+        // tracks visible in the predicted left image
+        let mut predictions = Vec::new();
+        for (index_previous, track) in self.tracks.iter().enumerate() {
+            let Some(pixel) = camera.project(&world_to_camera.transform(&track.world_coordinates))
+            else {
+                continue;
+            };
+            if !camera.is_in_image(&pixel, 0.0) {
+                continue;
+            }
+            predictions.push((index_previous, pixel));
+        }
+
+        // matched track per keypoint index
+        let mut matches = BTreeMap::<usize, (usize, f32)>::new();
+        let mut is_matched = vec![false; predictions.len()];
+        let mut search_radius_pixels = self.cfg.initial_search_radius_pixels;
+        loop {
+            let mut candidates = Vec::new();
+            for (index_prediction, (index_previous, pixel)) in predictions.iter().enumerate() {
+                if is_matched[index_prediction] {
+                    continue;
+                }
+                let Some((feature, descriptor_distance)) = feature_matcher_left
+                    .best_match_in_window(
+                        self.descriptors.row(*index_previous),
+                        pixel.x as f32,
+                        pixel.y as f32,
+                        search_radius_pixels,
+                        self.cfg.maximum_descriptor_distance,
+                    )?
+                else {
+                    continue;
+                };
+                candidates.push((
+                    descriptor_distance,
+                    index_prediction,
+                    feature.index_in_vector,
+                ));
+            }
+
+            // a keypoint keeps the track with the closest descriptor
+            candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+            for (descriptor_distance, index_prediction, keypoint_index) in candidates {
+                if let Entry::Vacant(entry) = matches.entry(keypoint_index) {
+                    entry.insert((index_prediction, descriptor_distance));
+                    is_matched[index_prediction] = true;
+                }
+            }
+
+            log::debug!(
+                "tracked {} of {} predicted points within {} pixels",
+                matches.len(),
+                predictions.len(),
+                search_radius_pixels
+            );
+            if matches.len() >= self.cfg.minimum_number_of_tracks
+                || matches.len() == predictions.len()
+                || search_radius_pixels >= self.cfg.maximum_search_radius_pixels
+            {
+                break;
+            }
+            search_radius_pixels = (search_radius_pixels * self.cfg.search_radius_growth)
+                .min(self.cfg.maximum_search_radius_pixels);
+        }
+
+        let correspondences = matches
+            .into_iter()
+            .map(
+                |(keypoint_index, (index_prediction, descriptor_distance))| {
+                    let (index_previous, pixel_predicted) = predictions[index_prediction];
+                    let track = &self.tracks[index_previous];
+                    let keypoint = &feature_matcher_left.features[keypoint_index].keypoint;
+                    TrackCorrespondence {
+                        track_identifier: track.track_identifier,
+                        index_previous,
+                        keypoint_index,
+                        world_coordinates: track.world_coordinates,
+                        pixel_predicted,
+                        pixel_measured: keypoint.pixel_coordinates(),
+                        descriptor_distance,
+                        track_length: track.track_length + 1,
+                    }
+                },
+            )
+            .collect();
+        Ok(correspondences)
+    }
+
+    // track identifier and length of every framepoint of the new frame (None without a left
+    // keypoint), framepoints of tracked keypoints continue their tracks, the others start new
+    // tracks with the next free identifiers
+    pub fn assign_tracks(
+        &self,
+        frame: &Frame,
+        correspondences: &[TrackCorrespondence],
+    ) -> Vec<Option<(usize, usize)>> {
+        let tracked: BTreeMap<_, _> = correspondences
+            .iter()
+            .map(|correspondence| (correspondence.keypoint_index, correspondence))
+            .collect();
+
+        let mut next_track_identifier = self.next_track_identifier;
+        frame
+            .created_points
+            .iter()
+            .map(|frame_point| {
+                let keypoint_index = frame_point.keypoint_index_left()?;
+                Some(match tracked.get(&keypoint_index) {
+                    Some(correspondence) => {
+                        (correspondence.track_identifier, correspondence.track_length)
+                    }
+                    None => {
+                        next_track_identifier += 1;
+                        (next_track_identifier - 1, 1)
+                    }
+                })
+            })
+            .collect()
+    }
+
+    // replaces the tracks by the framepoints of the new frame at its estimated pose, framepoints
+    // of tracked keypoints continue their tracks
+    pub fn update(
+        &mut self,
+        frame: &Frame,
+        robot_to_world: &Isometry3F64,
+        timestamp_seconds: f64,
+        correspondences: &[TrackCorrespondence],
+    ) -> Result<()> {
+        let assignments = self.assign_tracks(frame, correspondences);
+
+        let mut tracks = Vec::with_capacity(frame.created_points.len());
+        let mut keypoint_indices = Vec::with_capacity(frame.created_points.len());
+        for (frame_point, assignment) in frame.created_points.iter().zip(assignments) {
+            let (Some(keypoint_index), Some((track_identifier, track_length))) =
+                (frame_point.keypoint_index_left(), assignment)
+            else {
+                continue;
+            };
+            self.next_track_identifier = self.next_track_identifier.max(track_identifier + 1);
+            tracks.push(Track {
+                track_identifier,
+                world_coordinates: robot_to_world.transform(frame_point.robot_coordinates()),
+                track_length,
+            });
+            keypoint_indices.push(keypoint_index);
+        }
+
+        self.tracks = tracks;
+        self.descriptors = frame.descriptors_left.select(keypoint_indices);
+        self.previous_robot_to_world = *robot_to_world;
+        self.previous_timestamp_seconds = Some(timestamp_seconds);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use rslam_core::{GrayImage, Keypoint, PackedDescriptors};
+    use sophus::{
+        core::linalg::VecF64, image::ImageSize,
+        sensor::camera_enum::perspective_camera::PinholeCameraF64,
+    };
+
+    use super::*;
+    use crate::{
+        descriptor::{DescriptorMetric, DescriptorType},
+        frame::frame_point::FramePoint,
+    };
+
+    fn camera() -> PinholeCamera {
+        PinholeCamera::new(PinholeCameraF64::from_params_and_size(
+            &VecF64::<4>::new(400.0, 400.0, 320.0, 240.0),
+            ImageSize::new(640, 480),
+        ))
+    }
+
+    // ORB-256 descriptor of a landmark, random bits so that landmarks are far apart
+    fn descriptor(landmark: usize) -> [u64; 4] {
+        let mut state = landmark as u64;
+        [0; 4].map(|_| {
+            state = state.wrapping_add(0x9E3779B97F4A7C15);
+            let mut hash = state;
+            hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+            hash = (hash ^ (hash >> 27)).wrapping_mul(0x94D049BB133111EB);
+            hash ^ (hash >> 31)
+        })
+    }
+
+    // landmarks in front of the camera at the origin, identified by their index
+    fn landmarks(depth_meters: f64) -> Vec<Vector3<f64>> {
+        (0..20)
+            .map(|index| {
+                Vector3::new(
+                    -2.0 + (index % 5) as f64,
+                    -1.5 + (index / 5) as f64,
+                    depth_meters + (index % 3) as f64,
+                )
+            })
+            .collect()
+    }
+
+    // frame at robot_to_world with a framepoint and a left keypoint per visible landmark, the
+    // keypoints carry the descriptors of the landmarks
+    fn frame(robot_to_world: &Isometry3F64, landmarks: &[(usize, Vector3<f64>)]) -> Frame {
+        let camera = camera();
+        let mut frame = Frame::new(GrayImage::new(640, 480), GrayImage::new(640, 480));
+        let mut descriptors = PackedDescriptors::new(4);
+        let world_to_robot = robot_to_world.inverse();
+        for (landmark, world_coordinates) in landmarks {
+            let point_in_robot = world_to_robot.transform(world_coordinates);
+            let Some(pixel) = camera.project(&point_in_robot) else {
+                continue;
+            };
+            let mut frame_point =
+                FramePoint::new(point_in_robot, point_in_robot, *world_coordinates);
+            frame_point.set_keypoint_index_left(frame.keypoints_left.len());
+            frame.created_points.push(frame_point);
+            frame
+                .keypoints_left
+                .push(Keypoint::new(pixel.x as f32, pixel.y as f32, 7.0, 1.0));
+            descriptors.push_words(&descriptor(*landmark));
+        }
+        frame.descriptors_left = Descriptors::Binary(descriptors);
+        frame
+    }
+
+    fn feature_matcher(frame: &Frame) -> IntensityFeatureMatcher {
+        let mut feature_matcher = IntensityFeatureMatcher::default();
+        feature_matcher.configure(
+            NonZeroUsize::new(480).unwrap(),
+            NonZeroUsize::new(640).unwrap(),
+            DescriptorMetric::new(DescriptorType::Orb256, None).unwrap(),
+        );
+        feature_matcher
+            .set_fatures(&frame.keypoints_left, &frame.descriptors_left)
+            .unwrap();
+        feature_matcher
+    }
+
+    fn translation(x: f64, y: f64, z: f64) -> Isometry3F64 {
+        Isometry3F64::from_translation(&VecF64::<3>::new(x, y, z))
+    }
+
+    // tracks the frame and updates the tracker with the true pose
+    fn track_and_update(
+        frame_tracker: &mut FrameTracker,
+        frame: &Frame,
+        robot_to_world: &Isometry3F64,
+        timestamp_seconds: f64,
+    ) -> Vec<TrackCorrespondence> {
+        let correspondences = frame_tracker
+            .track(&feature_matcher(frame), &camera(), None, timestamp_seconds)
+            .unwrap();
+        frame_tracker
+            .update(frame, robot_to_world, timestamp_seconds, &correspondences)
+            .unwrap();
+        correspondences
+    }
+
+    #[test]
+    fn carries_identifiers_and_lengths_over_updates() {
+        let landmarks: Vec<_> = landmarks(5.0).into_iter().enumerate().collect();
+        let mut frame_tracker = FrameTrackerCfg::default().finalize().unwrap();
+
+        // the first frame starts a track per framepoint
+        let frame_0 = frame(&Isometry3F64::identity(), &landmarks);
+        assert!(
+            track_and_update(&mut frame_tracker, &frame_0, &Isometry3F64::identity(), 0.0)
+                .is_empty()
+        );
+        assert_eq!(frame_tracker.number_of_tracks(), 20);
+
+        // 15 landmarks are seen again next to 5 new ones
+        let robot_to_world_1 = translation(0.02, 0.0, 0.05);
+        let mut landmarks_1 = landmarks[5..].to_vec();
+        landmarks_1.extend((100..105).map(|landmark| (landmark, landmarks[landmark - 100].1)));
+        let frame_1 = frame(&robot_to_world_1, &landmarks_1);
+        let correspondences =
+            track_and_update(&mut frame_tracker, &frame_1, &robot_to_world_1, 0.1);
+        assert_eq!(correspondences.len(), 15);
+        for correspondence in &correspondences {
+            let (landmark, world_coordinates) = landmarks_1[correspondence.keypoint_index];
+            assert_eq!(correspondence.track_identifier, landmark);
+            assert_eq!(correspondence.track_length, 2);
+            assert_eq!(correspondence.world_coordinates, world_coordinates);
+        }
+        assert_eq!(frame_tracker.number_of_tracks(), 20);
+
+        // the new landmarks got the next free identifiers in the previous update
+        let robot_to_world_2 = translation(0.04, 0.0, 0.1);
+        let frame_2 = frame(&robot_to_world_2, &landmarks_1);
+        let correspondences =
+            track_and_update(&mut frame_tracker, &frame_2, &robot_to_world_2, 0.2);
+        assert_eq!(correspondences.len(), 20);
+        for correspondence in &correspondences {
+            let (landmark, _) = landmarks_1[correspondence.keypoint_index];
+            if landmark < 100 {
+                assert_eq!(correspondence.track_identifier, landmark);
+                assert_eq!(correspondence.track_length, 3);
+            } else {
+                assert_eq!(correspondence.track_identifier, landmark - 100 + 20);
+                assert_eq!(correspondence.track_length, 2);
+            }
+        }
+    }
+
+    #[test]
+    fn assigns_a_keypoint_to_one_track() {
+        // two tracks predicted at the same keypoint, one with a slightly different descriptor
+        let world_coordinates = Vector3::new(0.0, 0.0, 5.0);
+        let mut frame_tracker = FrameTrackerCfg::default().finalize().unwrap();
+        let mut frame_0 = frame(
+            &Isometry3F64::identity(),
+            &[
+                (0, world_coordinates),
+                (0, world_coordinates + Vector3::new(0.01, 0.0, 0.0)),
+            ],
+        );
+        let mut descriptors = PackedDescriptors::new(4);
+        let mut similar = descriptor(0);
+        similar[0] ^= 0xff;
+        descriptors.push_words(&similar);
+        descriptors.push_words(&descriptor(0));
+        frame_0.descriptors_left = Descriptors::Binary(descriptors);
+        track_and_update(&mut frame_tracker, &frame_0, &Isometry3F64::identity(), 0.0);
+
+        let frame_1 = frame(&Isometry3F64::identity(), &[(0, world_coordinates)]);
+        let correspondences = frame_tracker
+            .track(&feature_matcher(&frame_1), &camera(), None, 0.1)
+            .unwrap();
+        assert_eq!(correspondences.len(), 1);
+        assert_eq!(correspondences[0].track_identifier, 1);
+        assert_eq!(correspondences[0].descriptor_distance, 0.0);
+    }
+
+    #[test]
+    fn grows_the_search_window() {
+        // the robot moves sideways without a motion prior, the points shift by 25 pixels
+        let landmarks: Vec<_> = landmarks(5.0)
+            .into_iter()
+            .map(|point| Vector3::new(point.x, point.y, 5.0))
+            .enumerate()
+            .collect();
+        let robot_to_world = translation(0.3125, 0.0, 0.0);
+        let frame_0 = frame(&Isometry3F64::identity(), &landmarks);
+        let frame_1 = frame(&robot_to_world, &landmarks);
+
+        let track = |cfg: FrameTrackerCfg| {
+            let mut frame_tracker = cfg.finalize().unwrap();
+            track_and_update(&mut frame_tracker, &frame_0, &Isometry3F64::identity(), 0.0);
+            frame_tracker
+                .track(&feature_matcher(&frame_1), &camera(), None, 0.1)
+                .unwrap()
+        };
+        let correspondences = track(FrameTrackerCfg::default());
+        assert_eq!(correspondences.len(), 20);
+        for correspondence in &correspondences {
+            let shift = correspondence.pixel_predicted - correspondence.pixel_measured;
+            assert!((shift.x - 25.0).abs() < 1e-3 && shift.y.abs() < 1e-3);
+        }
+
+        let fixed_window = FrameTrackerCfg {
+            maximum_search_radius_pixels: 20.0,
+            ..FrameTrackerCfg::default()
+        };
+        assert!(track(fixed_window).is_empty());
+    }
+
+    #[test]
+    fn rejects_non_monotonic_timestamps() {
+        let landmarks: Vec<_> = landmarks(5.0).into_iter().enumerate().collect();
+        let frame = frame(&Isometry3F64::identity(), &landmarks);
+        let mut frame_tracker = FrameTrackerCfg::default().finalize().unwrap();
+        track_and_update(&mut frame_tracker, &frame, &Isometry3F64::identity(), 1.0);
+
+        let feature_matcher = feature_matcher(&frame);
+        for timestamp_seconds in [1.0, 0.5] {
+            assert!(frame_tracker
+                .track(&feature_matcher, &camera(), None, timestamp_seconds)
+                .is_err());
+        }
+        assert!(frame_tracker
+            .track(&feature_matcher, &camera(), None, 1.1)
+            .is_ok());
+    }
+}
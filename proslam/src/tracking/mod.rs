@@ -0,0 +1,30 @@
+#[cfg(feature = "opencv")]
+pub mod frame_tracker;
+#[cfg(feature = "opencv")]
+pub mod odometry;
+pub mod pose_estimation;
+pub mod visual_inertial;
+pub mod wheel_odometry;
+
+use rslam_core::PixelCoordinates;
+use sophus::lie::Isometry3F64;
+
+/// rectified stereo measurement of a landmark in a frame
+#[derive(Clone, Debug)]
+pub struct LandmarkObservation {
+    pub landmark_identifier: usize,
+    pub pixel_left: PixelCoordinates,
+    pub pixel_right: PixelCoordinates,
+}
+
+/// prediction of the robot pose of a new frame
+pub trait MotionPrior {
+    // robot_to_world at timestamp_seconds from the estimate of the previous frame,
+    // None if the prior does not cover the interval
+    fn predict_robot_to_world(
+        &self,
+        previous_robot_to_world: &Isometry3F64,
+        previous_timestamp_seconds: f64,
+        timestamp_seconds: f64,
+    ) -> Option<Isometry3F64>;
+}
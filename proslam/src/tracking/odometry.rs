@@ -0,0 +1,289 @@
+//! stereo odometry on top of the framepoint generator
+//!
+//! The framepoints of every frame are tracked into the next one with the frame tracker. In
+//! STEREO mode the robot pose is estimated from the reprojections of the tracked points alone.
+//! In VISUAL_INERTIAL mode the stereo observations of all tracks are optimized jointly with the
+//! IMU measurements by the visual-inertial tracker, which also predicts the pose for tracking.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Context, Result};
+use rslam_core::Camera;
+use rslam_sensor::{
+    imu::{Imu, ImuMeasurement},
+    stereo_camera::StereoCamera,
+};
+use serde::Deserialize;
+use sophus::lie::Isometry3F64;
+
+use super::{
+    frame_tracker::{FrameTracker, FrameTrackerCfg, TrackCorrespondence},
+    pose_estimation,
+    visual_inertial::{VisualInertialTracker, VisualInertialTrackerCfg},
+    LandmarkObservation, MotionPrior,
+};
+use crate::{
+    intensity_feature_matcher::IntensityFeatureMatcher, stereo_frame_point_generator::Frame,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackingMode {
+    // motion only estimation from the tracked points of the previous frame
+    Stereo,
+    // sliding window optimization of the stereo observations and the IMU measurements
+    VisualInertial,
+}
+
+impl TrackingMode {
+    pub const ALL: [TrackingMode; 2] = [TrackingMode::Stereo, TrackingMode::VisualInertial];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TrackingMode::Stereo => "STEREO",
+            TrackingMode::VisualInertial => "VISUAL_INERTIAL",
+        }
+    }
+}
+
+impl fmt::Display for TrackingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for TrackingMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mode = match s.trim().to_ascii_uppercase().as_str() {
+            "STEREO" => TrackingMode::Stereo,
+            "VISUAL_INERTIAL" => TrackingMode::VisualInertial,
+            _ => bail!(
+                "unknown tracking mode '{}', expected one of: {}",
+                s,
+                TrackingMode::ALL.map(|mode| mode.name()).join(", ")
+            ),
+        };
+        Ok(mode)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StereoOdometryCfg {
+    // STEREO or VISUAL_INERTIAL (requires an IMU)
+    tracking_mode: String,
+    frame_tracker: FrameTrackerCfg,
+    visual_inertial: VisualInertialTrackerCfg,
+
+    // STEREO: the predicted pose is kept with fewer tracks
+    minimum_number_of_tracks: usize,
+    maximum_iterations: usize,
+    huber_threshold_pixels: f64,
+}
+
+impl Default for StereoOdometryCfg {
+    fn default() -> Self {
+        Self {
+            tracking_mode: String::from("STEREO"),
+            frame_tracker: FrameTrackerCfg::default(),
+            visual_inertial: VisualInertialTrackerCfg::default(),
+
+            minimum_number_of_tracks: 10,
+            maximum_iterations: 10,
+            huber_threshold_pixels: 2.0,
+        }
+    }
+}
+
+impl StereoOdometryCfg {
+    pub fn finalize(self, stereo_camera: StereoCamera, imu: Option<Imu>) -> Result<StereoOdometry> {
+        let tracking_mode: TrackingMode = self
+            .tracking_mode
+            .parse()
+            .context("invalid tracking_mode")?;
+        if self.minimum_number_of_tracks < 3 {
+            bail!(
+                "minimum number of tracks must be at least 3, got {}",
+                self.minimum_number_of_tracks
+            );
+        }
+        let visual_inertial_tracker = match (tracking_mode, imu) {
+            (TrackingMode::Stereo, _) => None,
+            (TrackingMode::VisualInertial, Some(imu)) => {
+                Some(self.visual_inertial.finalize(imu, stereo_camera.clone())?)
+            }
+            (TrackingMode::VisualInertial, None) => {
+                bail!("tracking mode {} requires an imu", tracking_mode)
+            }
+        };
+
+        Ok(StereoOdometry {
+            tracking_mode,
+            frame_tracker: self.frame_tracker.finalize()?,
+            visual_inertial_tracker,
+            stereo_camera,
+            minimum_number_of_tracks: self.minimum_number_of_tracks,
+            maximum_iterations: self.maximum_iterations,
+            huber_threshold_pixels: self.huber_threshold_pixels,
+        })
+    }
+}
+
+pub struct StereoOdometry {
+    tracking_mode: TrackingMode,
+    frame_tracker: FrameTracker,
+    // Some in VISUAL_INERTIAL mode
+    visual_inertial_tracker: Option<VisualInertialTracker>,
+    stereo_camera: StereoCamera,
+
+    minimum_number_of_tracks: usize,
+    maximum_iterations: usize,
+    huber_threshold_pixels: f64,
+}
+
+impl StereoOdometry {
+    pub fn tracking_mode(&self) -> TrackingMode {
+        self.tracking_mode
+    }
+
+    pub fn visual_inertial_tracker(&self) -> Option<&VisualInertialTracker> {
+        self.visual_inertial_tracker.as_ref()
+    }
+
+    // ignored in STEREO mode
+    pub fn add_imu_measurement(&mut self, measurement: ImuMeasurement) {
+        if let Some(tracker) = self.visual_inertial_tracker.as_mut() {
+            tracker.add_imu_measurement(measurement);
+        }
+    }
+
+    // robot_to_world of a frame with computed framepoints, whose left features are loaded into
+    // the feature matcher
+    pub fn track(
+        &mut self,
+        frame: &Frame,
+        feature_matcher_left: &IntensityFeatureMatcher,
+        timestamp_seconds: f64,
+    ) -> Result<Isometry3F64> {
+        let motion_prior = self
+            .visual_inertial_tracker
+            .as_ref()
+            .map(|tracker| tracker as &dyn MotionPrior);
+        let correspondences = self.frame_tracker.track(
+            feature_matcher_left,
+            &self.stereo_camera.left,
+            motion_prior,
+            timestamp_seconds,
+        )?;
+        log::debug!(
+            "tracked {} of {} points",
+            correspondences.len(),
+            self.frame_tracker.number_of_tracks()
+        );
+
+        let robot_to_world = match self.tracking_mode {
+            TrackingMode::Stereo => {
+                self.estimate_robot_to_world(&correspondences, timestamp_seconds)
+            }
+            TrackingMode::VisualInertial => {
+                let observations = self.landmark_observations(frame, &correspondences);
+                let Some(tracker) = self.visual_inertial_tracker.as_mut() else {
+                    bail!("no visual-inertial tracker in mode {}", self.tracking_mode);
+                };
+                tracker.track(timestamp_seconds, observations)?
+            }
+        };
+
+        self.frame_tracker
+            .update(frame, &robot_to_world, timestamp_seconds, &correspondences)?;
+        Ok(robot_to_world)
+    }
+
+    fn estimate_robot_to_world(
+        &self,
+        correspondences: &[TrackCorrespondence],
+        timestamp_seconds: f64,
+    ) -> Isometry3F64 {
+        let predicted = self
+            .frame_tracker
+            .predict_robot_to_world(None, timestamp_seconds);
+        if self.frame_tracker.number_of_tracks() == 0 {
+            return predicted;
+        }
+        if correspondences.len() < self.minimum_number_of_tracks {
+            log::warn!(
+                "only {} tracks, keeping the predicted pose",
+                correspondences.len()
+            );
+            return predicted;
+        }
+
+        let measurements: Vec<_> = correspondences
+            .iter()
+            .map(|correspondence| {
+                (
+                    correspondence.world_coordinates,
+                    correspondence.pixel_measured,
+                )
+            })
+            .collect();
+        pose_estimation::estimate_robot_to_world(
+            &self.stereo_camera.left,
+            &predicted,
+            &measurements,
+            self.maximum_iterations,
+            self.huber_threshold_pixels,
+        )
+        .unwrap_or_else(|| {
+            log::warn!("pose estimation failed, keeping the predicted pose");
+            predicted
+        })
+    }
+
+    // stereo observations of the framepoints of the new frame, identified by their tracks so that
+    // new points are observed from their first frame on
+    fn landmark_observations(
+        &self,
+        frame: &Frame,
+        correspondences: &[TrackCorrespondence],
+    ) -> Vec<LandmarkObservation> {
+        self.frame_tracker
+            .assign_tracks(frame, correspondences)
+            .into_iter()
+            .zip(&frame.created_points)
+            .filter_map(|(assignment, frame_point)| {
+                let (track_identifier, _) = assignment?;
+                // the triangulated framepoint projects onto its stereo match
+                let point_in_left = frame_point.camera_coordinates_left();
+                Some(LandmarkObservation {
+                    landmark_identifier: track_identifier,
+                    pixel_left: self.stereo_camera.left.project(point_in_left)?,
+                    pixel_right: self
+                        .stereo_camera
+                        .right
+                        .project(&self.stereo_camera.left_to_right().transform(point_in_left))?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tracking_modes() {
+        for mode in TrackingMode::ALL {
+            assert_eq!(mode.name().parse::<TrackingMode>().unwrap(), mode);
+            assert_eq!(
+                mode.to_string()
+                    .to_lowercase()
+                    .parse::<TrackingMode>()
+                    .unwrap(),
+                mode
+            );
+        }
+        assert!("MONOCULAR".parse::<TrackingMode>().is_err());
+    }
+}
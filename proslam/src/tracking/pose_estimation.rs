@@ -0,0 +1,168 @@
+//! motion only estimation of the robot pose from tracked points
+//!
+//! The world coordinates of the points are kept fixed, the left camera pose is refined with
+//! Gauss-Newton on the reprojection errors, reweighted with the Huber loss in every iteration.
+
+use rslam_core::{Camera, PixelCoordinates, PointCoordinates};
+use rslam_sensor::pinhole_camera::PinholeCamera;
+use sophus::{
+    lie::{Isometry3F64, Rotation3F64},
+    nalgebra::{Matrix3, Matrix6, SMatrix, Vector6},
+};
+
+// points closer to the camera plane are ignored
+const MINIMUM_DEPTH_METERS: f64 = 1e-3;
+
+// robot_to_world minimizing the reprojection errors of the world points in the left image,
+// starting at the prediction, None if fewer than 3 points are in front of the camera
+pub fn estimate_robot_to_world(
+    camera: &PinholeCamera,
+    predicted_robot_to_world: &Isometry3F64,
+    measurements: &[(PointCoordinates, PixelCoordinates)],
+    maximum_iterations: usize,
+    huber_threshold_pixels: f64,
+) -> Option<Isometry3F64> {
+    let camera_to_robot = camera.camera_to_robot();
+    let mut camera_to_world = predicted_robot_to_world.group_mul(camera_to_robot);
+    for iteration in 0..maximum_iterations {
+        let world_to_camera = camera_to_world.inverse();
+        let mut hessian = Matrix6::<f64>::zeros();
+        let mut gradient = Vector6::<f64>::zeros();
+        let mut number_of_points = 0;
+        let mut squared_error_sum = 0.0;
+        for (world_coordinates, pixel) in measurements {
+            let point_in_camera = world_to_camera.transform(world_coordinates);
+            if point_in_camera.z < MINIMUM_DEPTH_METERS {
+                continue;
+            }
+            let Some(projection) = camera.project(&point_in_camera) else {
+                continue;
+            };
+            let error = projection - pixel;
+
+            // the camera pose is perturbed on the right, exp((translation, rotation))
+            let mut d_point_d_pose = SMatrix::<f64, 3, 6>::zeros();
+            d_point_d_pose
+                .fixed_view_mut::<3, 3>(0, 0)
+                .copy_from(&-Matrix3::identity());
+            d_point_d_pose
+                .fixed_view_mut::<3, 3>(0, 3)
+                .copy_from(&Rotation3F64::hat(&point_in_camera));
+            let jacobian = camera.dx_project_x(&point_in_camera) * d_point_d_pose;
+
+            let error_pixels = error.norm();
+            let weight = if error_pixels <= huber_threshold_pixels {
+                1.0
+            } else {
+                huber_threshold_pixels / error_pixels
+            };
+            hessian += jacobian.transpose() * jacobian * weight;
+            gradient += jacobian.transpose() * error * weight;
+            number_of_points += 1;
+            squared_error_sum += error_pixels * error_pixels;
+        }
+        if number_of_points < 3 {
+            return None;
+        }
+
+        let delta = -hessian.cholesky()?.solve(&gradient);
+        camera_to_world = camera_to_world.group_mul(&Isometry3F64::exp(&delta));
+        log::trace!(
+            "iteration: {}, points: {}, rmse: {}",
+            iteration,
+            number_of_points,
+            (squared_error_sum / number_of_points as f64).sqrt()
+        );
+        if delta.norm() < 1e-10 {
+            break;
+        }
+    }
+    Some(camera_to_world.group_mul(&camera_to_robot.inverse()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sophus::{
+        core::linalg::VecF64, image::ImageSize,
+        sensor::camera_enum::perspective_camera::PinholeCameraF64,
+    };
+
+    fn camera() -> PinholeCamera {
+        let mut camera = PinholeCamera::new(PinholeCameraF64::from_params_and_size(
+            &VecF64::<4>::new(400.0, 400.0, 320.0, 240.0),
+            ImageSize::new(640, 480),
+        ));
+        // camera mounted off the robot origin
+        camera.set_camera_to_robot(Isometry3F64::exp(&VecF64::<6>::new(
+            0.1, 0.0, 0.3, -0.2, 0.4, 0.1,
+        )));
+        camera
+    }
+
+    fn robot_to_world() -> Isometry3F64 {
+        Isometry3F64::exp(&VecF64::<6>::new(1.0, -0.5, 0.2, 0.05, -0.1, 0.3))
+    }
+
+    // world points in front of the camera with their pixels at the given pose
+    fn measurements(
+        camera: &PinholeCamera,
+        robot_to_world: &Isometry3F64,
+    ) -> Vec<(PointCoordinates, PixelCoordinates)> {
+        let camera_to_world = robot_to_world.group_mul(camera.camera_to_robot());
+        let mut measurements = vec![];
+        for row in 0..5 {
+            for col in 0..6 {
+                let point_in_camera = PointCoordinates::new(
+                    -2.5 + col as f64,
+                    -2.0 + row as f64,
+                    4.0 + ((row * 6 + col) % 7) as f64,
+                );
+                let pixel = camera.project(&point_in_camera).unwrap();
+                measurements.push((camera_to_world.transform(&point_in_camera), pixel));
+            }
+        }
+        measurements
+    }
+
+    fn pose_error(a: &Isometry3F64, b: &Isometry3F64) -> f64 {
+        a.inverse().group_mul(b).log().norm()
+    }
+
+    #[test]
+    fn recovers_the_pose_from_a_perturbed_prediction() {
+        let camera = camera();
+        let measurements = measurements(&camera, &robot_to_world());
+        let predicted = robot_to_world().group_mul(&Isometry3F64::exp(&VecF64::<6>::new(
+            0.1, -0.05, 0.08, 0.02, 0.03, -0.04,
+        )));
+        let estimated =
+            estimate_robot_to_world(&camera, &predicted, &measurements, 10, 2.0).unwrap();
+        assert!(pose_error(&estimated, &robot_to_world()) < 1e-6);
+    }
+
+    #[test]
+    fn tolerates_outliers() {
+        let camera = camera();
+        let mut measurements = measurements(&camera, &robot_to_world());
+        for (_, pixel) in measurements.iter_mut().step_by(10) {
+            pixel.x += 40.0;
+        }
+        let predicted = robot_to_world().group_mul(&Isometry3F64::exp(&VecF64::<6>::new(
+            0.05, 0.0, -0.05, 0.0, 0.02, 0.0,
+        )));
+        let estimated =
+            estimate_robot_to_world(&camera, &predicted, &measurements, 20, 2.0).unwrap();
+        assert!(pose_error(&estimated, &robot_to_world()) < 1e-2);
+    }
+
+    #[test]
+    fn needs_three_points() {
+        let camera = camera();
+        let measurements = measurements(&camera, &robot_to_world());
+        assert!(
+            estimate_robot_to_world(&camera, &robot_to_world(), &measurements[..2], 10, 2.0)
+                .is_none()
+        );
+    }
+}
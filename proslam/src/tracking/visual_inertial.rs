@@ -0,0 +1,1190 @@
+//! tightly coupled visual-inertial tracking over a sliding window of frames
+//!
+//! Every frame holds the IMU state (rotation, position, velocity in the world frame) and
+//! the IMU biases. Stereo reprojection errors of the landmarks, IMU preintegration errors
+//! and bias random walk errors between consecutive frames are minimized jointly with
+//! Levenberg-Marquardt, landmarks are eliminated with the Schur complement. The oldest
+//! frame of the window is anchored with a prior at its current estimate.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use anyhow::{bail, Result};
+use rslam_core::{Camera, PointCoordinates};
+use rslam_sensor::{
+    imu::{Imu, ImuBias, ImuMeasurement, GRAVITY_MAGNITUDE},
+    imu_preintegration::{right_jacobian_inverse_so3, ImuPreintegration, NavigationState},
+    stereo_camera::StereoCamera,
+};
+use serde::Deserialize;
+use sophus::{
+    lie::{Isometry3F64, Rotation3F64},
+    nalgebra::{DMatrix, DVector, Matrix3, SMatrix, SVector, Vector3},
+};
+
+use super::{LandmarkObservation, MotionPrior};
+
+// rotation, position, velocity, gyroscope bias, accelerometer bias
+const STATE_SIZE: usize = 15;
+
+type Matrix15 = SMatrix<f64, 15, 15>;
+type Vector15 = SVector<f64, 15>;
+
+#[derive(Debug, Deserialize)]
+pub struct VisualInertialTrackerCfg {
+    window_size: usize,
+    maximum_iterations: usize,
+    pixel_noise_sigma: f64,
+    huber_threshold_pixels: f64,
+    minimum_depth_meters: f64,
+
+    // standard deviations of the prior anchoring the oldest frame of the window
+    prior_rotation_sigma: f64,
+    prior_position_sigma: f64,
+    prior_gyroscope_bias_sigma: f64,
+    prior_accelerometer_bias_sigma: f64,
+
+    // re-integrate the IMU measurements once the bias moved this far from the linearization
+    maximum_gyroscope_bias_change: f64,
+    maximum_accelerometer_bias_change: f64,
+}
+
+impl Default for VisualInertialTrackerCfg {
+    fn default() -> Self {
+        Self {
+            window_size: 10,
+            maximum_iterations: 10,
+            pixel_noise_sigma: 1.0,
+            huber_threshold_pixels: 2.0,
+            minimum_depth_meters: 0.1,
+
+            prior_rotation_sigma: 1e-3,
+            prior_position_sigma: 1e-3,
+            prior_gyroscope_bias_sigma: 1e-2,
+            prior_accelerometer_bias_sigma: 1e-1,
+
+            maximum_gyroscope_bias_change: 1e-3,
+            maximum_accelerometer_bias_change: 1e-2,
+        }
+    }
+}
+
+impl VisualInertialTrackerCfg {
+    pub fn finalize(self, imu: Imu, stereo_camera: StereoCamera) -> Result<VisualInertialTracker> {
+        if self.window_size < 2 {
+            bail!("window size must be at least 2, got {}", self.window_size);
+        }
+        let camera_to_imu = imu
+            .imu_to_robot()
+            .inverse()
+            .group_mul(stereo_camera.left.camera_to_robot());
+
+        Ok(VisualInertialTracker {
+            cfg: self,
+            imu,
+            stereo_camera,
+            camera_to_imu,
+            gravity: Vector3::new(0.0, 0.0, -GRAVITY_MAGNITUDE),
+            window: VecDeque::new(),
+            landmarks: BTreeMap::new(),
+            prior: None,
+            imu_buffer: vec![],
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct WindowFrame {
+    timestamp_seconds: f64,
+    state: NavigationState,
+    bias: ImuBias,
+
+    // preintegration from the previous frame of the window, None for the first frame
+    preintegration: Option<ImuPreintegration>,
+    // raw measurements of the preintegrated interval, for re-integration
+    imu_measurements: Vec<ImuMeasurement>,
+
+    observations: Vec<LandmarkObservation>,
+}
+
+impl WindowFrame {
+    fn imu_to_world(&self) -> Isometry3F64 {
+        Isometry3F64::from_translation_and_rotation(&self.state.position, &self.state.rotation)
+    }
+
+    fn plus(&self, delta: &[f64]) -> WindowFrame {
+        let mut frame = self.clone();
+        frame.state.rotation = frame
+            .state
+            .rotation
+            .group_mul(&Rotation3F64::exp(&Vector3::new(
+                delta[0], delta[1], delta[2],
+            )));
+        frame.state.position += Vector3::new(delta[3], delta[4], delta[5]);
+        frame.state.velocity += Vector3::new(delta[6], delta[7], delta[8]);
+        frame.bias.gyroscope += Vector3::new(delta[9], delta[10], delta[11]);
+        frame.bias.accelerometer += Vector3::new(delta[12], delta[13], delta[14]);
+        frame
+    }
+}
+
+// stereo reprojection error (left u, v, right u, v) with the landmark in imu and camera coordinates
+struct Reprojection {
+    error: SVector<f64, 4>,
+    point_in_imu: Vector3<f64>,
+    point_in_left: Vector3<f64>,
+    point_in_right: Vector3<f64>,
+}
+
+#[derive(Clone, Debug)]
+struct Prior {
+    state: NavigationState,
+    bias: ImuBias,
+}
+
+pub struct VisualInertialTracker {
+    cfg: VisualInertialTrackerCfg,
+    imu: Imu,
+    stereo_camera: StereoCamera,
+    camera_to_imu: Isometry3F64,
+    gravity: Vector3<f64>,
+
+    window: VecDeque<WindowFrame>,
+    landmarks: BTreeMap<usize, PointCoordinates>,
+    prior: Option<Prior>,
+
+    // measurements not yet assigned to a frame interval (sorted by timestamp)
+    imu_buffer: Vec<ImuMeasurement>,
+}
+
+impl MotionPrior for VisualInertialTracker {
+    // the prediction starts from the tracker's own estimate of the latest frame
+    fn predict_robot_to_world(
+        &self,
+        _previous_robot_to_world: &Isometry3F64,
+        _previous_timestamp_seconds: f64,
+        timestamp_seconds: f64,
+    ) -> Option<Isometry3F64> {
+        VisualInertialTracker::predict_robot_to_world(self, timestamp_seconds)
+    }
+}
+
+// rotation aligning the measured specific force with the world z axis (yaw is arbitrary)
+fn gravity_aligned_rotation(specific_force: &Vector3<f64>) -> Rotation3F64 {
+    let up = specific_force.normalize();
+    let z = Vector3::z();
+    let axis = up.cross(&z);
+    let sine = axis.norm();
+    let cosine = up.dot(&z);
+    if sine < 1e-9 {
+        if cosine > 0.0 {
+            return Rotation3F64::identity();
+        }
+        return Rotation3F64::rot_x(std::f64::consts::PI);
+    }
+    Rotation3F64::exp(&(axis / sine * sine.atan2(cosine)))
+}
+
+impl VisualInertialTracker {
+    pub fn add_imu_measurement(&mut self, measurement: ImuMeasurement) {
+        if let Some(last) = self.imu_buffer.last() {
+            if measurement.timestamp_seconds <= last.timestamp_seconds {
+                log::warn!(
+                    "dropping out of order imu measurement at {}",
+                    measurement.timestamp_seconds
+                );
+                return;
+            }
+        }
+        self.imu_buffer.push(measurement);
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        !self.window.is_empty()
+    }
+
+    pub fn bias(&self) -> Option<&ImuBias> {
+        self.window.back().map(|frame| &frame.bias)
+    }
+
+    pub fn velocity(&self) -> Option<&Vector3<f64>> {
+        self.window.back().map(|frame| &frame.state.velocity)
+    }
+
+    pub fn robot_to_world(&self) -> Option<Isometry3F64> {
+        self.window
+            .back()
+            .map(|frame| self.imu_to_world_to_robot_to_world(&frame.imu_to_world()))
+    }
+
+    fn imu_to_world_to_robot_to_world(&self, imu_to_world: &Isometry3F64) -> Isometry3F64 {
+        imu_to_world.group_mul(&self.imu.imu_to_robot().inverse())
+    }
+
+    // measurements covering [timestamp_begin, timestamp_end], including the ones just
+    // outside the interval so that it is fully covered
+    fn imu_measurements_between(
+        &self,
+        timestamp_begin: f64,
+        timestamp_end: f64,
+    ) -> Vec<ImuMeasurement> {
+        let first = self
+            .imu_buffer
+            .iter()
+            .rposition(|measurement| measurement.timestamp_seconds <= timestamp_begin)
+            .unwrap_or(0);
+        let last = self
+            .imu_buffer
+            .iter()
+            .position(|measurement| measurement.timestamp_seconds >= timestamp_end)
+            .unwrap_or(self.imu_buffer.len().saturating_sub(1));
+        if self.imu_buffer.is_empty() || last < first {
+            return vec![];
+        }
+        self.imu_buffer[first..=last].to_vec()
+    }
+
+    // IMU prediction of robot_to_world at the given timestamp, usable as motion prior
+    pub fn predict_robot_to_world(&self, timestamp_seconds: f64) -> Option<Isometry3F64> {
+        let frame = self.window.back()?;
+        let measurements =
+            self.imu_measurements_between(frame.timestamp_seconds, timestamp_seconds);
+        let mut preintegration = ImuPreintegration::new(frame.bias, &self.imu.noise);
+        preintegration.integrate_measurements(
+            &measurements,
+            frame.timestamp_seconds,
+            timestamp_seconds,
+        );
+        let state = preintegration.predict(&frame.state, &frame.bias, &self.gravity);
+        Some(
+            self.imu_to_world_to_robot_to_world(&Isometry3F64::from_translation_and_rotation(
+                &state.position,
+                &state.rotation,
+            )),
+        )
+    }
+
+    // adds a frame with its stereo landmark observations and returns the estimated robot_to_world
+    pub fn track(
+        &mut self,
+        timestamp_seconds: f64,
+        observations: Vec<LandmarkObservation>,
+    ) -> Result<Isometry3F64> {
+        let frame = match self.window.back() {
+            None => self.initial_frame(timestamp_seconds, observations),
+            Some(previous) => {
+                if timestamp_seconds <= previous.timestamp_seconds {
+                    bail!(
+                        "frame timestamp {} not after previous frame {}",
+                        timestamp_seconds,
+                        previous.timestamp_seconds
+                    );
+                }
+                let imu_measurements =
+                    self.imu_measurements_between(previous.timestamp_seconds, timestamp_seconds);
+                if imu_measurements.len() < 2 {
+                    bail!(
+                        "no imu measurements between {} and {}",
+                        previous.timestamp_seconds,
+                        timestamp_seconds
+                    );
+                }
+                let mut preintegration = ImuPreintegration::new(previous.bias, &self.imu.noise);
+                preintegration.integrate_measurements(
+                    &imu_measurements,
+                    previous.timestamp_seconds,
+                    timestamp_seconds,
+                );
+                WindowFrame {
+                    timestamp_seconds,
+                    state: preintegration.predict(&previous.state, &previous.bias, &self.gravity),
+                    bias: previous.bias,
+                    preintegration: Some(preintegration),
+                    imu_measurements,
+                    observations,
+                }
+            }
+        };
+
+        self.add_landmarks(&frame);
+        self.window.push_back(frame);
+        if self.prior.is_none() {
+            self.set_prior();
+        }
+
+        self.optimize();
+        self.reintegrate();
+
+        if self.window.len() > self.cfg.window_size {
+            self.window.pop_front();
+            if let Some(first) = self.window.front_mut() {
+                first.preintegration = None;
+                first.imu_measurements.clear();
+            }
+            self.set_prior();
+            self.remove_unobserved_landmarks();
+        }
+
+        // keep the last measurement before the frame for the next interval
+        if let Some(index) = self
+            .imu_buffer
+            .iter()
+            .rposition(|measurement| measurement.timestamp_seconds <= timestamp_seconds)
+        {
+            self.imu_buffer.drain(..index);
+        }
+
+        log::debug!(
+            "window frames: {}, landmarks: {}, bias: {:?}",
+            self.window.len(),
+            self.landmarks.len(),
+            self.window.back().map(|frame| frame.bias)
+        );
+        self.robot_to_world()
+            .ok_or_else(|| anyhow::anyhow!("empty window"))
+    }
+
+    fn initial_frame(
+        &self,
+        timestamp_seconds: f64,
+        observations: Vec<LandmarkObservation>,
+    ) -> WindowFrame {
+        // the robot is assumed to rest while the first measurements are taken
+        let measurements: Vec<_> = self
+            .imu_buffer
+            .iter()
+            .filter(|measurement| measurement.timestamp_seconds <= timestamp_seconds)
+            .collect();
+        let rotation = if measurements.is_empty() {
+            log::warn!("no imu measurements for gravity alignment, using identity attitude");
+            Rotation3F64::identity()
+        } else {
+            let mean_specific_force = measurements
+                .iter()
+                .map(|measurement| measurement.linear_acceleration)
+                .sum::<Vector3<f64>>()
+                / measurements.len() as f64;
+            gravity_aligned_rotation(&mean_specific_force)
+        };
+        log::info!("initial attitude: {:?}", rotation.log().transpose());
+
+        WindowFrame {
+            timestamp_seconds,
+            state: NavigationState {
+                rotation,
+                position: Vector3::zeros(),
+                velocity: Vector3::zeros(),
+            },
+            bias: ImuBias::default(),
+            preintegration: None,
+            imu_measurements: vec![],
+            observations,
+        }
+    }
+
+    fn set_prior(&mut self) {
+        self.prior = self.window.front().map(|frame| Prior {
+            state: frame.state.clone(),
+            bias: frame.bias,
+        });
+    }
+
+    fn add_landmarks(&mut self, frame: &WindowFrame) {
+        let camera_to_world = frame.imu_to_world().group_mul(&self.camera_to_imu);
+        for observation in &frame.observations {
+            if self
+                .landmarks
+                .contains_key(&observation.landmark_identifier)
+            {
+                continue;
+            }
+            let Some(point_in_camera) = self
+                .stereo_camera
+                .triangulate(&observation.pixel_left, &observation.pixel_right)
+            else {
+                continue;
+            };
+            if point_in_camera.z < self.cfg.minimum_depth_meters {
+                continue;
+            }
+            self.landmarks.insert(
+                observation.landmark_identifier,
+                camera_to_world.transform(&point_in_camera),
+            );
+        }
+    }
+
+    fn remove_unobserved_landmarks(&mut self) {
+        let window = &self.window;
+        self.landmarks.retain(|identifier, _| {
+            window.iter().any(|frame| {
+                frame
+                    .observations
+                    .iter()
+                    .any(|observation| observation.landmark_identifier == *identifier)
+            })
+        });
+    }
+
+    // re-integrates intervals whose start bias moved away from the linearization point
+    fn reintegrate(&mut self) {
+        for index in 1..self.window.len() {
+            let bias = self.window[index - 1].bias;
+            let timestamp_begin = self.window[index - 1].timestamp_seconds;
+            let frame = &mut self.window[index];
+            let Some(preintegration) = frame.preintegration.as_mut() else {
+                continue;
+            };
+            let linearization = *preintegration.bias();
+            if (bias.gyroscope - linearization.gyroscope).norm()
+                > self.cfg.maximum_gyroscope_bias_change
+                || (bias.accelerometer - linearization.accelerometer).norm()
+                    > self.cfg.maximum_accelerometer_bias_change
+            {
+                preintegration.reset(bias);
+                preintegration.integrate_measurements(
+                    &frame.imu_measurements,
+                    timestamp_begin,
+                    frame.timestamp_seconds,
+                );
+            }
+        }
+    }
+
+    fn huber_weight(&self, error_pixels: f64) -> f64 {
+        if error_pixels <= self.cfg.huber_threshold_pixels {
+            1.0
+        } else {
+            self.cfg.huber_threshold_pixels / error_pixels
+        }
+    }
+
+    fn huber_cost(&self, error_pixels: f64) -> f64 {
+        let threshold = self.cfg.huber_threshold_pixels;
+        let cost = if error_pixels <= threshold {
+            error_pixels * error_pixels
+        } else {
+            2.0 * threshold * error_pixels - threshold * threshold
+        };
+        cost / (self.cfg.pixel_noise_sigma * self.cfg.pixel_noise_sigma)
+    }
+
+    // None if the landmark is not in front of both cameras
+    fn reprojection(
+        &self,
+        frame: &WindowFrame,
+        landmark: &PointCoordinates,
+        observation: &LandmarkObservation,
+    ) -> Option<Reprojection> {
+        let point_in_imu = frame.imu_to_world().inverse().transform(landmark);
+        let point_in_left = self.camera_to_imu.inverse().transform(&point_in_imu);
+        let point_in_right = self.stereo_camera.left_to_right().transform(&point_in_left);
+        if point_in_left.z < self.cfg.minimum_depth_meters
+            || point_in_right.z < self.cfg.minimum_depth_meters
+        {
+            return None;
+        }
+        let error_left = self.stereo_camera.left.project(&point_in_left)? - observation.pixel_left;
+        let error_right =
+            self.stereo_camera.right.project(&point_in_right)? - observation.pixel_right;
+        Some(Reprojection {
+            error: SVector::<f64, 4>::new(error_left.x, error_left.y, error_right.x, error_right.y),
+            point_in_imu,
+            point_in_left,
+            point_in_right,
+        })
+    }
+
+    // preintegration and bias random walk error between consecutive frames
+    fn inertial_residual(
+        &self,
+        previous: &WindowFrame,
+        current: &WindowFrame,
+    ) -> Option<(Vector15, Matrix15)> {
+        let preintegration = current.preintegration.as_ref()?;
+        let residual_preintegration = preintegration.residual(
+            &previous.state,
+            &current.state,
+            &previous.bias,
+            &self.gravity,
+        );
+
+        let mut residual = Vector15::zeros();
+        residual
+            .fixed_rows_mut::<9>(0)
+            .copy_from(&residual_preintegration);
+        residual
+            .fixed_rows_mut::<3>(9)
+            .copy_from(&(current.bias.gyroscope - previous.bias.gyroscope));
+        residual
+            .fixed_rows_mut::<3>(12)
+            .copy_from(&(current.bias.accelerometer - previous.bias.accelerometer));
+
+        let mut covariance = Matrix15::zeros();
+        covariance
+            .fixed_view_mut::<9, 9>(0, 0)
+            .copy_from(preintegration.covariance());
+        covariance
+            .fixed_view_mut::<6, 6>(9, 9)
+            .copy_from(&preintegration.bias_random_walk_covariance());
+        let information = (covariance + Matrix15::identity() * 1e-12).try_inverse()?;
+        Some((residual, information))
+    }
+
+    fn prior_residual(&self, frame: &WindowFrame) -> Option<(SVector<f64, 12>, SVector<f64, 12>)> {
+        let prior = self.prior.as_ref()?;
+        let mut residual = SVector::<f64, 12>::zeros();
+        residual.fixed_rows_mut::<3>(0).copy_from(
+            &prior
+                .state
+                .rotation
+                .inverse()
+                .group_mul(&frame.state.rotation)
+                .log(),
+        );
+        residual
+            .fixed_rows_mut::<3>(3)
+            .copy_from(&(frame.state.position - prior.state.position));
+        residual
+            .fixed_rows_mut::<3>(6)
+            .copy_from(&(frame.bias.gyroscope - prior.bias.gyroscope));
+        residual
+            .fixed_rows_mut::<3>(9)
+            .copy_from(&(frame.bias.accelerometer - prior.bias.accelerometer));
+
+        let sigmas = [
+            self.cfg.prior_rotation_sigma,
+            self.cfg.prior_position_sigma,
+            self.cfg.prior_gyroscope_bias_sigma,
+            self.cfg.prior_accelerometer_bias_sigma,
+        ];
+        let information = SVector::<f64, 12>::from_fn(|i, _| 1.0 / (sigmas[i / 3] * sigmas[i / 3]));
+        Some((residual, information))
+    }
+
+    fn cost(
+        &self,
+        window: &VecDeque<WindowFrame>,
+        landmarks: &BTreeMap<usize, PointCoordinates>,
+    ) -> f64 {
+        let mut cost = 0.0;
+        for frame in window {
+            for observation in &frame.observations {
+                let Some(landmark) = landmarks.get(&observation.landmark_identifier) else {
+                    continue;
+                };
+                if let Some(Reprojection { error, .. }) =
+                    self.reprojection(frame, landmark, observation)
+                {
+                    cost += self.huber_cost(error.fixed_rows::<2>(0).norm())
+                        + self.huber_cost(error.fixed_rows::<2>(2).norm());
+                }
+            }
+        }
+        for index in 1..window.len() {
+            if let Some((residual, information)) =
+                self.inertial_residual(&window[index - 1], &window[index])
+            {
+                cost += (residual.transpose() * information * residual)[0];
+            }
+        }
+        if let Some((residual, information)) =
+            window.front().and_then(|frame| self.prior_residual(frame))
+        {
+            cost += residual.component_mul(&information).dot(&residual);
+        }
+        cost
+    }
+
+    fn optimize(&mut self) {
+        let landmark_identifiers: Vec<usize> = self.landmarks.keys().copied().collect();
+        let landmark_index: BTreeMap<usize, usize> = landmark_identifiers
+            .iter()
+            .enumerate()
+            .map(|(index, identifier)| (*identifier, index))
+            .collect();
+
+        let mut cost = self.cost(&self.window, &self.landmarks);
+        let mut lambda = 1e-4;
+        for iteration in 0..self.cfg.maximum_iterations {
+            let system = self.linearize(&landmark_index);
+
+            let mut improved = false;
+            while lambda < 1e8 {
+                let Some((delta_states, delta_landmarks)) = system.solve(lambda) else {
+                    lambda *= 10.0;
+                    continue;
+                };
+                let window: VecDeque<WindowFrame> = self
+                    .window
+                    .iter()
+                    .enumerate()
+                    .map(|(index, frame)| {
+                        frame.plus(delta_states.rows(STATE_SIZE * index, STATE_SIZE).as_slice())
+                    })
+                    .collect();
+                let landmarks: BTreeMap<usize, PointCoordinates> = self
+                    .landmarks
+                    .iter()
+                    .map(|(identifier, landmark)| {
+                        (
+                            *identifier,
+                            landmark + delta_landmarks[landmark_index[identifier]],
+                        )
+                    })
+                    .collect();
+                let candidate_cost = self.cost(&window, &landmarks);
+                if candidate_cost < cost {
+                    improved = (cost - candidate_cost) / cost > 1e-6;
+                    cost = candidate_cost;
+                    self.window = window;
+                    self.landmarks = landmarks;
+                    lambda = (lambda / 10.0).max(1e-10);
+                    break;
+                }
+                lambda *= 10.0;
+            }
+            log::trace!("iteration: {}, cost: {}", iteration, cost);
+            if !improved {
+                break;
+            }
+        }
+    }
+
+    fn linearize(&self, landmark_index: &BTreeMap<usize, usize>) -> NormalEquations {
+        let number_of_states = STATE_SIZE * self.window.len();
+        let mut system = NormalEquations::new(number_of_states, landmark_index.len());
+        let information_pixels = 1.0 / (self.cfg.pixel_noise_sigma * self.cfg.pixel_noise_sigma);
+        let camera_from_imu = self.camera_to_imu.inverse().rotation().matrix();
+        let right_from_left = self.stereo_camera.left_to_right().rotation().matrix();
+
+        // stereo reprojection errors, analytic jacobians w.r.t. the frame pose and the landmark
+        for (frame_index, frame) in self.window.iter().enumerate() {
+            let world_to_imu = frame.state.rotation.inverse().matrix();
+            for observation in &frame.observations {
+                let Some(landmark) = self.landmarks.get(&observation.landmark_identifier) else {
+                    continue;
+                };
+                let Some(Reprojection {
+                    error,
+                    point_in_imu,
+                    point_in_left,
+                    point_in_right,
+                }) = self.reprojection(frame, landmark, observation)
+                else {
+                    continue;
+                };
+
+                let mut d_imu_d_pose = SMatrix::<f64, 3, 6>::zeros();
+                d_imu_d_pose
+                    .fixed_view_mut::<3, 3>(0, 0)
+                    .copy_from(&Rotation3F64::hat(&point_in_imu));
+                d_imu_d_pose
+                    .fixed_view_mut::<3, 3>(0, 3)
+                    .copy_from(&(-world_to_imu));
+                let d_left_d_imu = camera_from_imu;
+                let d_right_d_imu = right_from_left * camera_from_imu;
+
+                let projection_left = self.stereo_camera.left.dx_project_x(&point_in_left);
+                let projection_right = self.stereo_camera.right.dx_project_x(&point_in_right);
+
+                let mut jacobian_pose = SMatrix::<f64, 4, 6>::zeros();
+                jacobian_pose
+                    .fixed_view_mut::<2, 6>(0, 0)
+                    .copy_from(&(projection_left * d_left_d_imu * d_imu_d_pose));
+                jacobian_pose
+                    .fixed_view_mut::<2, 6>(2, 0)
+                    .copy_from(&(projection_right * d_right_d_imu * d_imu_d_pose));
+                let mut jacobian_landmark = SMatrix::<f64, 4, 3>::zeros();
+                jacobian_landmark
+                    .fixed_view_mut::<2, 3>(0, 0)
+                    .copy_from(&(projection_left * d_left_d_imu * world_to_imu));
+                jacobian_landmark
+                    .fixed_view_mut::<2, 3>(2, 0)
+                    .copy_from(&(projection_right * d_right_d_imu * world_to_imu));
+
+                // robust weights per camera
+                let mut weights = SVector::<f64, 4>::zeros();
+                let weight_left =
+                    self.huber_weight(error.fixed_rows::<2>(0).norm()) * information_pixels;
+                let weight_right =
+                    self.huber_weight(error.fixed_rows::<2>(2).norm()) * information_pixels;
+                weights.fixed_rows_mut::<2>(0).fill(weight_left);
+                weights.fixed_rows_mut::<2>(2).fill(weight_right);
+                let weighted_pose =
+                    SMatrix::<f64, 4, 6>::from_fn(|r, c| jacobian_pose[(r, c)] * weights[r]);
+                let weighted_landmark =
+                    SMatrix::<f64, 4, 3>::from_fn(|r, c| jacobian_landmark[(r, c)] * weights[r]);
+
+                let state_offset = STATE_SIZE * frame_index;
+                let landmark = landmark_index[&observation.landmark_identifier];
+                system.add_state_block(
+                    state_offset,
+                    state_offset,
+                    &DMatrix::from_column_slice(
+                        6,
+                        6,
+                        (jacobian_pose.transpose() * weighted_pose).as_slice(),
+                    ),
+                );
+                system.add_state_gradient(
+                    state_offset,
+                    (weighted_pose.transpose() * error).as_slice(),
+                );
+                system.landmark_hessians[landmark] +=
+                    jacobian_landmark.transpose() * weighted_landmark;
+                system.landmark_gradients[landmark] += weighted_landmark.transpose() * error;
+                system.couplings[landmark]
+                    .push((state_offset, weighted_pose.transpose() * jacobian_landmark));
+            }
+        }
+
+        // inertial errors, numeric jacobians w.r.t. both frame states
+        for index in 1..self.window.len() {
+            let (previous, current) = (&self.window[index - 1], &self.window[index]);
+            let Some((residual, information)) = self.inertial_residual(previous, current) else {
+                continue;
+            };
+            let mut jacobian = SMatrix::<f64, 15, 30>::zeros();
+            for k in 0..2 * STATE_SIZE {
+                let step = 1e-6;
+                let mut delta = [0.0; STATE_SIZE];
+                delta[k % STATE_SIZE] = step;
+                let (previous_plus, current_plus) = if k < STATE_SIZE {
+                    (previous.plus(&delta), current.clone())
+                } else {
+                    (previous.clone(), current.plus(&delta))
+                };
+                let (previous_minus, current_minus) = {
+                    let delta_minus = delta.map(|value| -value);
+                    if k < STATE_SIZE {
+                        (previous.plus(&delta_minus), current.clone())
+                    } else {
+                        (previous.clone(), current.plus(&delta_minus))
+                    }
+                };
+                if let (Some((plus, _)), Some((minus, _))) = (
+                    self.inertial_residual(&previous_plus, &current_plus),
+                    self.inertial_residual(&previous_minus, &current_minus),
+                ) {
+                    jacobian.set_column(k, &((plus - minus) / (2.0 * step)));
+                }
+            }
+            let hessian = jacobian.transpose() * information * jacobian;
+            let gradient = jacobian.transpose() * information * residual;
+            let offset = STATE_SIZE * (index - 1);
+            system.add_state_block(
+                offset,
+                offset,
+                &DMatrix::from_column_slice(30, 30, hessian.as_slice()),
+            );
+            system.add_state_gradient(offset, gradient.as_slice());
+        }
+
+        // prior on the oldest frame (rotation, position, biases)
+        if let Some(frame) = self.window.front() {
+            if let Some((residual, information)) = self.prior_residual(frame) {
+                let rotation_jacobian =
+                    right_jacobian_inverse_so3(&residual.fixed_rows::<3>(0).into_owned());
+                let mut jacobian = SMatrix::<f64, 12, 15>::zeros();
+                jacobian
+                    .fixed_view_mut::<3, 3>(0, 0)
+                    .copy_from(&rotation_jacobian);
+                jacobian
+                    .fixed_view_mut::<3, 3>(3, 3)
+                    .copy_from(&Matrix3::identity());
+                jacobian.fixed_view_mut::<6, 6>(6, 9).fill_with_identity();
+                let weighted =
+                    SMatrix::<f64, 12, 15>::from_fn(|r, c| jacobian[(r, c)] * information[r]);
+                let hessian = jacobian.transpose() * weighted;
+                let gradient = weighted.transpose() * residual;
+                system.add_state_block(
+                    0,
+                    0,
+                    &DMatrix::from_column_slice(15, 15, hessian.as_slice()),
+                );
+                system.add_state_gradient(0, gradient.as_slice());
+            }
+        }
+        system
+    }
+}
+
+// gauss-newton system split into frame states and landmarks
+struct NormalEquations {
+    state_hessian: DMatrix<f64>,
+    state_gradient: DVector<f64>,
+    landmark_hessians: Vec<Matrix3<f64>>,
+    landmark_gradients: Vec<Vector3<f64>>,
+    // pose x landmark blocks of the hessian with the offset of the pose in the states
+    couplings: Vec<Vec<(usize, SMatrix<f64, 6, 3>)>>,
+}
+
+impl NormalEquations {
+    fn new(number_of_states: usize, number_of_landmarks: usize) -> Self {
+        Self {
+            state_hessian: DMatrix::zeros(number_of_states, number_of_states),
+            state_gradient: DVector::zeros(number_of_states),
+            landmark_hessians: vec![Matrix3::zeros(); number_of_landmarks],
+            landmark_gradients: vec![Vector3::zeros(); number_of_landmarks],
+            couplings: vec![vec![]; number_of_landmarks],
+        }
+    }
+
+    fn add_state_block(&mut self, row: usize, column: usize, block: &DMatrix<f64>) {
+        let mut view = self
+            .state_hessian
+            .view_mut((row, column), (block.nrows(), block.ncols()));
+        view += block;
+    }
+
+    fn add_state_gradient(&mut self, row: usize, gradient: &[f64]) {
+        let mut view = self.state_gradient.rows_mut(row, gradient.len());
+        view += DVector::from_column_slice(gradient);
+    }
+
+    // solves the damped system with the Schur complement on the landmarks
+    fn solve(&self, lambda: f64) -> Option<(DVector<f64>, Vec<Vector3<f64>>)> {
+        let mut reduced_hessian = self.state_hessian.clone();
+        for i in 0..reduced_hessian.nrows() {
+            reduced_hessian[(i, i)] += lambda * self.state_hessian[(i, i)].max(1e-6);
+        }
+        let mut reduced_gradient = self.state_gradient.clone();
+
+        let mut landmark_inverses = Vec::with_capacity(self.landmark_hessians.len());
+        for ((hessian, gradient), couplings) in self
+            .landmark_hessians
+            .iter()
+            .zip(self.landmark_gradients.iter())
+            .zip(self.couplings.iter())
+        {
+            let mut damped = *hessian;
+            for i in 0..3 {
+                damped[(i, i)] += lambda * hessian[(i, i)].max(1e-6);
+            }
+            let inverse = damped.try_inverse()?;
+            for (row, coupling_row) in couplings {
+                let coupling_inverse = coupling_row * inverse;
+                let mut gradient_block = reduced_gradient.fixed_rows_mut::<6>(*row);
+                gradient_block -= coupling_inverse * gradient;
+                for (column, coupling_column) in couplings {
+                    let mut block = reduced_hessian.fixed_view_mut::<6, 6>(*row, *column);
+                    block -= coupling_inverse * coupling_column.transpose();
+                }
+            }
+            landmark_inverses.push(inverse);
+        }
+
+        let delta_states = -reduced_hessian.cholesky()?.solve(&reduced_gradient);
+        let delta_landmarks = landmark_inverses
+            .iter()
+            .zip(self.landmark_gradients.iter())
+            .zip(self.couplings.iter())
+            .map(|((inverse, gradient), couplings)| {
+                let coupled: Vector3<f64> = couplings
+                    .iter()
+                    .map(|(row, coupling)| {
+                        coupling.transpose() * delta_states.fixed_rows::<6>(*row)
+                    })
+                    .sum();
+                -(inverse * (gradient + coupled))
+            })
+            .collect();
+        Some((delta_states, delta_landmarks))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_sensor::{imu::ImuNoiseParameters, pinhole_camera::PinholeCamera};
+    use sophus::{
+        core::linalg::VecF64, image::ImageSize, lie::prelude::IsTranslationProductGroup,
+        sensor::camera_enum::perspective_camera::PinholeCameraF64,
+    };
+
+    // the robot rests until 0, then turns with a constant rate and accelerates uniformly
+    const ANGULAR_VELOCITY: [f64; 3] = [0.1, -0.08, 0.2];
+    const ACCELERATION_WORLD: [f64; 3] = [0.4, -0.3, 0.1];
+    const IMU_SAMPLE_INTERVAL_SECONDS: f64 = 1e-3;
+    const NUMBER_OF_REST_SAMPLES: usize = 200;
+    const FRAME_INTERVAL_SECONDS: f64 = 0.1;
+
+    fn robot_to_world_at(timestamp_seconds: f64) -> Isometry3F64 {
+        let timestamp_seconds = timestamp_seconds.max(0.0);
+        Isometry3F64::from_translation_and_rotation(
+            &(0.5 * Vector3::from(ACCELERATION_WORLD) * timestamp_seconds * timestamp_seconds),
+            &Rotation3F64::exp(&(Vector3::from(ANGULAR_VELOCITY) * timestamp_seconds)),
+        )
+    }
+
+    // samples from the rest phase up to the end timestamp, the imu is the robot frame
+    fn imu_measurements(bias: &ImuBias, timestamp_end: f64) -> Vec<ImuMeasurement> {
+        let gravity = Vector3::new(0.0, 0.0, -GRAVITY_MAGNITUDE);
+        let number_of_samples =
+            NUMBER_OF_REST_SAMPLES + (timestamp_end / IMU_SAMPLE_INTERVAL_SECONDS).ceil() as usize;
+        (0..=number_of_samples)
+            .map(|sample| {
+                let timestamp_seconds =
+                    (sample as f64 - NUMBER_OF_REST_SAMPLES as f64) * IMU_SAMPLE_INTERVAL_SECONDS;
+                let (angular_velocity, acceleration) = if sample > NUMBER_OF_REST_SAMPLES {
+                    (
+                        Vector3::from(ANGULAR_VELOCITY),
+                        Vector3::from(ACCELERATION_WORLD),
+                    )
+                } else {
+                    (Vector3::zeros(), Vector3::zeros())
+                };
+                let world_to_imu = robot_to_world_at(timestamp_seconds)
+                    .rotation()
+                    .inverse()
+                    .matrix();
+                ImuMeasurement::new(
+                    timestamp_seconds,
+                    angular_velocity + bias.gyroscope,
+                    world_to_imu * (acceleration - gravity) + bias.accelerometer,
+                )
+            })
+            .collect()
+    }
+
+    // rectified rig looking up along the robot z axis
+    fn stereo_camera() -> StereoCamera {
+        let camera = PinholeCamera::new(PinholeCameraF64::from_params_and_size(
+            &VecF64::<4>::new(400.0, 400.0, 320.0, 240.0),
+            ImageSize::new(640, 480),
+        ));
+        StereoCamera::new_rectified(camera.clone(), camera, 0.3, Isometry3F64::identity()).unwrap()
+    }
+
+    fn landmarks() -> Vec<PointCoordinates> {
+        let mut landmarks = vec![];
+        for row in 0..9 {
+            for col in 0..9 {
+                landmarks.push(PointCoordinates::new(
+                    col as f64 - 4.0,
+                    row as f64 - 4.0,
+                    6.0 + ((row * 9 + col) * 7 % 5) as f64 * 0.5,
+                ));
+            }
+        }
+        landmarks
+    }
+
+    fn observations(
+        stereo_camera: &StereoCamera,
+        timestamp_seconds: f64,
+    ) -> Vec<LandmarkObservation> {
+        let world_to_left = robot_to_world_at(timestamp_seconds)
+            .group_mul(stereo_camera.rig_to_robot())
+            .inverse();
+        landmarks()
+            .iter()
+            .enumerate()
+            .filter_map(|(landmark_identifier, landmark)| {
+                let point_in_left = world_to_left.transform(landmark);
+                let point_in_right = stereo_camera.left_to_right().transform(&point_in_left);
+                let pixel_left = stereo_camera.left.project(&point_in_left)?;
+                let pixel_right = stereo_camera.right.project(&point_in_right)?;
+                (stereo_camera.left.is_in_image(&pixel_left, 0.0)
+                    && stereo_camera.right.is_in_image(&pixel_right, 0.0))
+                .then_some(LandmarkObservation {
+                    landmark_identifier,
+                    pixel_left,
+                    pixel_right,
+                })
+            })
+            .collect()
+    }
+
+    fn tracker(cfg: VisualInertialTrackerCfg) -> VisualInertialTracker {
+        cfg.finalize(
+            Imu::new(ImuNoiseParameters::default(), Isometry3F64::identity()),
+            stereo_camera(),
+        )
+        .unwrap()
+    }
+
+    // tracks the frames up to the end timestamp with all imu measurements
+    fn track_frames(tracker: &mut VisualInertialTracker, bias: &ImuBias, number_of_frames: usize) {
+        let timestamp_end = (number_of_frames - 1) as f64 * FRAME_INTERVAL_SECONDS;
+        for measurement in imu_measurements(bias, timestamp_end) {
+            tracker.add_imu_measurement(measurement);
+        }
+        for index in 0..number_of_frames {
+            let timestamp_seconds = index as f64 * FRAME_INTERVAL_SECONDS;
+            tracker
+                .track(
+                    timestamp_seconds,
+                    observations(&stereo_camera(), timestamp_seconds),
+                )
+                .unwrap();
+        }
+    }
+
+    fn pose_error(a: &Isometry3F64, b: &Isometry3F64) -> (f64, f64) {
+        let error = a.inverse().group_mul(b);
+        (error.rotation().log().norm(), error.translation().norm())
+    }
+
+    #[test]
+    fn predicts_constant_rate_motion_from_the_imu() {
+        let mut tracker = tracker(VisualInertialTrackerCfg::default());
+        for measurement in imu_measurements(&ImuBias::default(), 0.5) {
+            tracker.add_imu_measurement(measurement);
+        }
+        assert!(tracker.predict_robot_to_world(0.5).is_none());
+
+        // gravity aligned attitude at rest
+        let robot_to_world = tracker.track(0.0, vec![]).unwrap();
+        let (rotation_error, translation_error) =
+            pose_error(&robot_to_world, &Isometry3F64::identity());
+        assert!(rotation_error < 1e-9 && translation_error < 1e-9);
+
+        for timestamp_seconds in [0.1, 0.25, 0.5] {
+            let predicted = tracker.predict_robot_to_world(timestamp_seconds).unwrap();
+            let (rotation_error, translation_error) =
+                pose_error(&predicted, &robot_to_world_at(timestamp_seconds));
+            // the first interval averages the rest sample and the first moving sample
+            assert!(rotation_error < 5e-4, "{}", rotation_error);
+            assert!(translation_error < 1e-3, "{}", translation_error);
+        }
+    }
+
+    #[test]
+    fn recovers_imu_biases() {
+        // horizontal accelerometer biases look like a tilt of the anchored first frame
+        let bias = ImuBias::new(
+            Vector3::new(0.02, -0.015, 0.01),
+            Vector3::new(0.0, 0.0, 0.15),
+        );
+        let mut tracker = tracker(VisualInertialTrackerCfg {
+            prior_gyroscope_bias_sigma: 1.0,
+            prior_accelerometer_bias_sigma: 1.0,
+            ..Default::default()
+        });
+        let number_of_frames = 21;
+        track_frames(&mut tracker, &bias, number_of_frames);
+
+        let estimated = tracker.bias().unwrap();
+        assert!(
+            (estimated.gyroscope - bias.gyroscope).norm() < 1e-3,
+            "{:?}",
+            estimated.gyroscope
+        );
+        assert!(
+            (estimated.accelerometer - bias.accelerometer).norm() < 2e-2,
+            "{:?}",
+            estimated.accelerometer
+        );
+
+        let timestamp_end = (number_of_frames - 1) as f64 * FRAME_INTERVAL_SECONDS;
+        let (rotation_error, translation_error) = pose_error(
+            &tracker.robot_to_world().unwrap(),
+            &robot_to_world_at(timestamp_end),
+        );
+        assert!(rotation_error < 1e-2, "{}", rotation_error);
+        assert!(translation_error < 2e-2, "{}", translation_error);
+    }
+
+    #[test]
+    fn slides_the_window_and_anchors_the_oldest_frame() {
+        let mut tracker = tracker(VisualInertialTrackerCfg {
+            window_size: 4,
+            ..Default::default()
+        });
+        track_frames(&mut tracker, &ImuBias::default(), 7);
+
+        assert_eq!(tracker.window.len(), 4);
+        let first = tracker.window.front().unwrap();
+        assert!(first.preintegration.is_none() && first.imu_measurements.is_empty());
+        assert!((first.timestamp_seconds - 3.0 * FRAME_INTERVAL_SECONDS).abs() < 1e-12);
+
+        // the prior holds the oldest frame at its estimate when it became the first frame
+        let prior = tracker.prior.as_ref().unwrap();
+        assert_eq!(prior.state.position, first.state.position);
+        assert_eq!(prior.bias, first.bias);
+        let (prior_residual, _) = tracker.prior_residual(first).unwrap();
+        assert!(prior_residual.norm() < 1e-12);
+
+        // consecutive frames are connected by their preintegrated interval
+        for (previous, current) in tracker.window.iter().zip(tracker.window.iter().skip(1)) {
+            let preintegration = current.preintegration.as_ref().unwrap();
+            assert!(
+                (preintegration.delta_time()
+                    - (current.timestamp_seconds - previous.timestamp_seconds))
+                    .abs()
+                    < 1e-9
+            );
+        }
+
+        // landmarks only seen by removed frames are dropped
+        for identifier in tracker.landmarks.keys() {
+            assert!(tracker.window.iter().any(|frame| frame
+                .observations
+                .iter()
+                .any(|observation| observation.landmark_identifier == *identifier)));
+        }
+    }
+
+    #[test]
+    fn schur_complement_solves_the_full_system() {
+        let mut tracker = tracker(VisualInertialTrackerCfg::default());
+        track_frames(&mut tracker, &ImuBias::default(), 3);
+        // move the states off the optimum to get a non trivial gradient
+        for frame in tracker.window.iter_mut() {
+            *frame = frame.plus(&[
+                0.01, -0.02, 0.01, 0.05, 0.02, -0.03, 0.1, 0.0, -0.1, 0.001, 0.0, -0.001, 0.01,
+                0.02, 0.0,
+            ]);
+        }
+        let landmark_index: BTreeMap<usize, usize> = tracker
+            .landmarks
+            .keys()
+            .enumerate()
+            .map(|(index, identifier)| (*identifier, index))
+            .collect();
+        let system = tracker.linearize(&landmark_index);
+        let lambda = 1e-3;
+        let (delta_states, delta_landmarks) = system.solve(lambda).unwrap();
+
+        let number_of_states = system.state_gradient.len();
+        let size = number_of_states + 3 * delta_landmarks.len();
+        let mut hessian = DMatrix::<f64>::zeros(size, size);
+        let mut gradient = DVector::<f64>::zeros(size);
+        hessian
+            .view_mut((0, 0), (number_of_states, number_of_states))
+            .copy_from(&system.state_hessian);
+        gradient
+            .rows_mut(0, number_of_states)
+            .copy_from(&system.state_gradient);
+        for (landmark, couplings) in system.couplings.iter().enumerate() {
+            let offset = number_of_states + 3 * landmark;
+            hessian
+                .fixed_view_mut::<3, 3>(offset, offset)
+                .copy_from(&system.landmark_hessians[landmark]);
+            gradient
+                .fixed_rows_mut::<3>(offset)
+                .copy_from(&system.landmark_gradients[landmark]);
+            for (row, coupling) in couplings {
+                let mut block = hessian.fixed_view_mut::<6, 3>(*row, offset);
+                block += coupling;
+                let mut block = hessian.fixed_view_mut::<3, 6>(offset, *row);
+                block += coupling.transpose();
+            }
+        }
+        for i in 0..size {
+            hessian[(i, i)] += lambda * hessian[(i, i)].max(1e-6);
+        }
+        let delta = -hessian.lu().solve(&gradient).unwrap();
+
+        let scale = delta.norm();
+        assert!(scale > 0.0);
+        assert!((delta.rows(0, number_of_states) - &delta_states).norm() < 1e-6 * scale);
+        for (landmark, delta_landmark) in delta_landmarks.iter().enumerate() {
+            assert!(
+                (delta.fixed_rows::<3>(number_of_states + 3 * landmark) - delta_landmark).norm()
+                    < 1e-6 * scale
+            );
+        }
+    }
+}
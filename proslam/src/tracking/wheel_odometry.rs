@@ -0,0 +1,128 @@
+use anyhow::{bail, Result};
+use rslam_sensor::wheel_odometry::{WheelOdometry, WheelOdometryMeasurement};
+use sophus::{lie::Isometry3F64, nalgebra::Matrix6};
+
+use super::MotionPrior;
+
+/// buffers wheel odometry between frames and predicts the robot motion from it
+pub struct WheelOdometryMotionPrior {
+    odometry: WheelOdometry,
+    measurements: Vec<WheelOdometryMeasurement>,
+}
+
+impl WheelOdometryMotionPrior {
+    pub fn new(odometry: WheelOdometry) -> Self {
+        Self {
+            odometry,
+            measurements: vec![],
+        }
+    }
+
+    pub fn odometry(&self) -> &WheelOdometry {
+        &self.odometry
+    }
+
+    pub fn add_measurement(&mut self, measurement: WheelOdometryMeasurement) -> Result<()> {
+        if let Some(last) = self.measurements.last() {
+            if measurement.timestamp_seconds <= last.timestamp_seconds {
+                bail!(
+                    "wheel odometry measurement at {} not after {}",
+                    measurement.timestamp_seconds,
+                    last.timestamp_seconds
+                );
+            }
+        }
+        self.measurements.push(measurement);
+        Ok(())
+    }
+
+    // robot frame at timestamp_end to robot frame at timestamp_begin with its covariance
+    pub fn relative_motion(
+        &self,
+        timestamp_begin: f64,
+        timestamp_end: f64,
+    ) -> Option<(Isometry3F64, Matrix6<f64>)> {
+        self.odometry
+            .relative_motion(&self.measurements, timestamp_begin, timestamp_end)
+    }
+
+    // drops measurements no longer needed for intervals starting at timestamp_seconds
+    pub fn remove_measurements_before(&mut self, timestamp_seconds: f64) {
+        if let Some(index) = self
+            .measurements
+            .iter()
+            .rposition(|measurement| measurement.timestamp_seconds <= timestamp_seconds)
+        {
+            self.measurements.drain(..index);
+        }
+    }
+}
+
+impl MotionPrior for WheelOdometryMotionPrior {
+    fn predict_robot_to_world(
+        &self,
+        previous_robot_to_world: &Isometry3F64,
+        previous_timestamp_seconds: f64,
+        timestamp_seconds: f64,
+    ) -> Option<Isometry3F64> {
+        let (robot_to_previous_robot, _) =
+            self.relative_motion(previous_timestamp_seconds, timestamp_seconds)?;
+        Some(previous_robot_to_world.group_mul(&robot_to_previous_robot))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_sensor::wheel_odometry::WheelOdometryNoiseParameters;
+    use sophus::{core::linalg::VecF64, nalgebra::Vector3};
+
+    fn motion_prior(timestamps: &[f64]) -> WheelOdometryMotionPrior {
+        let mut motion_prior = WheelOdometryMotionPrior::new(WheelOdometry::new(
+            WheelOdometryNoiseParameters::default(),
+            Isometry3F64::identity(),
+        ));
+        for timestamp_seconds in timestamps {
+            motion_prior
+                .add_measurement(WheelOdometryMeasurement::twist(
+                    *timestamp_seconds,
+                    Vector3::new(2.0, 0.0, 0.0),
+                    Vector3::new(0.0, 0.0, 0.4),
+                ))
+                .unwrap();
+        }
+        motion_prior
+    }
+
+    #[test]
+    fn predicts_the_pose_from_the_previous_frame() {
+        let motion_prior = motion_prior(&[0.0, 0.1, 0.2, 0.3]);
+        let previous_robot_to_world =
+            Isometry3F64::exp(&VecF64::<6>::new(1.0, 2.0, 0.0, 0.0, 0.0, 0.3));
+        let predicted = motion_prior
+            .predict_robot_to_world(&previous_robot_to_world, 0.05, 0.25)
+            .unwrap();
+        let expected = previous_robot_to_world.group_mul(&Isometry3F64::exp(&VecF64::<6>::new(
+            0.4, 0.0, 0.0, 0.0, 0.0, 0.08,
+        )));
+        assert!(predicted.inverse().group_mul(&expected).log().norm() < 1e-12);
+        assert!(motion_prior
+            .predict_robot_to_world(&previous_robot_to_world, 0.25, 0.35)
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_measurements_out_of_order() {
+        let mut motion_prior = motion_prior(&[0.0, 0.1]);
+        let measurement = WheelOdometryMeasurement::twist(0.1, Vector3::zeros(), Vector3::zeros());
+        assert!(motion_prior.add_measurement(measurement).is_err());
+    }
+
+    #[test]
+    fn keeps_the_measurement_starting_the_next_interval() {
+        let mut motion_prior = motion_prior(&[0.0, 0.1, 0.2, 0.3]);
+        motion_prior.remove_measurements_before(0.15);
+        assert!(motion_prior.relative_motion(0.15, 0.3).is_some());
+        assert!(motion_prior.relative_motion(0.05, 0.3).is_none());
+    }
+}
@@ -0,0 +1,231 @@
+//! georeferenced trajectory export for GIS tools, the world frame is the local tangent plane
+
+use std::{fmt::Write, path::Path};
+
+use anyhow::{Context, Result};
+use rslam_sensor::gnss::{GeodeticCoordinates, LocalTangentPlane};
+use sophus::lie::{prelude::IsTranslationProductGroup, Isometry3F64};
+
+fn geodetic_trajectory(
+    robot_to_world: &[Isometry3F64],
+    tangent_plane: &LocalTangentPlane,
+) -> Vec<GeodeticCoordinates> {
+    robot_to_world
+        .iter()
+        .map(|pose| tangent_plane.enu_to_geodetic(&pose.translation()))
+        .collect()
+}
+
+// quoted JSON string, Debug formatting emits Rust escapes like \u{7f} which are invalid JSON
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// GeoJSON feature collection with the trajectory as line string (longitude, latitude, altitude)
+pub fn format_geojson(
+    name: &str,
+    robot_to_world: &[Isometry3F64],
+    tangent_plane: &LocalTangentPlane,
+) -> String {
+    let coordinates: Vec<String> = geodetic_trajectory(robot_to_world, tangent_plane)
+        .iter()
+        .map(|geodetic| {
+            format!(
+                "[{:.9}, {:.9}, {:.3}]",
+                geodetic.longitude_degrees, geodetic.latitude_degrees, geodetic.altitude_meters
+            )
+        })
+        .collect();
+    let mut geojson = String::new();
+    writeln!(geojson, "{{").unwrap();
+    writeln!(geojson, "  \"type\": \"FeatureCollection\",").unwrap();
+    writeln!(geojson, "  \"features\": [{{").unwrap();
+    writeln!(geojson, "    \"type\": \"Feature\",").unwrap();
+    writeln!(
+        geojson,
+        "    \"properties\": {{ \"name\": {} }},",
+        json_string(name)
+    )
+    .unwrap();
+    writeln!(geojson, "    \"geometry\": {{").unwrap();
+    writeln!(geojson, "      \"type\": \"LineString\",").unwrap();
+    writeln!(
+        geojson,
+        "      \"coordinates\": [{}]",
+        coordinates.join(", ")
+    )
+    .unwrap();
+    writeln!(geojson, "    }}").unwrap();
+    writeln!(geojson, "  }}]").unwrap();
+    writeln!(geojson, "}}").unwrap();
+    geojson
+}
+
+pub fn write_geojson<P: AsRef<Path>>(
+    path: P,
+    name: &str,
+    robot_to_world: &[Isometry3F64],
+    tangent_plane: &LocalTangentPlane,
+) -> Result<()> {
+    let path = path.as_ref();
+    std::fs::write(path, format_geojson(name, robot_to_world, tangent_plane))
+        .with_context(|| format!("writing {:?}", path))
+}
+
+// KML document with the trajectory as absolute altitude line string
+pub fn format_kml(
+    name: &str,
+    robot_to_world: &[Isometry3F64],
+    tangent_plane: &LocalTangentPlane,
+) -> String {
+    let coordinates: Vec<String> = geodetic_trajectory(robot_to_world, tangent_plane)
+        .iter()
+        .map(|geodetic| {
+            format!(
+                "{:.9},{:.9},{:.3}",
+                geodetic.longitude_degrees, geodetic.latitude_degrees, geodetic.altitude_meters
+            )
+        })
+        .collect();
+    let name = name
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    let mut kml = String::new();
+    writeln!(kml, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+    writeln!(kml, "<kml xmlns=\"http://www.opengis.net/kml/2.2\">").unwrap();
+    writeln!(kml, "  <Document>").unwrap();
+    writeln!(kml, "    <name>{}</name>", name).unwrap();
+    writeln!(kml, "    <Placemark>").unwrap();
+    writeln!(kml, "      <name>{}</name>", name).unwrap();
+    writeln!(kml, "      <LineString>").unwrap();
+    writeln!(kml, "        <altitudeMode>absolute</altitudeMode>").unwrap();
+    writeln!(
+        kml,
+        "        <coordinates>{}</coordinates>",
+        coordinates.join(" ")
+    )
+    .unwrap();
+    writeln!(kml, "      </LineString>").unwrap();
+    writeln!(kml, "    </Placemark>").unwrap();
+    writeln!(kml, "  </Document>").unwrap();
+    writeln!(kml, "</kml>").unwrap();
+    kml
+}
+
+pub fn write_kml<P: AsRef<Path>>(
+    path: P,
+    name: &str,
+    robot_to_world: &[Isometry3F64],
+    tangent_plane: &LocalTangentPlane,
+) -> Result<()> {
+    let path = path.as_ref();
+    std::fs::write(path, format_kml(name, robot_to_world, tangent_plane))
+        .with_context(|| format!("writing {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_sensor::gnss::GeodeticCoordinates;
+    use sophus::{core::linalg::VecF64, lie::Rotation3F64};
+
+    fn tangent_plane() -> LocalTangentPlane {
+        LocalTangentPlane::new(GeodeticCoordinates::new(49.011, 8.4237, 116.4))
+    }
+
+    // a few hundred meters east and north, climbing
+    fn trajectory() -> Vec<Isometry3F64> {
+        (0..5)
+            .map(|index| {
+                let index = index as f64;
+                Isometry3F64::from_translation_and_rotation(
+                    &VecF64::<3>::new(100.0 * index, 50.0 * index, 2.0 * index),
+                    &Rotation3F64::rot_z(0.1 * index),
+                )
+            })
+            .collect()
+    }
+
+    fn assert_coordinates(coordinates: &[[f64; 3]]) {
+        let expected = geodetic_trajectory(&trajectory(), &tangent_plane());
+        assert_eq!(coordinates.len(), expected.len());
+        for (coordinates, expected) in coordinates.iter().zip(expected) {
+            assert!((coordinates[0] - expected.longitude_degrees).abs() < 1e-9);
+            assert!((coordinates[1] - expected.latitude_degrees).abs() < 1e-9);
+            assert!((coordinates[2] - expected.altitude_meters).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn writes_parsable_geojson() {
+        let name = "kitti \"00\"\n\u{1}";
+        let geojson: serde_json::Value =
+            serde_json::from_str(&format_geojson(name, &trajectory(), &tangent_plane())).unwrap();
+
+        assert_eq!(geojson["type"], "FeatureCollection");
+        let feature = &geojson["features"][0];
+        assert_eq!(feature["type"], "Feature");
+        assert_eq!(feature["properties"]["name"], name);
+        assert_eq!(feature["geometry"]["type"], "LineString");
+        let coordinates: Vec<[f64; 3]> =
+            serde_json::from_value(feature["geometry"]["coordinates"].clone()).unwrap();
+        assert_coordinates(&coordinates);
+    }
+
+    // text of the first element with the tag
+    fn element<'a>(xml: &'a str, tag: &str) -> &'a str {
+        let begin = xml.find(&format!("<{}>", tag)).unwrap() + tag.len() + 2;
+        let end = begin + xml[begin..].find(&format!("</{}>", tag)).unwrap();
+        &xml[begin..end]
+    }
+
+    #[test]
+    fn writes_parsable_kml() {
+        let kml = format_kml("a<b>&c", &trajectory(), &tangent_plane());
+        for tag in ["kml", "Document", "Placemark", "LineString"] {
+            assert_eq!(
+                kml.matches(&format!("<{}", tag)).count(),
+                kml.matches(&format!("</{}>", tag)).count()
+            );
+        }
+        assert_eq!(element(&kml, "name"), "a&lt;b&gt;&amp;c");
+        assert_eq!(element(&kml, "altitudeMode"), "absolute");
+
+        // longitude,latitude,altitude tuples separated by whitespace
+        let coordinates: Vec<[f64; 3]> = element(&kml, "coordinates")
+            .split_whitespace()
+            .map(|tuple| {
+                let values: Vec<f64> = tuple
+                    .split(',')
+                    .map(|value| value.parse().unwrap())
+                    .collect();
+                [values[0], values[1], values[2]]
+            })
+            .collect();
+        assert_coordinates(&coordinates);
+    }
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json_string("kitti 00"), "\"kitti 00\"");
+        assert_eq!(
+            json_string("a\"b\\c\nd\u{1}e'f\u{7f}g\u{e9}"),
+            "\"a\\\"b\\\\c\\nd\\u0001e'f\u{7f}g\u{e9}\""
+        );
+    }
+}
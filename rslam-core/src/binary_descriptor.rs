@@ -0,0 +1,146 @@
+/// binary descriptor packed into 64 bit words, bit i is bit i % 8 of byte i / 8 like the rows of
+/// an OpenCV descriptor matrix
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BinaryDescriptor<const WORDS: usize> {
+    words: [u64; WORDS],
+}
+
+// ORB, BRIEF-256, LATCH
+pub type BinaryDescriptor256 = BinaryDescriptor<4>;
+// BRISK, FREAK, BRIEF-512, AKAZE (486 bits)
+pub type BinaryDescriptor512 = BinaryDescriptor<8>;
+
+impl<const WORDS: usize> Default for BinaryDescriptor<WORDS> {
+    fn default() -> Self {
+        Self { words: [0; WORDS] }
+    }
+}
+
+impl<const WORDS: usize> BinaryDescriptor<WORDS> {
+    pub const BITS: usize = 64 * WORDS;
+    pub const BYTES: usize = 8 * WORDS;
+
+    pub fn from_words(words: [u64; WORDS]) -> Self {
+        Self { words }
+    }
+
+    // shorter descriptors are zero padded, None if the bytes don't fit
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > Self::BYTES {
+            return None;
+        }
+        let mut words = [0u64; WORDS];
+        for (index, byte) in bytes.iter().enumerate() {
+            words[index / 8] |= (*byte as u64) << (8 * (index % 8));
+        }
+        Some(Self { words })
+    }
+
+    pub fn words(&self) -> &[u64; WORDS] {
+        &self.words
+    }
+
+    // writes the first bytes.len() bytes
+    pub fn write_bytes(&self, bytes: &mut [u8]) {
+        for (index, byte) in bytes.iter_mut().take(Self::BYTES).enumerate() {
+            *byte = (self.words[index / 8] >> (8 * (index % 8))) as u8;
+        }
+    }
+
+    pub fn bit(&self, index: usize) -> bool {
+        self.words[index / 64] >> (index % 64) & 1 == 1
+    }
+
+    pub fn set_bit(&mut self, index: usize, value: bool) {
+        if value {
+            self.words[index / 64] |= 1 << (index % 64);
+        } else {
+            self.words[index / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    pub fn hamming_distance(&self, other: &Self) -> u32 {
+        crate::hamming_distance(&self.words, &other.words)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_the_bit_order_of_opencv() {
+        // OpenCV stores test k of a byte in bit k, least significant first
+        let mut bytes = [0u8; 32];
+        bytes[0] = 0b0000_0001;
+        bytes[1] = 0b1000_0000;
+        bytes[9] = 0b0000_0100;
+        let descriptor = BinaryDescriptor256::from_bytes(&bytes).unwrap();
+        let set_bits: Vec<_> = (0..BinaryDescriptor256::BITS)
+            .filter(|index| descriptor.bit(*index))
+            .collect();
+        assert_eq!(set_bits, vec![0, 15, 74]);
+        assert_eq!(descriptor.words(), &[1 << 15 | 1, 1 << 10, 0, 0]);
+    }
+
+    #[test]
+    fn round_trips_bytes() {
+        let bytes: Vec<u8> = (0..32).map(|index| (index * 37 + 11) as u8).collect();
+        let descriptor = BinaryDescriptor256::from_bytes(&bytes).unwrap();
+        let mut written = [0u8; 32];
+        descriptor.write_bytes(&mut written);
+        assert_eq!(written.as_slice(), bytes.as_slice());
+
+        for index in 0..BinaryDescriptor256::BITS {
+            assert_eq!(
+                descriptor.bit(index),
+                bytes[index / 8] >> (index % 8) & 1 == 1
+            );
+        }
+    }
+
+    #[test]
+    fn pads_short_descriptors() {
+        // AKAZE: 486 bits in 61 bytes, all set here
+        let descriptor = BinaryDescriptor512::from_bytes(&[0xff; 61]).unwrap();
+        assert_eq!(
+            (0..BinaryDescriptor512::BITS)
+                .filter(|index| descriptor.bit(*index))
+                .count(),
+            488
+        );
+        assert!(BinaryDescriptor256::from_bytes(&[0; 33]).is_none());
+
+        // only the requested bytes are written
+        let mut written = [0u8; 4];
+        descriptor.write_bytes(&mut written);
+        assert_eq!(written, [0xff; 4]);
+    }
+
+    #[test]
+    fn sets_bits_and_counts_differences() {
+        let mut descriptor = BinaryDescriptor256::default();
+        for index in [0, 63, 64, 255] {
+            descriptor.set_bit(index, true);
+        }
+        assert!(descriptor.bit(63) && descriptor.bit(64));
+        assert_eq!(
+            descriptor.hamming_distance(&BinaryDescriptor256::default()),
+            4
+        );
+        descriptor.set_bit(63, false);
+        assert!(!descriptor.bit(63));
+
+        // the hamming distance of the bytes as OpenCV computes it
+        let bytes_a: Vec<u8> = (0..32).map(|index| (index * 13) as u8).collect();
+        let bytes_b: Vec<u8> = (0..32).map(|index| (index * 29 + 7) as u8).collect();
+        let expected: u32 = bytes_a
+            .iter()
+            .zip(&bytes_b)
+            .map(|(a, b)| (a ^ b).count_ones())
+            .sum();
+        let a = BinaryDescriptor256::from_bytes(&bytes_a).unwrap();
+        let b = BinaryDescriptor256::from_bytes(&bytes_b).unwrap();
+        assert_eq!(a.hamming_distance(&b), expected);
+    }
+}
@@ -1,6 +1,27 @@
+use crate::{PixelCoordinates, PointCoordinates, Real};
+use sophus::nalgebra::Matrix2x3;
+
 pub trait Camera {
     fn rows(&self) -> usize;
     fn cols(&self) -> usize;
 
     fn camera_to_robot(&self) -> &sophus::lie::Isometry3F64;
+
+    // projects a point in camera coordinates onto the image plane,
+    // returns None for points on or behind the camera plane
+    fn project(&self, point_in_camera: &PointCoordinates) -> Option<PixelCoordinates>;
+
+    // back-projects a pixel to the point at the given depth (z) in camera coordinates
+    fn unproject(&self, pixel: &PixelCoordinates, depth: Real) -> PointCoordinates;
+
+    // jacobian of the projection w.r.t. the point in camera coordinates
+    fn dx_project_x(&self, point_in_camera: &PointCoordinates) -> Matrix2x3<Real>;
+
+    // checks if the pixel lies inside the image, keeping a margin (in pixels) to the border
+    fn is_in_image(&self, pixel: &PixelCoordinates, margin: Real) -> bool {
+        pixel.x >= margin
+            && pixel.y >= margin
+            && pixel.x < self.cols() as Real - margin
+            && pixel.y < self.rows() as Real - margin
+    }
 }
@@ -0,0 +1,331 @@
+//! hamming distances of packed binary descriptors, with AVX2 (runtime detected) and NEON kernels
+//! and a scalar fallback
+use std::ops::Range;
+
+/// binary descriptors packed into contiguous rows of 64 bit words, with the bit layout of
+/// `BinaryDescriptor`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackedDescriptors {
+    words_per_row: usize,
+    words: Vec<u64>,
+}
+
+impl PackedDescriptors {
+    pub fn new(words_per_row: usize) -> Self {
+        Self {
+            words_per_row,
+            words: Vec::new(),
+        }
+    }
+
+    pub fn words_for_bits(bits: usize) -> usize {
+        bits.div_ceil(64)
+    }
+
+    pub fn words_per_row(&self) -> usize {
+        self.words_per_row
+    }
+
+    pub fn len(&self) -> usize {
+        if self.words_per_row == 0 {
+            return 0;
+        }
+        self.words.len() / self.words_per_row
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.words.clear();
+    }
+
+    // discards the rows and changes the row width
+    pub fn reset(&mut self, words_per_row: usize) {
+        self.words.clear();
+        self.words_per_row = words_per_row;
+    }
+
+    pub fn row(&self, index: usize) -> &[u64] {
+        &self.words[index * self.words_per_row..(index + 1) * self.words_per_row]
+    }
+
+    pub fn words(&self) -> &[u64] {
+        &self.words
+    }
+
+    // zero padded to the row width, panics if the bytes don't fit
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        assert!(bytes.len() <= 8 * self.words_per_row);
+        let begin = self.words.len();
+        self.words.resize(begin + self.words_per_row, 0);
+        pack_bytes(bytes, &mut self.words[begin..]);
+    }
+
+    pub fn push_words(&mut self, words: &[u64]) {
+        assert_eq!(words.len(), self.words_per_row);
+        self.words.extend_from_slice(words);
+    }
+
+    // distances between the query and a range of rows
+    pub fn hamming_distances(&self, query: &[u64], rows: Range<usize>, distances: &mut Vec<u32>) {
+        assert_eq!(query.len(), self.words_per_row);
+        let candidates =
+            &self.words[rows.start * self.words_per_row..rows.end * self.words_per_row];
+        distances.clear();
+        distances.resize(rows.len(), 0);
+        hamming_distances(query, candidates, distances);
+    }
+
+    // distances between the query and arbitrary rows
+    pub fn hamming_distances_to_rows(
+        &self,
+        query: &[u64],
+        rows: &[usize],
+        distances: &mut Vec<u32>,
+    ) {
+        assert_eq!(query.len(), self.words_per_row);
+        distances.clear();
+        distances.resize(rows.len(), 0);
+        let candidates = rows.iter().map(|&row| self.row(row));
+        kernel::hamming_distances(query, candidates, distances);
+    }
+}
+
+// little endian bytes into words, bit i of the descriptor is bit i % 64 of word i / 64
+pub fn pack_bytes(bytes: &[u8], words: &mut [u64]) {
+    words.fill(0);
+    for (index, byte) in bytes.iter().enumerate() {
+        words[index / 8] |= (*byte as u64) << (8 * (index % 8));
+    }
+}
+
+pub fn hamming_distance(a: &[u64], b: &[u64]) -> u32 {
+    assert_eq!(a.len(), b.len());
+    let mut distance = [0];
+    kernel::hamming_distances(a, std::iter::once(b), &mut distance);
+    distance[0]
+}
+
+// distances between the query and the consecutive rows of query.len() words in candidates
+pub fn hamming_distances(query: &[u64], candidates: &[u64], distances: &mut [u32]) {
+    assert_eq!(candidates.len(), query.len() * distances.len());
+    if query.is_empty() {
+        distances.fill(0);
+        return;
+    }
+    kernel::hamming_distances(query, candidates.chunks_exact(query.len()), distances);
+}
+
+mod kernel {
+    pub fn hamming_distances<'a>(
+        query: &[u64],
+        candidates: impl Iterator<Item = &'a [u64]>,
+        distances: &mut [u32],
+    ) {
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: avx2 is available
+            unsafe { avx2::hamming_distances(query, candidates, distances) };
+            return;
+        }
+        #[cfg(target_arch = "aarch64")]
+        {
+            // SAFETY: neon is part of the aarch64 baseline
+            unsafe { neon::hamming_distances(query, candidates, distances) };
+            return;
+        }
+        #[allow(unreachable_code)]
+        scalar::hamming_distances(query, candidates, distances);
+    }
+
+    mod scalar {
+        pub fn hamming_distance(a: &[u64], b: &[u64]) -> u32 {
+            a.iter().zip(b).map(|(a, b)| (a ^ b).count_ones()).sum()
+        }
+
+        #[allow(dead_code)]
+        pub fn hamming_distances<'a>(
+            query: &[u64],
+            candidates: impl Iterator<Item = &'a [u64]>,
+            distances: &mut [u32],
+        ) {
+            for (candidate, distance) in candidates.zip(distances) {
+                *distance = hamming_distance(query, candidate);
+            }
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    mod avx2 {
+        use std::arch::x86_64::*;
+
+        // popcount of 256 bit blocks with a nibble lookup table, summed per 64 bit lane
+        #[target_feature(enable = "avx2")]
+        pub unsafe fn hamming_distances<'a>(
+            query: &[u64],
+            candidates: impl Iterator<Item = &'a [u64]>,
+            distances: &mut [u32],
+        ) {
+            let lookup = _mm256_setr_epi8(
+                0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3, 2, 3, 3, 4, 0, 1, 1, 2, 1, 2, 2, 3, 1, 2, 2, 3,
+                2, 3, 3, 4,
+            );
+            let low_mask = _mm256_set1_epi8(0x0f);
+            let number_of_blocks = query.len() / 4;
+
+            for (candidate, distance) in candidates.zip(distances) {
+                let mut sums = _mm256_setzero_si256();
+                for block in 0..number_of_blocks {
+                    let a = _mm256_loadu_si256(query.as_ptr().add(4 * block) as *const __m256i);
+                    let b = _mm256_loadu_si256(candidate.as_ptr().add(4 * block) as *const __m256i);
+                    let x = _mm256_xor_si256(a, b);
+                    let low = _mm256_shuffle_epi8(lookup, _mm256_and_si256(x, low_mask));
+                    let high = _mm256_shuffle_epi8(
+                        lookup,
+                        _mm256_and_si256(_mm256_srli_epi16(x, 4), low_mask),
+                    );
+                    let counts = _mm256_add_epi8(low, high);
+                    sums = _mm256_add_epi64(sums, _mm256_sad_epu8(counts, _mm256_setzero_si256()));
+                }
+                let mut lanes = [0u64; 4];
+                _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, sums);
+                *distance = lanes.iter().sum::<u64>() as u32
+                    + super::scalar::hamming_distance(
+                        &query[4 * number_of_blocks..],
+                        &candidate[4 * number_of_blocks..],
+                    );
+            }
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    mod neon {
+        use std::arch::aarch64::*;
+
+        // popcount of 128 bit blocks
+        #[target_feature(enable = "neon")]
+        pub unsafe fn hamming_distances<'a>(
+            query: &[u64],
+            candidates: impl Iterator<Item = &'a [u64]>,
+            distances: &mut [u32],
+        ) {
+            let number_of_blocks = query.len() / 2;
+
+            for (candidate, distance) in candidates.zip(distances) {
+                let mut sum = 0u32;
+                for block in 0..number_of_blocks {
+                    let a = vld1q_u8(query.as_ptr().add(2 * block) as *const u8);
+                    let b = vld1q_u8(candidate.as_ptr().add(2 * block) as *const u8);
+                    sum += vaddlvq_u8(vcntq_u8(veorq_u8(a, b))) as u32;
+                }
+                *distance = sum
+                    + super::scalar::hamming_distance(
+                        &query[2 * number_of_blocks..],
+                        &candidate[2 * number_of_blocks..],
+                    );
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // rows of 0 to 9 words cover full blocks and every tail length of both kernels
+        fn descriptors(words_per_row: usize, number_of_rows: usize) -> (Vec<u64>, Vec<u64>) {
+            let mut state = 0x853c49e6748fea9bu64;
+            let mut next = || {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state
+            };
+            let query = (0..words_per_row).map(|_| next()).collect();
+            let candidates = (0..words_per_row * number_of_rows)
+                .map(|_| next())
+                .collect();
+            (query, candidates)
+        }
+
+        fn scalar_distances(query: &[u64], candidates: &[u64], number_of_rows: usize) -> Vec<u32> {
+            let mut distances = vec![0; number_of_rows];
+            scalar::hamming_distances(
+                query,
+                candidates.chunks_exact(query.len().max(1)),
+                &mut distances,
+            );
+            distances
+        }
+
+        #[test]
+        fn dispatch_matches_scalar() {
+            for words_per_row in 1..10 {
+                let (query, candidates) = descriptors(words_per_row, 7);
+                let mut distances = vec![0; 7];
+                hamming_distances(
+                    &query,
+                    candidates.chunks_exact(words_per_row),
+                    &mut distances,
+                );
+                assert_eq!(distances, scalar_distances(&query, &candidates, 7));
+            }
+        }
+
+        #[test]
+        fn extreme_distances() {
+            for words_per_row in 1..10 {
+                let query = vec![0u64; words_per_row];
+                let candidates =
+                    [vec![0u64; words_per_row], vec![u64::MAX; words_per_row]].concat();
+                let mut distances = vec![0; 2];
+                hamming_distances(
+                    &query,
+                    candidates.chunks_exact(words_per_row),
+                    &mut distances,
+                );
+                assert_eq!(distances, [0, 64 * words_per_row as u32]);
+            }
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        #[test]
+        fn avx2_matches_scalar() {
+            if !is_x86_feature_detected!("avx2") {
+                return;
+            }
+            for words_per_row in 0..10 {
+                let (query, candidates) = descriptors(words_per_row, 7);
+                let mut distances = vec![0; 7];
+                // SAFETY: avx2 is available
+                unsafe {
+                    avx2::hamming_distances(
+                        &query,
+                        candidates.chunks_exact(words_per_row.max(1)),
+                        &mut distances,
+                    )
+                };
+                assert_eq!(distances, scalar_distances(&query, &candidates, 7));
+            }
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        #[test]
+        fn neon_matches_scalar() {
+            for words_per_row in 0..10 {
+                let (query, candidates) = descriptors(words_per_row, 7);
+                let mut distances = vec![0; 7];
+                // SAFETY: neon is part of the aarch64 baseline
+                unsafe {
+                    neon::hamming_distances(
+                        &query,
+                        candidates.chunks_exact(words_per_row.max(1)),
+                        &mut distances,
+                    )
+                };
+                assert_eq!(distances, scalar_distances(&query, &candidates, 7));
+            }
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::fmt::Debug;
+
+/// pixel types of single channel images
+pub trait Pixel: Copy + Default + PartialEq + Debug + Send + Sync + 'static {}
+
+impl Pixel for u8 {}
+impl Pixel for u16 {}
+impl Pixel for f32 {}
+
+/// owned single channel image, rows are `stride` pixels apart
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrayImage<T: Pixel = u8> {
+    data: Vec<T>,
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+
+impl<T: Pixel> GrayImage<T> {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            data: vec![T::default(); width * height],
+            width,
+            height,
+            stride: width,
+        }
+    }
+
+    // None if the buffer is too small for the given layout
+    pub fn from_vec(data: Vec<T>, width: usize, height: usize, stride: usize) -> Option<Self> {
+        if !is_valid_layout(data.len(), width, height, stride) {
+            return None;
+        }
+        Some(Self {
+            data,
+            width,
+            height,
+            stride,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+
+    pub fn row(&self, y: usize) -> &[T] {
+        &self.data[y * self.stride..y * self.stride + self.width]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [T] {
+        &mut self.data[y * self.stride..y * self.stride + self.width]
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> T {
+        self.data[y * self.stride + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, value: T) {
+        self.data[y * self.stride + x] = value;
+    }
+
+    pub fn view(&self) -> ImageView<'_, T> {
+        ImageView {
+            data: &self.data,
+            width: self.width,
+            height: self.height,
+            stride: self.stride,
+        }
+    }
+}
+
+/// borrowed single channel image, rows are `stride` pixels apart
+#[derive(Clone, Copy, Debug)]
+pub struct ImageView<'a, T: Pixel = u8> {
+    data: &'a [T],
+    width: usize,
+    height: usize,
+    stride: usize,
+}
+
+impl<'a, T: Pixel> ImageView<'a, T> {
+    // None if the buffer is too small for the given layout
+    pub fn new(data: &'a [T], width: usize, height: usize, stride: usize) -> Option<Self> {
+        if !is_valid_layout(data.len(), width, height, stride) {
+            return None;
+        }
+        Some(Self {
+            data,
+            width,
+            height,
+            stride,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    pub fn data(&self) -> &'a [T] {
+        self.data
+    }
+
+    pub fn row(&self, y: usize) -> &'a [T] {
+        &self.data[y * self.stride..y * self.stride + self.width]
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> T {
+        self.data[y * self.stride + x]
+    }
+
+    // region of the image sharing the buffer, None if it exceeds the image
+    pub fn sub_view(&self, x: usize, y: usize, width: usize, height: usize) -> Option<Self> {
+        if x + width > self.width || y + height > self.height {
+            return None;
+        }
+        if width == 0 || height == 0 {
+            return Some(Self {
+                data: &[],
+                width,
+                height,
+                stride: self.stride,
+            });
+        }
+        let begin = y * self.stride + x;
+        let end = (y + height - 1) * self.stride + x + width;
+        Some(Self {
+            data: &self.data[begin..end],
+            width,
+            height,
+            stride: self.stride,
+        })
+    }
+
+    // dense copy
+    pub fn to_image(&self) -> GrayImage<T> {
+        let mut data = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            data.extend_from_slice(self.row(y));
+        }
+        GrayImage {
+            data,
+            width: self.width,
+            height: self.height,
+            stride: self.width,
+        }
+    }
+}
+
+fn is_valid_layout(length: usize, width: usize, height: usize, stride: usize) -> bool {
+    stride >= width && (width == 0 || height == 0 || length >= (height - 1) * stride + width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 4x3 image in a buffer with stride 6, the padding pixels are 255
+    fn padded_buffer() -> Vec<u8> {
+        (0..3)
+            .flat_map(|y| (0..6).map(move |x| if x < 4 { (10 * y + x) as u8 } else { 255 }))
+            .collect()
+    }
+
+    #[test]
+    fn checks_the_layout() {
+        assert!(is_valid_layout(12, 4, 3, 4));
+        // the last row needs no padding
+        assert!(is_valid_layout(16, 4, 3, 6));
+        assert!(!is_valid_layout(15, 4, 3, 6));
+        assert!(!is_valid_layout(12, 4, 3, 3));
+        // empty images need no buffer
+        assert!(is_valid_layout(0, 0, 3, 0));
+        assert!(is_valid_layout(0, 4, 0, 4));
+    }
+
+    #[test]
+    fn rejects_short_buffers() {
+        assert!(GrayImage::from_vec(vec![0u8; 11], 4, 3, 4).is_none());
+        assert!(GrayImage::from_vec(vec![0u8; 12], 4, 3, 4).is_some());
+        assert!(ImageView::new(&[0u8; 15], 4, 3, 6).is_none());
+        assert!(ImageView::new(&[0u8; 16], 4, 3, 6).is_some());
+        assert!(ImageView::new(&[0u8; 12], 4, 3, 3).is_none());
+    }
+
+    #[test]
+    fn reads_strided_rows() {
+        let image = GrayImage::from_vec(padded_buffer(), 4, 3, 6).unwrap();
+        assert_eq!(image.row(1), &[10, 11, 12, 13]);
+        assert_eq!(image.get(3, 2), 23);
+        let view = image.view();
+        assert_eq!(view.stride(), 6);
+        assert_eq!(view.row(2), &[20, 21, 22, 23]);
+    }
+
+    #[test]
+    fn takes_sub_views_of_strided_images() {
+        let buffer = padded_buffer();
+        let view = ImageView::new(&buffer, 4, 3, 6).unwrap();
+
+        let sub_view = view.sub_view(1, 1, 2, 2).unwrap();
+        assert_eq!((sub_view.width(), sub_view.height()), (2, 2));
+        assert_eq!(sub_view.stride(), 6);
+        assert_eq!(sub_view.row(0), &[11, 12]);
+        assert_eq!(sub_view.row(1), &[21, 22]);
+        assert_eq!(sub_view.get(1, 1), 22);
+
+        // the bottom right corner ends at the last pixel of the image, not of the buffer
+        let corner = view.sub_view(2, 1, 2, 2).unwrap();
+        assert_eq!(corner.row(1), &[22, 23]);
+        assert_eq!(view.sub_view(0, 0, 4, 3).unwrap().row(2), view.row(2));
+        assert!(view.sub_view(3, 0, 2, 1).is_none());
+        assert!(view.sub_view(0, 2, 1, 2).is_none());
+        assert_eq!(view.sub_view(4, 3, 0, 0).unwrap().width(), 0);
+    }
+
+    #[test]
+    fn copies_views_densely() {
+        let buffer = padded_buffer();
+        let view = ImageView::new(&buffer, 4, 3, 6).unwrap();
+        let image = view.sub_view(1, 0, 3, 3).unwrap().to_image();
+        assert_eq!(image.stride(), 3);
+        assert_eq!(image.data(), &[1, 2, 3, 11, 12, 13, 21, 22, 23]);
+        assert_eq!(
+            image,
+            GrayImage::from_vec(vec![1, 2, 3, 11, 12, 13, 21, 22, 23], 3, 3, 3).unwrap()
+        );
+    }
+}
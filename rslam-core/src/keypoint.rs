@@ -0,0 +1,33 @@
+use sophus::nalgebra::Vector2;
+
+use crate::{PixelCoordinates, Real};
+
+/// image keypoint in the layout of `cv::KeyPoint`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keypoint {
+    // pixel coordinates (u, v)
+    pub pt: Vector2<f32>,
+    // diameter of the meaningful neighborhood
+    pub size: f32,
+    // orientation in degrees [0, 360), -1 if not computed
+    pub angle: f32,
+    pub response: f32,
+    // pyramid level
+    pub octave: i32,
+}
+
+impl Keypoint {
+    pub fn new(x: f32, y: f32, size: f32, response: f32) -> Self {
+        Self {
+            pt: Vector2::new(x, y),
+            size,
+            angle: -1.0,
+            response,
+            octave: 0,
+        }
+    }
+
+    pub fn pixel_coordinates(&self) -> PixelCoordinates {
+        PixelCoordinates::new(self.pt.x as Real, self.pt.y as Real)
+    }
+}
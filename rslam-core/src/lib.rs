@@ -4,9 +4,20 @@ mod camera;
 pub use camera::*;
 pub mod frame;
 pub mod framepoint;
+mod image;
+pub use image::*;
+mod keypoint;
+pub use keypoint::*;
+mod binary_descriptor;
+pub use binary_descriptor::*;
+mod hamming;
+pub use hamming::*;
+#[cfg(feature = "opencv")]
+mod opencv_conversions;
 
-use sophus::nalgebra::Vector3;
+use sophus::nalgebra::{Vector2, Vector3};
 
 pub type Real = f64;
 pub type PointCoordinates = Vector3<Real>;
 pub type ImageCoordinates = Vector3<Real>;
+pub type PixelCoordinates = Vector2<Real>;
@@ -0,0 +1,119 @@
+//! conversions between the core image types and OpenCV, images share their buffers
+use std::ffi::c_void;
+
+use opencv::{
+    boxed_ref::BoxedRef,
+    core::{DataType, KeyPoint, KeyPointTraitConst, Mat, MatTraitConst, StsBadArg},
+    Error,
+};
+
+use crate::{BinaryDescriptor, GrayImage, ImageView, Keypoint, Pixel};
+
+impl<'a, T: Pixel + DataType> ImageView<'a, T> {
+    // borrows the pixels of a 2D single channel matrix of the pixel type
+    pub fn from_mat(mat: &'a Mat) -> opencv::Result<Self> {
+        if mat.empty() {
+            return Ok(ImageView::new(&[], 0, 0, 0).unwrap());
+        }
+        if mat.dims() != 2 || mat.typ() != T::opencv_type() {
+            return Err(Error::new(
+                StsBadArg,
+                format!(
+                    "expected a 2D matrix of type {}, got type {} with {} dimensions",
+                    T::opencv_type(),
+                    mat.typ(),
+                    mat.dims()
+                ),
+            ));
+        }
+        let width = mat.cols() as usize;
+        let height = mat.rows() as usize;
+        let stride = mat.step1(0)?;
+        let length = (height - 1) * stride + width;
+        // the matrix keeps the buffer alive and unchanged while it is borrowed
+        let data = unsafe { std::slice::from_raw_parts(mat.data() as *const T, length) };
+        Ok(ImageView::new(data, width, height, stride).unwrap())
+    }
+
+    // matrix header on the pixels of the view
+    pub fn to_mat(&self) -> opencv::Result<BoxedRef<'a, Mat>> {
+        if self.width() == 0 || self.height() == 0 {
+            return Ok(Mat::default().into());
+        }
+        let mat = unsafe {
+            Mat::new_rows_cols_with_data_unsafe(
+                self.height() as i32,
+                self.width() as i32,
+                T::opencv_type(),
+                self.data().as_ptr() as *mut c_void,
+                self.stride() * std::mem::size_of::<T>(),
+            )?
+        };
+        Ok(mat.into())
+    }
+}
+
+impl<T: Pixel + DataType> GrayImage<T> {
+    // copies the pixels of a 2D single channel matrix of the pixel type
+    pub fn from_mat(mat: &Mat) -> opencv::Result<Self> {
+        Ok(ImageView::<T>::from_mat(mat)?.to_image())
+    }
+
+    pub fn to_mat(&self) -> opencv::Result<BoxedRef<'_, Mat>> {
+        self.view().to_mat()
+    }
+}
+
+impl Keypoint {
+    pub fn from_opencv(keypoint: &KeyPoint) -> Self {
+        Self {
+            pt: sophus::nalgebra::Vector2::new(keypoint.pt().x, keypoint.pt().y),
+            size: keypoint.size(),
+            angle: keypoint.angle(),
+            response: keypoint.response(),
+            octave: keypoint.octave(),
+        }
+    }
+
+    pub fn to_opencv(&self) -> opencv::Result<KeyPoint> {
+        KeyPoint::new_coords(
+            self.pt.x,
+            self.pt.y,
+            self.size,
+            self.angle,
+            self.response,
+            self.octave,
+            -1,
+        )
+    }
+}
+
+impl<const WORDS: usize> BinaryDescriptor<WORDS> {
+    // descriptor in a row of an 8 bit descriptor matrix
+    pub fn from_mat_row(descriptors: &Mat, row: i32) -> opencv::Result<Self> {
+        let bytes = descriptors.at_row::<u8>(row)?;
+        Self::from_bytes(bytes).ok_or_else(|| {
+            Error::new(
+                StsBadArg,
+                format!(
+                    "descriptor of {} bytes exceeds {} bytes",
+                    bytes.len(),
+                    Self::BYTES
+                ),
+            )
+        })
+    }
+
+    // one row of BYTES bytes per descriptor
+    pub fn to_mat(descriptors: &[Self]) -> opencv::Result<Mat> {
+        let mut bytes = vec![0u8; descriptors.len() * Self::BYTES];
+        for (descriptor, row) in descriptors.iter().zip(bytes.chunks_exact_mut(Self::BYTES)) {
+            descriptor.write_bytes(row);
+        }
+        Ok(Mat::from_slice_rows_cols(
+            &bytes,
+            descriptors.len(),
+            Self::BYTES,
+        )?)
+    }
+}
@@ -1,13 +1,24 @@
+use anyhow::{bail, Result};
 use opencv::{
     imgcodecs::{imread, IMREAD_GRAYSCALE},
     prelude::*,
 };
-use rslam_core::Camera;
-use rslam_sensor::{pinhole_camera::PinholeCamera, HasStereoCamera};
+use rslam_sensor::{
+    pinhole_camera::PinholeCamera,
+    sensor_source::{SensorCalibration, SensorData, SensorId, SensorMessage, SensorSource},
+    stereo_camera::StereoCamera,
+};
 use sophus::{
-    core::linalg::VecF64, image::ImageSize, lie::Isometry3F64, nalgebra::Vector3, sensor::camera_enum::perspective_camera::PinholeCameraF64
+    core::linalg::VecF64, image::ImageSize, lie::Isometry3F64, nalgebra::Vector3,
+    sensor::camera_enum::perspective_camera::PinholeCameraF64,
+};
+use std::{
+    io::BufRead,
+    path::{Path, PathBuf},
+    sync::Arc,
 };
-use std::{io::BufRead, path::PathBuf};
+
+const STEREO_SENSOR_ID: &str = "stereo";
 
 pub struct KittiReader {
     dataset_path: PathBuf,
@@ -15,7 +26,8 @@ pub struct KittiReader {
     cameras_pos: Vec<Isometry3F64>,
     timestamp: Vec<f64>,
     current_frame_index: usize,
-    pub baseline_pixel: Vector3<f64>,
+    stereo_camera: Option<StereoCamera>,
+    stereo_calibration: Option<Arc<SensorCalibration>>,
 }
 
 impl KittiReader {
@@ -26,16 +38,11 @@ impl KittiReader {
             cameras_pos: vec![],
             timestamp: vec![],
             current_frame_index: 0,
-            baseline_pixel: Vector3::zeros(),
+            stereo_camera: None,
+            stereo_calibration: None,
         }
     }
 
-    pub fn get_timestamp(&mut self) -> f64 {
-        let t = self.timestamp[self.current_frame_index];
-        self.current_frame_index += 1;
-        t
-    }
-
     pub fn get_cameras(&self) -> &Vec<PinholeCamera> {
         &self.cameras
     }
@@ -44,12 +51,19 @@ impl KittiReader {
         &self.cameras_pos
     }
 
-    pub fn load_camera(&mut self) {
+    pub fn get_stereo_camera(&self) -> Option<&StereoCamera> {
+        self.stereo_camera.as_ref()
+    }
+
+    pub fn load_camera(&mut self) -> Result<()> {
         let calib_file_path = self.dataset_path.join("calib.txt");
 
-        let file = std::fs::File::open(&calib_file_path).unwrap();
+        let file = std::fs::File::open(&calib_file_path)?;
         let file = std::io::BufReader::new(file);
 
+        // translation of each camera w.r.t. the rectified reference camera (meters)
+        let mut camera_translations = vec![];
+
         let lines = file.lines().filter_map(|l| l.ok());
         for (i, mut l) in lines.enumerate() {
             let init_image = self
@@ -65,8 +79,8 @@ impl KittiReader {
                 let cx = message_collect[2].parse::<f64>().unwrap();
                 let cy = message_collect[6].parse::<f64>().unwrap();
                 let x = message_collect[3].parse::<f64>().unwrap();
-                let y =  message_collect[7].parse::<f64>().unwrap();
-                let z =  message_collect[11].parse::<f64>().unwrap();
+                let y = message_collect[7].parse::<f64>().unwrap();
+                let z = message_collect[11].parse::<f64>().unwrap();
 
                 let camera = PinholeCameraF64::from_params_and_size(
                     &VecF64::<4>::new(fx, fy, cx, cy),
@@ -74,19 +88,34 @@ impl KittiReader {
                 );
                 log::debug!("loaded camera calibration matrix: {:?}", camera);
                 self.cameras.push(PinholeCamera::new(camera));
-                if x != 0.0 {
-                    let baseline_pixels = Vector3::<f64>::new(x, y, z);
-                    log::debug!("with baseline (pixels): {}", baseline_pixels.transpose());
-                    self.baseline_pixel = baseline_pixels;
-                }
+
+                // the fourth column of the projection matrix is K * t
+                let translation = Vector3::<f64>::new((x - cx * z) / fx, (y - cy * z) / fy, z);
+                log::debug!("with translation (m): {}", translation.transpose());
+                camera_translations.push(translation);
                 self.cameras_pos
-                    .push(Isometry3F64::from_translation(&VecF64::<3>::new(
-                        x,
-                        y,
-                        z,
-                    )));
+                    .push(Isometry3F64::from_translation(&VecF64::<3>::new(x, y, z)));
             }
         }
+
+        if self.cameras.len() < 2 {
+            bail!("stereo calibration not found in {:?}", calib_file_path);
+        }
+        let left_to_right =
+            Isometry3F64::from_translation(&(camera_translations[1] - camera_translations[0]));
+        let stereo_camera = StereoCamera::new(
+            self.cameras[0].clone(),
+            self.cameras[1].clone(),
+            left_to_right,
+            Isometry3F64::identity(),
+        )?;
+        log::debug!("baseline (m): {}", stereo_camera.baseline_meters());
+        self.stereo_calibration = Some(Arc::new(SensorCalibration::StereoCamera(
+            stereo_camera.clone(),
+        )));
+        self.stereo_camera = Some(stereo_camera);
+
+        Ok(())
     }
 
     pub fn load_timestamp(&mut self) {
@@ -103,10 +132,21 @@ impl KittiReader {
     }
 }
 
-impl HasStereoCamera for &mut KittiReader {
-    type FrameItem = Mat;
+impl SensorSource for KittiReader {
+    type Image = Mat;
+
+    fn sensor_ids(&self) -> Vec<SensorId> {
+        vec![SensorId::new(STEREO_SENSOR_ID)]
+    }
+
+    fn next_message(&mut self) -> Result<Option<SensorMessage<Mat>>> {
+        if self.stereo_calibration.is_none() || self.timestamp.is_empty() {
+            bail!("camera calibration and timestamps must be loaded first");
+        }
+        let Some(&timestamp_seconds) = self.timestamp.get(self.current_frame_index) else {
+            return Ok(None);
+        };
 
-    fn get_stereo_frame(self) -> Option<(Self::FrameItem, Self::FrameItem)> {
         let left_image_path = self
             .dataset_path
             .join("image_0")
@@ -120,17 +160,86 @@ impl HasStereoCamera for &mut KittiReader {
             left_image_path,
             right_image_path
         );
-        if let (Ok(left_image), Ok(right_image)) = (
-            imread(&left_image_path.display().to_string(), IMREAD_GRAYSCALE),
-            imread(&right_image_path.display().to_string(), IMREAD_GRAYSCALE),
-        ) {
-            if left_image.empty() || right_image.empty() {
-                return None;
-            }
-            Some((left_image, right_image))
-        } else {
-            None
-        }
+        // the timestamps list the frame, so a missing image is an error rather than the end
+        let left = read_image(&left_image_path)?;
+        let right = read_image(&right_image_path)?;
+
+        let message = SensorMessage {
+            sensor_id: SensorId::new(STEREO_SENSOR_ID),
+            timestamp_seconds,
+            sequence: self.current_frame_index as u64,
+            calibration: self.stereo_calibration.clone(),
+            data: SensorData::StereoPair { left, right },
+        };
+        self.current_frame_index += 1;
+        Ok(Some(message))
+    }
+}
+
+fn read_image(image_path: &Path) -> Result<Mat> {
+    let image = imread(&image_path.display().to_string(), IMREAD_GRAYSCALE)?;
+    if image.empty() {
+        bail!("failed to read image {:?}", image_path);
     }
+    Ok(image)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // first two rows of calib.txt of sequence 00
+    const CALIB: &str = "P0: 7.188560000000e+02 0.000000000000e+00 6.071928000000e+02 0.000000000000e+00 0.000000000000e+00 7.188560000000e+02 1.852157000000e+02 0.000000000000e+00 0.000000000000e+00 0.000000000000e+00 1.000000000000e+00 0.000000000000e+00
+P1: 7.188560000000e+02 0.000000000000e+00 6.071928000000e+02 -3.861448000000e+02 0.000000000000e+00 7.188560000000e+02 1.852157000000e+02 0.000000000000e+00 0.000000000000e+00 0.000000000000e+00 1.000000000000e+00 0.000000000000e+00
+";
+
+    // sequence directory with the calibration, two timestamps and the given image files
+    fn sequence(name: &str, images: &[&str]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("kitti-{}-{}", name, std::process::id()));
+        for camera in ["image_0", "image_1"] {
+            std::fs::create_dir_all(path.join(camera)).unwrap();
+        }
+        std::fs::write(path.join("calib.txt"), CALIB).unwrap();
+        std::fs::write(path.join("times.txt"), "0.000000e+00\n1.036400e-01\n").unwrap();
+        for image in images {
+            std::fs::write(path.join(image), []).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn loads_the_stereo_calibration() {
+        let path = sequence("calibration", &["image_0/000000.png", "image_1/000000.png"]);
+        let mut reader = KittiReader::new(path.to_str().unwrap());
+        let loaded = reader.load_camera();
+        reader.load_timestamp();
+        std::fs::remove_dir_all(&path).unwrap();
+        loaded.unwrap();
+
+        let stereo_camera = reader.get_stereo_camera().unwrap();
+        assert!((stereo_camera.baseline_meters() - 0.5372).abs() < 1e-4);
+        assert_eq!(reader.sensor_ids(), vec![SensorId::new(STEREO_SENSOR_ID)]);
+        assert_eq!(reader.timestamp, vec![0.0, 0.10364]);
+    }
+
+    #[test]
+    fn fails_on_unreadable_images() {
+        // empty files can't be decoded
+        let path = sequence("unreadable", &["image_0/000000.png", "image_1/000000.png"]);
+        let mut reader = KittiReader::new(path.to_str().unwrap());
+        reader.load_camera().unwrap();
+        reader.load_timestamp();
+        let message = reader.next_message();
+        std::fs::remove_dir_all(&path).unwrap();
+
+        let error = message.unwrap_err().to_string();
+        assert!(error.contains("000000.png"), "{}", error);
+    }
+
+    #[test]
+    fn requires_calibration_and_timestamps() {
+        let mut reader = KittiReader::new("/nonexistent");
+        assert!(reader.load_camera().is_err());
+        assert!(reader.next_message().is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Kalibr `camchain.yaml` / `camchain-imucam.yaml` files
+//!
+//! The robot frame is the IMU frame if the camchain contains `T_cam_imu`, otherwise it is
+//! the frame of `cam0`.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sophus::{
+    core::linalg::VecF64, image::ImageSize, lie::Isometry3F64, nalgebra::Matrix4,
+    sensor::camera_enum::perspective_camera::PinholeCameraF64,
+};
+
+use rslam_core::Camera;
+
+use super::isometry_from_matrix;
+use crate::{distortion::Distortion, pinhole_camera::PinholeCamera, stereo_camera::StereoCamera};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KalibrCamera {
+    #[serde(rename = "T_cam_imu", default, skip_serializing_if = "Option::is_none")]
+    t_cam_imu: Option<[[f64; 4]; 4]>,
+    #[serde(rename = "T_cn_cnm1", default, skip_serializing_if = "Option::is_none")]
+    t_cn_cnm1: Option<[[f64; 4]; 4]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cam_overlaps: Option<Vec<usize>>,
+    camera_model: String,
+    #[serde(default)]
+    distortion_coeffs: Vec<f64>,
+    #[serde(default = "default_distortion_model")]
+    distortion_model: String,
+    intrinsics: Vec<f64>,
+    resolution: [usize; 2],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rostopic: Option<String>,
+}
+
+fn default_distortion_model() -> String {
+    String::from("none")
+}
+
+fn to_matrix(rows: &[[f64; 4]; 4]) -> Matrix4<f64> {
+    Matrix4::from_fn(|r, c| rows[r][c])
+}
+
+fn from_matrix(matrix: &Matrix4<f64>) -> [[f64; 4]; 4] {
+    let mut rows = [[0.0; 4]; 4];
+    for (r, row) in rows.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = matrix[(r, c)];
+        }
+    }
+    rows
+}
+
+fn distortion_from_kalibr(model: &str, coefficients: &[f64]) -> Result<Distortion> {
+    match (model, coefficients.len()) {
+        ("none", _) => Ok(Distortion::None),
+        ("radtan", 4) => Ok(Distortion::RadialTangential([
+            coefficients[0],
+            coefficients[1],
+            coefficients[2],
+            coefficients[3],
+            0.0,
+        ])),
+        ("equidistant", 4) => Ok(Distortion::Equidistant([
+            coefficients[0],
+            coefficients[1],
+            coefficients[2],
+            coefficients[3],
+        ])),
+        _ => bail!(
+            "unsupported distortion model: {} with {} coefficients",
+            model,
+            coefficients.len()
+        ),
+    }
+}
+
+fn distortion_to_kalibr(distortion: &Distortion) -> Result<(String, Vec<f64>)> {
+    match distortion {
+        Distortion::None => Ok((String::from("none"), vec![])),
+        Distortion::RadialTangential([k1, k2, p1, p2, k3]) => {
+            if *k3 != 0.0 {
+                bail!("kalibr radtan model does not support k3: {}", k3);
+            }
+            Ok((String::from("radtan"), vec![*k1, *k2, *p1, *p2]))
+        }
+        Distortion::Equidistant(coefficients) => {
+            Ok((String::from("equidistant"), coefficients.to_vec()))
+        }
+    }
+}
+
+// the camchain keys sorted by camera index (cam0, cam1, ..., cam10)
+fn sorted_cameras(camchain: BTreeMap<String, KalibrCamera>) -> Result<Vec<KalibrCamera>> {
+    let mut cameras = camchain
+        .into_iter()
+        .map(|(key, camera)| {
+            key.strip_prefix("cam")
+                .and_then(|index| index.parse::<usize>().ok())
+                .map(|index| (index, camera))
+                .ok_or_else(|| anyhow!("unexpected camchain entry: {}", key))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    cameras.sort_by_key(|(index, _)| *index);
+    for (expected, (index, _)) in cameras.iter().enumerate() {
+        if *index != expected {
+            bail!("camchain is missing cam{}", expected);
+        }
+    }
+    Ok(cameras.into_iter().map(|(_, camera)| camera).collect())
+}
+
+pub fn load_camchain<P: AsRef<Path>>(path: P) -> Result<Vec<PinholeCamera>> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+    parse_camchain(&content).with_context(|| format!("parsing {:?}", path))
+}
+
+pub fn parse_camchain(content: &str) -> Result<Vec<PinholeCamera>> {
+    let camchain: BTreeMap<String, KalibrCamera> = serde_yaml::from_str(content)?;
+    let kalibr_cameras = sorted_cameras(camchain)?;
+
+    let mut cameras: Vec<PinholeCamera> = vec![];
+    for (index, kalibr_camera) in kalibr_cameras.iter().enumerate() {
+        if kalibr_camera.camera_model != "pinhole" {
+            bail!(
+                "cam{}: unsupported camera model: {}",
+                index,
+                kalibr_camera.camera_model
+            );
+        }
+        let [fx, fy, cx, cy] = kalibr_camera.intrinsics[..] else {
+            bail!("cam{}: expected 4 intrinsics", index);
+        };
+        let model = PinholeCameraF64::from_params_and_size(
+            &VecF64::<4>::new(fx, fy, cx, cy),
+            ImageSize::new(kalibr_camera.resolution[0], kalibr_camera.resolution[1]),
+        );
+        let distortion = distortion_from_kalibr(
+            &kalibr_camera.distortion_model,
+            &kalibr_camera.distortion_coeffs,
+        )?;
+
+        let camera_to_robot = match (&kalibr_camera.t_cam_imu, &kalibr_camera.t_cn_cnm1) {
+            (Some(t_cam_imu), _) => isometry_from_matrix(&to_matrix(t_cam_imu))?.inverse(),
+            (None, Some(t_cn_cnm1)) if index > 0 => {
+                let previous_to_current = isometry_from_matrix(&to_matrix(t_cn_cnm1))?;
+                cameras[index - 1]
+                    .camera_to_robot()
+                    .group_mul(&previous_to_current.inverse())
+            }
+            (None, _) if index == 0 => Isometry3F64::identity(),
+            _ => bail!("cam{}: missing T_cn_cnm1", index),
+        };
+
+        let mut camera = PinholeCamera::new(model).with_distortion(distortion);
+        camera.set_camera_to_robot(camera_to_robot);
+        cameras.push(camera);
+    }
+    Ok(cameras)
+}
+
+// loads cam0 and cam1 of the camchain as stereo rig
+pub fn load_stereo_camera<P: AsRef<Path>>(path: P) -> Result<StereoCamera> {
+    stereo_camera_from_cameras(load_camchain(path)?)
+}
+
+pub fn parse_stereo_camera(content: &str) -> Result<StereoCamera> {
+    stereo_camera_from_cameras(parse_camchain(content)?)
+}
+
+fn stereo_camera_from_cameras(mut cameras: Vec<PinholeCamera>) -> Result<StereoCamera> {
+    if cameras.len() < 2 {
+        bail!("camchain contains {} camera(s), expected 2", cameras.len());
+    }
+    let right = cameras.remove(1);
+    let left = cameras.remove(0);
+    let left_to_right = right
+        .camera_to_robot()
+        .inverse()
+        .group_mul(left.camera_to_robot());
+    let rig_to_robot = *left.camera_to_robot();
+    StereoCamera::new(left, right, left_to_right, rig_to_robot)
+}
+
+pub fn write_camchain<P: AsRef<Path>>(path: P, cameras: &[PinholeCamera]) -> Result<()> {
+    let path = path.as_ref();
+    std::fs::write(path, format_camchain(cameras)?).with_context(|| format!("writing {:?}", path))
+}
+
+// the robot frame of the cameras is written as IMU frame (T_cam_imu)
+pub fn format_camchain(cameras: &[PinholeCamera]) -> Result<String> {
+    let mut camchain = BTreeMap::new();
+    for (index, camera) in cameras.iter().enumerate() {
+        let params = camera.model.params();
+        let image_size = camera.model.image_size();
+        let (distortion_model, distortion_coeffs) = distortion_to_kalibr(camera.distortion())?;
+        let robot_to_camera = camera.camera_to_robot().inverse();
+        let t_cn_cnm1 = if index > 0 {
+            Some(from_matrix(
+                &robot_to_camera
+                    .group_mul(cameras[index - 1].camera_to_robot())
+                    .matrix(),
+            ))
+        } else {
+            None
+        };
+        camchain.insert(
+            format!("cam{}", index),
+            KalibrCamera {
+                t_cam_imu: Some(from_matrix(&robot_to_camera.matrix())),
+                t_cn_cnm1,
+                cam_overlaps: None,
+                camera_model: String::from("pinhole"),
+                distortion_coeffs,
+                distortion_model,
+                intrinsics: vec![params[0], params[1], params[2], params[3]],
+                resolution: [image_size.width, image_size.height],
+                rostopic: Some(format!("/cam{}/image_raw", index)),
+            },
+        );
+    }
+    Ok(serde_yaml::to_string(&camchain)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sophus::lie::prelude::IsTranslationProductGroup;
+
+    // EuRoC MAV camchain-imucam (cam0 and cam1 of the vi-sensor)
+    const CAMCHAIN: &str = "
+cam0:
+  T_cam_imu:
+  - [0.0148655429818, -0.999880929698, 0.00414029679422, -0.0216401454975]
+  - [0.999557249008, 0.0149672133247, 0.025715529948, -0.064676986768]
+  - [-0.0257744366974, 0.00375618835797, 0.999660727178, 0.00981073058949]
+  - [0.0, 0.0, 0.0, 1.0]
+  cam_overlaps: [1]
+  camera_model: pinhole
+  distortion_coeffs: [-0.28340811, 0.07395907, 0.00019359, 1.76187114e-05]
+  distortion_model: radtan
+  intrinsics: [458.654, 457.296, 367.215, 248.375]
+  resolution: [752, 480]
+  rostopic: /cam0/image_raw
+cam1:
+  T_cam_imu:
+  - [0.0125552670891, -0.999755099723, 0.0182237714554, -0.0198435579556]
+  - [0.999598781151, 0.0130119051815, 0.0251588363115, 0.0453689425024]
+  - [-0.0253898008918, 0.0179005838253, 0.999517347078, 0.00786212447038]
+  - [0.0, 0.0, 0.0, 1.0]
+  T_cn_cnm1:
+  - [0.999997256477881, 0.002312067192424, 0.000376008102415, -0.110073808127187]
+  - [-0.002317135723281, 0.999898048506644, 0.014089835846648, 0.000399121547014]
+  - [-0.000343393120525, -0.014090668452714, 0.999900662637729, -0.000853702503357]
+  - [0.0, 0.0, 0.0, 1.0]
+  cam_overlaps: [0]
+  camera_model: pinhole
+  distortion_coeffs: [-0.28368365, 0.07451284, -0.00010473, -3.55590700e-05]
+  distortion_model: radtan
+  intrinsics: [457.587, 456.134, 379.999, 255.238]
+  resolution: [752, 480]
+  rostopic: /cam1/image_raw
+";
+
+    #[test]
+    fn parses_euroc_camchain() {
+        let cameras = parse_camchain(CAMCHAIN).unwrap();
+        assert_eq!(cameras.len(), 2);
+        let params = cameras[1].model.params();
+        assert_eq!(
+            [params[0], params[1], params[2], params[3]],
+            [457.587, 456.134, 379.999, 255.238]
+        );
+        assert_eq!(cameras[0].model.image_size().width, 752);
+        assert_eq!(
+            cameras[0].distortion(),
+            &Distortion::RadialTangential([
+                -0.28340811,
+                0.07395907,
+                0.00019359,
+                1.76187114e-05,
+                0.0
+            ])
+        );
+
+        // T_cn_cnm1 agrees with the transform between the T_cam_imu of both cameras
+        let left_to_right = cameras[1]
+            .camera_to_robot()
+            .inverse()
+            .group_mul(cameras[0].camera_to_robot());
+        assert!((left_to_right.translation().norm() - 0.1101).abs() < 1e-3);
+    }
+
+    #[test]
+    fn camchain_round_trip() {
+        let cameras = parse_camchain(CAMCHAIN).unwrap();
+        let parsed = parse_camchain(&format_camchain(&cameras).unwrap()).unwrap();
+        for (camera, parsed) in cameras.iter().zip(parsed.iter()) {
+            assert_eq!(camera.distortion(), parsed.distortion());
+            assert!((camera.model.params() - parsed.model.params()).norm() < 1e-12);
+            let difference = camera
+                .camera_to_robot()
+                .inverse()
+                .group_mul(parsed.camera_to_robot());
+            assert!(difference.log().norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn loads_stereo_camera() {
+        let path = std::env::temp_dir().join(format!("camchain-{}.yaml", std::process::id()));
+        std::fs::write(&path, CAMCHAIN).unwrap();
+        let stereo_camera = load_stereo_camera(&path);
+        std::fs::remove_file(&path).unwrap();
+        let stereo_camera = stereo_camera.unwrap();
+
+        assert!((stereo_camera.baseline_meters() - 0.1101).abs() < 1e-3);
+        // the rig frame is cam0, the robot frame the IMU frame of the camchain
+        let cameras = parse_camchain(CAMCHAIN).unwrap();
+        for (camera, expected) in [&stereo_camera.left, &stereo_camera.right]
+            .into_iter()
+            .zip(cameras.iter())
+        {
+            let difference = camera
+                .camera_to_robot()
+                .inverse()
+                .group_mul(expected.camera_to_robot());
+            assert!(difference.log().norm() < 1e-9);
+            assert_eq!(camera.distortion(), expected.distortion());
+        }
+        assert!(
+            (stereo_camera.rig_to_robot().matrix() - cameras[0].camera_to_robot().matrix()).norm()
+                < 1e-12
+        );
+
+        assert!(load_stereo_camera(&path).is_err());
+        let mono = CAMCHAIN.split("cam1:").next().unwrap();
+        assert!(parse_stereo_camera(mono).is_err());
+    }
+
+    #[test]
+    fn rejects_unsupported_cameras() {
+        assert!(parse_camchain(&CAMCHAIN.replace("pinhole", "omni")).is_err());
+        assert!(parse_camchain(&CAMCHAIN.replace("radtan", "fov")).is_err());
+        assert!(parse_camchain(&CAMCHAIN.replace("cam1:", "cam2:")).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+//! camera calibration: estimation from target observations and calibration file formats
+
+pub mod kalibr;
+pub mod opencv_yaml;
+pub mod stereo_calibrator;
+pub mod target;
+
+use anyhow::{anyhow, Result};
+use sophus::{
+    lie::{prelude::IsTranslationProductGroup, Isometry3F64, Rotation3F64},
+    nalgebra::{Matrix3, Matrix4, Rotation3, Vector3},
+};
+
+// builds an isometry from a homogeneous transform, re-orthonormalizing the rotation
+// since calibration files are usually written with limited precision
+pub(crate) fn isometry_from_rotation_and_translation(
+    rotation: &Matrix3<f64>,
+    translation: &Vector3<f64>,
+) -> Result<Isometry3F64> {
+    let rotation = Rotation3::from_matrix_eps(rotation, 1e-12, 100, Rotation3::identity());
+    let rotation = Rotation3F64::try_from_mat(rotation.matrix())
+        .ok_or_else(|| anyhow!("invalid rotation matrix: {}", rotation.matrix()))?;
+    Ok(Isometry3F64::from_translation_and_rotation(
+        translation,
+        &rotation,
+    ))
+}
+
+pub(crate) fn isometry_from_matrix(matrix: &Matrix4<f64>) -> Result<Isometry3F64> {
+    isometry_from_rotation_and_translation(
+        &matrix.fixed_view::<3, 3>(0, 0).into_owned(),
+        &matrix.fixed_view::<3, 1>(0, 3).into_owned(),
+    )
+}
+
+pub(crate) fn rotation_and_translation(isometry: &Isometry3F64) -> (Matrix3<f64>, Vector3<f64>) {
+    (isometry.rotation().matrix(), isometry.translation())
+}
@@ -0,0 +1,457 @@
+//! OpenCV `FileStorage` YAML stereo calibrations (K1, D1, K2, D2, R, T)
+//!
+//! R and T transform points from the first (left) into the second (right) camera,
+//! as returned by `cv::stereoCalibrate`. The robot frame is the left camera frame.
+//! The optional `distortion_model` key selects between the plumb bob model of `cv::calibrateCamera`
+//! (default) and the equidistant model of `cv::fisheye::calibrate` for both cameras.
+
+use std::{fmt::Write, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sophus::{
+    core::linalg::VecF64,
+    image::ImageSize,
+    lie::Isometry3F64,
+    nalgebra::{Matrix3, Vector3},
+    sensor::camera_enum::perspective_camera::PinholeCameraF64,
+};
+
+use super::{isometry_from_rotation_and_translation, rotation_and_translation};
+use crate::{distortion::Distortion, pinhole_camera::PinholeCamera, stereo_camera::StereoCamera};
+
+#[derive(Debug, Deserialize)]
+struct OpenCvMatrix {
+    rows: usize,
+    cols: usize,
+    data: Vec<f64>,
+}
+
+impl OpenCvMatrix {
+    fn check_size(&self, name: &str, rows: usize, cols: usize) -> Result<()> {
+        if self.rows != rows || self.cols != cols || self.data.len() != rows * cols {
+            bail!(
+                "{}: expected {}x{} matrix, got {}x{} with {} elements",
+                name,
+                rows,
+                cols,
+                self.rows,
+                self.cols,
+                self.data.len()
+            );
+        }
+        Ok(())
+    }
+
+    // row or column vector, possibly empty
+    fn check_vector(&self, name: &str) -> Result<()> {
+        if (self.rows != 1 && self.cols != 1 && self.rows * self.cols != 0)
+            || self.data.len() != self.rows * self.cols
+        {
+            bail!(
+                "{}: expected a row or column vector, got {}x{} matrix with {} elements",
+                name,
+                self.rows,
+                self.cols,
+                self.data.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+struct OpenCvStereoCalibration {
+    image_width: usize,
+    image_height: usize,
+    #[serde(default = "default_distortion_model")]
+    distortion_model: String,
+    #[serde(alias = "M1")]
+    K1: OpenCvMatrix,
+    D1: OpenCvMatrix,
+    #[serde(alias = "M2")]
+    K2: OpenCvMatrix,
+    D2: OpenCvMatrix,
+    R: OpenCvMatrix,
+    T: OpenCvMatrix,
+}
+
+const DEFAULT_DISTORTION_MODEL: &str = "plumb_bob";
+
+fn default_distortion_model() -> String {
+    String::from(DEFAULT_DISTORTION_MODEL)
+}
+
+fn distortion_from_opencv(name: &str, model: &str, d: &[f64]) -> Result<Distortion> {
+    let distortion = match (model, d.len()) {
+        (_, 0) => Distortion::None,
+        ("plumb_bob" | "radtan", 4) => Distortion::RadialTangential([d[0], d[1], d[2], d[3], 0.0]),
+        ("plumb_bob" | "radtan", 5) => Distortion::RadialTangential([d[0], d[1], d[2], d[3], d[4]]),
+        // rational and thin prism terms are only supported if they are zero
+        ("plumb_bob" | "radtan", n)
+            if n > 5 && d[5..].iter().all(|coefficient| *coefficient == 0.0) =>
+        {
+            Distortion::RadialTangential([d[0], d[1], d[2], d[3], d[4]])
+        }
+        ("equidistant" | "fisheye", 4) => Distortion::Equidistant([d[0], d[1], d[2], d[3]]),
+        _ => bail!(
+            "{}: unsupported distortion model: {} with {} coefficients",
+            name,
+            model,
+            d.len()
+        ),
+    };
+    Ok(distortion)
+}
+
+fn camera_from_opencv(
+    names: (&str, &str),
+    camera_matrix: &OpenCvMatrix,
+    distortion: &OpenCvMatrix,
+    distortion_model: &str,
+    image_size: ImageSize,
+) -> Result<PinholeCamera> {
+    camera_matrix.check_size(names.0, 3, 3)?;
+    let k = &camera_matrix.data;
+    let model = PinholeCameraF64::from_params_and_size(
+        &VecF64::<4>::new(k[0], k[4], k[2], k[5]),
+        image_size,
+    );
+
+    distortion.check_vector(names.1)?;
+    let distortion = distortion_from_opencv(names.1, distortion_model, &distortion.data)?;
+
+    Ok(PinholeCamera::new(model).with_distortion(distortion))
+}
+
+pub fn load_stereo_camera<P: AsRef<Path>>(path: P) -> Result<StereoCamera> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path).with_context(|| format!("reading {:?}", path))?;
+    parse_stereo_camera(&content).with_context(|| format!("parsing {:?}", path))
+}
+
+pub fn parse_stereo_camera(content: &str) -> Result<StereoCamera> {
+    // FileStorage writes a YAML 1.0 directive and custom tags which are not plain YAML
+    let content = content
+        .lines()
+        .filter(|line| !line.starts_with('%'))
+        .map(|line| line.replace("!!opencv-matrix", ""))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let calibration: OpenCvStereoCalibration = serde_yaml::from_str(&content)?;
+
+    let image_size = ImageSize::new(calibration.image_width, calibration.image_height);
+    let distortion_model = calibration.distortion_model.trim().to_ascii_lowercase();
+    let left = camera_from_opencv(
+        ("K1", "D1"),
+        &calibration.K1,
+        &calibration.D1,
+        &distortion_model,
+        image_size,
+    )?;
+    let right = camera_from_opencv(
+        ("K2", "D2"),
+        &calibration.K2,
+        &calibration.D2,
+        &distortion_model,
+        image_size,
+    )?;
+
+    calibration.R.check_size("R", 3, 3)?;
+    calibration.T.check_size("T", 3, 1)?;
+    let left_to_right = isometry_from_rotation_and_translation(
+        &Matrix3::from_row_slice(&calibration.R.data),
+        &Vector3::from_column_slice(&calibration.T.data),
+    )?;
+
+    StereoCamera::new(left, right, left_to_right, Isometry3F64::identity())
+}
+
+pub fn write_stereo_camera<P: AsRef<Path>>(path: P, stereo_camera: &StereoCamera) -> Result<()> {
+    let path = path.as_ref();
+    std::fs::write(path, format_stereo_camera(stereo_camera)?)
+        .with_context(|| format!("writing {:?}", path))
+}
+
+fn write_matrix(output: &mut String, name: &str, rows: usize, cols: usize, data: &[f64]) {
+    let data = data
+        .iter()
+        .map(|value| format!("{:e}", value))
+        .collect::<Vec<_>>()
+        .join(", ");
+    // writing to a String cannot fail
+    let _ = writeln!(
+        output,
+        "{}: !!opencv-matrix\n   rows: {}\n   cols: {}\n   dt: d\n   data: [ {} ]",
+        name, rows, cols, data
+    );
+}
+
+fn camera_matrix(camera: &PinholeCamera) -> [f64; 9] {
+    let params = camera.model.params();
+    [
+        params[0], 0.0, params[2], 0.0, params[1], params[3], 0.0, 0.0, 1.0,
+    ]
+}
+
+// both cameras have to share the distortion model, cameras without distortion fit either model
+fn distortion_model_name(stereo_camera: &StereoCamera) -> Result<&'static str> {
+    let name = |distortion: &Distortion| match distortion {
+        Distortion::None => None,
+        Distortion::RadialTangential(_) => Some("plumb_bob"),
+        Distortion::Equidistant(_) => Some("equidistant"),
+    };
+    match (
+        name(stereo_camera.left.distortion()),
+        name(stereo_camera.right.distortion()),
+    ) {
+        (Some(left), Some(right)) if left != right => bail!(
+            "the cameras use different distortion models: {} and {}",
+            left,
+            right
+        ),
+        (left, right) => Ok(left.or(right).unwrap_or(DEFAULT_DISTORTION_MODEL)),
+    }
+}
+
+pub fn format_stereo_camera(stereo_camera: &StereoCamera) -> Result<String> {
+    let distortion_model = distortion_model_name(stereo_camera)?;
+    let image_size = stereo_camera.left.model.image_size();
+    let (rotation, translation) = rotation_and_translation(stereo_camera.left_to_right());
+    let rotation: Vec<f64> = rotation.transpose().iter().copied().collect();
+    let distortion_left = stereo_camera.left.distortion().coefficients();
+    let distortion_right = stereo_camera.right.distortion().coefficients();
+
+    let mut output = String::from("%YAML:1.0\n---\n");
+    let _ = writeln!(output, "image_width: {}", image_size.width);
+    let _ = writeln!(output, "image_height: {}", image_size.height);
+    let _ = writeln!(output, "distortion_model: {}", distortion_model);
+    write_matrix(&mut output, "K1", 3, 3, &camera_matrix(&stereo_camera.left));
+    write_matrix(
+        &mut output,
+        "D1",
+        1,
+        distortion_left.len(),
+        &distortion_left,
+    );
+    write_matrix(
+        &mut output,
+        "K2",
+        3,
+        3,
+        &camera_matrix(&stereo_camera.right),
+    );
+    write_matrix(
+        &mut output,
+        "D2",
+        1,
+        distortion_right.len(),
+        &distortion_right,
+    );
+    write_matrix(&mut output, "R", 3, 3, &rotation);
+    write_matrix(&mut output, "T", 3, 1, translation.as_slice());
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_core::Camera;
+
+    const STEREO_CALIBRATION: &str = "%YAML:1.0
+---
+image_width: 1280
+image_height: 720
+K1: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 7.0e+02, 0., 6.4e+02, 0., 7.1e+02, 3.6e+02, 0., 0., 1. ]
+D1: !!opencv-matrix
+   rows: 1
+   cols: 5
+   dt: d
+   data: [ -0.1, 0.02, 0.001, -0.002, 0.003 ]
+K2: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 7.05e+02, 0., 6.3e+02, 0., 7.15e+02, 3.5e+02, 0., 0., 1. ]
+D2: !!opencv-matrix
+   rows: 1
+   cols: 8
+   dt: d
+   data: [ -0.11, 0.03, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0 ]
+R: !!opencv-matrix
+   rows: 3
+   cols: 3
+   dt: d
+   data: [ 1., 0., 0., 0., 1., 0., 0., 0., 1. ]
+T: !!opencv-matrix
+   rows: 3
+   cols: 1
+   dt: d
+   data: [ -0.12, 0., 0. ]
+";
+
+    #[test]
+    fn parses_stereo_calibration() {
+        let stereo_camera = parse_stereo_camera(STEREO_CALIBRATION).unwrap();
+        assert!((stereo_camera.baseline_meters() - 0.12).abs() < 1e-12);
+        assert_eq!(stereo_camera.left.cols(), 1280);
+        assert_eq!(stereo_camera.right.rows(), 720);
+        let params = stereo_camera.right.model.params();
+        assert_eq!(
+            [params[0], params[1], params[2], params[3]],
+            [705.0, 715.0, 630.0, 350.0]
+        );
+        assert_eq!(
+            stereo_camera.left.distortion(),
+            &Distortion::RadialTangential([-0.1, 0.02, 0.001, -0.002, 0.003])
+        );
+        // trailing zero rational coefficients are dropped
+        assert_eq!(
+            stereo_camera.right.distortion(),
+            &Distortion::RadialTangential([-0.11, 0.03, 0.0, 0.0, 0.0])
+        );
+    }
+
+    #[test]
+    fn stereo_calibration_round_trip() {
+        let stereo_camera = parse_stereo_camera(STEREO_CALIBRATION).unwrap();
+        let parsed = parse_stereo_camera(&format_stereo_camera(&stereo_camera).unwrap()).unwrap();
+        assert_eq!(stereo_camera.left.distortion(), parsed.left.distortion());
+        assert!((stereo_camera.right.model.params() - parsed.right.model.params()).norm() < 1e-9);
+        let difference = stereo_camera
+            .left_to_right()
+            .inverse()
+            .group_mul(parsed.left_to_right());
+        assert!(difference.log().norm() < 1e-9);
+    }
+
+    // rotated rig with fisheye lenses
+    fn equidistant_stereo_camera() -> StereoCamera {
+        let camera = |params: VecF64<4>, distortion: [f64; 4]| {
+            PinholeCamera::new(PinholeCameraF64::from_params_and_size(
+                &params,
+                ImageSize::new(640, 480),
+            ))
+            .with_distortion(Distortion::Equidistant(distortion))
+        };
+        let left_to_right =
+            Isometry3F64::exp(&VecF64::<6>::new(-0.1, 0.002, 0.01, 0.01, -0.02, 0.005));
+        StereoCamera::new(
+            camera(
+                VecF64::<4>::new(380.0, 381.0, 320.5, 240.5),
+                [0.01, -0.02, 0.003, -0.001],
+            ),
+            camera(
+                VecF64::<4>::new(379.0, 380.0, 318.0, 242.0),
+                [0.02, -0.01, 0.001, 0.0],
+            ),
+            left_to_right,
+            Isometry3F64::identity(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn equidistant_round_trip() {
+        let stereo_camera = equidistant_stereo_camera();
+        let content = format_stereo_camera(&stereo_camera).unwrap();
+        assert!(content.contains("distortion_model: equidistant"));
+        let parsed = parse_stereo_camera(&content).unwrap();
+        for (camera, parsed) in [
+            (&stereo_camera.left, &parsed.left),
+            (&stereo_camera.right, &parsed.right),
+        ] {
+            assert_eq!(camera.distortion(), parsed.distortion());
+            assert!((camera.model.params() - parsed.model.params()).norm() < 1e-9);
+            assert_eq!(camera.model.image_size(), parsed.model.image_size());
+        }
+        let difference = stereo_camera
+            .left_to_right()
+            .inverse()
+            .group_mul(parsed.left_to_right());
+        assert!(difference.log().norm() < 1e-9);
+
+        // without the key the coefficients are read as plumb bob and kept
+        let content = content.replace("distortion_model: equidistant\n", "");
+        assert!(matches!(
+            parse_stereo_camera(&content).unwrap().left.distortion(),
+            Distortion::RadialTangential(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_mixed_distortion_models() {
+        let mut stereo_camera = equidistant_stereo_camera();
+        stereo_camera.right = stereo_camera
+            .right
+            .clone()
+            .with_distortion(Distortion::RadialTangential([0.01, 0.0, 0.0, 0.0, 0.0]));
+        assert!(format_stereo_camera(&stereo_camera).is_err());
+
+        // a camera without distortion fits the model of the other one
+        stereo_camera.right = stereo_camera
+            .right
+            .clone()
+            .with_distortion(Distortion::None);
+        let parsed = parse_stereo_camera(&format_stereo_camera(&stereo_camera).unwrap()).unwrap();
+        assert_eq!(parsed.right.distortion(), &Distortion::None);
+        assert!(matches!(
+            parsed.left.distortion(),
+            Distortion::Equidistant(_)
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_distortion() {
+        for data in [
+            "[ -0.1 ]",
+            "[ -0.1, 0.02, 0.001 ]",
+            "[ -0.1, 0.02, 0.001, 0.0, 0.0, 0.5 ]",
+        ] {
+            let cols = data.split(',').count();
+            let content = STEREO_CALIBRATION
+                .replace("cols: 5", &format!("cols: {}", cols))
+                .replace("[ -0.1, 0.02, 0.001, -0.002, 0.003 ]", data);
+            assert!(parse_stereo_camera(&content).is_err(), "{}", data);
+        }
+
+        // five equidistant coefficients
+        let content = STEREO_CALIBRATION.replace(
+            "image_height: 720\n",
+            "image_height: 720\ndistortion_model: equidistant\n",
+        );
+        assert!(parse_stereo_camera(&content).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_distortion_matrices() {
+        // 2x3 matrix, and a row count which does not match the data
+        for (size, data) in [
+            (
+                "rows: 2\n   cols: 3",
+                "[ -0.1, 0.02, 0.001, -0.002, 0.003, 0.0 ]",
+            ),
+            (
+                "rows: 2\n   cols: 5",
+                "[ -0.1, 0.02, 0.001, -0.002, 0.003 ]",
+            ),
+        ] {
+            let content = STEREO_CALIBRATION
+                .replacen("rows: 1\n   cols: 5", size, 1)
+                .replace("[ -0.1, 0.02, 0.001, -0.002, 0.003 ]", data);
+            assert!(parse_stereo_camera(&content).is_err(), "{}", size);
+        }
+
+        // column vectors are accepted
+        let content = STEREO_CALIBRATION.replacen("rows: 1\n   cols: 5", "rows: 5\n   cols: 1", 1);
+        assert_eq!(
+            parse_stereo_camera(&content).unwrap().left.distortion(),
+            &Distortion::RadialTangential([-0.1, 0.02, 0.001, -0.002, 0.003])
+        );
+    }
+}
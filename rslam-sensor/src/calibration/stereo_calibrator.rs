@@ -0,0 +1,628 @@
+//! joint stereo calibration from planar target observations
+//!
+//! Intrinsics are initialized from target homographies (principal point at the image
+//! center, no distortion), then intrinsics, radial-tangential distortion of both cameras,
+//! the stereo extrinsic and all target poses are refined by Levenberg-Marquardt on the
+//! reprojection error.
+
+use anyhow::{anyhow, bail, Result};
+use rslam_core::{PixelCoordinates, PointCoordinates, Real};
+use sophus::{
+    core::linalg::VecF64,
+    image::ImageSize,
+    lie::{traits::HasAverage, Isometry3F64},
+    nalgebra::{DMatrix, DVector, Matrix2, Matrix3, SymmetricEigen, Vector2, Vector3},
+    sensor::camera_enum::perspective_camera::PinholeCameraF64,
+};
+
+use super::{isometry_from_rotation_and_translation, target::StereoObservation};
+use crate::{distortion::Distortion, pinhole_camera::PinholeCamera, stereo_camera::StereoCamera};
+
+// fx, fy, cx, cy, k1, k2, p1, p2, k3
+const NUMBER_OF_INTRINSICS: usize = 9;
+// both intrinsics and the stereo extrinsic
+const NUMBER_OF_GLOBAL_PARAMETERS: usize = 2 * NUMBER_OF_INTRINSICS + 6;
+
+#[derive(Clone, Debug)]
+pub struct StereoCalibratorCfg {
+    pub estimate_k3: bool,
+    pub maximum_iterations: usize,
+    // stop once the relative cost decrease falls below this
+    pub function_tolerance: Real,
+}
+
+impl Default for StereoCalibratorCfg {
+    fn default() -> Self {
+        Self {
+            estimate_k3: false,
+            maximum_iterations: 100,
+            function_tolerance: 1e-10,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ViewError {
+    pub name: String,
+    pub rms_left_pixels: Real,
+    pub rms_right_pixels: Real,
+}
+
+#[derive(Clone, Debug)]
+pub struct StereoCalibration {
+    pub stereo_camera: StereoCamera,
+    pub rms_pixels: Real,
+    pub view_errors: Vec<ViewError>,
+}
+
+#[derive(Clone)]
+struct State {
+    intrinsics_left: [Real; NUMBER_OF_INTRINSICS],
+    intrinsics_right: [Real; NUMBER_OF_INTRINSICS],
+    left_to_right: Isometry3F64,
+    target_to_left: Vec<Isometry3F64>,
+}
+
+impl State {
+    // applies an increment on the active global parameters and the poses of all views
+    fn plus(&self, global_indices: &[usize], delta: &DVector<Real>) -> State {
+        let mut state = self.clone();
+        let mut global = [0.0; NUMBER_OF_GLOBAL_PARAMETERS];
+        for (index, parameter) in global_indices.iter().enumerate() {
+            global[*parameter] = delta[index];
+        }
+        state.apply_global(&global);
+        for (view, target_to_left) in state.target_to_left.iter_mut().enumerate() {
+            let offset = global_indices.len() + 6 * view;
+            *target_to_left = Isometry3F64::exp(&VecF64::<6>::from_iterator(
+                delta.rows(offset, 6).iter().copied(),
+            ))
+            .group_mul(target_to_left);
+        }
+        state
+    }
+
+    fn apply_global(&mut self, global: &[Real; NUMBER_OF_GLOBAL_PARAMETERS]) {
+        for i in 0..NUMBER_OF_INTRINSICS {
+            self.intrinsics_left[i] += global[i];
+            self.intrinsics_right[i] += global[NUMBER_OF_INTRINSICS + i];
+        }
+        let delta = VecF64::<6>::from_row_slice(&global[2 * NUMBER_OF_INTRINSICS..]);
+        self.left_to_right = Isometry3F64::exp(&delta).group_mul(&self.left_to_right);
+    }
+
+    fn residuals(
+        &self,
+        observation: &StereoObservation,
+        target_to_left: &Isometry3F64,
+    ) -> Vec<Real> {
+        let mut residuals = Vec::with_capacity(4 * observation.points_in_target.len());
+        for ((point_in_target, pixel_left), pixel_right) in observation
+            .points_in_target
+            .iter()
+            .zip(observation.pixels_left.iter())
+            .zip(observation.pixels_right.iter())
+        {
+            let point_in_left = target_to_left.transform(point_in_target);
+            let point_in_right = self.left_to_right.transform(&point_in_left);
+            let error_left = project(&self.intrinsics_left, &point_in_left) - pixel_left;
+            let error_right = project(&self.intrinsics_right, &point_in_right) - pixel_right;
+            residuals.extend_from_slice(&[
+                error_left.x,
+                error_left.y,
+                error_right.x,
+                error_right.y,
+            ]);
+        }
+        residuals
+    }
+
+    fn cost(&self, observations: &[StereoObservation]) -> Real {
+        observations
+            .iter()
+            .zip(self.target_to_left.iter())
+            .map(|(observation, target_to_left)| {
+                self.residuals(observation, target_to_left)
+                    .iter()
+                    .map(|residual| residual * residual)
+                    .sum::<Real>()
+            })
+            .sum()
+    }
+
+    fn camera(intrinsics: &[Real; NUMBER_OF_INTRINSICS], image_size: ImageSize) -> PinholeCamera {
+        let model = PinholeCameraF64::from_params_and_size(
+            &VecF64::<4>::new(intrinsics[0], intrinsics[1], intrinsics[2], intrinsics[3]),
+            image_size,
+        );
+        PinholeCamera::new(model).with_distortion(Distortion::RadialTangential([
+            intrinsics[4],
+            intrinsics[5],
+            intrinsics[6],
+            intrinsics[7],
+            intrinsics[8],
+        ]))
+    }
+}
+
+fn project(
+    intrinsics: &[Real; NUMBER_OF_INTRINSICS],
+    point_in_camera: &PointCoordinates,
+) -> PixelCoordinates {
+    let distortion = Distortion::RadialTangential([
+        intrinsics[4],
+        intrinsics[5],
+        intrinsics[6],
+        intrinsics[7],
+        intrinsics[8],
+    ]);
+    let point = distortion.distort(&PixelCoordinates::new(
+        point_in_camera.x / point_in_camera.z,
+        point_in_camera.y / point_in_camera.z,
+    ));
+    PixelCoordinates::new(
+        intrinsics[0] * point.x + intrinsics[2],
+        intrinsics[1] * point.y + intrinsics[3],
+    )
+}
+
+// normalized DLT homography from the target plane to (centered) pixels
+fn estimate_homography(
+    points_in_target: &[PointCoordinates],
+    pixels: &[PixelCoordinates],
+) -> Option<Matrix3<Real>> {
+    let normalization = |points: &[Vector2<Real>]| -> Matrix3<Real> {
+        let mean = points.iter().sum::<Vector2<Real>>() / points.len() as Real;
+        let spread = points
+            .iter()
+            .map(|point| (point - mean).norm())
+            .sum::<Real>()
+            / points.len() as Real;
+        let scale = if spread > 0.0 {
+            std::f64::consts::SQRT_2 / spread
+        } else {
+            1.0
+        };
+        Matrix3::new(
+            scale,
+            0.0,
+            -scale * mean.x,
+            0.0,
+            scale,
+            -scale * mean.y,
+            0.0,
+            0.0,
+            1.0,
+        )
+    };
+    let plane_points: Vec<_> = points_in_target.iter().map(|point| point.xy()).collect();
+    let normalization_target = normalization(&plane_points);
+    let normalization_pixels = normalization(pixels);
+
+    let mut a = DMatrix::<Real>::zeros(2 * pixels.len(), 9);
+    for (i, (point, pixel)) in plane_points.iter().zip(pixels.iter()).enumerate() {
+        let p = normalization_target * Vector3::new(point.x, point.y, 1.0);
+        let q = normalization_pixels * Vector3::new(pixel.x, pixel.y, 1.0);
+        let (x, y, u, v) = (p.x / p.z, p.y / p.z, q.x / q.z, q.y / q.z);
+        let rows = [
+            [-x, -y, -1.0, 0.0, 0.0, 0.0, u * x, u * y, u],
+            [0.0, 0.0, 0.0, -x, -y, -1.0, v * x, v * y, v],
+        ];
+        for (r, row) in rows.iter().enumerate() {
+            for (c, value) in row.iter().enumerate() {
+                a[(2 * i + r, c)] = *value;
+            }
+        }
+    }
+
+    let eigen = SymmetricEigen::new(a.transpose() * a);
+    let smallest = eigen.eigenvalues.argmin().0;
+    let h = eigen.eigenvectors.column(smallest);
+    let homography = Matrix3::new(h[0], h[1], h[2], h[3], h[4], h[5], h[6], h[7], h[8]);
+    let homography = normalization_pixels.try_inverse()? * homography * normalization_target;
+    Some(homography / homography.norm())
+}
+
+// focal lengths from the orthogonality constraints of the homographies (pixels centered
+// at the principal point), the constraints are linear in 1/fx^2 and 1/fy^2
+fn estimate_focal_lengths(homographies: &[Matrix3<Real>]) -> Option<(Real, Real)> {
+    let mut normal_matrix = Matrix2::<Real>::zeros();
+    let mut normal_vector = Vector2::<Real>::zeros();
+    for h in homographies {
+        let equations = [
+            (
+                Vector2::new(h[(0, 0)] * h[(0, 1)], h[(1, 0)] * h[(1, 1)]),
+                -h[(2, 0)] * h[(2, 1)],
+            ),
+            (
+                Vector2::new(
+                    h[(0, 0)] * h[(0, 0)] - h[(0, 1)] * h[(0, 1)],
+                    h[(1, 0)] * h[(1, 0)] - h[(1, 1)] * h[(1, 1)],
+                ),
+                -(h[(2, 0)] * h[(2, 0)] - h[(2, 1)] * h[(2, 1)]),
+            ),
+        ];
+        for (coefficients, value) in equations {
+            normal_matrix += coefficients * coefficients.transpose();
+            normal_vector += coefficients * value;
+        }
+    }
+    let solution = normal_matrix.try_inverse()? * normal_vector;
+    if solution.x <= 0.0 || solution.y <= 0.0 {
+        return None;
+    }
+    Some((1.0 / solution.x.sqrt(), 1.0 / solution.y.sqrt()))
+}
+
+// target pose from a homography (pixels centered at the principal point)
+fn pose_from_homography(homography: &Matrix3<Real>, fx: Real, fy: Real) -> Result<Isometry3F64> {
+    let m = Matrix3::new(1.0 / fx, 0.0, 0.0, 0.0, 1.0 / fy, 0.0, 0.0, 0.0, 1.0) * homography;
+    let mut scale = 1.0 / m.column(0).norm();
+    if m[(2, 2)] * scale < 0.0 {
+        // target in front of the camera
+        scale = -scale;
+    }
+    let r1 = m.column(0) * scale;
+    let r2 = m.column(1) * scale;
+    let translation = m.column(2) * scale;
+    let rotation = Matrix3::from_columns(&[r1, r2, r1.cross(&r2)]);
+
+    let svd = rotation.svd(true, true);
+    let (u, v_t) = (svd.u.unwrap(), svd.v_t.unwrap());
+    let mut rotation = u * v_t;
+    if rotation.determinant() < 0.0 {
+        rotation = -rotation;
+    }
+    isometry_from_rotation_and_translation(&rotation, &translation)
+}
+
+fn initialize_camera(
+    observations: &[StereoObservation],
+    pixels: impl Fn(&StereoObservation) -> &[PixelCoordinates],
+    image_size: ImageSize,
+) -> Result<([Real; NUMBER_OF_INTRINSICS], Vec<Isometry3F64>)> {
+    let cx = image_size.width as Real / 2.0;
+    let cy = image_size.height as Real / 2.0;
+    let homographies = observations
+        .iter()
+        .map(|observation| {
+            let centered: Vec<_> = pixels(observation)
+                .iter()
+                .map(|pixel| pixel - PixelCoordinates::new(cx, cy))
+                .collect();
+            estimate_homography(&observation.points_in_target, &centered)
+                .ok_or_else(|| anyhow!("degenerate target observation: {}", observation.name))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let (fx, fy) = estimate_focal_lengths(&homographies).unwrap_or_else(|| {
+        log::warn!("focal length initialization failed, using image width");
+        (image_size.width as Real, image_size.width as Real)
+    });
+    log::debug!("initial focal lengths: {} {}", fx, fy);
+
+    let poses = homographies
+        .iter()
+        .map(|homography| pose_from_homography(homography, fx, fy))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(([fx, fy, cx, cy, 0.0, 0.0, 0.0, 0.0, 0.0], poses))
+}
+
+pub fn calibrate_stereo(
+    cfg: &StereoCalibratorCfg,
+    observations: &[StereoObservation],
+    image_size: ImageSize,
+) -> Result<StereoCalibration> {
+    if observations.len() < 3 {
+        bail!(
+            "at least 3 target observations required, got {}",
+            observations.len()
+        );
+    }
+
+    let (intrinsics_left, target_to_left) = initialize_camera(
+        observations,
+        |observation| &observation.pixels_left,
+        image_size,
+    )?;
+    let (intrinsics_right, target_to_right) = initialize_camera(
+        observations,
+        |observation| &observation.pixels_right,
+        image_size,
+    )?;
+    let left_to_right_per_view: Vec<_> = target_to_left
+        .iter()
+        .zip(target_to_right.iter())
+        .map(|(target_to_left, target_to_right)| {
+            target_to_right.group_mul(&target_to_left.inverse())
+        })
+        .collect();
+    let left_to_right = Isometry3F64::average(&left_to_right_per_view)
+        .map_err(|_| anyhow!("no stereo extrinsic estimate"))?;
+
+    let mut state = State {
+        intrinsics_left,
+        intrinsics_right,
+        left_to_right,
+        target_to_left,
+    };
+
+    let global_indices: Vec<usize> = (0..NUMBER_OF_GLOBAL_PARAMETERS)
+        .filter(|index| cfg.estimate_k3 || (*index != 8 && *index != NUMBER_OF_INTRINSICS + 8))
+        .collect();
+    let number_of_global = global_indices.len();
+    let number_of_parameters = number_of_global + 6 * observations.len();
+
+    let mut cost = state.cost(observations);
+    let mut lambda = 1e-3;
+    log::info!("initial reprojection cost: {}", cost);
+
+    for iteration in 0..cfg.maximum_iterations {
+        let (hessian, gradient) = normal_equations(
+            &mut state,
+            observations,
+            &global_indices,
+            number_of_parameters,
+        );
+
+        let mut improved = false;
+        while lambda < 1e10 {
+            let mut damped = hessian.clone();
+            for i in 0..number_of_parameters {
+                damped[(i, i)] += lambda * hessian[(i, i)].max(1e-9);
+            }
+            let Some(cholesky) = damped.cholesky() else {
+                lambda *= 10.0;
+                continue;
+            };
+            let delta = -cholesky.solve(&gradient);
+            let candidate = state.plus(&global_indices, &delta);
+            let candidate_cost = candidate.cost(observations);
+            if candidate_cost < cost {
+                let relative_decrease = (cost - candidate_cost) / cost;
+                state = candidate;
+                cost = candidate_cost;
+                lambda = (lambda / 10.0).max(1e-12);
+                improved = relative_decrease > cfg.function_tolerance;
+                break;
+            }
+            lambda *= 10.0;
+        }
+        log::debug!(
+            "iteration: {}, cost: {}, lambda: {}",
+            iteration,
+            cost,
+            lambda
+        );
+        if !improved {
+            break;
+        }
+    }
+
+    let mut view_errors = vec![];
+    let mut number_of_residuals = 0;
+    for (observation, target_to_left) in observations.iter().zip(state.target_to_left.iter()) {
+        let residuals = state.residuals(observation, target_to_left);
+        let squared_error = |offset: usize| -> Real {
+            residuals
+                .chunks(4)
+                .map(|chunk| chunk[offset] * chunk[offset] + chunk[offset + 1] * chunk[offset + 1])
+                .sum()
+        };
+        let number_of_points = observation.points_in_target.len() as Real;
+        view_errors.push(ViewError {
+            name: observation.name.clone(),
+            rms_left_pixels: (squared_error(0) / number_of_points).sqrt(),
+            rms_right_pixels: (squared_error(2) / number_of_points).sqrt(),
+        });
+        number_of_residuals += 2 * observation.points_in_target.len();
+    }
+
+    let stereo_camera = StereoCamera::new(
+        State::camera(&state.intrinsics_left, image_size),
+        State::camera(&state.intrinsics_right, image_size),
+        state.left_to_right,
+        Isometry3F64::identity(),
+    )?;
+    Ok(StereoCalibration {
+        stereo_camera,
+        rms_pixels: (cost / number_of_residuals as Real).sqrt(),
+        view_errors,
+    })
+}
+
+// gauss-newton normal equations with forward-difference jacobians, a view only depends
+// on the global parameters and its own target pose. the global parameters are perturbed in
+// place and restored afterwards, cloning the state would copy all target poses per column
+fn normal_equations(
+    state: &mut State,
+    observations: &[StereoObservation],
+    global_indices: &[usize],
+    number_of_parameters: usize,
+) -> (DMatrix<Real>, DVector<Real>) {
+    let number_of_global = global_indices.len();
+    let mut hessian = DMatrix::<Real>::zeros(number_of_parameters, number_of_parameters);
+    let mut gradient = DVector::<Real>::zeros(number_of_parameters);
+
+    for (view, observation) in observations.iter().enumerate() {
+        let target_to_left = state.target_to_left[view];
+        let residuals = DVector::from_vec(state.residuals(observation, &target_to_left));
+        let mut jacobian = DMatrix::<Real>::zeros(residuals.len(), number_of_global + 6);
+
+        for (column, parameter) in global_indices.iter().enumerate() {
+            let mut global = [0.0; NUMBER_OF_GLOBAL_PARAMETERS];
+            let step = if *parameter < 2 * NUMBER_OF_INTRINSICS {
+                let intrinsics = if *parameter < NUMBER_OF_INTRINSICS {
+                    &state.intrinsics_left
+                } else {
+                    &state.intrinsics_right
+                };
+                1e-7 * intrinsics[parameter % NUMBER_OF_INTRINSICS].abs().max(1.0)
+            } else {
+                1e-7
+            };
+            global[*parameter] = step;
+            let (intrinsics_left, intrinsics_right, left_to_right) = (
+                state.intrinsics_left,
+                state.intrinsics_right,
+                state.left_to_right,
+            );
+            state.apply_global(&global);
+            let perturbed_residuals =
+                DVector::from_vec(state.residuals(observation, &target_to_left));
+            state.intrinsics_left = intrinsics_left;
+            state.intrinsics_right = intrinsics_right;
+            state.left_to_right = left_to_right;
+            jacobian.set_column(column, &((perturbed_residuals - &residuals) / step));
+        }
+        for k in 0..6 {
+            let step = 1e-7;
+            let mut delta = VecF64::<6>::zeros();
+            delta[k] = step;
+            let perturbed_pose = Isometry3F64::exp(&delta).group_mul(&target_to_left);
+            let perturbed_residuals =
+                DVector::from_vec(state.residuals(observation, &perturbed_pose));
+            jacobian.set_column(
+                number_of_global + k,
+                &((perturbed_residuals - &residuals) / step),
+            );
+        }
+
+        let block_hessian = jacobian.transpose() * &jacobian;
+        let block_gradient = jacobian.transpose() * &residuals;
+        let offset = number_of_global + 6 * view;
+        let indices: Vec<usize> = (0..number_of_global).chain(offset..offset + 6).collect();
+        for (i, row) in indices.iter().enumerate() {
+            gradient[*row] += block_gradient[i];
+            for (j, column) in indices.iter().enumerate() {
+                hessian[(*row, *column)] += block_hessian[(i, j)];
+            }
+        }
+    }
+    (hessian, gradient)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_core::Camera;
+
+    // rig with distortion, a slightly rotated right camera and a 12 cm baseline
+    fn rig() -> State {
+        State {
+            intrinsics_left: [460.0, 465.0, 322.0, 238.0, -0.12, 0.03, 0.001, -0.0005, 0.0],
+            intrinsics_right: [
+                462.0, 466.0, 318.0, 241.0, -0.11, 0.025, -0.0008, 0.0004, 0.0,
+            ],
+            left_to_right: Isometry3F64::exp(&VecF64::<6>::new(
+                -0.12, 0.002, 0.001, 0.004, -0.01, 0.002,
+            )),
+            target_to_left: vec![],
+        }
+    }
+
+    // 9x6 corners with 4 cm spacing, seen from different distances and tilts
+    fn observations(rig: &State) -> Vec<StereoObservation> {
+        let points_in_target: Vec<_> = (0..6)
+            .flat_map(|row| {
+                (0..9).map(move |col| {
+                    PointCoordinates::new(0.04 * col as Real, 0.04 * row as Real, 0.0)
+                })
+            })
+            .collect();
+        let views = [
+            [-0.16, -0.1, 0.6, 0.0, 0.0, 0.0],
+            [-0.2, -0.1, 0.7, 0.3, 0.0, 0.05],
+            [-0.1, -0.12, 0.65, -0.3, 0.1, 0.0],
+            [-0.18, -0.05, 0.55, 0.0, 0.35, -0.1],
+            [-0.12, -0.15, 0.75, 0.1, -0.35, 0.1],
+            [-0.22, -0.08, 0.5, 0.25, 0.25, 0.2],
+            [-0.15, -0.1, 0.8, -0.2, -0.2, -0.15],
+        ];
+        views
+            .iter()
+            .enumerate()
+            .map(|(view, tangent)| {
+                let target_to_left = Isometry3F64::exp(&VecF64::<6>::from_row_slice(tangent));
+                let (pixels_left, pixels_right) = points_in_target
+                    .iter()
+                    .map(|point_in_target| {
+                        let point_in_left = target_to_left.transform(point_in_target);
+                        let point_in_right = rig.left_to_right.transform(&point_in_left);
+                        (
+                            project(&rig.intrinsics_left, &point_in_left),
+                            project(&rig.intrinsics_right, &point_in_right),
+                        )
+                    })
+                    .unzip();
+                StereoObservation {
+                    name: format!("view_{}", view),
+                    points_in_target: points_in_target.clone(),
+                    pixels_left,
+                    pixels_right,
+                }
+            })
+            .collect()
+    }
+
+    fn assert_camera(camera: &PinholeCamera, intrinsics: &[Real; NUMBER_OF_INTRINSICS]) {
+        let params = camera.model.params();
+        for i in 0..4 {
+            assert!(
+                (params[i] - intrinsics[i]).abs() < 1e-3,
+                "{} vs {:?}",
+                params,
+                intrinsics
+            );
+        }
+        let coefficients = camera.distortion().coefficients();
+        for i in 0..5 {
+            assert!(
+                (coefficients[i] - intrinsics[4 + i]).abs() < 1e-5,
+                "{:?} vs {:?}",
+                coefficients,
+                intrinsics
+            );
+        }
+    }
+
+    #[test]
+    fn recovers_a_synthetic_rig() {
+        let rig = rig();
+        let observations = observations(&rig);
+        let calibration = calibrate_stereo(
+            &StereoCalibratorCfg::default(),
+            &observations,
+            ImageSize::new(640, 480),
+        )
+        .unwrap();
+
+        assert!(calibration.rms_pixels < 1e-6, "{}", calibration.rms_pixels);
+        for view_error in &calibration.view_errors {
+            assert!(view_error.rms_left_pixels < 1e-6 && view_error.rms_right_pixels < 1e-6);
+        }
+        let stereo_camera = &calibration.stereo_camera;
+        assert_camera(&stereo_camera.left, &rig.intrinsics_left);
+        assert_camera(&stereo_camera.right, &rig.intrinsics_right);
+        assert_eq!(stereo_camera.left.cols(), 640);
+        let extrinsic_error = stereo_camera
+            .left_to_right()
+            .inverse()
+            .group_mul(&rig.left_to_right)
+            .log()
+            .norm();
+        assert!(extrinsic_error < 1e-6, "{}", extrinsic_error);
+    }
+
+    #[test]
+    fn needs_three_views() {
+        let rig = rig();
+        let observations = observations(&rig);
+        assert!(calibrate_stereo(
+            &StereoCalibratorCfg::default(),
+            &observations[..2],
+            ImageSize::new(640, 480)
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+use rslam_core::{PixelCoordinates, PointCoordinates, Real};
+
+/// planar calibration target, all corners lie on the z=0 plane of the target frame
+#[derive(Clone, Debug, PartialEq)]
+pub enum CalibrationTarget {
+    // inner corners of a chessboard, numbered row-major
+    Chessboard {
+        rows: usize,
+        cols: usize,
+        square_size_meters: Real,
+    },
+    // Kalibr AprilGrid (tag family 36h11), tag 0 is in the bottom left corner and every
+    // tag has four corners numbered clockwise starting top left: id = 4 * tag_id + corner
+    AprilGrid {
+        rows: usize,
+        cols: usize,
+        tag_size_meters: Real,
+        // space between tags as ratio of the tag size
+        tag_spacing: Real,
+    },
+}
+
+impl CalibrationTarget {
+    pub fn number_of_corners(&self) -> usize {
+        match self {
+            CalibrationTarget::Chessboard { rows, cols, .. } => rows * cols,
+            CalibrationTarget::AprilGrid { rows, cols, .. } => 4 * rows * cols,
+        }
+    }
+
+    pub fn corner(&self, id: usize) -> Option<PointCoordinates> {
+        if id >= self.number_of_corners() {
+            return None;
+        }
+        match self {
+            CalibrationTarget::Chessboard {
+                cols,
+                square_size_meters,
+                ..
+            } => Some(PointCoordinates::new(
+                (id % cols) as Real * square_size_meters,
+                (id / cols) as Real * square_size_meters,
+                0.0,
+            )),
+            CalibrationTarget::AprilGrid {
+                cols,
+                tag_size_meters,
+                tag_spacing,
+                ..
+            } => {
+                let tag_id = id / 4;
+                let pitch = tag_size_meters * (1.0 + tag_spacing);
+                let x = (tag_id % cols) as Real * pitch;
+                let y = (tag_id / cols) as Real * pitch;
+                let (dx, dy) = match id % 4 {
+                    0 => (0.0, *tag_size_meters),
+                    1 => (*tag_size_meters, *tag_size_meters),
+                    2 => (*tag_size_meters, 0.0),
+                    _ => (0.0, 0.0),
+                };
+                Some(PointCoordinates::new(x + dx, y + dy, 0.0))
+            }
+        }
+    }
+}
+
+// parses "chessboard:<rows>x<cols>:<square size>" or
+// "aprilgrid:<rows>x<cols>:<tag size>:<tag spacing>" (sizes in meters)
+impl FromStr for CalibrationTarget {
+    type Err = Error;
+
+    fn from_str(description: &str) -> Result<Self> {
+        let fields: Vec<&str> = description.split(':').collect();
+        let grid = |field: &str| -> Result<(usize, usize)> {
+            let (rows, cols) = field
+                .split_once('x')
+                .with_context(|| format!("invalid grid size: {}", field))?;
+            Ok((rows.parse()?, cols.parse()?))
+        };
+        match fields[..] {
+            ["chessboard", size, square_size] => {
+                let (rows, cols) = grid(size)?;
+                Ok(CalibrationTarget::Chessboard {
+                    rows,
+                    cols,
+                    square_size_meters: square_size.parse()?,
+                })
+            }
+            ["aprilgrid", size, tag_size, tag_spacing] => {
+                let (rows, cols) = grid(size)?;
+                Ok(CalibrationTarget::AprilGrid {
+                    rows,
+                    cols,
+                    tag_size_meters: tag_size.parse()?,
+                    tag_spacing: tag_spacing.parse()?,
+                })
+            }
+            _ => bail!("invalid calibration target: {}", description),
+        }
+    }
+}
+
+/// corners of a target found in a single image
+#[derive(Clone, Debug, Default)]
+pub struct TargetDetection {
+    pub corner_ids: Vec<usize>,
+    pub pixels: Vec<PixelCoordinates>,
+}
+
+/// target corners seen in both images of a stereo pair
+#[derive(Clone, Debug)]
+pub struct StereoObservation {
+    pub name: String,
+    pub points_in_target: Vec<PointCoordinates>,
+    pub pixels_left: Vec<PixelCoordinates>,
+    pub pixels_right: Vec<PixelCoordinates>,
+}
+
+impl StereoObservation {
+    // keeps the corners detected in both images, None if there are too few for a pose
+    pub fn from_detections(
+        name: &str,
+        target: &CalibrationTarget,
+        left: &TargetDetection,
+        right: &TargetDetection,
+    ) -> Option<Self> {
+        let mut observation = StereoObservation {
+            name: String::from(name),
+            points_in_target: vec![],
+            pixels_left: vec![],
+            pixels_right: vec![],
+        };
+        for (id, pixel_left) in left.corner_ids.iter().zip(left.pixels.iter()) {
+            let Some(index_right) = right.corner_ids.iter().position(|other| other == id) else {
+                continue;
+            };
+            let Some(point_in_target) = target.corner(*id) else {
+                continue;
+            };
+            observation.points_in_target.push(point_in_target);
+            observation.pixels_left.push(*pixel_left);
+            observation.pixels_right.push(right.pixels[index_right]);
+        }
+
+        if observation.points_in_target.len() < 6 {
+            return None;
+        }
+        Some(observation)
+    }
+}
@@ -0,0 +1,105 @@
+use rslam_core::{PixelCoordinates, Real};
+use sophus::nalgebra::Matrix2;
+
+/// lens distortion of a camera, applied on the z=1 plane before the pinhole intrinsics
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Distortion {
+    // rectified or ideal camera
+    #[default]
+    None,
+    // radial-tangential (plumb bob): k1, k2, p1, p2, k3
+    RadialTangential([Real; 5]),
+    // equidistant (fisheye): k1, k2, k3, k4
+    Equidistant([Real; 4]),
+}
+
+impl Distortion {
+    pub fn coefficients(&self) -> Vec<Real> {
+        match self {
+            Distortion::None => vec![],
+            Distortion::RadialTangential(coefficients) => coefficients.to_vec(),
+            Distortion::Equidistant(coefficients) => coefficients.to_vec(),
+        }
+    }
+
+    // maps a point on the z=1 plane to its distorted location on the z=1 plane
+    pub fn distort(&self, point: &PixelCoordinates) -> PixelCoordinates {
+        match self {
+            Distortion::None => *point,
+            Distortion::RadialTangential([k1, k2, p1, p2, k3]) => {
+                let (x, y) = (point.x, point.y);
+                let r2 = x * x + y * y;
+                let radial = 1.0 + r2 * (k1 + r2 * (k2 + r2 * k3));
+                PixelCoordinates::new(
+                    x * radial + 2.0 * p1 * x * y + p2 * (r2 + 2.0 * x * x),
+                    y * radial + p1 * (r2 + 2.0 * y * y) + 2.0 * p2 * x * y,
+                )
+            }
+            Distortion::Equidistant([k1, k2, k3, k4]) => {
+                let r = point.norm();
+                if r < 1e-9 {
+                    return *point;
+                }
+                let theta = r.atan();
+                let theta2 = theta * theta;
+                let theta_distorted =
+                    theta * (1.0 + theta2 * (k1 + theta2 * (k2 + theta2 * (k3 + theta2 * k4))));
+                point * (theta_distorted / r)
+            }
+        }
+    }
+
+    // jacobian of distort by the undistorted point on the z=1 plane
+    pub fn dx_distort_x(&self, point: &PixelCoordinates) -> Matrix2<Real> {
+        match self {
+            Distortion::None => Matrix2::identity(),
+            Distortion::RadialTangential([k1, k2, p1, p2, k3]) => {
+                let (x, y) = (point.x, point.y);
+                let r2 = x * x + y * y;
+                let radial = 1.0 + r2 * (k1 + r2 * (k2 + r2 * k3));
+                let radial_by_r2 = k1 + r2 * (2.0 * k2 + 3.0 * r2 * k3);
+                Matrix2::new(
+                    radial + 2.0 * x * x * radial_by_r2 + 2.0 * p1 * y + 6.0 * p2 * x,
+                    2.0 * x * y * radial_by_r2 + 2.0 * p1 * x + 2.0 * p2 * y,
+                    2.0 * x * y * radial_by_r2 + 2.0 * p1 * x + 2.0 * p2 * y,
+                    radial + 2.0 * y * y * radial_by_r2 + 6.0 * p1 * y + 2.0 * p2 * x,
+                )
+            }
+            Distortion::Equidistant([k1, k2, k3, k4]) => {
+                let r = point.norm();
+                if r < 1e-9 {
+                    return Matrix2::identity();
+                }
+                // distort(p) = p * s(r) with s = theta_distorted / r
+                let theta = r.atan();
+                let theta2 = theta * theta;
+                let theta_distorted =
+                    theta * (1.0 + theta2 * (k1 + theta2 * (k2 + theta2 * (k3 + theta2 * k4))));
+                let theta_distorted_by_theta = 1.0
+                    + theta2
+                        * (3.0 * k1
+                            + theta2 * (5.0 * k2 + theta2 * (7.0 * k3 + theta2 * 9.0 * k4)));
+                let theta_distorted_by_r = theta_distorted_by_theta / (1.0 + r * r);
+                let scale = theta_distorted / r;
+                let scale_by_r = (theta_distorted_by_r * r - theta_distorted) / (r * r);
+                Matrix2::identity() * scale + point * point.transpose() * (scale_by_r / r)
+            }
+        }
+    }
+
+    // inverse of distort, solved with fixed point iterations
+    pub fn undistort(&self, point: &PixelCoordinates) -> PixelCoordinates {
+        if *self == Distortion::None {
+            return *point;
+        }
+        let mut undistorted = *point;
+        for _ in 0..20 {
+            let error = self.distort(&undistorted) - point;
+            undistorted -= error;
+            if error.norm() < 1e-12 {
+                break;
+            }
+        }
+        undistorted
+    }
+}
@@ -0,0 +1,342 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sophus::nalgebra::{Matrix3, Vector3};
+
+// WGS84 ellipsoid
+const SEMI_MAJOR_AXIS_METERS: f64 = 6378137.0;
+const FLATTENING: f64 = 1.0 / 298.257223563;
+const ECCENTRICITY_SQUARED: f64 = FLATTENING * (2.0 - FLATTENING);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeodeticCoordinates {
+    pub latitude_degrees: f64,
+    pub longitude_degrees: f64,
+    // above the ellipsoid
+    pub altitude_meters: f64,
+}
+
+impl GeodeticCoordinates {
+    pub fn new(latitude_degrees: f64, longitude_degrees: f64, altitude_meters: f64) -> Self {
+        Self {
+            latitude_degrees,
+            longitude_degrees,
+            altitude_meters,
+        }
+    }
+
+    // earth centered, earth fixed (m)
+    pub fn to_ecef(&self) -> Vector3<f64> {
+        let latitude = self.latitude_degrees.to_radians();
+        let longitude = self.longitude_degrees.to_radians();
+        let prime_vertical_radius =
+            SEMI_MAJOR_AXIS_METERS / (1.0 - ECCENTRICITY_SQUARED * latitude.sin().powi(2)).sqrt();
+        Vector3::new(
+            (prime_vertical_radius + self.altitude_meters) * latitude.cos() * longitude.cos(),
+            (prime_vertical_radius + self.altitude_meters) * latitude.cos() * longitude.sin(),
+            (prime_vertical_radius * (1.0 - ECCENTRICITY_SQUARED) + self.altitude_meters)
+                * latitude.sin(),
+        )
+    }
+
+    pub fn from_ecef(ecef: &Vector3<f64>) -> Self {
+        let longitude = ecef.y.atan2(ecef.x);
+        let distance_to_axis = ecef.x.hypot(ecef.y);
+        let mut latitude = ecef
+            .z
+            .atan2(distance_to_axis * (1.0 - ECCENTRICITY_SQUARED));
+        let mut altitude = 0.0;
+        for _ in 0..10 {
+            let prime_vertical_radius = SEMI_MAJOR_AXIS_METERS
+                / (1.0 - ECCENTRICITY_SQUARED * latitude.sin().powi(2)).sqrt();
+            altitude = distance_to_axis * latitude.cos() + ecef.z * latitude.sin()
+                - SEMI_MAJOR_AXIS_METERS
+                    * (1.0 - ECCENTRICITY_SQUARED * latitude.sin().powi(2)).sqrt();
+            latitude = ecef.z.atan2(
+                distance_to_axis
+                    * (1.0
+                        - ECCENTRICITY_SQUARED * prime_vertical_radius
+                            / (prime_vertical_radius + altitude)),
+            );
+        }
+        Self::new(latitude.to_degrees(), longitude.to_degrees(), altitude)
+    }
+}
+
+/// local east, north, up frame tangent to the ellipsoid at an origin
+#[derive(Clone, Debug)]
+pub struct LocalTangentPlane {
+    origin: GeodeticCoordinates,
+    origin_ecef: Vector3<f64>,
+    ecef_to_enu: Matrix3<f64>,
+}
+
+impl LocalTangentPlane {
+    pub fn new(origin: GeodeticCoordinates) -> Self {
+        let latitude = origin.latitude_degrees.to_radians();
+        let longitude = origin.longitude_degrees.to_radians();
+        let (sin_latitude, cos_latitude) = latitude.sin_cos();
+        let (sin_longitude, cos_longitude) = longitude.sin_cos();
+        Self {
+            origin,
+            origin_ecef: origin.to_ecef(),
+            ecef_to_enu: Matrix3::new(
+                -sin_longitude,
+                cos_longitude,
+                0.0,
+                -sin_latitude * cos_longitude,
+                -sin_latitude * sin_longitude,
+                cos_latitude,
+                cos_latitude * cos_longitude,
+                cos_latitude * sin_longitude,
+                sin_latitude,
+            ),
+        }
+    }
+
+    pub fn origin(&self) -> &GeodeticCoordinates {
+        &self.origin
+    }
+
+    pub fn ecef_to_enu(&self, ecef: &Vector3<f64>) -> Vector3<f64> {
+        self.ecef_to_enu * (ecef - self.origin_ecef)
+    }
+
+    pub fn enu_to_ecef(&self, enu: &Vector3<f64>) -> Vector3<f64> {
+        self.ecef_to_enu.transpose() * enu + self.origin_ecef
+    }
+
+    pub fn geodetic_to_enu(&self, geodetic: &GeodeticCoordinates) -> Vector3<f64> {
+        self.ecef_to_enu(&geodetic.to_ecef())
+    }
+
+    pub fn enu_to_geodetic(&self, enu: &Vector3<f64>) -> GeodeticCoordinates {
+        GeodeticCoordinates::from_ecef(&self.enu_to_ecef(enu))
+    }
+}
+
+/// satellite navigation position fix (WGS84)
+#[derive(Clone, Debug)]
+pub struct GnssMeasurement {
+    pub timestamp_seconds: f64,
+    pub latitude_degrees: f64,
+    pub longitude_degrees: f64,
+    // above the ellipsoid
+    pub altitude_meters: f64,
+    // east, north, up (m^2), None if the receiver does not report it
+    pub covariance: Option<Matrix3<f64>>,
+}
+
+impl GnssMeasurement {
+    pub fn new(
+        timestamp_seconds: f64,
+        latitude_degrees: f64,
+        longitude_degrees: f64,
+        altitude_meters: f64,
+    ) -> Self {
+        Self {
+            timestamp_seconds,
+            latitude_degrees,
+            longitude_degrees,
+            altitude_meters,
+            covariance: None,
+        }
+    }
+
+    pub fn with_covariance(mut self, covariance: Matrix3<f64>) -> Self {
+        self.covariance = Some(covariance);
+        self
+    }
+
+    pub fn geodetic(&self) -> GeodeticCoordinates {
+        GeodeticCoordinates::new(
+            self.latitude_degrees,
+            self.longitude_degrees,
+            self.altitude_meters,
+        )
+    }
+
+    // KITTI raw OXTS record: lat lon alt roll pitch yaw ... pos_accuracy (24th value) ...
+    pub fn from_oxts(timestamp_seconds: f64, line: &str) -> Result<Self> {
+        let values = line
+            .split_whitespace()
+            .map(|value| value.parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .context("invalid oxts record")?;
+        if values.len() < 30 {
+            bail!("oxts record with {} values, expected 30", values.len());
+        }
+        let position_accuracy_meters = values[23];
+        Ok(
+            Self::new(timestamp_seconds, values[0], values[1], values[2])
+                .with_covariance(Matrix3::identity() * position_accuracy_meters.powi(2)),
+        )
+    }
+
+    // NMEA GGA sentence, the covariance is derived from the horizontal dilution of precision;
+    // None if the sentence has no fix
+    pub fn from_nmea_gga(
+        timestamp_seconds: f64,
+        sentence: &str,
+        receiver: &GnssReceiver,
+    ) -> Result<Option<Self>> {
+        let sentence = sentence.trim();
+        let sentence = sentence.split('*').next().unwrap_or(sentence);
+        let fields: Vec<&str> = sentence.split(',').collect();
+        if fields.len() < 12 || !fields[0].ends_with("GGA") {
+            bail!("not a GGA sentence: {}", sentence);
+        }
+        if fields[6].is_empty() || fields[6] == "0" {
+            return Ok(None);
+        }
+
+        // ddmm.mmmm and dddmm.mmmm
+        let degrees_minutes = |value: &str, hemisphere: &str, negative: &str| -> Result<f64> {
+            let value: f64 = value
+                .parse()
+                .with_context(|| format!("invalid coordinate {}", value))?;
+            let degrees = (value / 100.0).trunc() + (value % 100.0) / 60.0;
+            Ok(if hemisphere == negative {
+                -degrees
+            } else {
+                degrees
+            })
+        };
+        let latitude_degrees = degrees_minutes(fields[2], fields[3], "S")?;
+        let longitude_degrees = degrees_minutes(fields[4], fields[5], "W")?;
+        let horizontal_dilution: f64 = fields[8].parse().context("invalid hdop")?;
+        // mean sea level altitude plus geoid separation
+        let altitude_meters = fields[9].parse::<f64>().context("invalid altitude")?
+            + fields[11].parse::<f64>().unwrap_or(0.0);
+
+        let horizontal_sigma = horizontal_dilution * receiver.range_error_sigma_meters;
+        let vertical_sigma = horizontal_sigma * receiver.vertical_to_horizontal_error_ratio;
+        Ok(Some(
+            Self::new(
+                timestamp_seconds,
+                latitude_degrees,
+                longitude_degrees,
+                altitude_meters,
+            )
+            .with_covariance(Matrix3::from_diagonal(&Vector3::new(
+                horizontal_sigma.powi(2),
+                horizontal_sigma.powi(2),
+                vertical_sigma.powi(2),
+            ))),
+        ))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GnssReceiver {
+    // antenna phase center in the robot frame (m)
+    pub antenna_in_robot: [f64; 3],
+    // user equivalent range error, scaled by the dilution of precision (m)
+    pub range_error_sigma_meters: f64,
+    pub vertical_to_horizontal_error_ratio: f64,
+    // used when a measurement carries no covariance (m)
+    pub default_sigma_meters: f64,
+}
+
+impl Default for GnssReceiver {
+    fn default() -> Self {
+        Self {
+            antenna_in_robot: [0.0; 3],
+            range_error_sigma_meters: 2.0,
+            vertical_to_horizontal_error_ratio: 1.5,
+            default_sigma_meters: 5.0,
+        }
+    }
+}
+
+impl GnssReceiver {
+    pub fn antenna_in_robot(&self) -> Vector3<f64> {
+        Vector3::from(self.antenna_in_robot)
+    }
+
+    pub fn covariance(&self, measurement: &GnssMeasurement) -> Matrix3<f64> {
+        measurement
+            .covariance
+            .unwrap_or_else(|| Matrix3::identity() * self.default_sigma_meters.powi(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coordinates() -> Vec<GeodeticCoordinates> {
+        vec![
+            GeodeticCoordinates::new(49.011, 8.4237, 116.4),
+            GeodeticCoordinates::new(-33.8688, 151.2093, 58.0),
+            GeodeticCoordinates::new(0.0, 0.0, 0.0),
+            GeodeticCoordinates::new(89.9, -120.0, 2500.0),
+            GeodeticCoordinates::new(-45.0, -179.5, -30.0),
+        ]
+    }
+
+    #[test]
+    fn ecef_of_known_points() {
+        let equator = GeodeticCoordinates::new(0.0, 0.0, 0.0).to_ecef();
+        assert!((equator - Vector3::new(SEMI_MAJOR_AXIS_METERS, 0.0, 0.0)).norm() < 1e-6);
+
+        let semi_minor_axis_meters = SEMI_MAJOR_AXIS_METERS * (1.0 - FLATTENING);
+        let north_pole = GeodeticCoordinates::new(90.0, 0.0, 10.0).to_ecef();
+        assert!((north_pole - Vector3::new(0.0, 0.0, semi_minor_axis_meters + 10.0)).norm() < 1e-6);
+    }
+
+    #[test]
+    fn ecef_round_trip() {
+        for geodetic in coordinates() {
+            let round_trip = GeodeticCoordinates::from_ecef(&geodetic.to_ecef());
+            assert!((round_trip.latitude_degrees - geodetic.latitude_degrees).abs() < 1e-9);
+            assert!((round_trip.longitude_degrees - geodetic.longitude_degrees).abs() < 1e-9);
+            assert!((round_trip.altitude_meters - geodetic.altitude_meters).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn local_tangent_plane_round_trip() {
+        let plane = LocalTangentPlane::new(GeodeticCoordinates::new(49.011, 8.4237, 116.4));
+        assert!(plane.geodetic_to_enu(plane.origin()).norm() < 1e-6);
+
+        for enu in [
+            Vector3::new(100.0, -50.0, 2.0),
+            Vector3::new(-2500.0, 4000.0, -15.0),
+        ] {
+            let round_trip = plane.geodetic_to_enu(&plane.enu_to_geodetic(&enu));
+            assert!((round_trip - enu).norm() < 1e-6);
+        }
+
+        // up is the ellipsoid normal, north increases the latitude
+        let up = plane.enu_to_geodetic(&Vector3::new(0.0, 0.0, 10.0));
+        assert!((up.altitude_meters - 126.4).abs() < 1e-6);
+        assert!((up.latitude_degrees - 49.011).abs() < 1e-9);
+        let north = plane.enu_to_geodetic(&Vector3::new(0.0, 100.0, 0.0));
+        assert!(north.latitude_degrees > 49.011);
+        assert!((north.longitude_degrees - 8.4237).abs() < 1e-9);
+    }
+
+    #[test]
+    fn parses_nmea_gga() {
+        let receiver = GnssReceiver::default();
+        let measurement = GnssMeasurement::from_nmea_gga(
+            1.0,
+            "$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47",
+            &receiver,
+        )
+        .unwrap()
+        .unwrap();
+        assert!((measurement.latitude_degrees - (48.0 + 7.038 / 60.0)).abs() < 1e-12);
+        assert!((measurement.longitude_degrees - (11.0 + 31.0 / 60.0)).abs() < 1e-12);
+        assert!((measurement.altitude_meters - 592.3).abs() < 1e-9);
+        let covariance = receiver.covariance(&measurement);
+        assert!((covariance[(0, 0)] - 1.8f64.powi(2)).abs() < 1e-12);
+        assert!((covariance[(2, 2)] - 2.7f64.powi(2)).abs() < 1e-12);
+
+        let no_fix =
+            GnssMeasurement::from_nmea_gga(1.0, "$GPGGA,123519,,,,,0,00,,,M,,M,,*66", &receiver)
+                .unwrap();
+        assert!(no_fix.is_none());
+        assert!(GnssMeasurement::from_nmea_gga(1.0, "$GPRMC,123519,A", &receiver).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use serde::Deserialize;
+use sophus::{lie::Isometry3F64, nalgebra::Vector3};
+
+pub const GRAVITY_MAGNITUDE: f64 = 9.81;
+
+/// single inertial measurement, expressed in the IMU frame
+#[derive(Clone, Debug)]
+pub struct ImuMeasurement {
+    pub timestamp_seconds: f64,
+    // rad/s
+    pub angular_velocity: Vector3<f64>,
+    // m/s^2, including gravity
+    pub linear_acceleration: Vector3<f64>,
+}
+
+impl ImuMeasurement {
+    pub fn new(
+        timestamp_seconds: f64,
+        angular_velocity: Vector3<f64>,
+        linear_acceleration: Vector3<f64>,
+    ) -> Self {
+        Self {
+            timestamp_seconds,
+            angular_velocity,
+            linear_acceleration,
+        }
+    }
+}
+
+/// continuous time noise parameters (Kalibr imu.yaml naming)
+#[derive(Clone, Debug, Deserialize)]
+pub struct ImuNoiseParameters {
+    // rad/s/sqrt(Hz)
+    pub gyroscope_noise_density: f64,
+    // m/s^2/sqrt(Hz)
+    pub accelerometer_noise_density: f64,
+    // rad/s^2/sqrt(Hz)
+    pub gyroscope_random_walk: f64,
+    // m/s^3/sqrt(Hz)
+    pub accelerometer_random_walk: f64,
+}
+
+impl Default for ImuNoiseParameters {
+    // EuRoC MAV ADIS16448
+    fn default() -> Self {
+        Self {
+            gyroscope_noise_density: 1.6968e-4,
+            accelerometer_noise_density: 2.0e-3,
+            gyroscope_random_walk: 1.9393e-5,
+            accelerometer_random_walk: 3.0e-3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ImuBias {
+    pub gyroscope: Vector3<f64>,
+    pub accelerometer: Vector3<f64>,
+}
+
+impl ImuBias {
+    pub fn new(gyroscope: Vector3<f64>, accelerometer: Vector3<f64>) -> Self {
+        Self {
+            gyroscope,
+            accelerometer,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Imu {
+    pub noise: ImuNoiseParameters,
+
+    // imu to robot transform (usually constant during operation)
+    imu_to_robot: Isometry3F64,
+}
+
+impl Imu {
+    pub fn new(noise: ImuNoiseParameters, imu_to_robot: Isometry3F64) -> Self {
+        Self {
+            noise,
+            imu_to_robot,
+        }
+    }
+
+    pub fn imu_to_robot(&self) -> &Isometry3F64 {
+        &self.imu_to_robot
+    }
+}
@@ -0,0 +1,510 @@
+//! on-manifold IMU preintegration (Forster et al., "On-Manifold Preintegration for
+//! Real-Time Visual-Inertial Odometry", TRO 2017)
+//!
+//! Deltas are expressed in the IMU frame at the first timestamp and do not depend on the
+//! initial state or gravity. Covariance and bias jacobians are ordered (rotation, velocity,
+//! position) and (gyroscope, accelerometer).
+
+use sophus::{
+    core::linalg::VecF64,
+    lie::Rotation3F64,
+    nalgebra::{Matrix3, Matrix6, SMatrix, Vector3},
+};
+
+use crate::imu::{ImuBias, ImuMeasurement, ImuNoiseParameters};
+
+pub type Matrix9 = SMatrix<f64, 9, 9>;
+pub type Vector9 = SMatrix<f64, 9, 1>;
+
+// right jacobian of SO(3)
+pub fn right_jacobian_so3(omega: &Vector3<f64>) -> Matrix3<f64> {
+    let theta = omega.norm();
+    let omega_hat = Rotation3F64::hat(omega);
+    if theta < 1e-8 {
+        return Matrix3::identity() - 0.5 * omega_hat;
+    }
+    let theta2 = theta * theta;
+    Matrix3::identity() - (1.0 - theta.cos()) / theta2 * omega_hat
+        + (theta - theta.sin()) / (theta2 * theta) * omega_hat * omega_hat
+}
+
+pub fn right_jacobian_inverse_so3(omega: &Vector3<f64>) -> Matrix3<f64> {
+    let theta = omega.norm();
+    let omega_hat = Rotation3F64::hat(omega);
+    if theta < 1e-8 {
+        return Matrix3::identity() + 0.5 * omega_hat;
+    }
+    let theta2 = theta * theta;
+    Matrix3::identity()
+        + 0.5 * omega_hat
+        + (1.0 / theta2 - (1.0 + theta.cos()) / (2.0 * theta * theta.sin())) * omega_hat * omega_hat
+}
+
+/// rotation, position and velocity of the IMU in the world frame
+#[derive(Clone, Debug)]
+pub struct NavigationState {
+    pub rotation: Rotation3F64,
+    pub position: Vector3<f64>,
+    pub velocity: Vector3<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImuPreintegration {
+    // bias the measurements were integrated with
+    bias: ImuBias,
+    // squared noise densities of gyroscope and accelerometer
+    noise_covariance: Matrix6<f64>,
+    bias_random_walk_covariance: Matrix6<f64>,
+
+    delta_time: f64,
+    delta_rotation: Rotation3F64,
+    delta_velocity: Vector3<f64>,
+    delta_position: Vector3<f64>,
+    covariance: Matrix9,
+
+    d_rotation_d_bias_gyroscope: Matrix3<f64>,
+    d_velocity_d_bias_gyroscope: Matrix3<f64>,
+    d_velocity_d_bias_accelerometer: Matrix3<f64>,
+    d_position_d_bias_gyroscope: Matrix3<f64>,
+    d_position_d_bias_accelerometer: Matrix3<f64>,
+}
+
+impl ImuPreintegration {
+    pub fn new(bias: ImuBias, noise: &ImuNoiseParameters) -> Self {
+        let squared = |value: f64| Vector3::repeat(value * value);
+        let noise_covariance = Matrix6::from_diagonal(&VecF64::<6>::from_iterator(
+            squared(noise.gyroscope_noise_density)
+                .iter()
+                .chain(squared(noise.accelerometer_noise_density).iter())
+                .copied(),
+        ));
+        let bias_random_walk_covariance = Matrix6::from_diagonal(&VecF64::<6>::from_iterator(
+            squared(noise.gyroscope_random_walk)
+                .iter()
+                .chain(squared(noise.accelerometer_random_walk).iter())
+                .copied(),
+        ));
+        Self {
+            bias,
+            noise_covariance,
+            bias_random_walk_covariance,
+            delta_time: 0.0,
+            delta_rotation: Rotation3F64::identity(),
+            delta_velocity: Vector3::zeros(),
+            delta_position: Vector3::zeros(),
+            covariance: Matrix9::zeros(),
+            d_rotation_d_bias_gyroscope: Matrix3::zeros(),
+            d_velocity_d_bias_gyroscope: Matrix3::zeros(),
+            d_velocity_d_bias_accelerometer: Matrix3::zeros(),
+            d_position_d_bias_gyroscope: Matrix3::zeros(),
+            d_position_d_bias_accelerometer: Matrix3::zeros(),
+        }
+    }
+
+    // restarts the integration, e.g. after the bias estimate changed significantly
+    pub fn reset(&mut self, bias: ImuBias) {
+        self.bias = bias;
+        self.delta_time = 0.0;
+        self.delta_rotation = Rotation3F64::identity();
+        self.delta_velocity = Vector3::zeros();
+        self.delta_position = Vector3::zeros();
+        self.covariance = Matrix9::zeros();
+        self.d_rotation_d_bias_gyroscope = Matrix3::zeros();
+        self.d_velocity_d_bias_gyroscope = Matrix3::zeros();
+        self.d_velocity_d_bias_accelerometer = Matrix3::zeros();
+        self.d_position_d_bias_gyroscope = Matrix3::zeros();
+        self.d_position_d_bias_accelerometer = Matrix3::zeros();
+    }
+
+    pub fn integrate(
+        &mut self,
+        angular_velocity: &Vector3<f64>,
+        linear_acceleration: &Vector3<f64>,
+        delta_time: f64,
+    ) {
+        if delta_time <= 0.0 {
+            return;
+        }
+        let omega = (angular_velocity - self.bias.gyroscope) * delta_time;
+        let acceleration = linear_acceleration - self.bias.accelerometer;
+        let rotation_increment = Rotation3F64::exp(&omega);
+        let rotation_increment_transposed = rotation_increment.inverse().matrix();
+        let right_jacobian = right_jacobian_so3(&omega);
+
+        let delta_rotation = self.delta_rotation.matrix();
+        let acceleration_hat = Rotation3F64::hat(&acceleration);
+        let dt2 = delta_time * delta_time;
+
+        // covariance propagation with the error state (rotation, velocity, position)
+        let mut a = Matrix9::identity();
+        a.fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&rotation_increment_transposed);
+        a.fixed_view_mut::<3, 3>(3, 0)
+            .copy_from(&(-delta_rotation * acceleration_hat * delta_time));
+        a.fixed_view_mut::<3, 3>(6, 0)
+            .copy_from(&(-0.5 * delta_rotation * acceleration_hat * dt2));
+        a.fixed_view_mut::<3, 3>(6, 3)
+            .copy_from(&(Matrix3::identity() * delta_time));
+
+        let mut b = SMatrix::<f64, 9, 6>::zeros();
+        b.fixed_view_mut::<3, 3>(0, 0)
+            .copy_from(&(right_jacobian * delta_time));
+        b.fixed_view_mut::<3, 3>(3, 3)
+            .copy_from(&(delta_rotation * delta_time));
+        b.fixed_view_mut::<3, 3>(6, 3)
+            .copy_from(&(0.5 * delta_rotation * dt2));
+
+        self.covariance = a * self.covariance * a.transpose()
+            + b * (self.noise_covariance / delta_time) * b.transpose();
+
+        // bias jacobians, using the deltas before this step
+        self.d_position_d_bias_gyroscope += self.d_velocity_d_bias_gyroscope * delta_time
+            - 0.5 * delta_rotation * acceleration_hat * self.d_rotation_d_bias_gyroscope * dt2;
+        self.d_position_d_bias_accelerometer +=
+            self.d_velocity_d_bias_accelerometer * delta_time - 0.5 * delta_rotation * dt2;
+        self.d_velocity_d_bias_gyroscope -=
+            delta_rotation * acceleration_hat * self.d_rotation_d_bias_gyroscope * delta_time;
+        self.d_velocity_d_bias_accelerometer -= delta_rotation * delta_time;
+        self.d_rotation_d_bias_gyroscope = rotation_increment_transposed
+            * self.d_rotation_d_bias_gyroscope
+            - right_jacobian * delta_time;
+
+        // deltas
+        self.delta_position +=
+            self.delta_velocity * delta_time + 0.5 * delta_rotation * acceleration * dt2;
+        self.delta_velocity += delta_rotation * acceleration * delta_time;
+        self.delta_rotation = self.delta_rotation.group_mul(&rotation_increment);
+        self.delta_time += delta_time;
+    }
+
+    // integrates the measurements between two frame timestamps, each interval between two
+    // measurements uses their mean and is clipped to [timestamp_begin, timestamp_end]
+    pub fn integrate_measurements(
+        &mut self,
+        measurements: &[ImuMeasurement],
+        timestamp_begin: f64,
+        timestamp_end: f64,
+    ) {
+        for interval in measurements.windows(2) {
+            let (first, second) = (&interval[0], &interval[1]);
+            let begin = first.timestamp_seconds.max(timestamp_begin);
+            let end = second.timestamp_seconds.min(timestamp_end);
+            if end <= begin {
+                continue;
+            }
+            self.integrate(
+                &((first.angular_velocity + second.angular_velocity) / 2.0),
+                &((first.linear_acceleration + second.linear_acceleration) / 2.0),
+                end - begin,
+            );
+        }
+    }
+
+    pub fn bias(&self) -> &ImuBias {
+        &self.bias
+    }
+
+    pub fn delta_time(&self) -> f64 {
+        self.delta_time
+    }
+
+    pub fn delta_rotation(&self) -> &Rotation3F64 {
+        &self.delta_rotation
+    }
+
+    pub fn delta_velocity(&self) -> &Vector3<f64> {
+        &self.delta_velocity
+    }
+
+    pub fn delta_position(&self) -> &Vector3<f64> {
+        &self.delta_position
+    }
+
+    pub fn covariance(&self) -> &Matrix9 {
+        &self.covariance
+    }
+
+    // covariance of the bias change over the integrated time (gyroscope, accelerometer)
+    pub fn bias_random_walk_covariance(&self) -> Matrix6<f64> {
+        self.bias_random_walk_covariance * self.delta_time
+    }
+
+    pub fn d_rotation_d_bias_gyroscope(&self) -> &Matrix3<f64> {
+        &self.d_rotation_d_bias_gyroscope
+    }
+
+    pub fn d_velocity_d_bias_gyroscope(&self) -> &Matrix3<f64> {
+        &self.d_velocity_d_bias_gyroscope
+    }
+
+    pub fn d_velocity_d_bias_accelerometer(&self) -> &Matrix3<f64> {
+        &self.d_velocity_d_bias_accelerometer
+    }
+
+    pub fn d_position_d_bias_gyroscope(&self) -> &Matrix3<f64> {
+        &self.d_position_d_bias_gyroscope
+    }
+
+    pub fn d_position_d_bias_accelerometer(&self) -> &Matrix3<f64> {
+        &self.d_position_d_bias_accelerometer
+    }
+
+    // first order bias correction of the deltas
+    pub fn corrected_delta_rotation(&self, bias: &ImuBias) -> Rotation3F64 {
+        let delta_bias_gyroscope = bias.gyroscope - self.bias.gyroscope;
+        self.delta_rotation.group_mul(&Rotation3F64::exp(
+            &(self.d_rotation_d_bias_gyroscope * delta_bias_gyroscope),
+        ))
+    }
+
+    pub fn corrected_delta_velocity(&self, bias: &ImuBias) -> Vector3<f64> {
+        self.delta_velocity
+            + self.d_velocity_d_bias_gyroscope * (bias.gyroscope - self.bias.gyroscope)
+            + self.d_velocity_d_bias_accelerometer * (bias.accelerometer - self.bias.accelerometer)
+    }
+
+    pub fn corrected_delta_position(&self, bias: &ImuBias) -> Vector3<f64> {
+        self.delta_position
+            + self.d_position_d_bias_gyroscope * (bias.gyroscope - self.bias.gyroscope)
+            + self.d_position_d_bias_accelerometer * (bias.accelerometer - self.bias.accelerometer)
+    }
+
+    // propagates a state over the integrated interval
+    pub fn predict(
+        &self,
+        state: &NavigationState,
+        bias: &ImuBias,
+        gravity: &Vector3<f64>,
+    ) -> NavigationState {
+        let dt = self.delta_time;
+        let rotation = state.rotation.matrix();
+        NavigationState {
+            rotation: state
+                .rotation
+                .group_mul(&self.corrected_delta_rotation(bias)),
+            position: state.position
+                + state.velocity * dt
+                + 0.5 * gravity * dt * dt
+                + rotation * self.corrected_delta_position(bias),
+            velocity: state.velocity
+                + gravity * dt
+                + rotation * self.corrected_delta_velocity(bias),
+        }
+    }
+
+    // preintegration error between two states (rotation, velocity, position),
+    // to be weighted with the inverse of covariance()
+    pub fn residual(
+        &self,
+        state_i: &NavigationState,
+        state_j: &NavigationState,
+        bias: &ImuBias,
+        gravity: &Vector3<f64>,
+    ) -> Vector9 {
+        let dt = self.delta_time;
+        let rotation_i_transposed = state_i.rotation.inverse().matrix();
+        let residual_rotation = self
+            .corrected_delta_rotation(bias)
+            .inverse()
+            .group_mul(&state_i.rotation.inverse())
+            .group_mul(&state_j.rotation)
+            .log();
+        let residual_velocity = rotation_i_transposed
+            * (state_j.velocity - state_i.velocity - gravity * dt)
+            - self.corrected_delta_velocity(bias);
+        let residual_position = rotation_i_transposed
+            * (state_j.position
+                - state_i.position
+                - state_i.velocity * dt
+                - 0.5 * gravity * dt * dt)
+            - self.corrected_delta_position(bias);
+
+        let mut residual = Vector9::zeros();
+        residual
+            .fixed_rows_mut::<3>(0)
+            .copy_from(&residual_rotation);
+        residual
+            .fixed_rows_mut::<3>(3)
+            .copy_from(&residual_velocity);
+        residual
+            .fixed_rows_mut::<3>(6)
+            .copy_from(&residual_position);
+        residual
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imu::GRAVITY_MAGNITUDE;
+
+    const ANGULAR_VELOCITY: [f64; 3] = [0.3, -0.2, 0.5];
+    const ACCELERATION_WORLD: [f64; 3] = [0.5, -0.3, 0.2];
+    const DELTA_TIME: f64 = 0.005;
+    const NUMBER_OF_SAMPLES: usize = 200;
+
+    fn gravity() -> Vector3<f64> {
+        Vector3::new(0.0, 0.0, -GRAVITY_MAGNITUDE)
+    }
+
+    fn bias() -> ImuBias {
+        ImuBias::new(
+            Vector3::new(0.01, -0.02, 0.005),
+            Vector3::new(0.1, 0.05, -0.08),
+        )
+    }
+
+    // constant body rate and constant world acceleration, starting at the identity
+    fn rotation_at(timestamp_seconds: f64) -> Rotation3F64 {
+        Rotation3F64::exp(&(Vector3::from(ANGULAR_VELOCITY) * timestamp_seconds))
+    }
+
+    fn measurements(bias: &ImuBias) -> Vec<ImuMeasurement> {
+        (0..=NUMBER_OF_SAMPLES)
+            .map(|sample| {
+                let timestamp_seconds = sample as f64 * DELTA_TIME;
+                let specific_force = rotation_at(timestamp_seconds).inverse().matrix()
+                    * (Vector3::from(ACCELERATION_WORLD) - gravity());
+                ImuMeasurement::new(
+                    timestamp_seconds,
+                    Vector3::from(ANGULAR_VELOCITY) + bias.gyroscope,
+                    specific_force + bias.accelerometer,
+                )
+            })
+            .collect()
+    }
+
+    fn preintegrate(bias: ImuBias) -> ImuPreintegration {
+        let mut preintegration = ImuPreintegration::new(bias, &ImuNoiseParameters::default());
+        preintegration.integrate_measurements(
+            &measurements(&self::bias()),
+            0.0,
+            NUMBER_OF_SAMPLES as f64 * DELTA_TIME,
+        );
+        preintegration
+    }
+
+    fn state_at(timestamp_seconds: f64, initial_velocity: &Vector3<f64>) -> NavigationState {
+        let acceleration = Vector3::from(ACCELERATION_WORLD);
+        NavigationState {
+            rotation: rotation_at(timestamp_seconds),
+            position: initial_velocity * timestamp_seconds
+                + 0.5 * acceleration * timestamp_seconds * timestamp_seconds,
+            velocity: initial_velocity + acceleration * timestamp_seconds,
+        }
+    }
+
+    #[test]
+    fn right_jacobian_inverse_inverts_right_jacobian() {
+        for omega in [
+            Vector3::new(0.3, -0.1, 0.7),
+            Vector3::new(1e-9, 0.0, 0.0),
+            Vector3::zeros(),
+        ] {
+            let product = right_jacobian_so3(&omega) * right_jacobian_inverse_so3(&omega);
+            assert!((product - Matrix3::identity()).norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn stationary_imu_keeps_the_state() {
+        let mut preintegration =
+            ImuPreintegration::new(ImuBias::default(), &ImuNoiseParameters::default());
+        for _ in 0..100 {
+            preintegration.integrate(&Vector3::zeros(), &-gravity(), 0.01);
+        }
+        assert!((preintegration.delta_time() - 1.0).abs() < 1e-12);
+
+        let state = NavigationState {
+            rotation: Rotation3F64::identity(),
+            position: Vector3::new(1.0, 2.0, 3.0),
+            velocity: Vector3::zeros(),
+        };
+        let predicted = preintegration.predict(&state, &ImuBias::default(), &gravity());
+        assert!(predicted.rotation.log().norm() < 1e-12);
+        assert!((predicted.position - state.position).norm() < 1e-9);
+        assert!(predicted.velocity.norm() < 1e-9);
+    }
+
+    #[test]
+    fn predicts_constant_rate_motion() {
+        let preintegration = preintegrate(bias());
+        let duration = NUMBER_OF_SAMPLES as f64 * DELTA_TIME;
+        assert!((preintegration.delta_time() - duration).abs() < 1e-12);
+
+        let initial_velocity = Vector3::new(1.0, 0.0, 0.0);
+        let state_begin = state_at(0.0, &initial_velocity);
+        let state_end = state_at(duration, &initial_velocity);
+        let predicted = preintegration.predict(&state_begin, &bias(), &gravity());
+        assert!(
+            predicted
+                .rotation
+                .inverse()
+                .group_mul(&state_end.rotation)
+                .log()
+                .norm()
+                < 1e-9
+        );
+        // each step rotates the specific force with the rotation at its beginning, the error is
+        // first order in the sample interval
+        assert!((predicted.velocity - state_end.velocity).norm() < 2e-2);
+        assert!((predicted.position - state_end.position).norm() < 2e-2);
+        assert!(
+            preintegration
+                .residual(&state_begin, &state_end, &bias(), &gravity())
+                .norm()
+                < 2e-2
+        );
+    }
+
+    #[test]
+    fn bias_correction_approximates_reintegration() {
+        let preintegration = preintegrate(bias());
+        let bias_changed = ImuBias::new(
+            bias().gyroscope + Vector3::new(1e-3, -2e-3, 5e-4),
+            bias().accelerometer + Vector3::new(-1e-2, 2e-2, 1e-2),
+        );
+        let reintegrated = preintegrate(bias_changed);
+
+        let rotation_error = |rotation: &Rotation3F64| {
+            rotation
+                .inverse()
+                .group_mul(reintegrated.delta_rotation())
+                .log()
+                .norm()
+        };
+        let corrected = rotation_error(&preintegration.corrected_delta_rotation(&bias_changed));
+        let uncorrected = rotation_error(preintegration.delta_rotation());
+        assert!(corrected < 0.01 * uncorrected);
+
+        let corrected = (preintegration.corrected_delta_velocity(&bias_changed)
+            - reintegrated.delta_velocity())
+        .norm();
+        let uncorrected = (preintegration.delta_velocity() - reintegrated.delta_velocity()).norm();
+        assert!(corrected < 0.01 * uncorrected);
+
+        let corrected = (preintegration.corrected_delta_position(&bias_changed)
+            - reintegrated.delta_position())
+        .norm();
+        let uncorrected = (preintegration.delta_position() - reintegrated.delta_position()).norm();
+        assert!(corrected < 0.01 * uncorrected);
+    }
+
+    #[test]
+    fn covariance_grows_and_stays_symmetric() {
+        let mut preintegration =
+            ImuPreintegration::new(ImuBias::default(), &ImuNoiseParameters::default());
+        let mut previous_trace = 0.0;
+        for measurement in measurements(&ImuBias::default()) {
+            preintegration.integrate(
+                &measurement.angular_velocity,
+                &measurement.linear_acceleration,
+                DELTA_TIME,
+            );
+            let covariance = preintegration.covariance();
+            assert!((covariance - covariance.transpose()).norm() < 1e-15);
+            assert!(covariance.trace() > previous_trace);
+            previous_trace = covariance.trace();
+        }
+    }
+}
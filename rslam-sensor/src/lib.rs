@@ -1,7 +1,10 @@
+pub mod calibration;
+pub mod distortion;
+pub mod gnss;
+pub mod imu;
+pub mod imu_preintegration;
 pub mod pinhole_camera;
-
-pub trait HasStereoCamera {
-    type FrameItem;
-
-    fn get_stereo_frame(self) -> Option<(Self::FrameItem, Self::FrameItem)>;
-}
+pub mod sensor_source;
+pub mod stereo_camera;
+pub mod synchronization;
+pub mod wheel_odometry;
@@ -1,9 +1,21 @@
-use sophus::{lie::Isometry3F64, sensor::camera_enum::perspective_camera::PinholeCameraF64};
+use rslam_core::{PixelCoordinates, PointCoordinates, Real};
+use crate::distortion::Distortion;
+use sophus::{
+    lie::Isometry3F64,
+    nalgebra::Matrix2x3,
+    sensor::{
+        camera_enum::perspective_camera::PinholeCameraF64,
+        projections::perspective::PerspectiveProjectionImpl, traits::IsProjection,
+    },
+};
 
 #[derive(Clone, Debug)]
 pub struct PinholeCamera {
     pub model: PinholeCameraF64,
 
+    // lens distortion of the raw images (None for rectified images)
+    distortion: Distortion,
+
     // camera to robot transform (usually constant during operation)
     camera_to_robot: Isometry3F64,
 }
@@ -20,6 +32,32 @@ impl rslam_core::Camera for PinholeCamera {
     fn camera_to_robot(&self) -> &sophus::lie::Isometry3F64 {
         &self.camera_to_robot
     }
+
+    // projections include the lens distortion, see ideal_pixel for the distortion free camera
+    fn project(&self, point_in_camera: &PointCoordinates) -> Option<PixelCoordinates> {
+        if point_in_camera.z <= 0.0 {
+            return None;
+        }
+        let point_in_z1_plane = PerspectiveProjectionImpl::<f64, 1>::proj(point_in_camera);
+        let distorted = self.distortion.distort(&point_in_z1_plane);
+        Some(
+            self.model
+                .cam_proj(&PointCoordinates::new(distorted.x, distorted.y, 1.0)),
+        )
+    }
+
+    fn unproject(&self, pixel: &PixelCoordinates, depth: Real) -> PointCoordinates {
+        let normalized = self.normalized_coordinates(pixel);
+        PointCoordinates::new(normalized.x * depth, normalized.y * depth, depth)
+    }
+
+    fn dx_project_x(&self, point_in_camera: &PointCoordinates) -> Matrix2x3<Real> {
+        let point_in_z1_plane = PerspectiveProjectionImpl::<f64, 1>::proj(point_in_camera);
+        let distorted = self.distortion.distort(&point_in_z1_plane);
+        self.model.dx_distort_x(&distorted)
+            * self.distortion.dx_distort_x(&point_in_z1_plane)
+            * PerspectiveProjectionImpl::<f64, 1>::dx_proj_x(point_in_camera)
+    }
 }
 
 impl PinholeCamera {
@@ -27,7 +65,104 @@ impl PinholeCamera {
         Self {
             model,
 
+            distortion: Distortion::None,
             camera_to_robot: Isometry3F64::identity(),
         }
     }
+
+    pub fn with_distortion(mut self, distortion: Distortion) -> Self {
+        self.distortion = distortion;
+        self
+    }
+
+    pub fn distortion(&self) -> &Distortion {
+        &self.distortion
+    }
+
+    pub fn set_camera_to_robot(&mut self, camera_to_robot: Isometry3F64) {
+        self.camera_to_robot = camera_to_robot;
+    }
+
+    // undistorted point on the z=1 plane of a raw pixel
+    pub fn normalized_coordinates(&self, pixel: &PixelCoordinates) -> PixelCoordinates {
+        let point_in_z1_plane = self.model.cam_unproj_with_z(pixel, 1.0);
+        self.distortion
+            .undistort(&PixelCoordinates::new(point_in_z1_plane.x, point_in_z1_plane.y))
+    }
+
+    // pixel of the ideal (distortion free) camera with the same intrinsics
+    pub fn ideal_pixel(&self, pixel: &PixelCoordinates) -> PixelCoordinates {
+        let normalized = self.normalized_coordinates(pixel);
+        self.model
+            .cam_proj(&PointCoordinates::new(normalized.x, normalized.y, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rslam_core::Camera;
+    use sophus::{core::linalg::VecF64, image::ImageSize};
+
+    fn cameras() -> Vec<PinholeCamera> {
+        let model = PinholeCameraF64::from_params_and_size(
+            &VecF64::<4>::new(460.0, 455.0, 370.0, 250.0),
+            ImageSize::new(752, 480),
+        );
+        vec![
+            PinholeCamera::new(model),
+            PinholeCamera::new(model).with_distortion(Distortion::RadialTangential([
+                -0.28, 0.07, 0.0002, 0.00002, 0.01,
+            ])),
+            PinholeCamera::new(model)
+                .with_distortion(Distortion::Equidistant([0.01, -0.005, 0.002, -0.001])),
+        ]
+    }
+
+    #[test]
+    fn unproject_inverts_project() {
+        let point = PointCoordinates::new(0.4, -0.3, 2.5);
+        for camera in cameras() {
+            let pixel = camera.project(&point).unwrap();
+            let unprojected = camera.unproject(&pixel, point.z);
+            assert!(
+                (unprojected - point).norm() < 1e-9,
+                "{:?}",
+                camera.distortion()
+            );
+        }
+    }
+
+    #[test]
+    fn dx_project_x_matches_finite_differences() {
+        let point = PointCoordinates::new(0.4, -0.3, 2.5);
+        for camera in cameras() {
+            let jacobian = camera.dx_project_x(&point);
+            for column in 0..3 {
+                let mut offset = PointCoordinates::zeros();
+                offset[column] = 1e-6;
+                let numeric = (camera.project(&(point + offset)).unwrap()
+                    - camera.project(&(point - offset)).unwrap())
+                    / 2e-6;
+                assert!(
+                    (jacobian.column(column) - numeric).norm() < 1e-5,
+                    "{:?}: {} vs {}",
+                    camera.distortion(),
+                    jacobian.column(column),
+                    numeric
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ideal_pixel_removes_distortion() {
+        let point = PointCoordinates::new(-0.6, 0.35, 1.5);
+        let cameras = cameras();
+        let ideal = cameras[0].project(&point).unwrap();
+        for camera in &cameras {
+            let pixel = camera.project(&point).unwrap();
+            assert!((camera.ideal_pixel(&pixel) - ideal).norm() < 1e-6);
+        }
+    }
 }
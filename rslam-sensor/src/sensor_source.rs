@@ -0,0 +1,288 @@
+//! common input of the pipeline: live drivers, recorded logs and datasets all yield
+//! timestamped messages tagged with the sensor they come from
+
+use std::{fmt, sync::Arc};
+
+use anyhow::Result;
+
+use crate::{
+    gnss::GnssMeasurement,
+    imu::{Imu, ImuMeasurement},
+    pinhole_camera::PinholeCamera,
+    stereo_camera::StereoCamera,
+    synchronization::Timestamped,
+    wheel_odometry::{WheelOdometry, WheelOdometryMeasurement},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SensorId(pub String);
+
+impl SensorId {
+    pub fn new(name: &str) -> Self {
+        Self(name.to_string())
+    }
+}
+
+impl fmt::Display for SensorId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// calibration the data of a message has to be interpreted with
+// shared behind an Arc by the messages, the variant sizes don't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum SensorCalibration {
+    Camera(PinholeCamera),
+    StereoCamera(StereoCamera),
+    Imu(Imu),
+    WheelOdometry(WheelOdometry),
+}
+
+#[derive(Clone, Debug)]
+pub enum SensorData<I> {
+    StereoPair { left: I, right: I },
+    MonoImage(I),
+    // depth image registered to the camera of the calibration (meters)
+    Depth(I),
+    Imu(ImuMeasurement),
+    WheelOdometry(WheelOdometryMeasurement),
+    Gnss(GnssMeasurement),
+}
+
+#[derive(Clone, Debug)]
+pub struct SensorMessage<I> {
+    pub sensor_id: SensorId,
+    // sensor clock
+    pub timestamp_seconds: f64,
+    // per sensor, increasing by one for every message of the sensor
+    pub sequence: u64,
+    // shared between the messages of a sensor
+    pub calibration: Option<Arc<SensorCalibration>>,
+    pub data: SensorData<I>,
+}
+
+impl<I: Clone> Timestamped for SensorMessage<I> {
+    fn timestamp_seconds(&self) -> f64 {
+        self.timestamp_seconds
+    }
+
+    // IMU and wheel odometry messages of the same sensor can be interpolated
+    fn interpolate(before: &Self, after: &Self, timestamp_seconds: f64) -> Option<Self> {
+        if before.sensor_id != after.sensor_id {
+            return None;
+        }
+        let data = match (&before.data, &after.data) {
+            (SensorData::Imu(before), SensorData::Imu(after)) => SensorData::Imu(
+                ImuMeasurement::interpolate(before, after, timestamp_seconds)?,
+            ),
+            (SensorData::WheelOdometry(before), SensorData::WheelOdometry(after)) => {
+                SensorData::WheelOdometry(WheelOdometryMeasurement::interpolate(
+                    before,
+                    after,
+                    timestamp_seconds,
+                )?)
+            }
+            _ => return None,
+        };
+        Some(Self {
+            sensor_id: after.sensor_id.clone(),
+            timestamp_seconds,
+            sequence: after.sequence,
+            calibration: after.calibration.clone(),
+            data,
+        })
+    }
+}
+
+pub trait SensorSource {
+    type Image;
+
+    // sensors this source yields messages for
+    fn sensor_ids(&self) -> Vec<SensorId>;
+
+    // next message, in timestamp order per sensor; Ok(None) once the source is exhausted
+    fn next_message(&mut self) -> Result<Option<SensorMessage<Self::Image>>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use sophus::{lie::Isometry3F64, nalgebra::Vector3};
+
+    use super::*;
+    use crate::{
+        imu::ImuNoiseParameters,
+        synchronization::{SynchronizationPolicy, SynchronizationSourceCfg, Synchronizer},
+    };
+
+    // recorded messages, images are their frame number
+    struct RecordedSource {
+        messages: VecDeque<SensorMessage<u32>>,
+    }
+
+    impl SensorSource for RecordedSource {
+        type Image = u32;
+
+        fn sensor_ids(&self) -> Vec<SensorId> {
+            let mut sensor_ids: Vec<_> = self
+                .messages
+                .iter()
+                .map(|message| message.sensor_id.clone())
+                .collect();
+            sensor_ids.sort();
+            sensor_ids.dedup();
+            sensor_ids
+        }
+
+        fn next_message(&mut self) -> Result<Option<SensorMessage<u32>>> {
+            Ok(self.messages.pop_front())
+        }
+    }
+
+    fn imu_calibration() -> Arc<SensorCalibration> {
+        Arc::new(SensorCalibration::Imu(Imu::new(
+            ImuNoiseParameters::default(),
+            Isometry3F64::identity(),
+        )))
+    }
+
+    // the angular velocity encodes the timestamp to check interpolated messages
+    fn imu_message(
+        calibration: &Arc<SensorCalibration>,
+        sequence: u64,
+        timestamp_seconds: f64,
+    ) -> SensorMessage<u32> {
+        SensorMessage {
+            sensor_id: SensorId::new("imu"),
+            timestamp_seconds,
+            sequence,
+            calibration: Some(calibration.clone()),
+            data: SensorData::Imu(ImuMeasurement::new(
+                timestamp_seconds,
+                Vector3::new(timestamp_seconds, 0.0, 0.0),
+                Vector3::new(0.0, 0.0, 9.81),
+            )),
+        }
+    }
+
+    fn stereo_message(sequence: u64, timestamp_seconds: f64) -> SensorMessage<u32> {
+        SensorMessage {
+            sensor_id: SensorId::new("stereo"),
+            timestamp_seconds,
+            sequence,
+            calibration: None,
+            data: SensorData::StereoPair {
+                left: sequence as u32,
+                right: sequence as u32,
+            },
+        }
+    }
+
+    fn angular_velocity(message: &SensorMessage<u32>) -> f64 {
+        match &message.data {
+            SensorData::Imu(measurement) => measurement.angular_velocity.x,
+            data => panic!("expected an imu message, got {:?}", data),
+        }
+    }
+
+    #[test]
+    fn orders_and_prints_sensor_ids() {
+        let mut sensor_ids = vec![SensorId::new("stereo"), SensorId::new("imu")];
+        sensor_ids.sort();
+        assert_eq!(
+            sensor_ids,
+            vec![SensorId::new("imu"), SensorId::new("stereo")]
+        );
+        assert_eq!(SensorId::new("imu").to_string(), "imu");
+    }
+
+    #[test]
+    fn interpolates_imu_messages_of_the_same_sensor() {
+        let calibration = imu_calibration();
+        let before = imu_message(&calibration, 4, 1.0);
+        let after = imu_message(&calibration, 5, 1.2);
+        let interpolated = SensorMessage::interpolate(&before, &after, 1.05).unwrap();
+
+        assert_eq!(interpolated.sensor_id, SensorId::new("imu"));
+        assert_eq!(interpolated.timestamp_seconds, 1.05);
+        assert_eq!(interpolated.sequence, 5);
+        assert!((angular_velocity(&interpolated) - 1.05).abs() < 1e-12);
+        // the calibration is shared, not copied
+        assert!(Arc::ptr_eq(
+            interpolated.calibration.as_ref().unwrap(),
+            &calibration
+        ));
+    }
+
+    #[test]
+    fn does_not_interpolate_images_or_different_sensors() {
+        assert!(
+            SensorMessage::interpolate(&stereo_message(0, 1.0), &stereo_message(1, 1.1), 1.05)
+                .is_none()
+        );
+
+        let calibration = imu_calibration();
+        let mut other = imu_message(&calibration, 5, 1.2);
+        other.sensor_id = SensorId::new("imu_1");
+        assert!(
+            SensorMessage::interpolate(&imu_message(&calibration, 4, 1.0), &other, 1.05).is_none()
+        );
+    }
+
+    #[test]
+    fn synchronizes_the_messages_of_a_source() {
+        // stereo at 10 Hz, imu at 100 Hz, interleaved like a recording
+        let calibration = imu_calibration();
+        let mut messages = VecDeque::new();
+        for sequence in 0..=30 {
+            let timestamp_seconds = sequence as f64 * 0.01;
+            messages.push_back(imu_message(&calibration, sequence, timestamp_seconds));
+            if sequence % 10 == 5 {
+                messages.push_back(stereo_message(sequence / 10, timestamp_seconds + 0.002));
+            }
+        }
+        let mut source = RecordedSource { messages };
+        assert_eq!(
+            source.sensor_ids(),
+            vec![SensorId::new("imu"), SensorId::new("stereo")]
+        );
+
+        let mut synchronizer = Synchronizer::new(vec![
+            SynchronizationSourceCfg::new("stereo", SynchronizationPolicy::Exact),
+            SynchronizationSourceCfg::new("imu", SynchronizationPolicy::Interpolated),
+        ])
+        .unwrap();
+        let mut bundles = vec![];
+        while let Some(message) = source.next_message().unwrap() {
+            let index = if message.sensor_id == SensorId::new("stereo") {
+                0
+            } else {
+                1
+            };
+            synchronizer.push(index, message).unwrap();
+            bundles.extend(std::iter::from_fn(|| synchronizer.pop_bundle()));
+        }
+        assert!(source.next_message().unwrap().is_none());
+
+        assert_eq!(bundles.len(), 3);
+        for (frame, bundle) in bundles.iter().enumerate() {
+            let timestamp_seconds = 0.052 + frame as f64 * 0.1;
+            assert!((bundle.timestamp_seconds - timestamp_seconds).abs() < 1e-12);
+            let stereo = &bundle.messages[0];
+            assert_eq!(stereo.len(), 1);
+            assert!(matches!(
+                stereo[0].data,
+                SensorData::StereoPair { left, right } if left == frame as u32 && right == frame as u32
+            ));
+
+            // closed by an imu message interpolated at the frame
+            let imu = bundle.messages[1].last().unwrap();
+            assert!((imu.timestamp_seconds - timestamp_seconds).abs() < 1e-12);
+            assert!((angular_velocity(imu) - timestamp_seconds).abs() < 1e-12);
+            assert!(Arc::ptr_eq(imu.calibration.as_ref().unwrap(), &calibration));
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{bail, Result};
+use rslam_core::{Camera, PixelCoordinates, PointCoordinates, Real};
+use sophus::{
+    core::linalg::VecF64,
+    lie::{prelude::IsTranslationProductGroup, Isometry3F64},
+    nalgebra::{Matrix3, Matrix3x4, Matrix4, Vector3},
+};
+
+use crate::pinhole_camera::PinholeCamera;
+
+/// two view triangulation of correspondences of an unrectified rig
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriangulationMethod {
+    // midpoint of the shortest segment between the two viewing rays
+    Midpoint,
+    // linear (direct linear transform) solution of the projection equations
+    Dlt,
+}
+
+impl TriangulationMethod {
+    pub const ALL: [TriangulationMethod; 2] =
+        [TriangulationMethod::Midpoint, TriangulationMethod::Dlt];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TriangulationMethod::Midpoint => "MIDPOINT",
+            TriangulationMethod::Dlt => "DLT",
+        }
+    }
+}
+
+impl fmt::Display for TriangulationMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for TriangulationMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let method = match s.trim().to_ascii_uppercase().as_str() {
+            "MIDPOINT" => TriangulationMethod::Midpoint,
+            "DLT" => TriangulationMethod::Dlt,
+            _ => bail!(
+                "unknown triangulation method '{}', expected one of: {}",
+                s,
+                TriangulationMethod::ALL
+                    .map(|method| method.name())
+                    .join(", ")
+            ),
+        };
+        Ok(method)
+    }
+}
+
+/// stereo rig made of two pinhole cameras, the rig frame coincides with the left camera frame
+#[derive(Clone, Debug)]
+pub struct StereoCamera {
+    pub left: PinholeCamera,
+    pub right: PinholeCamera,
+
+    // transforms points from left camera into right camera coordinates
+    left_to_right: Isometry3F64,
+    baseline_meters: Real,
+
+    // rig (left camera) to robot transform
+    rig_to_robot: Isometry3F64,
+}
+
+impl StereoCamera {
+    pub fn new(
+        mut left: PinholeCamera,
+        mut right: PinholeCamera,
+        left_to_right: Isometry3F64,
+        rig_to_robot: Isometry3F64,
+    ) -> Result<Self> {
+        let baseline_meters = left_to_right.translation().norm();
+        if baseline_meters <= 0.0 || !baseline_meters.is_finite() {
+            bail!("invalid baseline (m): {}", baseline_meters);
+        }
+
+        left.set_camera_to_robot(rig_to_robot);
+        right.set_camera_to_robot(rig_to_robot.group_mul(&left_to_right.inverse()));
+
+        Ok(Self {
+            left,
+            right,
+            left_to_right,
+            baseline_meters,
+            rig_to_robot,
+        })
+    }
+
+    // rectified rig: right camera displaced by the baseline along the x axis of the left camera
+    pub fn new_rectified(
+        left: PinholeCamera,
+        right: PinholeCamera,
+        baseline_meters: Real,
+        rig_to_robot: Isometry3F64,
+    ) -> Result<Self> {
+        if baseline_meters <= 0.0 {
+            bail!("invalid baseline (m): {}", baseline_meters);
+        }
+        let left_to_right =
+            Isometry3F64::from_translation(&VecF64::<3>::new(-baseline_meters, 0.0, 0.0));
+        Self::new(left, right, left_to_right, rig_to_robot)
+    }
+
+    pub fn left_to_right(&self) -> &Isometry3F64 {
+        &self.left_to_right
+    }
+
+    pub fn baseline_meters(&self) -> Real {
+        self.baseline_meters
+    }
+
+    pub fn rig_to_robot(&self) -> &Isometry3F64 {
+        &self.rig_to_robot
+    }
+
+    pub fn focal_length_pixels(&self) -> Real {
+        self.left.model.params()[0]
+    }
+
+    // depth (z) of a point in the rectified left camera for the given disparity
+    pub fn disparity_to_depth(&self, disparity_pixels: Real) -> Real {
+        self.focal_length_pixels() * self.baseline_meters / disparity_pixels
+    }
+
+    pub fn depth_to_disparity(&self, depth_meters: Real) -> Real {
+        self.focal_length_pixels() * self.baseline_meters / depth_meters
+    }
+
+    // triangulates a rectified stereo correspondence into left camera coordinates,
+    // returns None if the disparity is not positive
+    pub fn triangulate(
+        &self,
+        pixel_left: &PixelCoordinates,
+        pixel_right: &PixelCoordinates,
+    ) -> Option<PointCoordinates> {
+        let disparity_pixels = pixel_left.x - pixel_right.x;
+        if disparity_pixels <= 0.0 {
+            return None;
+        }
+        let depth_meters = self.disparity_to_depth(disparity_pixels);
+
+        // average in case we have an epipolar offset in v
+        let pixel = PixelCoordinates::new(pixel_left.x, (pixel_left.y + pixel_right.y) / 2.0);
+        Some(self.left.unproject(&pixel, depth_meters))
+    }
+
+    // covariance of the triangulated point in left camera coordinates, with independent pixel
+    // noise of the given standard deviation on both coordinates of both pixels
+    pub fn triangulation_covariance(
+        &self,
+        pixel_left: &PixelCoordinates,
+        pixel_right: &PixelCoordinates,
+        pixel_noise_sigma: Real,
+    ) -> Option<Matrix3<Real>> {
+        let disparity_pixels = pixel_left.x - pixel_right.x;
+        let point = self.triangulate(pixel_left, pixel_right)?;
+        let focal_length_y = self.left.model.params()[1];
+
+        // derivatives of (x, y, z) by (u left, v left, u right, v right), z = f b / d and
+        // v = (v left + v right) / 2
+        let x_by_d = point.x / disparity_pixels;
+        let y_by_d = point.y / disparity_pixels;
+        let z_by_d = point.z / disparity_pixels;
+        let y_by_v = point.z / (2.0 * focal_length_y);
+        let jacobian = Matrix3x4::new(
+            point.z / self.focal_length_pixels() - x_by_d,
+            0.0,
+            x_by_d,
+            0.0,
+            -y_by_d,
+            y_by_v,
+            y_by_d,
+            y_by_v,
+            -z_by_d,
+            0.0,
+            z_by_d,
+            0.0,
+        );
+        Some(pixel_noise_sigma * pixel_noise_sigma * jacobian * jacobian.transpose())
+    }
+
+    // essential matrix E = [t]x R of the left to right transform, x_right^T E x_left = 0 for
+    // normalized coordinates of a correspondence
+    pub fn essential_matrix(&self) -> Matrix3<Real> {
+        let rotation = self.left_to_right.rotation().matrix();
+        let translation = self.left_to_right.translation();
+        translation.cross_matrix() * rotation
+    }
+
+    // fundamental matrix for ideal (undistorted) pixels, see PinholeCamera::ideal_pixel
+    pub fn fundamental_matrix(&self) -> Matrix3<Real> {
+        let inverse_left = inverse_intrinsics(&self.left);
+        let inverse_right = inverse_intrinsics(&self.right);
+        inverse_right.transpose() * self.essential_matrix() * inverse_left
+    }
+
+    // triangulates a correspondence of raw (distorted) pixels into left camera coordinates,
+    // returns None for points behind either camera or (nearly) parallel rays
+    pub fn triangulate_unrectified(
+        &self,
+        pixel_left: &PixelCoordinates,
+        pixel_right: &PixelCoordinates,
+        method: TriangulationMethod,
+    ) -> Option<PointCoordinates> {
+        let normalized_left = self.left.normalized_coordinates(pixel_left);
+        let normalized_right = self.right.normalized_coordinates(pixel_right);
+        let rotation = self.left_to_right.rotation().matrix();
+        let translation = self.left_to_right.translation();
+
+        let point = match method {
+            TriangulationMethod::Midpoint => {
+                // rays in left camera coordinates, the right camera center is -R^T t
+                let direction_left = normalized_left.push(1.0);
+                let direction_right = rotation.transpose() * normalized_right.push(1.0);
+                let center_right = -rotation.transpose() * translation;
+
+                let a = direction_left.dot(&direction_left);
+                let b = direction_left.dot(&direction_right);
+                let c = direction_right.dot(&direction_right);
+                let d = -direction_left.dot(&center_right);
+                let e = -direction_right.dot(&center_right);
+                let denominator = a * c - b * b;
+                if denominator < 1e-12 * a * c {
+                    return None;
+                }
+                let scale_left = (b * e - c * d) / denominator;
+                let scale_right = (a * e - b * d) / denominator;
+                if scale_left <= 0.0 || scale_right <= 0.0 {
+                    return None;
+                }
+                (direction_left * scale_left + center_right + direction_right * scale_right) / 2.0
+            }
+            TriangulationMethod::Dlt => {
+                // projection matrices [I | 0] and [R | t] of the normalized cameras
+                let mut projection_right = Matrix3x4::zeros();
+                projection_right
+                    .fixed_view_mut::<3, 3>(0, 0)
+                    .copy_from(&rotation);
+                projection_right.set_column(3, &translation);
+                let projection_left = Matrix3x4::identity();
+
+                let mut a = Matrix4::zeros();
+                for (row, (projection, normalized)) in [
+                    (&projection_left, &normalized_left),
+                    (&projection_right, &normalized_right),
+                ]
+                .into_iter()
+                .enumerate()
+                {
+                    a.set_row(
+                        2 * row,
+                        &(normalized.x * projection.row(2) - projection.row(0)),
+                    );
+                    a.set_row(
+                        2 * row + 1,
+                        &(normalized.y * projection.row(2) - projection.row(1)),
+                    );
+                }
+
+                // right singular vector of the smallest singular value
+                let svd = a.svd(false, true);
+                let v_t = svd.v_t?;
+                let (index_min, _) = svd.singular_values.argmin();
+                let homogeneous = v_t.row(index_min).transpose();
+                if homogeneous.w.abs() < 1e-12 {
+                    return None;
+                }
+                let point = homogeneous.xyz() / homogeneous.w;
+                if (rotation * point + translation).z <= 0.0 {
+                    return None;
+                }
+                point
+            }
+        };
+        if point.z <= 0.0 || !point.iter().all(|x| x.is_finite()) {
+            return None;
+        }
+        Some(point)
+    }
+
+    // covariance of triangulate_unrectified with independent pixel noise of the given standard
+    // deviation, propagated with a central difference Jacobian
+    pub fn unrectified_triangulation_covariance(
+        &self,
+        pixel_left: &PixelCoordinates,
+        pixel_right: &PixelCoordinates,
+        method: TriangulationMethod,
+        pixel_noise_sigma: Real,
+    ) -> Option<Matrix3<Real>> {
+        const STEP_PIXELS: Real = 1e-3;
+        let mut jacobian = Matrix3x4::zeros();
+        for column in 0..4 {
+            let mut offset = Vector3::zeros();
+            offset[column % 2] = STEP_PIXELS;
+            let offset = offset.xy();
+            let (plus, minus) = if column < 2 {
+                (
+                    self.triangulate_unrectified(&(pixel_left + offset), pixel_right, method)?,
+                    self.triangulate_unrectified(&(pixel_left - offset), pixel_right, method)?,
+                )
+            } else {
+                (
+                    self.triangulate_unrectified(pixel_left, &(pixel_right + offset), method)?,
+                    self.triangulate_unrectified(pixel_left, &(pixel_right - offset), method)?,
+                )
+            };
+            jacobian.set_column(column, &((plus - minus) / (2.0 * STEP_PIXELS)));
+        }
+        Some(pixel_noise_sigma * pixel_noise_sigma * jacobian * jacobian.transpose())
+    }
+}
+
+// inverse of the pinhole intrinsics matrix
+fn inverse_intrinsics(camera: &PinholeCamera) -> Matrix3<Real> {
+    let params = camera.model.params();
+    let (fx, fy, cx, cy) = (params[0], params[1], params[2], params[3]);
+    Matrix3::new(
+        1.0 / fx,
+        0.0,
+        -cx / fx,
+        0.0,
+        1.0 / fy,
+        -cy / fy,
+        0.0,
+        0.0,
+        1.0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::distortion::Distortion;
+    use sophus::{image::ImageSize, sensor::camera_enum::perspective_camera::PinholeCameraF64};
+
+    fn camera(focal_length: Real) -> PinholeCamera {
+        PinholeCamera::new(PinholeCameraF64::from_params_and_size(
+            &VecF64::<4>::new(focal_length, focal_length + 3.0, 640.0, 360.0),
+            ImageSize::new(1280, 720),
+        ))
+        .with_distortion(Distortion::RadialTangential([
+            -0.28, 0.07, 0.0002, 0.00002, 0.0,
+        ]))
+    }
+
+    // verged rig with a mostly horizontal baseline
+    fn unrectified_rig() -> StereoCamera {
+        StereoCamera::new(
+            camera(500.0),
+            camera(510.0),
+            Isometry3F64::exp(&VecF64::<6>::new(-0.12, 0.01, 0.005, 0.01, -0.03, 0.02)),
+            Isometry3F64::identity(),
+        )
+        .unwrap()
+    }
+
+    fn points() -> Vec<PointCoordinates> {
+        vec![
+            PointCoordinates::new(0.5, -0.3, 4.0),
+            PointCoordinates::new(-2.0, 1.0, 8.0),
+            PointCoordinates::new(0.1, 0.2, 1.2),
+        ]
+    }
+
+    // raw pixels of a point in left camera coordinates
+    fn observe(
+        stereo: &StereoCamera,
+        point: &PointCoordinates,
+    ) -> (PixelCoordinates, PixelCoordinates) {
+        (
+            stereo.left.project(point).unwrap(),
+            stereo
+                .right
+                .project(&stereo.left_to_right().transform(point))
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn correspondences_satisfy_the_epipolar_constraint() {
+        let stereo = unrectified_rig();
+        let essential = stereo.essential_matrix();
+        let fundamental = stereo.fundamental_matrix();
+        for point in points() {
+            let (pixel_left, pixel_right) = observe(&stereo, &point);
+            let normalized_left = stereo.left.normalized_coordinates(&pixel_left).push(1.0);
+            let normalized_right = stereo.right.normalized_coordinates(&pixel_right).push(1.0);
+            assert!(normalized_right.dot(&(essential * normalized_left)).abs() < 1e-9);
+
+            let ideal_left = stereo.left.ideal_pixel(&pixel_left).push(1.0);
+            let ideal_right = stereo.right.ideal_pixel(&pixel_right).push(1.0);
+            assert!(ideal_right.dot(&(fundamental * ideal_left)).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn triangulates_unrectified_correspondences() {
+        let stereo = unrectified_rig();
+        for point in points() {
+            let (pixel_left, pixel_right) = observe(&stereo, &point);
+            for method in TriangulationMethod::ALL {
+                let triangulated = stereo
+                    .triangulate_unrectified(&pixel_left, &pixel_right, method)
+                    .unwrap();
+                assert!((triangulated - point).norm() < 1e-6, "{}", method);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_points_behind_the_rig() {
+        let stereo = unrectified_rig();
+        let (pixel_left, pixel_right) = observe(&stereo, &PointCoordinates::new(0.5, -0.3, 4.0));
+        // swapping the views mirrors the point behind the cameras
+        for method in TriangulationMethod::ALL {
+            assert!(stereo
+                .triangulate_unrectified(&pixel_right, &pixel_left, method)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn unrectified_triangulation_agrees_with_disparity_on_a_rectified_rig() {
+        let camera = || {
+            PinholeCamera::new(PinholeCameraF64::from_params_and_size(
+                &VecF64::<4>::new(718.0, 718.0, 607.0, 185.0),
+                ImageSize::new(1241, 376),
+            ))
+        };
+        let stereo =
+            StereoCamera::new_rectified(camera(), camera(), 0.54, Isometry3F64::identity())
+                .unwrap();
+        let pixel_left = PixelCoordinates::new(800.3, 250.7);
+        let pixel_right = PixelCoordinates::new(770.1, 250.7);
+        let expected = stereo.triangulate(&pixel_left, &pixel_right).unwrap();
+        for method in TriangulationMethod::ALL {
+            let triangulated = stereo
+                .triangulate_unrectified(&pixel_left, &pixel_right, method)
+                .unwrap();
+            assert!((triangulated - expected).norm() < 1e-9, "{}", method);
+        }
+        assert!(stereo.triangulate(&pixel_right, &pixel_left).is_none());
+    }
+
+    #[test]
+    fn parses_triangulation_methods() {
+        for method in TriangulationMethod::ALL {
+            assert_eq!(
+                method.name().parse::<TriangulationMethod>().unwrap(),
+                method
+            );
+        }
+        assert!("SVD".parse::<TriangulationMethod>().is_err());
+    }
+
+    #[test]
+    fn triangulation_covariance_matches_finite_differences() {
+        let camera = || {
+            PinholeCamera::new(PinholeCameraF64::from_params_and_size(
+                &VecF64::<4>::new(718.0, 710.0, 607.0, 185.0),
+                ImageSize::new(1241, 376),
+            ))
+        };
+        let stereo =
+            StereoCamera::new_rectified(camera(), camera(), 0.54, Isometry3F64::identity())
+                .unwrap();
+        let pixels = [800.3, 250.7, 770.1, 251.2];
+        let triangulate = |pixels: [Real; 4]| {
+            stereo
+                .triangulate(
+                    &PixelCoordinates::new(pixels[0], pixels[1]),
+                    &PixelCoordinates::new(pixels[2], pixels[3]),
+                )
+                .unwrap()
+        };
+        let mut jacobian = Matrix3x4::zeros();
+        for column in 0..4 {
+            let (mut plus, mut minus) = (pixels, pixels);
+            plus[column] += 1e-5;
+            minus[column] -= 1e-5;
+            jacobian.set_column(column, &((triangulate(plus) - triangulate(minus)) / 2e-5));
+        }
+        let expected = 0.25 * jacobian * jacobian.transpose();
+
+        let pixel_left = PixelCoordinates::new(pixels[0], pixels[1]);
+        let pixel_right = PixelCoordinates::new(pixels[2], pixels[3]);
+        let covariance = stereo
+            .triangulation_covariance(&pixel_left, &pixel_right, 0.5)
+            .unwrap();
+        assert!((covariance - expected).abs().max() < 1e-6 * expected.abs().max());
+
+        // without an epipolar offset the central difference propagation of the rays agrees
+        let pixel_right = PixelCoordinates::new(pixels[2], pixels[1]);
+        let covariance = stereo
+            .triangulation_covariance(&pixel_left, &pixel_right, 0.5)
+            .unwrap();
+        let unrectified = stereo
+            .unrectified_triangulation_covariance(
+                &pixel_left,
+                &pixel_right,
+                TriangulationMethod::Midpoint,
+                0.5,
+            )
+            .unwrap();
+        assert!((covariance[(2, 2)] - unrectified[(2, 2)]).abs() < 1e-3 * covariance[(2, 2)]);
+    }
+
+    #[test]
+    fn depth_uncertainty_grows_with_distance() {
+        let stereo = unrectified_rig();
+        let mut previous_sigma = 0.0;
+        for depth in [1.0, 4.0, 16.0] {
+            let (pixel_left, pixel_right) =
+                observe(&stereo, &PointCoordinates::new(0.2, 0.1, depth));
+            let covariance = stereo
+                .unrectified_triangulation_covariance(
+                    &pixel_left,
+                    &pixel_right,
+                    TriangulationMethod::Dlt,
+                    1.0,
+                )
+                .unwrap();
+            let sigma = covariance[(2, 2)].sqrt();
+            assert!(sigma > previous_sigma);
+            previous_sigma = sigma;
+        }
+    }
+}
@@ -0,0 +1,691 @@
+//! time alignment of independent sensor streams
+//!
+//! The first source is the reference (usually the camera): every reference message opens a
+//! bundle and the other sources are matched to its timestamp with their own policy. Source
+//! timestamps are shifted by a per-source offset into the reference clock before matching.
+//! A source that stays silent for longer than its maximum latency is unmatched for the
+//! reference timestamp instead of holding back all later bundles.
+
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+use serde::Deserialize;
+use sophus::lie::Isometry3F64;
+
+use crate::{
+    imu::ImuMeasurement,
+    wheel_odometry::{WheelOdometryData, WheelOdometryMeasurement},
+};
+
+// timestamps closer than this are identical for the exact policy
+const EXACT_TOLERANCE_SECONDS: f64 = 1e-9;
+
+pub trait Timestamped: Clone {
+    fn timestamp_seconds(&self) -> f64;
+
+    // message at the timestamp between two messages, None if the message type can't be
+    // interpolated (the nearest message is used instead)
+    fn interpolate(_before: &Self, _after: &Self, _timestamp_seconds: f64) -> Option<Self> {
+        None
+    }
+}
+
+fn interpolation_factor(before: f64, after: f64, timestamp_seconds: f64) -> f64 {
+    if after > before {
+        (timestamp_seconds - before) / (after - before)
+    } else {
+        0.0
+    }
+}
+
+impl Timestamped for ImuMeasurement {
+    fn timestamp_seconds(&self) -> f64 {
+        self.timestamp_seconds
+    }
+
+    fn interpolate(before: &Self, after: &Self, timestamp_seconds: f64) -> Option<Self> {
+        let factor = interpolation_factor(
+            before.timestamp_seconds,
+            after.timestamp_seconds,
+            timestamp_seconds,
+        );
+        Some(ImuMeasurement::new(
+            timestamp_seconds,
+            before
+                .angular_velocity
+                .lerp(&after.angular_velocity, factor),
+            before
+                .linear_acceleration
+                .lerp(&after.linear_acceleration, factor),
+        ))
+    }
+}
+
+impl Timestamped for WheelOdometryMeasurement {
+    fn timestamp_seconds(&self) -> f64 {
+        self.timestamp_seconds
+    }
+
+    // twists are interpolated linearly, incremental poses are cut at the timestamp (the
+    // interpolated increment covers the motion since `before`)
+    fn interpolate(before: &Self, after: &Self, timestamp_seconds: f64) -> Option<Self> {
+        let factor = interpolation_factor(
+            before.timestamp_seconds,
+            after.timestamp_seconds,
+            timestamp_seconds,
+        );
+        match (&before.data, &after.data) {
+            (
+                WheelOdometryData::Twist {
+                    linear_velocity: linear_before,
+                    angular_velocity: angular_before,
+                },
+                WheelOdometryData::Twist {
+                    linear_velocity: linear_after,
+                    angular_velocity: angular_after,
+                },
+            ) => Some(WheelOdometryMeasurement::twist(
+                timestamp_seconds,
+                linear_before.lerp(linear_after, factor),
+                angular_before.lerp(angular_after, factor),
+            )),
+            (_, WheelOdometryData::IncrementalPose(current_to_previous)) => {
+                Some(WheelOdometryMeasurement::incremental_pose(
+                    timestamp_seconds,
+                    Isometry3F64::exp(&(current_to_previous.log() * factor)),
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum SynchronizationPolicy {
+    // identical timestamps (hardware triggered sensors)
+    Exact,
+    // nearest message within the tolerance
+    Approximate { tolerance_seconds: f64 },
+    // all messages since the previous bundle, closed by a message interpolated at the bundle
+    // timestamp (IMU, odometry)
+    Interpolated,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SynchronizationSourceCfg {
+    pub name: String,
+    pub policy: SynchronizationPolicy,
+    // added to the source timestamps to obtain reference time
+    pub time_offset_seconds: f64,
+    pub maximum_buffer_size: usize,
+    // waiting time (reference clock, measured on the newest message of all sources) for a
+    // message matching a reference timestamp
+    pub maximum_latency_seconds: f64,
+}
+
+impl SynchronizationSourceCfg {
+    pub fn new(name: &str, policy: SynchronizationPolicy) -> Self {
+        Self {
+            name: name.to_string(),
+            policy,
+            time_offset_seconds: 0.0,
+            maximum_buffer_size: 1000,
+            maximum_latency_seconds: 1.0,
+        }
+    }
+
+    pub fn with_time_offset(mut self, time_offset_seconds: f64) -> Self {
+        self.time_offset_seconds = time_offset_seconds;
+        self
+    }
+
+    pub fn with_maximum_latency(mut self, maximum_latency_seconds: f64) -> Self {
+        self.maximum_latency_seconds = maximum_latency_seconds;
+        self
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SynchronizationStatistics {
+    pub received: usize,
+    // rejected on arrival: out of order or buffer overflow
+    pub dropped: usize,
+    // discarded without being part of a bundle
+    pub unmatched: usize,
+    pub matched: usize,
+}
+
+/// time aligned messages, one entry per source in source order
+#[derive(Clone, Debug)]
+pub struct SynchronizedBundle<M> {
+    // reference clock
+    pub timestamp_seconds: f64,
+    // a single message for exact and approximate sources, the messages since the previous
+    // bundle for interpolated sources (the last one at the bundle timestamp); messages keep
+    // the timestamps of their source clock
+    pub messages: Vec<Vec<M>>,
+}
+
+struct Source<M> {
+    cfg: SynchronizationSourceCfg,
+    buffer: VecDeque<M>,
+    statistics: SynchronizationStatistics,
+}
+
+impl<M: Timestamped> Source<M> {
+    fn reference_time(&self, message: &M) -> f64 {
+        message.timestamp_seconds() + self.cfg.time_offset_seconds
+    }
+
+    fn newest_reference_time(&self) -> Option<f64> {
+        self.buffer
+            .back()
+            .map(|message| self.reference_time(message))
+    }
+
+    fn discard_front(&mut self, count: usize) {
+        self.statistics.unmatched += count.min(self.buffer.len());
+        self.buffer.drain(..count.min(self.buffer.len()));
+    }
+}
+
+// outcome of matching a source to a reference timestamp, positions index the source buffer
+enum Match {
+    // more messages are needed to decide
+    Pending,
+    // the expired messages in front of the buffer
+    Unmatched { expired: usize },
+    Single { position: usize },
+    // the first message at or after the reference timestamp
+    Interpolated { after: usize },
+}
+
+pub struct Synchronizer<M> {
+    sources: Vec<Source<M>>,
+    last_bundle_timestamp_seconds: Option<f64>,
+    bundles: usize,
+}
+
+impl<M: Timestamped> Synchronizer<M> {
+    // the first source is the reference, its policy is ignored
+    pub fn new(sources: Vec<SynchronizationSourceCfg>) -> Result<Self> {
+        if sources.is_empty() {
+            bail!("synchronizer without sources");
+        }
+        for cfg in &sources {
+            if cfg.maximum_buffer_size == 0 {
+                bail!("source {}: buffer size must be positive", cfg.name);
+            }
+            if cfg.maximum_latency_seconds.is_nan() || cfg.maximum_latency_seconds < 0.0 {
+                bail!(
+                    "source {}: invalid maximum latency {}",
+                    cfg.name,
+                    cfg.maximum_latency_seconds
+                );
+            }
+            if let SynchronizationPolicy::Approximate { tolerance_seconds } = cfg.policy {
+                if tolerance_seconds < 0.0 {
+                    bail!(
+                        "source {}: negative tolerance {}",
+                        cfg.name,
+                        tolerance_seconds
+                    );
+                }
+            }
+        }
+        Ok(Self {
+            sources: sources
+                .into_iter()
+                .map(|cfg| Source {
+                    cfg,
+                    buffer: VecDeque::new(),
+                    statistics: SynchronizationStatistics::default(),
+                })
+                .collect(),
+            last_bundle_timestamp_seconds: None,
+            bundles: 0,
+        })
+    }
+
+    pub fn number_of_sources(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn source_name(&self, source: usize) -> Option<&str> {
+        self.sources
+            .get(source)
+            .map(|source| source.cfg.name.as_str())
+    }
+
+    pub fn statistics(&self, source: usize) -> Option<&SynchronizationStatistics> {
+        self.sources.get(source).map(|source| &source.statistics)
+    }
+
+    pub fn number_of_bundles(&self) -> usize {
+        self.bundles
+    }
+
+    pub fn set_time_offset(&mut self, source: usize, time_offset_seconds: f64) -> Result<()> {
+        let Some(source) = self.sources.get_mut(source) else {
+            bail!("unknown source {}", source);
+        };
+        source.cfg.time_offset_seconds = time_offset_seconds;
+        Ok(())
+    }
+
+    pub fn push(&mut self, source: usize, message: M) -> Result<()> {
+        let Some(source) = self.sources.get_mut(source) else {
+            bail!("unknown source {}", source);
+        };
+        source.statistics.received += 1;
+        if let Some(last) = source.buffer.back() {
+            if message.timestamp_seconds() <= last.timestamp_seconds() {
+                log::warn!(
+                    "{}: dropping out of order message at {}",
+                    source.cfg.name,
+                    message.timestamp_seconds()
+                );
+                source.statistics.dropped += 1;
+                return Ok(());
+            }
+        }
+        if source.buffer.len() == source.cfg.maximum_buffer_size {
+            log::warn!("{}: buffer full, dropping oldest message", source.cfg.name);
+            source.buffer.pop_front();
+            source.statistics.dropped += 1;
+        }
+        source.buffer.push_back(message);
+        Ok(())
+    }
+
+    // newest timestamp of all sources in the reference clock
+    fn newest_reference_time(&self) -> Option<f64> {
+        self.sources
+            .iter()
+            .filter_map(|source| source.newest_reference_time())
+            .reduce(f64::max)
+    }
+
+    fn find_match(&self, index: usize, timestamp_seconds: f64, newest_seconds: f64) -> Match {
+        let source = &self.sources[index];
+        let timed_out = newest_seconds - timestamp_seconds > source.cfg.maximum_latency_seconds;
+        let Some(newest) = source.newest_reference_time() else {
+            if timed_out {
+                return Match::Unmatched { expired: 0 };
+            }
+            return Match::Pending;
+        };
+        if source.cfg.policy == SynchronizationPolicy::Exact {
+            if let Some(position) = source.buffer.iter().position(|message| {
+                (source.reference_time(message) - timestamp_seconds).abs() < EXACT_TOLERANCE_SECONDS
+            }) {
+                return Match::Single { position };
+            }
+        }
+        // later messages can't get closer to the reference once one is past it
+        if newest < timestamp_seconds {
+            if !timed_out {
+                return Match::Pending;
+            }
+            log::debug!(
+                "{}: no message within {} s after reference timestamp {}",
+                source.cfg.name,
+                source.cfg.maximum_latency_seconds,
+                timestamp_seconds
+            );
+            return match source.cfg.policy {
+                SynchronizationPolicy::Approximate { tolerance_seconds }
+                    if timestamp_seconds - newest <= tolerance_seconds =>
+                {
+                    Match::Single {
+                        position: source.buffer.len() - 1,
+                    }
+                }
+                // the interval stays open for the next reference timestamp
+                SynchronizationPolicy::Interpolated => Match::Unmatched { expired: 0 },
+                _ => Match::Unmatched {
+                    expired: source.buffer.len(),
+                },
+            };
+        }
+        let after = source
+            .buffer
+            .iter()
+            .position(|message| source.reference_time(message) >= timestamp_seconds)
+            .unwrap();
+
+        match source.cfg.policy {
+            // no message within the tolerance on either side
+            SynchronizationPolicy::Exact => Match::Unmatched { expired: after },
+            SynchronizationPolicy::Approximate { tolerance_seconds } => {
+                let difference = |position: usize| {
+                    (source.reference_time(&source.buffer[position]) - timestamp_seconds).abs()
+                };
+                let nearest = if after > 0 && difference(after - 1) < difference(after) {
+                    after - 1
+                } else {
+                    after
+                };
+                if difference(nearest) <= tolerance_seconds {
+                    Match::Single { position: nearest }
+                } else {
+                    Match::Unmatched { expired: after }
+                }
+            }
+            SynchronizationPolicy::Interpolated => {
+                if after == 0 {
+                    // nothing before the reference, the interval is not covered
+                    Match::Unmatched { expired: 0 }
+                } else {
+                    Match::Interpolated { after }
+                }
+            }
+        }
+    }
+
+    fn take_matched(&mut self, index: usize, matched: &Match, timestamp_seconds: f64) -> Vec<M> {
+        let last_bundle_timestamp_seconds = self.last_bundle_timestamp_seconds;
+        let source = &mut self.sources[index];
+        match *matched {
+            Match::Single { position } => {
+                source.discard_front(position);
+                source.statistics.matched += 1;
+                vec![source.buffer.pop_front().unwrap()]
+            }
+            Match::Interpolated { after } => {
+                let before = &source.buffer[after - 1];
+                let after_message = &source.buffer[after];
+                let source_timestamp = timestamp_seconds - source.cfg.time_offset_seconds;
+                let closing = M::interpolate(before, after_message, source_timestamp)
+                    .unwrap_or_else(|| {
+                        if source_timestamp - before.timestamp_seconds()
+                            < after_message.timestamp_seconds() - source_timestamp
+                        {
+                            before.clone()
+                        } else {
+                            after_message.clone()
+                        }
+                    });
+
+                let mut messages: Vec<M> = source
+                    .buffer
+                    .iter()
+                    .take(after)
+                    .filter(|message| {
+                        last_bundle_timestamp_seconds
+                            .is_none_or(|last| source.reference_time(message) > last)
+                    })
+                    .cloned()
+                    .collect();
+                source.statistics.matched += messages.len();
+                messages.push(closing);
+
+                // keep the message before the reference for the next interval
+                source.buffer.drain(..after - 1);
+                messages
+            }
+            Match::Pending | Match::Unmatched { .. } => vec![],
+        }
+    }
+
+    // next complete bundle, None while messages of some source are still missing
+    pub fn pop_bundle(&mut self) -> Option<SynchronizedBundle<M>> {
+        loop {
+            let reference = &self.sources[0];
+            let timestamp_seconds = reference.reference_time(reference.buffer.front()?);
+
+            let newest_seconds = self.newest_reference_time()?;
+            let matches: Vec<Match> = (1..self.sources.len())
+                .map(|index| self.find_match(index, timestamp_seconds, newest_seconds))
+                .collect();
+            if matches
+                .iter()
+                .any(|matched| matches!(matched, Match::Pending))
+            {
+                return None;
+            }
+
+            if matches
+                .iter()
+                .any(|matched| matches!(matched, Match::Unmatched { .. }))
+            {
+                // matched messages stay buffered for the next reference message
+                for (index, matched) in matches.iter().enumerate() {
+                    if let Match::Unmatched { expired } = matched {
+                        log::debug!(
+                            "{}: no match for reference timestamp {}",
+                            self.sources[index + 1].cfg.name,
+                            timestamp_seconds
+                        );
+                        self.sources[index + 1].discard_front(*expired);
+                    }
+                }
+                self.sources[0].discard_front(1);
+                continue;
+            }
+
+            let mut messages = Vec::with_capacity(self.sources.len());
+            let reference = &mut self.sources[0];
+            reference.statistics.matched += 1;
+            messages.push(vec![reference.buffer.pop_front().unwrap()]);
+            for (index, matched) in matches.iter().enumerate() {
+                messages.push(self.take_matched(index + 1, matched, timestamp_seconds));
+            }
+            self.last_bundle_timestamp_seconds = Some(timestamp_seconds);
+            self.bundles += 1;
+            return Some(SynchronizedBundle {
+                timestamp_seconds,
+                messages,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sophus::nalgebra::Vector3;
+
+    // the angular velocity encodes the timestamp to check interpolated messages
+    fn message(timestamp_seconds: f64) -> ImuMeasurement {
+        ImuMeasurement::new(
+            timestamp_seconds,
+            Vector3::new(timestamp_seconds, 0.0, 0.0),
+            Vector3::zeros(),
+        )
+    }
+
+    fn synchronizer(secondary: SynchronizationSourceCfg) -> Synchronizer<ImuMeasurement> {
+        Synchronizer::new(vec![
+            SynchronizationSourceCfg::new("camera", SynchronizationPolicy::Exact),
+            secondary,
+        ])
+        .unwrap()
+    }
+
+    fn push_all(
+        synchronizer: &mut Synchronizer<ImuMeasurement>,
+        source: usize,
+        timestamps: &[f64],
+    ) {
+        for timestamp_seconds in timestamps {
+            synchronizer
+                .push(source, message(*timestamp_seconds))
+                .unwrap();
+        }
+    }
+
+    fn bundles(synchronizer: &mut Synchronizer<ImuMeasurement>) -> Vec<(f64, Vec<f64>)> {
+        std::iter::from_fn(|| synchronizer.pop_bundle())
+            .map(|bundle| {
+                assert_eq!(bundle.messages[0].len(), 1);
+                (
+                    bundle.timestamp_seconds,
+                    bundle.messages[1]
+                        .iter()
+                        .map(|message| message.timestamp_seconds)
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn exact_policy_matches_timestamps_on_both_sides() {
+        let mut synchronizer = synchronizer(SynchronizationSourceCfg::new(
+            "camera_right",
+            SynchronizationPolicy::Exact,
+        ));
+        push_all(&mut synchronizer, 0, &[0.0, 0.1, 0.2, 0.3]);
+        push_all(&mut synchronizer, 1, &[0.05, 0.1 + 1e-12, 0.2 - 1e-12, 0.3]);
+
+        assert_eq!(
+            bundles(&mut synchronizer),
+            vec![
+                (0.1, vec![0.1 + 1e-12]),
+                (0.2, vec![0.2 - 1e-12]),
+                (0.3, vec![0.3])
+            ]
+        );
+        // the reference at 0 and the secondary at 0.05 have no partner
+        assert_eq!(synchronizer.statistics(0).unwrap().unmatched, 1);
+        assert_eq!(synchronizer.statistics(1).unwrap().unmatched, 1);
+        assert_eq!(synchronizer.number_of_bundles(), 3);
+    }
+
+    #[test]
+    fn approximate_policy_picks_the_nearest_message_within_the_tolerance() {
+        let mut synchronizer = synchronizer(SynchronizationSourceCfg::new(
+            "lidar",
+            SynchronizationPolicy::Approximate {
+                tolerance_seconds: 0.02,
+            },
+        ));
+        push_all(&mut synchronizer, 0, &[0.0, 0.1, 0.2]);
+        push_all(&mut synchronizer, 1, &[0.005, 0.095, 0.11, 0.25]);
+
+        assert_eq!(
+            bundles(&mut synchronizer),
+            vec![(0.0, vec![0.005]), (0.1, vec![0.095])]
+        );
+        // 0.11 expired without a match, 0.25 is too far from 0.2
+        assert_eq!(synchronizer.statistics(0).unwrap().unmatched, 1);
+        assert_eq!(synchronizer.statistics(1).unwrap().unmatched, 1);
+        assert_eq!(synchronizer.statistics(1).unwrap().matched, 2);
+    }
+
+    #[test]
+    fn interpolated_policy_closes_the_interval_at_the_reference() {
+        let mut synchronizer = synchronizer(SynchronizationSourceCfg::new(
+            "imu",
+            SynchronizationPolicy::Interpolated,
+        ));
+        push_all(&mut synchronizer, 0, &[0.01, 0.11]);
+        let imu: Vec<f64> = (0..20)
+            .map(|sample| -0.05 + sample as f64 * 0.025)
+            .collect();
+        push_all(&mut synchronizer, 1, &imu);
+
+        let first = synchronizer.pop_bundle().unwrap();
+        let timestamps: Vec<f64> = first.messages[1]
+            .iter()
+            .map(|message| message.timestamp_seconds)
+            .collect();
+        assert_eq!(timestamps, vec![-0.05, -0.025, 0.0, 0.01]);
+        assert!((first.messages[1][3].angular_velocity.x - 0.01).abs() < 1e-12);
+
+        // the interval starts after the previous bundle
+        let second = synchronizer.pop_bundle().unwrap();
+        let timestamps: Vec<f64> = second.messages[1]
+            .iter()
+            .map(|message| message.timestamp_seconds)
+            .collect();
+        assert_eq!(timestamps.len(), 5);
+        assert!(timestamps[..4]
+            .iter()
+            .all(|timestamp_seconds| *timestamp_seconds > 0.01 && *timestamp_seconds < 0.11));
+        assert!((second.messages[1][4].angular_velocity.x - 0.11).abs() < 1e-12);
+        assert!(synchronizer.pop_bundle().is_none());
+    }
+
+    #[test]
+    fn time_offsets_shift_sources_into_the_reference_clock() {
+        let mut synchronizer = synchronizer(
+            SynchronizationSourceCfg::new(
+                "lidar",
+                SynchronizationPolicy::Approximate {
+                    tolerance_seconds: 1e-3,
+                },
+            )
+            .with_time_offset(0.5),
+        );
+        push_all(&mut synchronizer, 0, &[0.1, 0.2]);
+        push_all(&mut synchronizer, 1, &[-0.4, -0.3]);
+        // messages keep their source timestamps
+        assert_eq!(
+            bundles(&mut synchronizer),
+            vec![(0.1, vec![-0.4]), (0.2, vec![-0.3])]
+        );
+
+        synchronizer.set_time_offset(1, 0.0).unwrap();
+        push_all(&mut synchronizer, 0, &[0.3]);
+        push_all(&mut synchronizer, 1, &[0.3]);
+        assert_eq!(bundles(&mut synchronizer), vec![(0.3, vec![0.3])]);
+        assert!(synchronizer.set_time_offset(2, 0.0).is_err());
+    }
+
+    #[test]
+    fn silent_sources_time_out() {
+        let mut synchronizer = synchronizer(
+            SynchronizationSourceCfg::new("imu", SynchronizationPolicy::Interpolated)
+                .with_maximum_latency(0.25),
+        );
+        let reference: Vec<f64> = (0..=10).map(|frame| frame as f64 * 0.1).collect();
+        push_all(&mut synchronizer, 0, &reference);
+
+        // the references up to 0.7 wait longer than the latency
+        assert!(synchronizer.pop_bundle().is_none());
+        assert_eq!(synchronizer.statistics(0).unwrap().unmatched, 8);
+
+        push_all(&mut synchronizer, 1, &[0.75, 0.85]);
+        assert_eq!(bundles(&mut synchronizer), vec![(0.8, vec![0.75, 0.8])]);
+    }
+
+    #[test]
+    fn drops_out_of_order_messages_and_overflowing_buffers() {
+        let mut cfg = SynchronizationSourceCfg::new("imu", SynchronizationPolicy::Interpolated);
+        cfg.maximum_buffer_size = 3;
+        let mut synchronizer = synchronizer(cfg);
+        push_all(&mut synchronizer, 1, &[0.1, 0.3, 0.2, 0.3, 0.4, 0.5]);
+
+        let statistics = synchronizer.statistics(1).unwrap();
+        assert_eq!(statistics.received, 6);
+        // 0.2 and the second 0.3 are out of order, 0.1 overflows
+        assert_eq!(statistics.dropped, 3);
+        let buffered: Vec<f64> = synchronizer.sources[1]
+            .buffer
+            .iter()
+            .map(|message| message.timestamp_seconds)
+            .collect();
+        assert_eq!(buffered, vec![0.3, 0.4, 0.5]);
+        assert!(synchronizer.push(2, message(0.6)).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_sources() {
+        assert!(Synchronizer::<ImuMeasurement>::new(vec![]).is_err());
+        let mut cfg = SynchronizationSourceCfg::new("imu", SynchronizationPolicy::Interpolated);
+        cfg.maximum_buffer_size = 0;
+        assert!(Synchronizer::<ImuMeasurement>::new(vec![cfg]).is_err());
+        let cfg = SynchronizationSourceCfg::new(
+            "lidar",
+            SynchronizationPolicy::Approximate {
+                tolerance_seconds: -1.0,
+            },
+        );
+        assert!(Synchronizer::<ImuMeasurement>::new(vec![cfg]).is_err());
+        let cfg = SynchronizationSourceCfg::new("imu", SynchronizationPolicy::Interpolated)
+            .with_maximum_latency(f64::NAN);
+        assert!(Synchronizer::<ImuMeasurement>::new(vec![cfg]).is_err());
+    }
+}
@@ -0,0 +1,330 @@
+use serde::Deserialize;
+use sophus::{
+    core::linalg::VecF64,
+    lie::Isometry3F64,
+    nalgebra::{Matrix6, Vector3},
+};
+
+#[derive(Clone, Debug)]
+pub enum WheelOdometryData {
+    // body velocities in the odometry frame, held until the next measurement (m/s, rad/s)
+    Twist {
+        linear_velocity: Vector3<f64>,
+        angular_velocity: Vector3<f64>,
+    },
+    // motion since the previous measurement, current odometry frame to previous odometry frame
+    IncrementalPose(Isometry3F64),
+}
+
+#[derive(Clone, Debug)]
+pub struct WheelOdometryMeasurement {
+    pub timestamp_seconds: f64,
+    pub data: WheelOdometryData,
+}
+
+impl WheelOdometryMeasurement {
+    pub fn twist(
+        timestamp_seconds: f64,
+        linear_velocity: Vector3<f64>,
+        angular_velocity: Vector3<f64>,
+    ) -> Self {
+        Self {
+            timestamp_seconds,
+            data: WheelOdometryData::Twist {
+                linear_velocity,
+                angular_velocity,
+            },
+        }
+    }
+
+    pub fn incremental_pose(timestamp_seconds: f64, current_to_previous: Isometry3F64) -> Self {
+        Self {
+            timestamp_seconds,
+            data: WheelOdometryData::IncrementalPose(current_to_previous),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct WheelOdometryNoiseParameters {
+    // twist measurements (m/s, rad/s)
+    pub linear_velocity_sigma: f64,
+    pub angular_velocity_sigma: f64,
+    // incremental pose measurements, relative to the travelled distance (m/m) and angle (rad/rad)
+    pub translation_drift: f64,
+    pub rotation_drift: f64,
+    // lower bound of the uncertainty of a relative motion (m, rad), keeps the information of a
+    // standing robot finite
+    pub minimum_translation_sigma: f64,
+    pub minimum_rotation_sigma: f64,
+}
+
+impl Default for WheelOdometryNoiseParameters {
+    fn default() -> Self {
+        Self {
+            linear_velocity_sigma: 0.05,
+            angular_velocity_sigma: 0.02,
+            translation_drift: 0.02,
+            rotation_drift: 0.01,
+            minimum_translation_sigma: 1e-3,
+            minimum_rotation_sigma: 1e-3,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WheelOdometry {
+    pub noise: WheelOdometryNoiseParameters,
+    odometry_to_robot: Isometry3F64,
+}
+
+impl WheelOdometry {
+    pub fn new(noise: WheelOdometryNoiseParameters, odometry_to_robot: Isometry3F64) -> Self {
+        Self {
+            noise,
+            odometry_to_robot,
+        }
+    }
+
+    pub fn odometry_to_robot(&self) -> &Isometry3F64 {
+        &self.odometry_to_robot
+    }
+
+    // motion and noise of a measurement, scaled by the covered time for twists and by the
+    // covered fraction of the interval for incremental poses
+    fn increment(
+        &self,
+        measurement: &WheelOdometryMeasurement,
+        scale: f64,
+    ) -> (Isometry3F64, Matrix6<f64>) {
+        match &measurement.data {
+            WheelOdometryData::Twist {
+                linear_velocity,
+                angular_velocity,
+            } => {
+                let tangent = VecF64::<6>::new(
+                    linear_velocity.x,
+                    linear_velocity.y,
+                    linear_velocity.z,
+                    angular_velocity.x,
+                    angular_velocity.y,
+                    angular_velocity.z,
+                ) * scale;
+                let translation_variance = (self.noise.linear_velocity_sigma * scale).powi(2);
+                let rotation_variance = (self.noise.angular_velocity_sigma * scale).powi(2);
+                (
+                    Isometry3F64::exp(&tangent),
+                    Matrix6::from_diagonal(&VecF64::<6>::new(
+                        translation_variance,
+                        translation_variance,
+                        translation_variance,
+                        rotation_variance,
+                        rotation_variance,
+                        rotation_variance,
+                    )),
+                )
+            }
+            WheelOdometryData::IncrementalPose(current_to_previous) => {
+                let tangent = current_to_previous.log() * scale;
+                let translation_sigma =
+                    self.noise.translation_drift * tangent.fixed_rows::<3>(0).norm();
+                let rotation_sigma = self.noise.rotation_drift * tangent.fixed_rows::<3>(3).norm();
+                (
+                    Isometry3F64::exp(&tangent),
+                    Matrix6::from_diagonal(&VecF64::<6>::new(
+                        translation_sigma.powi(2),
+                        translation_sigma.powi(2),
+                        translation_sigma.powi(2),
+                        rotation_sigma.powi(2),
+                        rotation_sigma.powi(2),
+                        rotation_sigma.powi(2),
+                    )),
+                )
+            }
+        }
+    }
+
+    // integrates the measurements (sorted by timestamp) into the robot motion between two
+    // timestamps: robot frame at timestamp_end to robot frame at timestamp_begin, with its
+    // covariance (translation, rotation; right perturbation)
+    //
+    // twists hold from their timestamp until the next measurement, incremental poses cover the
+    // interval since the previous measurement, partially covered intervals are interpolated.
+    // None if the interval is empty or not covered by the measurements
+    pub fn relative_motion(
+        &self,
+        measurements: &[WheelOdometryMeasurement],
+        timestamp_begin: f64,
+        timestamp_end: f64,
+    ) -> Option<(Isometry3F64, Matrix6<f64>)> {
+        if timestamp_end <= timestamp_begin || measurements.len() < 2 {
+            return None;
+        }
+        if measurements[0].timestamp_seconds > timestamp_begin
+            || measurements[measurements.len() - 1].timestamp_seconds < timestamp_end
+        {
+            return None;
+        }
+
+        let mut end_to_begin = Isometry3F64::identity();
+        let translation_variance = self.noise.minimum_translation_sigma.powi(2);
+        let rotation_variance = self.noise.minimum_rotation_sigma.powi(2);
+        let mut covariance = Matrix6::from_diagonal(&VecF64::<6>::new(
+            translation_variance,
+            translation_variance,
+            translation_variance,
+            rotation_variance,
+            rotation_variance,
+            rotation_variance,
+        ));
+        for pair in measurements.windows(2) {
+            let (start, end) = (pair[0].timestamp_seconds, pair[1].timestamp_seconds);
+            let overlap = end.min(timestamp_end) - start.max(timestamp_begin);
+            if overlap <= 0.0 || end <= start {
+                continue;
+            }
+            let (measurement, scale) = match (&pair[0].data, &pair[1].data) {
+                (_, WheelOdometryData::IncrementalPose(_)) => (&pair[1], overlap / (end - start)),
+                (WheelOdometryData::Twist { .. }, _) => (&pair[0], overlap),
+                // the twist holds from its own timestamp on, the interval before it is unknown
+                (WheelOdometryData::IncrementalPose(_), WheelOdometryData::Twist { .. }) => {
+                    log::warn!(
+                        "wheel odometry switches from incremental poses to twists at {}",
+                        end
+                    );
+                    return None;
+                }
+            };
+            let (increment, increment_covariance) = self.increment(measurement, scale);
+            let adjoint = increment.inverse().adj();
+            covariance = adjoint * covariance * adjoint.transpose() + increment_covariance;
+            end_to_begin = end_to_begin.group_mul(&increment);
+        }
+
+        let adjoint = self.odometry_to_robot.adj();
+        Some((
+            self.odometry_to_robot
+                .group_mul(&end_to_begin)
+                .group_mul(&self.odometry_to_robot.inverse()),
+            adjoint * covariance * adjoint.transpose(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn odometry(odometry_to_robot: Isometry3F64) -> WheelOdometry {
+        WheelOdometry::new(WheelOdometryNoiseParameters::default(), odometry_to_robot)
+    }
+
+    fn twist_tangent() -> VecF64<6> {
+        VecF64::<6>::new(1.0, 0.1, 0.0, 0.0, 0.0, 0.5)
+    }
+
+    fn twists(timestamps: &[f64]) -> Vec<WheelOdometryMeasurement> {
+        let tangent = twist_tangent();
+        timestamps
+            .iter()
+            .map(|timestamp_seconds| {
+                WheelOdometryMeasurement::twist(
+                    *timestamp_seconds,
+                    tangent.fixed_rows::<3>(0).into_owned(),
+                    tangent.fixed_rows::<3>(3).into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    fn pose_error(a: &Isometry3F64, b: &Isometry3F64) -> f64 {
+        a.inverse().group_mul(b).log().norm()
+    }
+
+    #[test]
+    fn integrates_constant_twists() {
+        let odometry = odometry(Isometry3F64::identity());
+        let measurements = twists(&[0.0, 0.5, 1.0, 1.5]);
+
+        let (end_to_begin, covariance) = odometry.relative_motion(&measurements, 0.0, 1.0).unwrap();
+        assert!(pose_error(&end_to_begin, &Isometry3F64::exp(&twist_tangent())) < 1e-12);
+        // partially covered intervals
+        let (end_to_begin, covariance_partial) =
+            odometry.relative_motion(&measurements, 0.25, 0.75).unwrap();
+        assert!(pose_error(&end_to_begin, &Isometry3F64::exp(&(twist_tangent() * 0.5))) < 1e-12);
+        assert!(covariance.trace() > covariance_partial.trace());
+        assert!((covariance - covariance.transpose()).norm() < 1e-15);
+    }
+
+    #[test]
+    fn integrates_incremental_poses() {
+        let odometry = odometry(Isometry3F64::identity());
+        let increment = VecF64::<6>::new(0.5, 0.0, 0.02, 0.0, 0.01, 0.2);
+        let measurements: Vec<_> = [0.0, 0.5, 1.0]
+            .iter()
+            .map(|timestamp_seconds| {
+                WheelOdometryMeasurement::incremental_pose(
+                    *timestamp_seconds,
+                    Isometry3F64::exp(&increment),
+                )
+            })
+            .collect();
+
+        let (end_to_begin, _) = odometry.relative_motion(&measurements, 0.0, 1.0).unwrap();
+        assert!(pose_error(&end_to_begin, &Isometry3F64::exp(&(increment * 2.0))) < 1e-12);
+        let (end_to_begin, _) = odometry.relative_motion(&measurements, 0.25, 1.0).unwrap();
+        assert!(pose_error(&end_to_begin, &Isometry3F64::exp(&(increment * 1.5))) < 1e-12);
+    }
+
+    #[test]
+    fn expresses_the_motion_in_the_robot_frame() {
+        let odometry_to_robot = Isometry3F64::exp(&VecF64::<6>::new(0.3, 0.0, 0.1, 0.0, 0.0, 1.0));
+        let odometry = odometry(odometry_to_robot);
+        let (end_to_begin, _) = odometry
+            .relative_motion(&twists(&[0.0, 1.0]), 0.0, 1.0)
+            .unwrap();
+        let expected = odometry_to_robot
+            .group_mul(&Isometry3F64::exp(&twist_tangent()))
+            .group_mul(&odometry_to_robot.inverse());
+        assert!(pose_error(&end_to_begin, &expected) < 1e-12);
+    }
+
+    #[test]
+    fn keeps_the_covariance_of_a_standing_robot_positive() {
+        let odometry = odometry(Isometry3F64::identity());
+        let measurements: Vec<_> = [0.0, 0.5, 1.0]
+            .iter()
+            .map(|timestamp_seconds| {
+                WheelOdometryMeasurement::incremental_pose(
+                    *timestamp_seconds,
+                    Isometry3F64::identity(),
+                )
+            })
+            .collect();
+        let (end_to_begin, covariance) = odometry.relative_motion(&measurements, 0.0, 1.0).unwrap();
+        assert!(end_to_begin.log().norm() < 1e-12);
+        assert!(covariance.cholesky().is_some());
+        assert!(
+            (covariance[(0, 0)] - odometry.noise.minimum_translation_sigma.powi(2)).abs() < 1e-15
+        );
+    }
+
+    #[test]
+    fn rejects_uncovered_intervals() {
+        let odometry = odometry(Isometry3F64::identity());
+        let measurements = twists(&[0.0, 1.0]);
+        assert!(odometry.relative_motion(&measurements, 0.5, 0.5).is_none());
+        assert!(odometry.relative_motion(&measurements, 0.5, 0.25).is_none());
+        assert!(odometry.relative_motion(&measurements, -0.5, 0.5).is_none());
+        assert!(odometry.relative_motion(&measurements, 0.5, 1.5).is_none());
+
+        // an incremental pose followed by a twist leaves the interval between them open
+        let mixed = vec![
+            WheelOdometryMeasurement::incremental_pose(0.0, Isometry3F64::identity()),
+            WheelOdometryMeasurement::incremental_pose(0.5, Isometry3F64::identity()),
+            twists(&[1.0])[0].clone(),
+        ];
+        assert!(odometry.relative_motion(&mixed, 0.0, 0.5).is_some());
+        assert!(odometry.relative_motion(&mixed, 0.0, 1.0).is_none());
+    }
+}
@@ -0,0 +1,218 @@
+//! stereo calibration from a directory of target images
+//!
+//! usage: stereo_calibration <image directory> <target> <output file> [--kalibr]
+//!
+//! The image directory contains the subdirectories `left` and `right` with equally named
+//! images of each stereo pair. The target is given as `chessboard:<rows>x<cols>:<square size>`
+//! (inner corners) or `aprilgrid:<rows>x<cols>:<tag size>:<tag spacing>`, sizes in meters.
+//! The result is written as OpenCV stereo YAML, or as Kalibr camchain with `--kalibr`.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use opencv::{
+    calib3d,
+    core::{Point2f, Size, TermCriteria, TermCriteria_Type, Vector},
+    imgcodecs::{imread, IMREAD_GRAYSCALE},
+    imgproc, objdetect,
+    prelude::*,
+};
+use rslam_core::PixelCoordinates;
+use rslam_sensor::calibration::{
+    kalibr, opencv_yaml,
+    stereo_calibrator::{calibrate_stereo, StereoCalibratorCfg},
+    target::{CalibrationTarget, StereoObservation, TargetDetection},
+};
+use sophus::image::ImageSize;
+
+fn detect_chessboard(image: &Mat, rows: usize, cols: usize) -> Result<TargetDetection> {
+    let mut corners = Vector::<Point2f>::new();
+    // opencv expects the pattern size as (points per row, points per column)
+    let found = calib3d::find_chessboard_corners(
+        image,
+        Size::new(cols as i32, rows as i32),
+        &mut corners,
+        calib3d::CALIB_CB_ADAPTIVE_THRESH + calib3d::CALIB_CB_NORMALIZE_IMAGE,
+    )?;
+    if !found {
+        return Ok(TargetDetection::default());
+    }
+    imgproc::corner_sub_pix(
+        image,
+        &mut corners,
+        Size::new(11, 11),
+        Size::new(-1, -1),
+        TermCriteria::new(
+            TermCriteria_Type::COUNT as i32 + TermCriteria_Type::EPS as i32,
+            30,
+            0.01,
+        )?,
+    )?;
+    Ok(TargetDetection {
+        corner_ids: (0..corners.len()).collect(),
+        pixels: corners
+            .iter()
+            .map(|corner| PixelCoordinates::new(corner.x as f64, corner.y as f64))
+            .collect(),
+    })
+}
+
+fn detect_aprilgrid(
+    detector: &objdetect::ArucoDetector,
+    image: &Mat,
+    number_of_tags: usize,
+) -> Result<TargetDetection> {
+    let mut tag_corners = Vector::<Vector<Point2f>>::new();
+    let mut tag_ids = Vector::<i32>::new();
+    let mut rejected = Vector::<Vector<Point2f>>::new();
+    detector.detect_markers(image, &mut tag_corners, &mut tag_ids, &mut rejected)?;
+
+    let mut detection = TargetDetection::default();
+    for (tag_id, corners) in tag_ids.iter().zip(tag_corners.iter()) {
+        let tag_id = tag_id as usize;
+        if tag_id >= number_of_tags {
+            continue;
+        }
+        // corners are ordered clockwise starting top left, as in CalibrationTarget
+        for (corner_index, corner) in corners.iter().enumerate() {
+            detection.corner_ids.push(4 * tag_id + corner_index);
+            detection
+                .pixels
+                .push(PixelCoordinates::new(corner.x as f64, corner.y as f64));
+        }
+    }
+    Ok(detection)
+}
+
+struct TargetDetector {
+    target: CalibrationTarget,
+    aruco_detector: Option<objdetect::ArucoDetector>,
+}
+
+impl TargetDetector {
+    fn new(target: CalibrationTarget) -> Result<Self> {
+        let aruco_detector = match target {
+            CalibrationTarget::AprilGrid { .. } => {
+                let dictionary = objdetect::get_predefined_dictionary(
+                    objdetect::PredefinedDictionaryType::DICT_APRILTAG_36h11,
+                )?;
+                Some(objdetect::ArucoDetector::new(
+                    &dictionary,
+                    &objdetect::DetectorParameters::default()?,
+                    objdetect::RefineParameters::new(10.0, 3.0, true)?,
+                )?)
+            }
+            CalibrationTarget::Chessboard { .. } => None,
+        };
+        Ok(Self {
+            target,
+            aruco_detector,
+        })
+    }
+
+    fn detect(&self, image: &Mat) -> Result<TargetDetection> {
+        match (&self.target, &self.aruco_detector) {
+            (CalibrationTarget::Chessboard { rows, cols, .. }, _) => {
+                detect_chessboard(image, *rows, *cols)
+            }
+            (CalibrationTarget::AprilGrid { rows, cols, .. }, Some(detector)) => {
+                detect_aprilgrid(detector, image, rows * cols)
+            }
+            _ => bail!("no detector for target {:?}", self.target),
+        }
+    }
+}
+
+fn read_image(path: &Path) -> Result<Mat> {
+    let image = imread(&path.display().to_string(), IMREAD_GRAYSCALE)?;
+    if image.empty() {
+        bail!("cannot read image {:?}", path);
+    }
+    Ok(image)
+}
+
+fn image_names(directory: &Path) -> Result<Vec<String>> {
+    let mut names = std::fs::read_dir(directory)
+        .with_context(|| format!("listing {:?}", directory))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+fn main() -> Result<()> {
+    env_logger::init();
+
+    let arguments: Vec<String> = std::env::args().collect();
+    if arguments.len() < 4 {
+        bail!(
+            "usage: {} <image directory> <target> <output file> [--kalibr]",
+            arguments[0]
+        );
+    }
+    let image_directory = PathBuf::from(&arguments[1]);
+    let target: CalibrationTarget = arguments[2].parse()?;
+    let output_path = PathBuf::from(&arguments[3]);
+    let write_kalibr = arguments[4..].iter().any(|argument| argument == "--kalibr");
+
+    let detector = TargetDetector::new(target.clone())?;
+    let mut observations = vec![];
+    let mut image_size = None;
+    for name in image_names(&image_directory.join("left"))? {
+        let left = read_image(&image_directory.join("left").join(&name))?;
+        let right_path = image_directory.join("right").join(&name);
+        if !right_path.exists() {
+            log::warn!("no right image for {}", name);
+            continue;
+        }
+        let right = read_image(&right_path)?;
+        image_size.get_or_insert(ImageSize::new(left.cols() as usize, left.rows() as usize));
+
+        let detection_left = detector.detect(&left)?;
+        let detection_right = detector.detect(&right)?;
+        match StereoObservation::from_detections(&name, &target, &detection_left, &detection_right)
+        {
+            Some(observation) => {
+                log::info!(
+                    "{}: {} common corners",
+                    name,
+                    observation.points_in_target.len()
+                );
+                observations.push(observation);
+            }
+            None => log::warn!("{}: target not found in both images", name),
+        }
+    }
+    let Some(image_size) = image_size else {
+        bail!("no stereo pairs found in {:?}", image_directory);
+    };
+
+    let calibration = calibrate_stereo(&StereoCalibratorCfg::default(), &observations, image_size)?;
+
+    println!("image                     rms left (px)  rms right (px)");
+    for view_error in &calibration.view_errors {
+        println!(
+            "{:<25} {:>13.3}  {:>14.3}",
+            view_error.name, view_error.rms_left_pixels, view_error.rms_right_pixels
+        );
+    }
+    println!(
+        "overall rms reprojection error (px): {:.3}",
+        calibration.rms_pixels
+    );
+    println!(
+        "baseline (m): {:.5}",
+        calibration.stereo_camera.baseline_meters()
+    );
+
+    let stereo_camera = calibration.stereo_camera;
+    if write_kalibr {
+        kalibr::write_camchain(&output_path, &[stereo_camera.left, stereo_camera.right])?;
+    } else {
+        opencv_yaml::write_stereo_camera(&output_path, &stereo_camera)?;
+    }
+    println!("calibration written to {:?}", output_path);
+    Ok(())
+}